@@ -0,0 +1,143 @@
+//! Structures passed across the syscall boundary
+//!
+//! The kernel and user code both use these definitions, so they always agree on the layout. Each
+//! is repr(C), with any padding spelled out, and its size is checked below. Fields are only ever
+//! added in place of padding or at the end.
+
+use core::mem::size_of;
+use core::str;
+
+// The version of the syscall interface, raised whenever a syscall or structure is added. Existing
+// syscalls never change, so a kernel serves any program built for its version or an older one.
+pub const ABI_VERSION: u32 = 6;
+
+// Optional parts of the interface a kernel may leave out, as bits of AbiVersion::features
+pub const FEATURE_FDS: u32 = 1 << 0;       // File descriptors: Syscall::Open, Read, Write and Close
+pub const FEATURE_SIGNALS: u32 = 1 << 1;   // Signal handlers: Syscall::SigAction and SigReturn
+pub const FEATURE_MMAP: u32 = 1 << 2;      // Mapping files and anonymous memory
+pub const FEATURE_NET: u32 = 1 << 3;       // UDP through Syscall::SendTo and RecvFrom
+
+// Longest file name a DirEnt holds.
+pub const DIRENT_NAME_MAX: usize = 248;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Signals a process can set a handler for with Syscall::SigAction
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt = 2,      // Ctrl-C was typed, and the process is in the foreground
+}
+
+impl TryFrom<usize> for Signal {
+    type Error = usize;
+
+    fn try_from(signo: usize) -> Result<Self, usize> {
+        match signo {
+            2 => Ok(Signal::Interrupt),
+            _ => Err(signo),
+        }
+    }
+}
+
+// What Syscall::Fstat reports about a file.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,      // In bytes
+    pub inode: u64,     // Identifies the file within its file system
+}
+
+// What Syscall::GetVersion reports about the kernel.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AbiVersion {
+    pub version: u32,   // ABI_VERSION the kernel was built with
+    pub features: u32,  // FEATURE_* bits for what it supports
+}
+
+// A directory entry, as Syscall::ReadDir fills it in.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirEnt {
+    pub inode: u32,
+    pub name_len: u32,
+    pub name: [u8; DIRENT_NAME_MAX],
+}
+
+// A time as Syscall::ClockGettime reports it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeSpec {
+    pub sec: u64,
+    pub nsec: u32,      // Below one second
+    pub _pad: u32,
+}
+
+// The program and arguments for Syscall::Spawn. `argv` holds each argument, starting with the
+// path, followed by a NUL. The pointers are into the calling process's memory.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SpawnArgs {
+    pub path: *const u8,
+    pub path_len: usize,
+    pub argv: *const u8,
+    pub argv_len: usize,
+}
+
+const _: () = assert!(size_of::<AbiVersion>() == 8);
+const _: () = assert!(size_of::<Stat>() == 16);
+const _: () = assert!(size_of::<DirEnt>() == 256);
+const _: () = assert!(size_of::<TimeSpec>() == 16);
+const _: () = assert!(size_of::<SpawnArgs>() == 4 * size_of::<usize>());
+
+impl DirEnt {
+    // Fails if `name` is longer than DIRENT_NAME_MAX.
+    pub fn new(inode: u32, name: &str) -> Option<Self> {
+        let mut entry = DirEnt { inode, name_len: name.len() as u32, name: [0; DIRENT_NAME_MAX] };
+        entry.name.get_mut(..name.len())?.copy_from_slice(name.as_bytes());
+        Some(entry)
+    }
+
+    pub fn name(&self) -> &str {
+        let name = self.name.get(..self.name_len as usize).unwrap_or(&self.name);
+        str::from_utf8(name).unwrap_or_default()
+    }
+}
+
+impl Default for DirEnt {
+    fn default() -> Self {
+        DirEnt { inode: 0, name_len: 0, name: [0; DIRENT_NAME_MAX] }
+    }
+}
+
+impl TimeSpec {
+    pub const fn from_nanos(ns: u64) -> Self {
+        TimeSpec { sec: ns / NANOS_PER_SEC, nsec: (ns % NANOS_PER_SEC) as u32, _pad: 0 }
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.sec * NANOS_PER_SEC + self.nsec as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirent_name_round_trips() {
+        let entry = DirEnt::new(3, "hello.txt").unwrap();
+        assert_eq!(entry.name(), "hello.txt");
+        assert_eq!(entry.inode, 3);
+        assert!(DirEnt::new(0, &"x".repeat(DIRENT_NAME_MAX)).is_some());
+        assert!(DirEnt::new(0, &"x".repeat(DIRENT_NAME_MAX + 1)).is_none());
+    }
+
+    #[test]
+    fn timespec_splits_nanos() {
+        let t = TimeSpec::from_nanos(3 * NANOS_PER_SEC + 42);
+        assert_eq!((t.sec, t.nsec), (3, 42));
+        assert_eq!(t.as_nanos(), 3 * NANOS_PER_SEC + 42);
+    }
+}
@@ -0,0 +1,270 @@
+//! Fixed-capacity collections
+//!
+//! ArrayVec and ArrayString hold up to N items in place, with no heap, so the kernel and
+//! programs without an allocator can use them. Adding past the capacity fails rather than
+//! growing.
+
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+
+// Returned when an item does not fit in what is left of a collection's capacity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityError;
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("capacity exceeded")
+    }
+}
+
+// A vector of up to N Copy items. The first `len` are initialised.
+#[derive(Clone, Copy)]
+pub struct ArrayVec<T: Copy, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> ArrayVec<T, N> {
+    pub const fn new() -> Self {
+        Self { items: [const { MaybeUninit::uninit() }; N], len: 0 }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Adds `item` at the end, or hands it back if the vector is full.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[self.len].write(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.len = self.len.checked_sub(1)?;
+        // Safety: the item was below `len`, so it is initialised.
+        Some(unsafe { self.items[self.len].assume_init() })
+    }
+
+    /// Adds all of `items` at the end, or none of them if they do not fit.
+    pub fn extend_from_slice(&mut self, items: &[T]) -> Result<(), CapacityError> {
+        if items.len() > N - self.len {
+            return Err(CapacityError);
+        }
+        for (slot, &item) in self.items[self.len..].iter_mut().zip(items) {
+            slot.write(item);
+        }
+        self.len += items.len();
+        Ok(())
+    }
+
+    /// Shortens the vector to `len` items, if it is longer.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        // Safety: the first `len` items are initialised, and MaybeUninit<T> has T's layout.
+        unsafe { slice::from_raw_parts(self.items.as_ptr() as *const T, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // Safety: the first `len` items are initialised, and MaybeUninit<T> has T's layout.
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+impl<T: Copy, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, const N: usize> Deref for ArrayVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Copy, const N: usize> DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: Copy + fmt::Debug, const N: usize> fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl<T: Copy + PartialEq, const N: usize> PartialEq for ArrayVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + Eq, const N: usize> Eq for ArrayVec<T, N> {}
+
+// A string of up to N bytes of UTF-8.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ArrayString<const N: usize> {
+    bytes: ArrayVec<u8, N>,
+}
+
+impl<const N: usize> ArrayString<N> {
+    pub const fn new() -> Self {
+        Self { bytes: ArrayVec::new() }
+    }
+
+    /// Copies `s`, or fails if it is longer than N bytes.
+    pub fn from(s: &str) -> Result<Self, CapacityError> {
+        let mut string = Self::new();
+        string.push_str(s)?;
+        Ok(string)
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.bytes.is_full()
+    }
+
+    /// Adds `s` at the end, or nothing if it does not fit.
+    pub fn push_str(&mut self, s: &str) -> Result<(), CapacityError> {
+        self.bytes.extend_from_slice(s.as_bytes())
+    }
+
+    pub fn push(&mut self, c: char) -> Result<(), CapacityError> {
+        self.push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.chars().next_back()?;
+        self.bytes.truncate(self.len() - c.len_utf8());
+        Some(c)
+    }
+
+    /// Shortens the string to `len` bytes, if it is longer.
+    ///
+    /// # Panics
+    /// If `len` is not at a character boundary.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            assert!(self.is_char_boundary(len), "ArrayString::truncate inside a character");
+            self.bytes.truncate(len);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Safety: only whole strs are added, and truncation keeps to character boundaries.
+        unsafe { str::from_utf8_unchecked(&self.bytes) }
+    }
+}
+
+impl<const N: usize> Default for ArrayString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for ArrayString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> PartialEq<str> for ArrayString<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&str> for ArrayString<N> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl<const N: usize> fmt::Write for ArrayString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl<const N: usize> fmt::Display for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn array_vec_fills_to_capacity() {
+        let mut v: ArrayVec<u8, 3> = ArrayVec::new();
+        assert_eq!(v.push(1), Ok(()));
+        assert_eq!(v.extend_from_slice(&[2, 3]), Ok(()));
+        assert_eq!(v.push(4), Err(4));
+        assert_eq!(v.extend_from_slice(&[4]), Err(CapacityError));
+        assert_eq!(*v, [1, 2, 3]);
+        assert_eq!(v.pop(), Some(3));
+        v.truncate(1);
+        assert_eq!(*v, [1]);
+        v.clear();
+        assert_eq!(v.pop(), None);
+    }
+
+    #[test]
+    fn array_string_keeps_whole_characters() {
+        let mut s: ArrayString<8> = ArrayString::from("héllo").unwrap();
+        assert_eq!(s.len(), 6);
+        assert_eq!(s.push_str("!!!"), Err(CapacityError));
+        assert_eq!(s, "héllo");
+        assert_eq!(s.push('🦀'), Err(CapacityError));
+        assert_eq!(s.pop(), Some('o'));
+        s.truncate(3);
+        assert_eq!(s, "hé");
+        assert!(write!(s, "{}", 42).is_ok());
+        assert_eq!(s.as_str(), "hé42");
+    }
+
+    #[test]
+    #[should_panic]
+    fn array_string_truncate_inside_character_panics() {
+        let mut s: ArrayString<4> = ArrayString::from("é").unwrap();
+        s.truncate(1);
+    }
+}
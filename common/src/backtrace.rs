@@ -0,0 +1,67 @@
+//! Backtraces
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+use core::ptr;
+
+const BACKTRACE_MAX: usize = 32;    // Frames printed at most
+
+/// Prints the return addresses of the calls leading to the frame at `fp`, innermost first, by
+/// following frame pointers. The kernel and programs are built with -Cforce-frame-pointers, so
+/// every function keeps its return address and its caller's frame pointer just below its frame.
+/// Frames are followed while they are in `stack`.
+///
+/// # Safety
+/// All of `stack` must be readable.
+pub unsafe fn backtrace(out: &mut impl Write, mut fp: usize, stack: Range<usize>) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    for depth in 0..BACKTRACE_MAX {
+        let saved = fp.wrapping_sub(2 * size_of::<usize>());   // The saved ra and fp
+        if !fp.is_multiple_of(size_of::<usize>()) || !stack.contains(&saved) {
+            break;
+        }
+        // Safety: fp is aligned and the two words below it are in `stack`
+        let (ra, caller_fp) = unsafe {
+            let frame = fp as *const usize;
+            (ptr::read_volatile(frame.sub(1)), ptr::read_volatile(frame.sub(2)))
+        };
+        if ra == 0 {
+            break;
+        }
+        writeln!(out, "  {:>2}: 0x{:08x}", depth, ra)?;
+        // Callers' frames are further up the stack. Anything else is not a frame pointer.
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_frames_up_the_stack() {
+        let mut stack = [0usize; 6];
+        let base = stack.as_ptr() as usize;
+        let addr = |i: usize| base + i * size_of::<usize>();
+        // The inner frame at 2 returns to 0x1000 and its caller's frame is at 5, which returns to
+        // 0x2000 and has no caller.
+        let (inner, outer) = (addr(2), addr(5));
+        stack[0] = outer;
+        stack[1] = 0x1000;
+        stack[4] = 0x2000;
+        let range = addr(0)..addr(stack.len());
+
+        let mut out = String::new();
+        unsafe { backtrace(&mut out, inner, range.clone()) }.unwrap();
+        assert_eq!(out, "backtrace:\n   0: 0x00001000\n   1: 0x00002000\n");
+
+        // A frame pointer off the stack has no frames.
+        out.clear();
+        unsafe { backtrace(&mut out, addr(1), range) }.unwrap();
+        assert_eq!(out, "backtrace:\n");
+    }
+}
@@ -0,0 +1,86 @@
+//! Block devices
+
+pub const SECTOR_SIZE: usize = 512;
+
+// A disk read and written a sector at a time.
+pub trait BlockDevice {
+    fn sector_count(&self) -> u64;
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64);
+    fn write_sector(&mut self, buf: &[u8], sector: u64);
+
+    // Whether the device refuses writes. Writes to it are dropped.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // Waits until every write so far survives a power failure. Devices that only complete a
+    // write once it is stored, such as ones without a write cache, have nothing to do.
+    fn flush(&mut self) {}
+
+    // Reads consecutive sectors starting at `sector` into `bufs` in turn. Each buffer holds a
+    // whole number of sectors. Devices that can read them in one request override this.
+    fn read_sectors(&mut self, bufs: &mut [&mut [u8]], sector: u64) {
+        let chunks = bufs.iter_mut().flat_map(|buf| buf.chunks_exact_mut(SECTOR_SIZE));
+        for (chunk, sector) in chunks.zip(sector..) {
+            self.read_sector(chunk, sector);
+        }
+    }
+
+    // Writes `bufs` in turn to consecutive sectors starting at `sector`. Each buffer holds a
+    // whole number of sectors. Devices that can write them in one request override this.
+    fn write_sectors(&mut self, bufs: &[&[u8]], sector: u64) {
+        let chunks = bufs.iter().flat_map(|buf| buf.chunks_exact(SECTOR_SIZE));
+        for (chunk, sector) in chunks.zip(sector..) {
+            self.write_sector(chunk, sector);
+        }
+    }
+}
+
+// A disk in memory, for running file system code on the host.
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Default)]
+pub struct MemDisk(pub std::vec::Vec<[u8; SECTOR_SIZE]>);
+
+#[cfg(any(test, feature = "std"))]
+impl MemDisk {
+    pub fn new(sectors: usize) -> Self {
+        Self(std::vec![[0; SECTOR_SIZE]; sectors])
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl BlockDevice for MemDisk {
+    fn sector_count(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64) {
+        buf.copy_from_slice(&self.0[sector as usize]);
+    }
+
+    fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        self.0[sector as usize].copy_from_slice(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sectors_span_buffers() {
+        let mut disk = MemDisk::new(4);
+        let (a, b) = ([1u8; SECTOR_SIZE], [2u8; 2 * SECTOR_SIZE]);
+        disk.write_sectors(&[&a, &b], 1);
+        assert_eq!(disk.0[0], [0; SECTOR_SIZE]);
+        assert_eq!(disk.0[1], [1; SECTOR_SIZE]);
+        assert_eq!(disk.0[2], [2; SECTOR_SIZE]);
+        assert_eq!(disk.0[3], [2; SECTOR_SIZE]);
+
+        let (mut c, mut d) = ([0u8; 2 * SECTOR_SIZE], [0u8; SECTOR_SIZE]);
+        disk.read_sectors(&mut [&mut c, &mut d], 0);
+        assert_eq!(c[..SECTOR_SIZE], [0; SECTOR_SIZE]);
+        assert_eq!(c[SECTOR_SIZE..], [1; SECTOR_SIZE]);
+        assert_eq!(d, [2; SECTOR_SIZE]);
+    }
+}
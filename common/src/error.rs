@@ -0,0 +1,124 @@
+//! Errors returned by syscalls
+//!
+//! A syscall that fails returns the negative value of an OsError in place of a length or file
+//! descriptor. The values are part of the syscall interface, so they never change, and new
+//! errors take new values.
+
+use core::fmt;
+
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OsError {
+    NotFound = -1,      // No such file
+    Exists = -2,        // The file or mount point already exists
+    NoSpace = -3,       // The file system, file or a kernel table is full
+    InvalidName = -4,   // The file name cannot be stored by the file system
+    Unsupported = -5,   // The file system or device does not support the operation
+    Corrupt = -6,       // The file contents failed their integrity check
+    WouldBlock = -7,    // The file is locked by another process, or the futex word changed
+    Busy = -8,          // The device or mount point is in use
+    Permission = -9,    // Only a privileged process may do this
+    Unreachable = -10,  // No reply from the network address
+    ReadOnly = -11,     // The file system is mounted read-only
+    Invalid = -12,      // An argument is out of range or misaligned
+    BadFd = -13,        // No file is open at the file descriptor
+    NoMem = -14,        // The kernel is out of memory
+    Fault = -15,        // A pointer argument is outside the process's memory
+    Interrupted = -16,  // The call was cut short by Ctrl-C
+    BrokenPipe = -17,   // Writing to a pipe whose read end is closed
+}
+
+const ALL: [OsError; 17] = [
+    OsError::NotFound,
+    OsError::Exists,
+    OsError::NoSpace,
+    OsError::InvalidName,
+    OsError::Unsupported,
+    OsError::Corrupt,
+    OsError::WouldBlock,
+    OsError::Busy,
+    OsError::Permission,
+    OsError::Unreachable,
+    OsError::ReadOnly,
+    OsError::Invalid,
+    OsError::BadFd,
+    OsError::NoMem,
+    OsError::Fault,
+    OsError::Interrupted,
+    OsError::BrokenPipe,
+];
+
+impl OsError {
+    // The value a syscall returns for this error.
+    pub const fn code(self) -> isize {
+        self as isize
+    }
+
+    // The error as the kernel leaves it in a0.
+    pub const fn to_usize(self) -> usize {
+        self as isize as usize
+    }
+
+    pub fn from_code(code: isize) -> Option<Self> {
+        ALL.iter().copied().find(|e| e.code() == code)
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            OsError::NotFound => "not found",
+            OsError::Exists => "already exists",
+            OsError::NoSpace => "no space left",
+            OsError::InvalidName => "invalid name",
+            OsError::Unsupported => "not supported",
+            OsError::Corrupt => "corrupt",
+            OsError::WouldBlock => "would block",
+            OsError::Busy => "busy",
+            OsError::Permission => "permission denied",
+            OsError::Unreachable => "unreachable",
+            OsError::ReadOnly => "read-only file system",
+            OsError::Invalid => "invalid argument",
+            OsError::BadFd => "bad file descriptor",
+            OsError::NoMem => "out of memory",
+            OsError::Fault => "bad address",
+            OsError::Interrupted => "interrupted",
+            OsError::BrokenPipe => "broken pipe",
+        }
+    }
+}
+
+impl fmt::Display for OsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+// Splits a syscall return value into a result. A negative value that is not a known error
+// counts as Invalid.
+pub fn check(ret: isize) -> Result<usize, OsError> {
+    if ret >= 0 {
+        Ok(ret as usize)
+    } else {
+        Err(OsError::from_code(ret).unwrap_or(OsError::Invalid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for (i, &e) in ALL.iter().enumerate() {
+            assert_eq!(e.code(), -(i as isize) - 1);
+            assert_eq!(OsError::from_code(e.code()), Some(e));
+        }
+        assert_eq!(OsError::from_code(-100), None);
+    }
+
+    #[test]
+    fn check_splits_return_values() {
+        assert_eq!(check(5), Ok(5));
+        assert_eq!(check(OsError::Corrupt.code()), Err(OsError::Corrupt));
+        assert_eq!(check(-100), Err(OsError::Invalid));
+    }
+}
@@ -1,11 +1,69 @@
 //! Common library
 
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+pub mod abi;
+pub mod array;
+pub mod backtrace;
+pub mod block;
+pub mod error;
+pub mod net;
+pub mod p9;
+pub mod path;
 pub mod print;
+pub mod ring;
+pub mod syscall;
+pub mod tar;
 
-pub const SYS_PUTBYTE: usize = 1;
-pub const SYS_GETCHAR: usize = 2;
-pub const SYS_EXIT: usize = 3;
-pub const SYS_READFILE: usize = 4;
-pub const SYS_WRITEFILE: usize = 5;
+pub use error::OsError;
+pub use syscall::Syscall;
+
+// Flags for Syscall::Open and Syscall::WriteFile
+pub const OPEN_APPEND: usize = 1 << 0;    // Write at the end of the file, ignoring the offset
+pub const OPEN_CREATE: usize = 1 << 1;    // Create the file if it does not exist
+pub const OPEN_TRUNC: usize = 1 << 2;     // Empty the file, so writes replace it
+
+// Where Syscall::Seek measures the offset from
+pub const SEEK_SET: usize = 0;            // The start of the file
+pub const SEEK_CUR: usize = 1;            // The current offset
+pub const SEEK_END: usize = 2;            // The end of the file
+
+// Operations for Syscall::Flock: one of LOCK_SH, LOCK_EX or LOCK_UN, optionally with LOCK_NB
+pub const LOCK_SH: usize = 1 << 0;        // Shared lock, held by any number of processes
+pub const LOCK_EX: usize = 1 << 1;        // Exclusive lock, held by one process
+pub const LOCK_NB: usize = 1 << 2;        // Fail with WouldBlock instead of waiting
+pub const LOCK_UN: usize = 1 << 3;        // Release the lock
+
+// Actions for Syscall::Shutdown
+pub const SHUTDOWN_POWEROFF: usize = 0;
+pub const SHUTDOWN_REBOOT: usize = 1;
+pub const SHUTDOWN_EXIT: usize = 2;       // Power off, and have QEMU exit with the status in a1
+
+// Exit statuses from Syscall::Wait: the code the process passed to Syscall::Exit, 0 to 255, or
+// one of these if the kernel ended it
+pub const EXIT_KILLED: usize = 256;       // Killed for a fault, at a breakpoint or by the watchdog
+pub const EXIT_INTERRUPTED: usize = 257;  // Ended by Ctrl-C
+pub const EXIT_STOPPED: usize = 258;      // Stopped by Ctrl-Z, with WAIT_STOPPED, and not ended yet
+
+// Flags for Syscall::Wait
+pub const WAIT_NOHANG: usize = 1 << 0;    // Fail with WouldBlock instead of waiting
+pub const WAIT_STOPPED: usize = 1 << 1;   // Also return if the process is stopped
+
+// Clocks for Syscall::ClockGettime
+pub const CLOCK_MONOTONIC: usize = 0;     // Nanoseconds since boot, never going backwards
+
+// Levels for Syscall::LogLevel: the kernel prints messages at the level set and the ones before it
+pub const LOG_ERROR: usize = 1;
+pub const LOG_WARN: usize = 2;
+pub const LOG_INFO: usize = 3;
+pub const LOG_DEBUG: usize = 4;
+pub const LOG_TRACE: usize = 5;
+
+// Operations for Syscall::Futex
+pub const FUTEX_WAIT: usize = 0;          // Sleep if the word still holds the given value
+pub const FUTEX_WAKE: usize = 1;          // Wake up to the given number of sleepers
+
+// File descriptors every process starts with, all open on /dev/console
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
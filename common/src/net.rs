@@ -0,0 +1,46 @@
+//! Networking
+
+// An IPv4 address and UDP port, as passed to Syscall::SendTo and Syscall::RecvFrom.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SockAddr {
+    pub ip: [u8; 4],
+    pub port: u16,
+}
+
+// The internet checksum (RFC 1071) used by IPv4 headers: the one's complement of the
+// one's complement sum of the data as big endian 16-bit words.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0)))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_of_ipv4_header() {
+        // The IPv4 header of a UDP packet, with its checksum field zeroed.
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+
+        // A header holding its own checksum sums to zero.
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[test]
+    fn checksum_pads_odd_length() {
+        assert_eq!(checksum(&[0x01]), !0x0100);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+}
@@ -0,0 +1,213 @@
+//! 9P2000.L messages
+//!
+//! Building requests and parsing replies for the kernel's virtio-9p file system. Every message
+//! is `size[4] type[1] tag[2]` followed by its fields, all little endian. Strings are a 16-bit
+//! length and UTF-8 bytes without a nul.
+
+pub const HEADER_SIZE: usize = 7;
+pub const NOTAG: u16 = !0;          // Tag of Tversion
+pub const NOFID: u32 = !0;          // No authentication fid in Tattach
+pub const QID_SIZE: usize = 13;
+
+// Request types. The reply to each is the next number, or Rlerror.
+pub const RLERROR: u8 = 7;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+// Builds a message in a buffer. Fields that do not fit are dropped and make `finish` fail.
+#[derive(Debug)]
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8], msg_type: u8, tag: u16) -> Self {
+        let mut writer = Self { buf, len: 0, overflow: false };
+        writer.u32(0).u8(msg_type).u16(tag);  // The size is filled in by `finish`
+        writer
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                self.len += bytes.len();
+            },
+            None => self.overflow = true,
+        }
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn str(&mut self, s: &str) -> &mut Self {
+        match u16::try_from(s.len()) {
+            Ok(len) => self.u16(len).bytes(s.as_bytes()),
+            Err(_) => {
+                self.overflow = true;
+                self
+            },
+        }
+    }
+
+    // Stores the size of the message and returns it, or None if the message did not fit.
+    pub fn finish(self) -> Option<usize> {
+        if self.overflow {
+            return None;
+        }
+        self.buf[..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        Some(self.len)
+    }
+}
+
+// Reads the fields of a message in order. Reading past the end returns None.
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes(b.try_into().unwrap_or_default()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| u64::from_le_bytes(b.try_into().unwrap_or_default()))
+    }
+
+    pub fn str(&mut self) -> Option<&'a str> {
+        let len = self.u16()? as usize;
+        str::from_utf8(self.bytes(len)?).ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplyError {
+    Lerror(u32),    // The server failed the request with this Linux errno
+    Malformed,      // The reply is truncated or is not the reply to the request
+}
+
+// Checks that `buf` holds the reply to a request of type `msg_type` with `tag`, and returns a
+// reader over the fields after the header.
+pub fn parse_reply(buf: &[u8], msg_type: u8, tag: u16) -> Result<Reader<'_>, ReplyError> {
+    let mut header = Reader::new(buf);
+    let size = header.u32().ok_or(ReplyError::Malformed)? as usize;
+    let reply_type = header.u8().ok_or(ReplyError::Malformed)?;
+    let reply_tag = header.u16().ok_or(ReplyError::Malformed)?;
+    if size < HEADER_SIZE || size > buf.len() || reply_tag != tag {
+        return Err(ReplyError::Malformed);
+    }
+
+    let mut fields = Reader::new(&buf[HEADER_SIZE..size]);
+    match reply_type {
+        RLERROR => Err(ReplyError::Lerror(fields.u32().ok_or(ReplyError::Malformed)?)),
+        t if t == msg_type.wrapping_add(1) => Ok(fields),
+        _ => Err(ReplyError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_request_bytes() {
+        let mut buf = [0u8; 32];
+        let mut writer = Writer::new(&mut buf, TVERSION, NOTAG);
+        writer.u32(8192).str("9P2000.L");
+        let len = writer.finish();
+        assert_eq!(len, Some(21));
+        assert_eq!(&buf[..21], b"\x15\0\0\0\x64\xff\xff\x00\x20\0\0\x08\x009P2000.L");
+    }
+
+    #[test]
+    fn writer_overflow_fails_finish() {
+        let mut buf = [0u8; 10];
+        let mut writer = Writer::new(&mut buf, TCLUNK, 0);
+        writer.u32(1).u32(2);
+        assert_eq!(writer.finish(), None);
+    }
+
+    #[test]
+    fn reply_fields_round_trip() {
+        let mut buf = [0u8; 64];
+        let mut writer = Writer::new(&mut buf, TREAD + 1, 3);
+        writer.u32(5).bytes(b"hello").u64(u64::MAX).str("name");
+        writer.finish().expect("reply should fit");
+
+        let mut fields = parse_reply(&buf, TREAD, 3).expect("reply should parse");
+        let count = fields.u32().expect("count") as usize;
+        assert_eq!(fields.bytes(count), Some(&b"hello"[..]));
+        assert_eq!(fields.u64(), Some(u64::MAX));
+        assert_eq!(fields.str(), Some("name"));
+        assert!(fields.is_empty());
+        assert_eq!(fields.u8(), None);
+    }
+
+    #[test]
+    fn lerror_reply() {
+        let mut buf = [0u8; 16];
+        let mut writer = Writer::new(&mut buf, RLERROR, 0);
+        writer.u32(2);
+        writer.finish().expect("reply should fit");
+        assert_eq!(parse_reply(&buf, TWALK, 0).map(|_| ()), Err(ReplyError::Lerror(2)));
+    }
+
+    #[test]
+    fn mismatched_reply_is_malformed() {
+        let mut buf = [0u8; 16];
+        Writer::new(&mut buf, TCLUNK + 1, 0).finish().expect("reply should fit");
+        assert_eq!(parse_reply(&buf, TWALK, 0).map(|_| ()), Err(ReplyError::Malformed));
+        assert_eq!(parse_reply(&buf, TCLUNK, 1).map(|_| ()), Err(ReplyError::Malformed));
+        assert_eq!(parse_reply(&buf[..4], TCLUNK, 0).map(|_| ()), Err(ReplyError::Malformed));
+    }
+}
@@ -0,0 +1,65 @@
+//! Paths
+
+// Resolves `.`, `..`, repeated slashes and trailing slashes in `path` into `buf`, returning the
+// path below the root without leading or trailing slashes. There is no current directory, so
+// relative paths start at the root, and `..` at the root stays there.
+// Returns None if `buf` is too small.
+pub fn normalize<'a>(path: &str, buf: &'a mut [u8]) -> Option<&'a str> {
+    let mut len = 0;
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                // Drop the last component and the slash before it.
+                len = buf[..len].iter().rposition(|&b| b == b'/').unwrap_or(0);
+            },
+            _ => {
+                let start = if len == 0 { 0 } else { len + 1 };
+                let end = start + component.len();
+                if end > buf.len() {
+                    return None;
+                }
+                if len > 0 {
+                    buf[len] = b'/';
+                }
+                buf[start..end].copy_from_slice(component.as_bytes());
+                len = end;
+            },
+        }
+    }
+    // Only whole UTF-8 components were copied.
+    str::from_utf8(&buf[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn norm(path: &str) -> Option<std::string::String> {
+        let mut buf = [0u8; 64];
+        normalize(path, &mut buf).map(std::string::String::from)
+    }
+
+    #[test]
+    fn normalize_removes_extra_slashes() {
+        assert_eq!(norm("/dev//null/").as_deref(), Some("dev/null"));
+        assert_eq!(norm("hello.txt").as_deref(), Some("hello.txt"));
+        assert_eq!(norm("/").as_deref(), Some(""));
+        assert_eq!(norm("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!(norm("./foo").as_deref(), Some("foo"));
+        assert_eq!(norm("/proc/../dev/./null").as_deref(), Some("dev/null"));
+        assert_eq!(norm("a/b/../../c").as_deref(), Some("c"));
+        assert_eq!(norm("../../hello.txt").as_deref(), Some("hello.txt"));
+    }
+
+    #[test]
+    fn normalize_fails_when_buffer_is_too_small() {
+        let mut buf = [0u8; 4];
+        assert_eq!(normalize("hello.txt", &mut buf), None);
+        assert_eq!(normalize("/a/b/", &mut buf), Some("a/b"));
+    }
+}
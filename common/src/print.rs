@@ -1,6 +1,10 @@
 //! Print to debug console
+//!
+//! Also ANSI escape sequences for colors and cursor movement. Colors can be turned off with
+//! `set_color`, for consoles that would show the sequences as text.
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub struct DebugConsole;
 
@@ -17,6 +21,68 @@ impl fmt::Write for DebugConsole {
     }
 }
 
+// Styles, for Styled and print_color!
+pub const RESET: &str = "\x1b[0m";
+pub const BOLD: &str = "\x1b[1m";
+pub const DIM: &str = "\x1b[2m";
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const BLUE: &str = "\x1b[34m";
+pub const MAGENTA: &str = "\x1b[35m";
+pub const CYAN: &str = "\x1b[36m";
+
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+// Turns styles on or off for everything printed from now on.
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+// `self.1` printed in the style `self.0`, such as RED, or plainly if colors are off.
+#[derive(Clone, Copy, Debug)]
+pub struct Styled<T>(pub &'static str, pub T);
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if color_enabled() && !self.0.is_empty() {
+            write!(f, "{}{}{}", self.0, self.1, RESET)
+        } else {
+            write!(f, "{}", self.1)
+        }
+    }
+}
+
+// Cursor and screen control. Rows and columns count from 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cursor {
+    To(usize, usize),   // Row, column
+    Up(usize),
+    Down(usize),
+    Forward(usize),
+    Back(usize),
+    ClearLine,
+    ClearScreen,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cursor::To(row, col) => write!(f, "\x1b[{};{}H", row + 1, col + 1),
+            Cursor::Up(n) => write!(f, "\x1b[{}A", n),
+            Cursor::Down(n) => write!(f, "\x1b[{}B", n),
+            Cursor::Forward(n) => write!(f, "\x1b[{}C", n),
+            Cursor::Back(n) => write!(f, "\x1b[{}D", n),
+            Cursor::ClearLine => write!(f, "\r\x1b[2K"),
+            Cursor::ClearScreen => write!(f, "\x1b[2J\x1b[H"),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -37,3 +103,39 @@ macro_rules! println {
         }
     };
 }
+
+// As print!, in a style such as RED.
+#[macro_export]
+macro_rules! print_color {
+    ($style:expr, $($arg:tt)*) => {
+        $crate::print!("{}", $crate::print::Styled($style, format_args!($($arg)*)))
+    };
+}
+
+// As println!, in a style such as RED. The newline is not styled.
+#[macro_export]
+macro_rules! println_color {
+    ($style:expr, $($arg:tt)*) => {
+        $crate::println!("{}", $crate::print::Styled($style, format_args!($($arg)*)))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_unless_colors_are_off() {
+        assert_eq!(format!("{}", Styled(RED, 42)), "\x1b[31m42\x1b[0m");
+        assert_eq!(format!("{}", Styled("", "plain")), "plain");
+        set_color(false);
+        assert_eq!(format!("{}", Styled(RED, 42)), "42");
+        set_color(true);
+    }
+
+    #[test]
+    fn cursor_positions_count_from_one() {
+        assert_eq!(format!("{}", Cursor::To(0, 4)), "\x1b[1;5H");
+        assert_eq!(format!("{}", Cursor::Back(3)), "\x1b[3D");
+    }
+}
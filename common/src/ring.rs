@@ -0,0 +1,127 @@
+//! Ring buffers
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering::{Acquire, Relaxed, Release}};
+
+// A fixed-size queue with one producer and one consumer that takes no locks, so an interrupt
+// handler can push into it while a process pops. `head` and `tail` count every pop and push,
+// wrapping; N must be a power of two so that slots stay in step when they wrap.
+pub struct SpscRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    head: AtomicUsize,      // Next slot to pop, written only by the consumer
+    tail: AtomicUsize,      // Next slot to push, written only by the producer
+}
+
+// Safety: a slot is only read by the consumer after the producer has published it with `tail`,
+// and only written again after the consumer has released it with `head`.
+unsafe impl<T: Send, const N: usize> Sync for SpscRing<T, N> {}
+
+impl<T: Copy, const N: usize> SpscRing<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "ring size must be a power of two");
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds `value` at the end, or hands it back if the ring is full.
+    ///
+    /// # Safety
+    /// Only one context may push, though it may run alongside the one that pops.
+    pub unsafe fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Relaxed);
+        if tail.wrapping_sub(self.head.load(Acquire)) == N {
+            return Err(value);
+        }
+        // Safety: the slot is not in use, and only the producer writes slots.
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Release);
+        Ok(())
+    }
+
+    /// Removes the oldest value, if there is one.
+    ///
+    /// # Safety
+    /// Only one context may pop, though it may run alongside the one that pushes.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Relaxed);
+        if head == self.tail.load(Acquire) {
+            return None;
+        }
+        // Safety: the producer wrote the slot before publishing it.
+        let value = unsafe { (*self.slots[head % N].get()).assume_init() };
+        self.head.store(head.wrapping_add(1), Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Acquire).wrapping_sub(self.head.load(Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Copy, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> core::fmt::Debug for SpscRing<T, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SpscRing")
+            .field("head", &self.head.load(Relaxed))
+            .field("tail", &self.tail.load(Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_in_push_order_and_refuses_when_full() {
+        let ring: SpscRing<u8, 4> = SpscRing::new();
+        unsafe {
+            for b in 1..=4 {
+                assert_eq!(ring.push(b), Ok(()));
+            }
+            assert_eq!(ring.push(5), Err(5));
+            assert_eq!(ring.len(), 4);
+            assert_eq!(ring.pop(), Some(1));
+            assert_eq!(ring.push(5), Ok(()));
+            assert_eq!((ring.pop(), ring.pop(), ring.pop(), ring.pop()), (Some(2), Some(3), Some(4), Some(5)));
+            assert_eq!(ring.pop(), None);
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn keeps_order_across_threads() {
+        let ring: SpscRing<u32, 8> = SpscRing::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..10_000 {
+                    // Safety: this is the only producer.
+                    while unsafe { ring.push(i) }.is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < 10_000 {
+                // Safety: this is the only consumer.
+                if let Some(i) = unsafe { ring.pop() } {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,125 @@
+//! Syscall numbers
+//!
+//! User code passes the number of the syscall in a4. The numbers are part of the syscall
+//! interface, so they never change, and new syscalls take new numbers.
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    PutByte = 1,
+    GetChar = 2,
+    Exit = 3,
+    ReadFile = 4,
+    WriteFile = 5,
+    Fsync = 6,
+    Open = 7,
+    Read = 8,
+    Write = 9,
+    Close = 10,
+    Flock = 11,
+    Mount = 12,
+    Umount = 13,
+    SendTo = 14,
+    RecvFrom = 15,
+    Shutdown = 16,
+    ClockGettime = 17,
+    Futex = 18,
+    Dmesg = 19,
+    LogLevel = 20,
+    ReadLine = 21,
+    Sbrk = 22,
+    Spawn = 23,
+    Wait = 24,
+    Seek = 25,
+    Fstat = 26,
+    Sleep = 27,
+    GetRandom = 28,
+    GetEnv = 29,
+    SetEnv = 30,
+    ListEnv = 31,
+    ReadDir = 32,
+    GetVersion = 33,
+    TestReport = 34,
+    SigAction = 35,
+    SigReturn = 36,
+    ThreadCreate = 37,
+    Unlink = 38,
+    Rename = 39,
+    Pipe = 40,
+    Dup = 41,
+    Dup2 = 42,
+    SetGroup = 43,
+    SetForeground = 44,
+    Continue = 45,
+}
+
+const ALL: [Syscall; 45] = [
+    Syscall::PutByte,
+    Syscall::GetChar,
+    Syscall::Exit,
+    Syscall::ReadFile,
+    Syscall::WriteFile,
+    Syscall::Fsync,
+    Syscall::Open,
+    Syscall::Read,
+    Syscall::Write,
+    Syscall::Close,
+    Syscall::Flock,
+    Syscall::Mount,
+    Syscall::Umount,
+    Syscall::SendTo,
+    Syscall::RecvFrom,
+    Syscall::Shutdown,
+    Syscall::ClockGettime,
+    Syscall::Futex,
+    Syscall::Dmesg,
+    Syscall::LogLevel,
+    Syscall::ReadLine,
+    Syscall::Sbrk,
+    Syscall::Spawn,
+    Syscall::Wait,
+    Syscall::Seek,
+    Syscall::Fstat,
+    Syscall::Sleep,
+    Syscall::GetRandom,
+    Syscall::GetEnv,
+    Syscall::SetEnv,
+    Syscall::ListEnv,
+    Syscall::ReadDir,
+    Syscall::GetVersion,
+    Syscall::TestReport,
+    Syscall::SigAction,
+    Syscall::SigReturn,
+    Syscall::ThreadCreate,
+    Syscall::Unlink,
+    Syscall::Rename,
+    Syscall::Pipe,
+    Syscall::Dup,
+    Syscall::Dup2,
+    Syscall::SetGroup,
+    Syscall::SetForeground,
+    Syscall::Continue,
+];
+
+impl TryFrom<usize> for Syscall {
+    type Error = usize;
+
+    // Fails with the number itself when no syscall has it.
+    fn try_from(sysno: usize) -> Result<Self, usize> {
+        ALL.iter().copied().find(|&s| s as usize == sysno).ok_or(sysno)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_round_trip() {
+        for s in ALL {
+            assert_eq!(Syscall::try_from(s as usize), Ok(s));
+        }
+        assert_eq!(Syscall::try_from(0), Err(0));
+        assert_eq!(Syscall::try_from(ALL.len() + 1), Err(ALL.len() + 1));
+    }
+}
@@ -0,0 +1,252 @@
+//! Tar archive format
+//!
+//! Header parsing and serialization for the kernel's tar file system. It works on any
+//! `BlockDevice`, so it can be tested on the host against an in-memory disk.
+
+use core::ffi::CStr;
+use core::mem::offset_of;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct TarHeader {
+    pub name: [u8; 100],
+    pub mode: [u8; 8],
+    pub uid: [u8; 8],
+    pub gid: [u8; 8],
+    pub size: [u8; 12],
+    pub mtime: [u8; 12],
+    pub checksum: [u8; 8],
+    pub typeflag: u8,
+    pub linkname: [u8; 100],
+    pub magic: [u8; 6],
+    pub version: [u8; 2],
+    pub uname: [u8; 32],
+    pub gname: [u8; 32],
+    pub devmajor: [u8; 8],
+    pub devminor: [u8; 8],
+    pub prefix: [u8; 155],
+    // Not part of ustar: os1k keeps the CRC32 of the file data in the header padding,
+    // as an octal string like the other numbers. Tar tools leave it zeroed.
+    pub crc32: [u8; 12],
+    // data follows as a byte array size `size`
+}
+
+const _: () = assert!(size_of::<TarHeader>() == SECTOR_SIZE);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderError {
+    BadMagic,                                       // Not a ustar header
+    BadChecksum { stored: usize, computed: usize }, // The header is corrupt
+    ChecksumNotOctal,                               // The checksum field is corrupt
+}
+
+// A nul-terminated string field of a header.
+fn field_str(field: &[u8]) -> &str {
+    CStr::from_bytes_until_nul(field)
+    .ok()
+    .and_then(|cstr| cstr.to_str().ok())
+    .unwrap_or("<invalid name>")
+}
+
+impl TarHeader {
+    pub fn zeroed() -> Self {
+        // SAFETY: TarHeader contains only arrays of integers.
+        // All-zero bytes is a valid representation: integers become 0.
+        unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
+    }
+
+    // A header for a new entry, with the name and type set. The size and checksum are left to the caller.
+    // A name of 100 bytes or more is cut short to leave its nul terminator.
+    pub fn new(name: &str, typeflag: u8) -> Self {
+        let mut header = Self::zeroed();
+        let len = name.len().min(header.name.len() - 1);
+        header.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        header.mode.copy_from_slice("00000644".as_bytes()); // Read and write permissions
+        header.magic.copy_from_slice("ustar\0".as_bytes());
+        header.version.copy_from_slice("00".as_bytes());
+        header.typeflag = typeflag;
+        header
+    }
+
+    pub fn from_sector(buf: &[u8; SECTOR_SIZE]) -> Self {
+        // Safety: TarHeader is exactly one sector of byte arrays with 1 byte alignment,
+        // so any sector is a valid header.
+        unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Self) }
+    }
+
+    pub fn to_sector(&self) -> [u8; SECTOR_SIZE] {
+        let mut buf = [0u8; SECTOR_SIZE];
+        // Safety: buf is exactly the size of a TarHeader and 1 byte alignment is all it needs.
+        unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut Self, *self) };
+        buf
+    }
+
+    // Sum of all header bytes, counting the checksum field itself as spaces.
+    pub fn compute_checksum(&self) -> usize {
+        let checksum_field = offset_of!(TarHeader, checksum)..offset_of!(TarHeader, typeflag);
+        self.to_sector().iter()
+        .enumerate()
+        .map(|(i, &byte)| if checksum_field.contains(&i) { b' ' } else { byte })
+        .fold(0, | checksum, byte | checksum + byte as usize )
+    }
+
+    // Stores the checksum, once every other field is final.
+    pub fn seal(&mut self) {
+        let checksum = self.compute_checksum();
+        int2oct(checksum, &mut self.checksum);
+    }
+
+    pub fn name_str(&self) -> &str {
+        field_str(&self.name)
+    }
+
+    pub fn linkname_str(&self) -> &str {
+        field_str(&self.linkname)
+    }
+
+    // Reads and verifies the header at `sector`. Returns None at the end of the archive.
+    pub fn read(dev: &mut impl BlockDevice, sector: u64) -> Result<Option<Self>, HeaderError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        dev.read_sector(&mut buf, sector);
+        let header = Self::from_sector(&buf);
+
+        if header.name[0] == b'\0' { // name is a c string with nul terminator
+            return Ok(None);
+        }
+
+        if header.magic != *b"ustar\0" {
+            return Err(HeaderError::BadMagic);
+        }
+
+        let computed = header.compute_checksum();
+        match oct2int(&header.checksum) {
+            Ok(stored) if stored == computed => Ok(Some(header)),
+            Ok(stored) => Err(HeaderError::BadChecksum { stored, computed }),
+            Err(_) => Err(HeaderError::ChecksumNotOctal),
+        }
+    }
+
+    pub fn write(&self, dev: &mut impl BlockDevice, sector: u64) {
+        dev.write_sector(&self.to_sector(), sector);
+    }
+}
+
+// A number field holds something other than octal digits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NotOctal;
+
+pub fn oct2int(oct: &[u8]) -> Result<usize, NotOctal> {
+    oct.iter()
+    .skip_while(|&&b | b == b' ')  // Some tar writers pad numbers with leading spaces
+    .take_while(|&&b | b != 0 && b != b' ')  // Nul or space terminated octal slice so stop here
+    .try_fold(0, | dec, &b | {
+        match b {
+            b'0'..=b'7' => Ok(dec * 8 + (b - b'0') as usize),
+              _ => Err(NotOctal)
+        }
+    })
+}
+
+// Turn the file size into a nul terminated octal string.
+pub fn int2oct(dec: usize, oct: &mut [u8]) {
+    let mut num = dec;
+    oct.fill(b' ');  // Fill with spaces
+    if let Some(last_byte) = oct.last_mut() {
+        *last_byte = b'\0'; // Set last byte to nul terminator
+    }
+    oct.iter_mut()
+    .rev()
+    .skip(1) // Skip the last byte to leave as nul terminator
+    .for_each(|byte| {
+        *byte = (num % 8) as u8 + b'0';
+    num /= 8;
+    });
+}
+
+// CRC-32 (IEEE 802.3, as used by zip and gzip), computed a bit at a time.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MemDisk;
+
+    #[test]
+    fn octal_round_trip() {
+        let mut oct = [0u8; 12];
+        for n in [0, 7, 8, 512, 1024, 0o77_777_777_777] {
+            int2oct(n, &mut oct);
+            assert_eq!(oct[11], b'\0');
+            assert_eq!(oct2int(&oct), Ok(n));
+        }
+    }
+
+    #[test]
+    fn oct2int_accepts_padding() {
+        assert_eq!(oct2int(b"  644 \0\0"), Ok(0o644));
+        assert_eq!(oct2int(b"0000012\0"), Ok(10));
+        assert_eq!(oct2int(b"\0\0\0\0"), Ok(0));
+    }
+
+    #[test]
+    fn oct2int_rejects_non_octal() {
+        assert_eq!(oct2int(b"0009\0"), Err(NotOctal));
+        assert_eq!(oct2int(b"12a\0"), Err(NotOctal));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut disk = MemDisk::new(4);
+        let mut header = TarHeader::new("hello.txt", b'0');
+        int2oct(42, &mut header.size);
+        header.seal();
+        header.write(&mut disk, 1);
+
+        let read = TarHeader::read(&mut disk, 1)
+            .expect("header should be valid")
+            .expect("header should not be the end of the archive");
+        assert_eq!(read.name_str(), "hello.txt");
+        assert_eq!(read.typeflag, b'0');
+        assert_eq!(oct2int(&read.size), Ok(42));
+        assert_eq!(read.to_sector(), header.to_sector());
+    }
+
+    #[test]
+    fn zeroed_sector_ends_archive() {
+        let mut disk = MemDisk::new(1);
+        assert_eq!(TarHeader::read(&mut disk, 0).map(|h| h.is_some()), Ok(false));
+    }
+
+    #[test]
+    fn corrupt_header_fails_checksum() {
+        let mut disk = MemDisk::new(1);
+        let mut header = TarHeader::new("hello.txt", b'0');
+        int2oct(42, &mut header.size);
+        header.seal();
+        header.write(&mut disk, 0);
+
+        disk.0[0][offset_of!(TarHeader, size)] ^= 1;
+        assert!(matches!(TarHeader::read(&mut disk, 0), Err(HeaderError::BadChecksum { .. })));
+    }
+
+    #[test]
+    fn foreign_header_fails_magic() {
+        let mut disk = MemDisk::new(1);
+        disk.0[0][..4].copy_from_slice(b"FAT!");
+        assert!(matches!(TarHeader::read(&mut disk, 0), Err(HeaderError::BadMagic)));
+    }
+}
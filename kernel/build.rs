@@ -8,4 +8,9 @@ fn main() {
 
     // Link the shell binary
     println!("cargo:rustc-link-arg=shell.bin.o");
+
+    // Link the RAM disk image
+    if std::env::var_os("CARGO_FEATURE_RAMDISK").is_some() {
+        println!("cargo:rustc-link-arg=ramdisk.img.o");
+    }
 }
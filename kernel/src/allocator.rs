@@ -1,9 +1,14 @@
 //! Allocate memory pages
+//!
+//! Every allocation is a run of whole pages. Freed runs go on a list, kept in address order and
+//! stored in the runs themselves, and are reused first. New pages come from the end of the memory
+//! allocated so far.
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::write_bytes;
 
 use crate::address::{align_up, PAddr};
+use crate::once::LazyLock;
 use crate::spinlock::SpinLock;
 
 pub const PAGE_SIZE: usize = 4096;
@@ -14,45 +19,123 @@ unsafe extern "C" {
     static __free_ram_end: u8;
 }
 
+// A run of freed pages, held in its own first bytes.
+struct FreeRun {
+    next: usize,    // Address of the next run up, or 0 for the last
+    size: usize,    // Bytes in the run, a whole number of pages
+}
+
 #[derive(Debug)]
-struct BumpAllocator(SpinLock<Option<PAddr>>);
+struct Pages {
+    next_paddr: PAddr,  // Start of the memory never allocated
+    free: usize,        // Address of the lowest freed run, or 0 if there are none
+    free_bytes: usize,  // Bytes in all freed runs
+}
+
+impl Pages {
+    // Takes `size` bytes from the first freed run that holds them, from the end of the run.
+    fn take_free(&mut self, size: usize) -> Option<usize> {
+        let mut link: *mut usize = &raw mut self.free;
+        // Safety: every address on the list is a freed run holding a FreeRun
+        unsafe {
+            while *link != 0 {
+                let run = *link as *mut FreeRun;
+                if (*run).size >= size {
+                    (*run).size -= size;
+                    if (*run).size == 0 {
+                        *link = (*run).next;
+                    }
+                    self.free_bytes -= size;
+                    return Some(run as usize + (*run).size);
+                }
+                link = &raw mut (*run).next;
+            }
+        }
+        None
+    }
+
+    // Puts the `size` bytes at `addr` on the list, joining them to the runs either side if they touch.
+    fn give_back(&mut self, addr: usize, size: usize) {
+        let mut link: *mut usize = &raw mut self.free;
+        let mut prev: *mut FreeRun = core::ptr::null_mut();
+        // Safety: every address on the list is a freed run holding a FreeRun, and the caller no
+        // longer uses the run at `addr`
+        unsafe {
+            while *link != 0 && *link < addr {
+                prev = *link as *mut FreeRun;
+                link = &raw mut (*prev).next;
+            }
+            let run = addr as *mut FreeRun;
+            run.write(FreeRun { next: *link, size });
+            if (*run).next == addr + size {
+                let next = (*run).next as *mut FreeRun;
+                (*run).size += (*next).size;
+                (*run).next = (*next).next;
+            }
+            *link = addr;
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += (*run).size;
+                (*prev).next = (*run).next;
+            }
+        }
+        self.free_bytes += size;
+    }
+}
+
+#[derive(Debug)]
+struct PageAllocator(LazyLock<SpinLock<Pages>>);
 
 #[global_allocator]
-static ALLOCATOR: BumpAllocator = BumpAllocator(
-    SpinLock::new(None),
+static ALLOCATOR: PageAllocator = PageAllocator(
+    // Initialise on first use
+    LazyLock::new(|| SpinLock::new(Pages {
+        next_paddr: PAddr::new(&raw const __free_ram as usize),
+        free: 0,
+        free_bytes: 0,
+    })),
 );
 
-unsafe impl GlobalAlloc for BumpAllocator {
+unsafe impl GlobalAlloc for PageAllocator {
     // Safety: Caller must ensure that Layout has a non-zero size
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         debug_assert!(layout.size() > 0, "allocation size must be non-zero");
 
-        let mut next_paddr = self.0.lock();
-
-        // Initialise on first use
-        let mut paddr = *next_paddr.get_or_insert_with(|| {
-            PAddr::new(&raw const __free_ram as usize)
-        });
-
+        let mut pages = self.0.lock();
         let aligned_size = align_up(layout.size(), PAGE_SIZE);
 
-        let new_paddr = paddr.as_usize() + aligned_size;
-        if new_paddr > &raw const __free_ram_end as usize {
-            panic!("out of memory");
-        }
-
-        *next_paddr = Some(PAddr::new(new_paddr));
+        let mut paddr = match pages.take_free(aligned_size) {
+            Some(addr) => PAddr::new(addr),
+            None => {
+                let paddr = pages.next_paddr;
+                let new_paddr = paddr.as_usize() + aligned_size;
+                if new_paddr > &raw const __free_ram_end as usize {
+                    panic!("out of memory");
+                }
+                pages.next_paddr = PAddr::new(new_paddr);
+                paddr
+            },
+        };
 
         // Safety: paddr.as_ptr_mut() is aligned and not null; entire aligned_size of bytes is available for write
         unsafe{ write_bytes(paddr.as_ptr_mut() as *mut u8, 0x55, aligned_size) };
 
         // crate::println!("alloc page: {:x} with {} pages allocated", paddr.as_usize(), aligned_size / PAGE_SIZE);
-        // for _ in 0..5 {
-        //     crate::delay();
-        // }
+        // crate::timer::delay_ms(100);
 
         paddr.as_ptr() as *mut u8
     }
 
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) {}
+    // Safety: Caller must pass a pointer returned by alloc with the same layout, and not use it again
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().give_back(ptr as usize, align_up(layout.size(), PAGE_SIZE));
+    }
+}
+
+// Returns the number of bytes in use and the total size of free RAM.
+pub fn memory_stats() -> (usize, usize) {
+    let start = &raw const __free_ram as usize;
+    let total = &raw const __free_ram_end as usize - start;
+    let pages = ALLOCATOR.0.lock();
+    let used = pages.next_paddr.as_usize() - start - pages.free_bytes;
+    (used, total)
 }
@@ -0,0 +1,148 @@
+//! Block devices for os1k
+//!
+//! The sector cache, the journal and the file systems reach a disk through a `Disk` handle,
+//! which forwards to the backend driver as a `BlockDevice`. A new backend only needs to
+//! implement the trait: nothing above this module knows which device holds a disk.
+//!
+//! Disks are registered once, at boot, and named "disk0", "disk1"... in order. The RAM disk
+//! comes first if the kernel is built with the `ramdisk` feature or the machine has no virtio
+//! block device, then every virtio block device in the order they were found. "disk0" holds
+//! the root file system.
+
+pub use common::block::{BlockDevice, SECTOR_SIZE};
+
+use crate::log_info;
+use crate::ramdisk::{ramdisk_init, RamDisk};
+use crate::spinlock::SpinLock;
+use crate::virtio::{
+    virtio_blk_capacity,
+    virtio_blk_flush,
+    virtio_blk_init,
+    virtio_blk_read,
+    virtio_blk_read_only,
+    virtio_blk_write,
+    BLK_DEVICES_MAX,
+};
+
+pub const DISKS_MAX: usize = BLK_DEVICES_MAX + 1;   // Every virtio block device and the RAM disk
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    Virtio(usize),  // Virtio block device unit
+    Ram,
+}
+
+static DISKS: SpinLock<[Option<Backend>; DISKS_MAX]> = SpinLock::new([None; DISKS_MAX]);
+
+// A virtio block device.
+#[derive(Debug)]
+pub struct VirtioBlk(pub usize);
+
+impl BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        virtio_blk_capacity(self.0) / SECTOR_SIZE as u64
+    }
+
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64) {
+        virtio_blk_read(self.0, &mut [buf], sector);
+    }
+
+    fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        virtio_blk_write(self.0, &[buf], sector);
+    }
+
+    fn read_only(&self) -> bool {
+        virtio_blk_read_only(self.0)
+    }
+
+    fn flush(&mut self) {
+        virtio_blk_flush(self.0);
+    }
+
+    // Scattered buffers go in as few requests as the device allows.
+    fn read_sectors(&mut self, bufs: &mut [&mut [u8]], sector: u64) {
+        virtio_blk_read(self.0, bufs, sector);
+    }
+
+    fn write_sectors(&mut self, bufs: &[&[u8]], sector: u64) {
+        virtio_blk_write(self.0, bufs, sector);
+    }
+}
+
+// Registers every disk.
+pub fn block_init() {
+    let virtio_units = virtio_blk_init();
+    let ram = cfg!(feature = "ramdisk") || virtio_units == 0;
+    if ram {
+        ramdisk_init();
+    }
+
+    let backends = ram.then_some(Backend::Ram).into_iter().chain((0..virtio_units).map(Backend::Virtio));
+    let mut disks = DISKS.lock();
+    for (i, (slot, backend)) in disks.iter_mut().zip(backends).enumerate() {
+        *slot = Some(backend);
+        match backend {
+            Backend::Virtio(unit) => log_info!("disk{} is virtio-blk{}", i, unit),
+            Backend::Ram => log_info!("disk{} is the RAM disk", i),
+        }
+    }
+}
+
+// A registered disk, read and written straight through to its device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Disk(usize);
+
+impl Disk {
+    // The disk holding the root file system.
+    pub const ROOT: Disk = Disk(0);
+
+    // Returns the disk called `name`, such as "disk1", if it is registered.
+    pub fn find(name: &str) -> Option<Disk> {
+        let index: usize = name.strip_prefix("disk")?.parse().ok()?;
+        DISKS.lock().get(index)?.map(|_| Disk(index))
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
+
+    // Runs `f` on the device holding the disk.
+    fn with_backend<R>(self, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> R {
+        // Copied out, so the lock is not held during I/O.
+        let backend = DISKS.lock()[self.0];
+        match backend.expect("disk should be registered") {
+            Backend::Virtio(unit) => f(&mut VirtioBlk(unit)),
+            Backend::Ram => f(&mut RamDisk),
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_count(&self) -> u64 {
+        self.with_backend(|dev| dev.sector_count())
+    }
+
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64) {
+        self.with_backend(|dev| dev.read_sector(buf, sector));
+    }
+
+    fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        self.with_backend(|dev| dev.write_sector(buf, sector));
+    }
+
+    fn read_only(&self) -> bool {
+        self.with_backend(|dev| dev.read_only())
+    }
+
+    fn flush(&mut self) {
+        self.with_backend(|dev| dev.flush());
+    }
+
+    fn read_sectors(&mut self, bufs: &mut [&mut [u8]], sector: u64) {
+        self.with_backend(|dev| dev.read_sectors(bufs, sector));
+    }
+
+    fn write_sectors(&mut self, bufs: &[&[u8]], sector: u64) {
+        self.with_backend(|dev| dev.write_sectors(bufs, sector));
+    }
+}
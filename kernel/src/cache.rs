@@ -0,0 +1,143 @@
+//! Sector cache for os1k
+//!
+//! One cache is shared by every disk: entries are keyed by disk and sector.
+
+use crate::block::{BlockDevice, Disk, SECTOR_SIZE};
+use crate::mutex::Mutex;
+
+const CACHE_ENTRIES: usize = 8;
+
+#[derive(Debug)]
+struct CacheEntry {
+    disk: Disk,             // Disk the sector belongs to
+    sector: Option<u64>,    // Sector held by this entry, None if unused
+    dirty: bool,            // Modified since it was last written to disk
+    last_used: usize,       // Cache tick of the most recent access, for LRU eviction
+    data: [u8; SECTOR_SIZE],
+}
+
+impl CacheEntry {
+    const fn empty() -> Self {
+        Self {
+            disk: Disk::ROOT,
+            sector: None,
+            dirty: false,
+            last_used: 0,
+            data: [0; SECTOR_SIZE],
+        }
+    }
+
+    fn write_back(&mut self) {
+        if let Some(sector) = self.sector && self.dirty {
+            self.disk.write_sector(&self.data, sector);
+            self.dirty = false;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SectorCache {
+    entries: [CacheEntry; CACHE_ENTRIES],
+    tick: usize,
+}
+
+impl SectorCache {
+    // Returns the entry holding `sector` of `disk`, loading it (and evicting the least recently
+    // used entry) on a miss. `load` is false when the caller will overwrite the whole sector.
+    fn entry(&mut self, disk: Disk, sector: u64, load: bool) -> &mut CacheEntry {
+        self.tick += 1;
+
+        let index = match self.entries.iter().position(|e| e.disk == disk && e.sector == Some(sector)) {
+            Some(index) => index,
+            None => {
+                let (index, victim) = self.entries.iter_mut()
+                    .enumerate()
+                    .min_by_key(|(_, e)| (e.sector.is_some(), e.last_used))
+                    .expect("cache should have entries");
+                victim.write_back();
+                victim.disk = disk;
+                victim.sector = Some(sector);
+                if load {
+                    victim.disk.read_sector(&mut victim.data, sector);
+                }
+                index
+            },
+        };
+
+        let entry = &mut self.entries[index];
+        entry.last_used = self.tick;
+        entry
+    }
+}
+
+// A Mutex, as misses wait for the disk.
+static CACHE: Mutex<SectorCache> = Mutex::new(SectorCache {
+    entries: [const { CacheEntry::empty() }; CACHE_ENTRIES],
+    tick: 0,
+});
+
+// Reads a sector of `disk` through the cache.
+pub fn read_sector(disk: Disk, buf: &mut [u8], sector: u64) {
+    buf.copy_from_slice(&CACHE.lock().entry(disk, sector, true).data);
+}
+
+// Writes a sector into the cache. It reaches the disk on eviction or `flush`,
+// so repeated writes to the same sector are coalesced.
+pub fn write_sector(disk: Disk, buf: &[u8], sector: u64) {
+    let mut cache = CACHE.lock();
+    let entry = cache.entry(disk, sector, false);
+    entry.data.copy_from_slice(buf);
+    entry.dirty = true;
+}
+
+// Writes every dirty sector to its disk, then flushes the disks written to so the sectors are on
+// stable storage. Runs of consecutive sectors go in a single request.
+pub fn flush() {
+    let mut cache = CACHE.lock();
+    cache.entries.sort_unstable_by_key(|e| e.sector.map(|sector| (e.disk, sector)));
+    let runs = cache.entries.chunk_by_mut(|a, b| {
+        a.dirty && b.dirty && a.disk == b.disk && a.sector.zip(b.sector).is_some_and(|(a, b)| b == a + 1)
+    });
+    let mut written: [Option<Disk>; CACHE_ENTRIES] = [None; CACHE_ENTRIES];
+    for run in runs {
+        if let Some(sector) = run[0].sector && run[0].dirty {
+            let mut bufs: [&[u8]; CACHE_ENTRIES] = [&[]; CACHE_ENTRIES];
+            bufs.iter_mut().zip(run.iter()).for_each(|(buf, e)| *buf = &e.data);
+            let mut disk = run[0].disk;
+            disk.write_sectors(&bufs[..run.len()], sector);
+            run.iter_mut().for_each(|e| e.dirty = false);
+            if let Some(slot) = written.iter_mut().find(|d| d.is_none_or(|d| d == disk)) {
+                *slot = Some(disk);
+            }
+        }
+    }
+    for mut disk in written.into_iter().flatten() {
+        disk.flush();
+    }
+}
+
+// A disk, read and written through the cache.
+#[derive(Debug)]
+pub struct CacheDisk(pub Disk);
+
+impl BlockDevice for CacheDisk {
+    fn sector_count(&self) -> u64 {
+        self.0.sector_count()
+    }
+
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64) {
+        read_sector(self.0, buf, sector);
+    }
+
+    fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        write_sector(self.0, buf, sector);
+    }
+}
+
+// Drops every sector of `disk` from the cache, writing back dirty data first.
+pub fn invalidate_all(disk: Disk) {
+    for entry in CACHE.lock().entries.iter_mut().filter(|e| e.disk == disk) {
+        entry.write_back();
+        entry.sector = None;
+    }
+}
@@ -0,0 +1,45 @@
+//! Condition variable for os1k
+//!
+//! A Condvar lets a process holding a SpinLock give it up and sleep until the state the lock
+//! protects changes, such as a device freeing room for another request. Wake-ups can be
+//! spurious, so callers check their condition again in a loop.
+
+use core::hint::spin_loop;
+
+use crate::scheduler::WaitQueue;
+use crate::spinlock::Guard;
+
+pub struct Condvar(WaitQueue);
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self(WaitQueue::new())
+    }
+
+    // Unlocks `guard`, sleeps until notified, then locks it again. Only one hart runs processes
+    // and the kernel is not preempted, so a notify cannot slip in between the two. Where the
+    // process cannot block, this returns at once and the caller's loop polls.
+    #[track_caller]
+    pub fn wait<'a, T>(&self, guard: Guard<'a, T>) -> Guard<'a, T> {
+        let lock = Guard::unlock(guard);
+        if !self.0.wait() {
+            spin_loop();
+        }
+        lock.lock()
+    }
+
+    #[allow(dead_code)]
+    pub fn notify_one(&self) {
+        self.0.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.0.wake_all();
+    }
+}
+
+impl core::fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("Condvar")
+    }
+}
@@ -0,0 +1,168 @@
+//! Console for os1k
+//!
+//! Output and keyboard input go through the virtio console if the machine has one, and through
+//! the 16550 UART otherwise. The backend is chosen once, at boot, by `console_init`; anything
+//! printed before then goes to the UART. Output is also drawn on the display, if there is one,
+//! and what the kernel prints is kept in the message log.
+//!
+//! Output is held back until the end of each line and then written in one go, so a line costs
+//! one pass through the locks and one display update, not one per byte. The kernel and every
+//! process have a line of their own, so lines from different processes never run into each
+//! other.
+
+use core::mem;
+
+use crate::dmesg::log_bytes;
+use crate::fbcon::fbcon_write;
+use crate::log_info;
+use crate::process::{PROCS, PROCS_MAX};
+use crate::scheduler::{running_pid, yield_now};
+use crate::spinlock::SpinLock;
+use crate::uart::{uart_get_char, uart_init, uart_read_char, uart_write};
+use crate::virtio_console::{virtio_console_get_char, virtio_console_init, virtio_console_write};
+
+const LINE_MAX: usize = 128;    // Output held back before it is written anyway
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    Uart,
+    Virtio,
+}
+
+static BACKEND: SpinLock<Backend> = SpinLock::new(Backend::Uart);
+
+// Output not yet written: the start of the current line.
+#[derive(Debug)]
+struct Line {
+    bytes: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self { bytes: [0; LINE_MAX], len: 0 }
+    }
+
+    // Adds `b`, and returns whether the line is ready to be written.
+    fn push(&mut self, b: u8) -> bool {
+        self.bytes[self.len] = b;
+        self.len += 1;
+        b == b'\n' || self.len == LINE_MAX
+    }
+
+    // Empties the line, returning what it held.
+    fn take(&mut self) -> &[u8] {
+        let len = mem::take(&mut self.len);
+        &self.bytes[..len]
+    }
+}
+
+static LINE: SpinLock<Line> = SpinLock::new(Line::new());
+
+// Output from processes, by process slot.
+static PROC_LINES: SpinLock<[Line; PROCS_MAX]> = SpinLock::new([const { Line::new() }; PROCS_MAX]);
+
+// Turns on UART input, and switches to the virtio console if there is one.
+pub fn console_init() {
+    uart_init();
+    if virtio_console_init() {
+        *BACKEND.lock() = Backend::Virtio;
+        log_info!("using the virtio console");
+    }
+}
+
+// Kernel output, through print! and println!. It is kept in the message log as well.
+#[unsafe(no_mangle)]
+pub fn put_byte(b: u8) -> Result<isize, isize> {
+    let mut line = LINE.lock();
+    if line.push(b) {
+        let bytes = line.take();
+        log_bytes(bytes);
+        emit(bytes)
+    } else {
+        Ok(0)
+    }
+}
+
+// Writes out kernel output held back waiting for the end of its line, such as a prompt.
+pub fn console_flush() {
+    let mut line = LINE.lock();
+    let bytes = line.take();
+    if !bytes.is_empty() {
+        log_bytes(bytes);
+        let _ = emit(bytes);
+    }
+}
+
+fn emit(bytes: &[u8]) -> Result<isize, isize> {
+    let backend = *BACKEND.lock();
+    fbcon_write(bytes);
+    match backend {
+        Backend::Uart => uart_write(bytes),
+        Backend::Virtio => virtio_console_write(bytes),
+    }
+}
+
+// Output from the running process, without logging it. Processes write through Syscall::PutByte
+// and /dev/console.
+pub fn write_bytes(bytes: &[u8]) -> Result<isize, isize> {
+    let Some(slot) = PROCS.try_get_index(running_pid()) else {
+        console_flush();
+        return emit(bytes);
+    };
+    let mut lines = PROC_LINES.lock();
+    let line = &mut lines[slot];
+    for &b in bytes {
+        if line.push(b) {
+            console_flush();
+            emit(line.take())?;
+        }
+    }
+    Ok(0)
+}
+
+pub fn write_byte(b: u8) -> Result<isize, isize> {
+    write_bytes(&[b])
+}
+
+// Writes out what the running process has written of its current line, as before it reads
+// input or once it exits.
+pub fn flush_process_output() {
+    let Some(slot) = PROCS.try_get_index(running_pid()) else {
+        return;
+    };
+    let mut lines = PROC_LINES.lock();
+    let bytes = lines[slot].take();
+    if !bytes.is_empty() {
+        console_flush();
+        let _ = emit(bytes);
+    }
+}
+
+// Returns the next byte typed, or Err(-1) if there is none yet.
+pub fn get_char() -> Result<isize, isize> {
+    console_flush();
+    flush_process_output();
+    let backend = *BACKEND.lock();
+    match backend {
+        Backend::Uart => uart_get_char(),
+        Backend::Virtio => virtio_console_get_char(),
+    }
+}
+
+// Returns the next byte typed, waiting for one. Only the UART interrupts on input, so the
+// virtio console is polled, letting other processes run in between.
+pub fn read_char() -> u8 {
+    console_flush();
+    flush_process_output();
+    let backend = *BACKEND.lock();
+    match backend {
+        Backend::Uart => uart_read_char(),
+        Backend::Virtio => loop {
+            if let Ok(ch) = virtio_console_get_char() {
+                break ch as u8;
+            }
+            yield_now();
+        },
+    }
+}
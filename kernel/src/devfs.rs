@@ -0,0 +1,99 @@
+//! Device file system for os1k
+
+use alloc::string::String;
+
+use crate::console::{get_char, read_char, write_bytes};
+use crate::random::fill_random;
+use crate::vfs::{DirEntry, FileSystem, OsError, Inode, OpenFile, Stat};
+
+// Devices in inode order.
+const DEVICES: [&str; 4] = ["console", "null", "zero", "random"];
+const DEV_CONSOLE: Inode = 0;
+const DEV_NULL: Inode = 1;
+const DEV_ZERO: Inode = 2;
+const DEV_RANDOM: Inode = 3;
+
+#[derive(Debug)]
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn lookup(&self, name: &str) -> Option<Inode> {
+        DEVICES.iter().position(|&dev| dev == name)
+    }
+
+    fn create(&self, _name: &str) -> Result<Inode, OsError> {
+        Err(OsError::Unsupported)
+    }
+
+    fn read(&self, inode: Inode, _offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        match inode {
+            DEV_CONSOLE => {
+                // Wait for the first byte, then return whatever else is already available.
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = read_char();
+                let mut len = 1;
+                while len < buf.len() {
+                    let Ok(ch) = get_char() else {
+                        break;
+                    };
+                    buf[len] = ch as u8;
+                    len += 1;
+                }
+                Ok(len)
+            },
+            DEV_NULL => Ok(0),  // Always at the end of file
+            DEV_ZERO => {
+                buf.fill(0);
+                Ok(buf.len())
+            },
+            DEV_RANDOM => {
+                fill_random(buf);
+                Ok(buf.len())
+            },
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    fn write(&self, inode: Inode, _offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        match inode {
+            DEV_CONSOLE => {
+                let _ = write_bytes(buf);
+                Ok(buf.len())
+            },
+            DEV_NULL | DEV_ZERO | DEV_RANDOM => Ok(buf.len()),  // Discarded
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    // Devices have no contents to empty, so redirecting output to one works.
+    fn truncate(&self, inode: Inode) -> Result<(), OsError> {
+        match inode {
+            DEV_CONSOLE | DEV_NULL | DEV_ZERO | DEV_RANDOM => Ok(()),
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        DEVICES.get(index).map(|&name| DirEntry { name: String::from(name), inode: index })
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        match inode {
+            DEV_CONSOLE..=DEV_RANDOM => Ok(Stat { size: 0 }),
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    fn sync(&self, _inode: Inode) {}
+
+    fn sync_all(&self) {}
+}
+
+pub static DEVFS: DevFs = DevFs;
+
+// Whether the file is /dev/console, so reading it can go through the line discipline.
+pub fn is_console(file: &OpenFile) -> bool {
+    core::ptr::addr_eq(file.fs, &DEVFS) && file.inode == DEV_CONSOLE
+}
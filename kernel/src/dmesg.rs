@@ -0,0 +1,39 @@
+//! Kernel message log for os1k
+//!
+//! Everything the kernel prints is also kept in a ring buffer, so messages that have scrolled
+//! away, such as those from boot, can be read back with Syscall::Dmesg. Once the buffer is full,
+//! new messages overwrite the oldest. Output from processes is not kept.
+
+use crate::spinlock::SpinLock;
+
+const LOG_SIZE: usize = 16 * 1024;
+
+struct Log {
+    bytes: [u8; LOG_SIZE],
+    written: usize,     // Bytes logged since boot; the last LOG_SIZE of them are kept
+}
+
+static LOG: SpinLock<Log> = SpinLock::new(Log { bytes: [0; LOG_SIZE], written: 0 });
+
+pub fn log_bytes(bytes: &[u8]) {
+    let mut log = LOG.lock();
+    for &b in bytes {
+        let i = log.written % LOG_SIZE;
+        log.bytes[i] = b;
+        log.written += 1;
+    }
+}
+
+// Copies the kept messages, from `offset` bytes into them, into `buf`. Returns the number of
+// bytes copied, which is 0 at the end.
+pub fn log_read(offset: usize, buf: &mut [u8]) -> usize {
+    let log = LOG.lock();
+    let kept = log.written.min(LOG_SIZE);
+    let start = log.written - kept;
+    let mut len = 0;
+    for (b, pos) in buf.iter_mut().zip(offset..kept) {
+        *b = log.bytes[(start + pos) % LOG_SIZE];
+        len += 1;
+    }
+    len
+}
@@ -1,26 +1,135 @@
 //! Kernel entry
 
 use alloc::slice;
+use alloc::vec::Vec;
 use core::arch::naked_asm;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use common::{
-    SYS_PUTBYTE,
-    SYS_GETCHAR,
-    SYS_EXIT,
-    SYS_READFILE,
-    SYS_WRITEFILE,
+    CLOCK_MONOTONIC,
+    FUTEX_WAIT,
+    FUTEX_WAKE,
+    SHUTDOWN_POWEROFF,
+    SHUTDOWN_REBOOT,
+    SHUTDOWN_EXIT,
+    EXIT_INTERRUPTED,
+    EXIT_KILLED,
+    LOCK_EX,
+    LOCK_NB,
+    LOCK_SH,
+    LOCK_UN,
+    SEEK_SET,
+    SEEK_CUR,
+    SEEK_END,
+    STDIN,
 };
+use common::abi::{AbiVersion, DirEnt, Signal, SpawnArgs, Stat, TimeSpec};
+use common::abi::{ABI_VERSION, FEATURE_FDS, FEATURE_NET, FEATURE_SIGNALS};
+use common::Syscall;
+use common::net::SockAddr;
 
-use crate::process::{PROCS, State};
-use crate::sbi::{put_byte, get_char};
-use crate::scheduler::{yield_now, CURRENT_PROC};
-use crate::tar::{FILES, fs_flush};
-use crate::{println, read_csr, write_csr};
+use crate::address::align_up;
+use crate::allocator::PAGE_SIZE;
+use crate::console::{read_char, write_byte};
+use crate::devfs::is_console;
+use crate::dmesg::log_read;
+use crate::env::{getenv, listenv, setenv};
+use crate::flock;
+use crate::fstype;
+use crate::futex::{futex_wait, futex_wake};
+use crate::log::{set_log_level, LOG_TRACE};
+use crate::net;
+use crate::ipi::ipi_handle;
+use crate::plic::{plic_claim, plic_complete};
+use crate::pipe;
+use crate::power;
+use crate::process::{create_thread, exit_current, sbrk, set_group, spawn, wait, FDS_MAX, INIT_PID, PROCS};
+use crate::random::fill_random;
+use crate::scheduler::{running_pid, sleep_ms, CURRENT_PROC};
+use crate::smp::boot_hart;
+use crate::timer::{monotonic_ns, timer_handle_interrupt};
+use crate::tty::{continue_group, read_line, set_foreground, take_interrupt};
+use crate::uart::{uart_handle_interrupt, UART_IRQ};
+use crate::vfs::{self, OsError};
+use crate::virtio::virtio_handle_interrupt;
+use crate::{log_info, log_warn, print, println, read_csr};
 
-const SCAUSE_ECALL: usize = 8;
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);  // Set for interrupts, clear for exceptions
+
+// Interrupt cause codes
+const IRQ_SUPERVISOR_SOFTWARE: usize = 1;
+const IRQ_SUPERVISOR_TIMER: usize = 5;
+const IRQ_SUPERVISOR_EXTERNAL: usize = 9;
+
+// Exception cause codes
+const EXC_INSTRUCTION_MISALIGNED: usize = 0;
+const EXC_ILLEGAL_INSTRUCTION: usize = 2;
+const EXC_BREAKPOINT: usize = 3;
+const EXC_LOAD_MISALIGNED: usize = 4;
+const EXC_STORE_MISALIGNED: usize = 6;
+const EXC_ECALL_FROM_USER: usize = 8;
+const EXC_INSTRUCTION_PAGE_FAULT: usize = 12;
+const EXC_LOAD_PAGE_FAULT: usize = 13;
+const EXC_STORE_PAGE_FAULT: usize = 15;
+
+// The optional parts of the syscall interface this kernel has, for Syscall::GetVersion
+const FEATURES: u32 = FEATURE_FDS | FEATURE_NET | FEATURE_SIGNALS;
+
+// The cause of a trap, decoded from scause.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trap {
+    SupervisorSoftware,
+    SupervisorTimer,
+    SupervisorExternal,
+    Interrupt(usize),           // Any other interrupt, by cause code
+    IllegalInstruction,
+    Breakpoint,
+    Misaligned(Access),
+    UserEcall,
+    PageFault(Access),
+    Exception(usize),           // Any other exception, by cause code
+}
+
+// What a faulting access was doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Trap {
+    fn from_scause(scause: usize) -> Self {
+        let code = scause & !SCAUSE_INTERRUPT;
+        if scause & SCAUSE_INTERRUPT != 0 {
+            match code {
+                IRQ_SUPERVISOR_SOFTWARE => Trap::SupervisorSoftware,
+                IRQ_SUPERVISOR_TIMER => Trap::SupervisorTimer,
+                IRQ_SUPERVISOR_EXTERNAL => Trap::SupervisorExternal,
+                _ => Trap::Interrupt(code),
+            }
+        } else {
+            match code {
+                EXC_INSTRUCTION_MISALIGNED => Trap::Misaligned(Access::Fetch),
+                EXC_ILLEGAL_INSTRUCTION => Trap::IllegalInstruction,
+                EXC_BREAKPOINT => Trap::Breakpoint,
+                EXC_LOAD_MISALIGNED => Trap::Misaligned(Access::Load),
+                EXC_STORE_MISALIGNED => Trap::Misaligned(Access::Store),
+                EXC_ECALL_FROM_USER => Trap::UserEcall,
+                EXC_INSTRUCTION_PAGE_FAULT => Trap::PageFault(Access::Fetch),
+                EXC_LOAD_PAGE_FAULT => Trap::PageFault(Access::Load),
+                EXC_STORE_PAGE_FAULT => Trap::PageFault(Access::Store),
+                _ => Trap::Exception(code),
+            }
+        }
+    }
+}
 
 #[repr(C, packed)]
-struct TrapFrame{
+#[derive(Clone, Copy)]
+pub struct TrapFrame{
     ra: usize,
     gp: usize,
     tp: usize,
@@ -52,15 +161,60 @@ struct TrapFrame{
     s10: usize,
     s11: usize,
     sp: usize,
+    sepc: usize,        // Where the trap returns to
+    scause: usize,
+    sstatus: usize,     // SPP tells whether the trap came from the kernel
+}
+
+impl TrapFrame {
+    fn is_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+}
+
+// Shows the registers, four to a line. The fields are copied out, as the struct is packed.
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regs = [
+            ("pc", self.sepc), ("ra", self.ra), ("sp", self.sp), ("gp", self.gp),
+            ("tp", self.tp), ("t0", self.t0), ("t1", self.t1), ("t2", self.t2),
+            ("s0", self.s0), ("s1", self.s1), ("a0", self.a0), ("a1", self.a1),
+            ("a2", self.a2), ("a3", self.a3), ("a4", self.a4), ("a5", self.a5),
+            ("a6", self.a6), ("a7", self.a7), ("s2", self.s2), ("s3", self.s3),
+            ("s4", self.s4), ("s5", self.s5), ("s6", self.s6), ("s7", self.s7),
+            ("s8", self.s8), ("s9", self.s9), ("s10", self.s10), ("s11", self.s11),
+            ("t3", self.t3), ("t4", self.t4), ("t5", self.t5), ("t6", self.t6),
+        ];
+        for line in regs.chunks(4) {
+            for (name, value) in line {
+                write!(f, "{:>3}=0x{:08x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// The frame of the most recent trap, for the panic handler. It stays on its kernel stack after
+// the trap returns, so it may be stale but is always readable.
+static LAST_TRAP: AtomicPtr<TrapFrame> = AtomicPtr::new(ptr::null_mut());
+
+pub fn last_trap() -> Option<&'static TrapFrame> {
+    // Safety: LAST_TRAP is null or points at a frame on a kernel stack, which is never freed
+    unsafe { LAST_TRAP.load(Ordering::Relaxed).as_ref() }
 }
 
 #[unsafe(naked)]
 pub unsafe extern "C" fn kernel_entry() {
     naked_asm!(
         ".align 2",
-        // Retrieve the kernel stack of the running process from sscratch.
+        // While a process runs in user mode, sscratch holds its kernel stack. While the hart
+        // runs the kernel it holds 0, and a trap stays on the current stack.
+        "csrrw sp, sscratch, sp",
+        "bnez sp, 1f",
         "csrrw sp, sscratch, sp",
-        "addi sp, sp, -4 * 31",
+        "1:",
+        "addi sp, sp, -4 * 34",
         "sw ra,  4 * 0(sp)",
         "sw gp,  4 * 1(sp)",
         "sw tp,  4 * 2(sp)",
@@ -92,17 +246,38 @@ pub unsafe extern "C" fn kernel_entry() {
         "sw s10, 4 * 28(sp)",
         "sw s11, 4 * 29(sp)",
 
-        // Retrieve and save the sp at the time of exeception
-        "csrr a0, sscratch",
+        // Save the sp at the time of the trap: the user sp left in sscratch, or the kernel sp
+        // above the frame. The hart is in the kernel now, so sscratch becomes 0.
+        "csrrw a0, sscratch, zero",
+        "bnez a0, 2f",
+        "addi a0, sp, 4 * 34",
+        "2:",
         "sw a0, 4 * 30(sp)",
 
-        // Reset the kernel stack.
-        "addi a0, sp, 4 * 31",
-        "csrw sscratch, a0",
+        // Save the trap CSRs, which a nested trap or a switch to another process overwrites.
+        "csrr a0, sepc",
+        "sw a0, 4 * 31(sp)",
+        "csrr a0, scause",
+        "sw a0, 4 * 32(sp)",
+        "csrr a0, sstatus",
+        "sw a0, 4 * 33(sp)",
 
         "mv a0, sp",
         "call handle_trap",
 
+        "lw a0, 4 * 31(sp)",
+        "csrw sepc, a0",
+        "lw a0, 4 * 33(sp)",
+        "csrw sstatus, a0",
+
+        // Returning to user mode, the frame is at the top of the kernel stack: hand the stack
+        // back to sscratch for the next trap.
+        "andi a0, a0, {sstatus_spp}",
+        "bnez a0, 3f",
+        "addi a0, sp, 4 * 34",
+        "csrw sscratch, a0",
+        "3:",
+
         "lw ra,  4 * 0(sp)",
         "lw gp,  4 * 1(sp)",
         "lw tp,  4 * 2(sp)",
@@ -134,7 +309,8 @@ pub unsafe extern "C" fn kernel_entry() {
         "lw s10, 4 * 28(sp)",
         "lw s11, 4 * 29(sp)",
         "lw sp,  4 * 30(sp)",
-        "sret"
+        "sret",
+        sstatus_spp = const SSTATUS_SPP,
     )
 }
 
@@ -142,12 +318,69 @@ pub unsafe extern "C" fn kernel_entry() {
 // starting address defined in `user.ld`.
 pub const USER_BASE: usize = 0x1000000;
 
+// Checks that the `len` bytes at `addr` are in the current process's memory: its image, which
+// holds its stack, then its arguments and heap, which follow one after the other from USER_BASE.
+// Fails with Fault otherwise, as a pointer from a process could reach the kernel's own memory.
+pub fn check_user(addr: usize, len: usize) -> Result<(), OsError> {
+    let end = PROCS.with_leader(|p| align_up(p.heap_end, PAGE_SIZE));
+    match addr.checked_add(len) {
+        Some(last) if addr >= USER_BASE && last <= end => Ok(()),
+        _ => Err(OsError::Fault),
+    }
+}
+
+// The `len` bytes a process passed at `addr`, once they are checked to be its own.
+fn user_bytes<'a>(addr: usize, len: usize) -> Result<&'a [u8], OsError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_user(addr, len)?;
+    // Safety: the bytes are mapped user memory, which the kernel can read with SUM set
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+}
+
+// As user_bytes, for a buffer the kernel fills.
+fn user_bytes_mut<'a>(addr: usize, len: usize) -> Result<&'a mut [u8], OsError> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    check_user(addr, len)?;
+    // Safety: the bytes are mapped user memory, which the kernel can write with SUM set
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+// As user_bytes, for a path or name. Fails with Invalid if it is not UTF-8.
+fn user_str<'a>(addr: usize, len: usize) -> Result<&'a str, OsError> {
+    str::from_utf8(user_bytes(addr, len)?).map_err(|_| OsError::Invalid)
+}
+
+// Reads a structure a process passed at `addr`.
+fn read_user<T: Copy>(addr: usize) -> Result<T, OsError> {
+    check_user(addr, size_of::<T>())?;
+    // Safety: the structure is in mapped user memory, which the kernel can read with SUM set
+    Ok(unsafe { (addr as *const T).read_unaligned() })
+}
+
+// Writes `value` to the structure a process passed at `addr`.
+fn write_user<T>(addr: usize, value: T) -> Result<(), OsError> {
+    check_user(addr, size_of::<T>())?;
+    // Safety: the structure is in mapped user memory, which the kernel can write with SUM set
+    unsafe { (addr as *mut T).write_unaligned(value) };
+    Ok(())
+}
+
 const SSTATUS_SPIE: usize =  1 << 5;    // Enable user mode
+const SSTATUS_SPP: usize = 1 << 8;      // Set if the trap came from supervisor mode
 const SSTATUS_SUM: usize = 1 << 18;
 
 #[unsafe(naked)]
 pub extern "C" fn  user_entry() {
     naked_asm!(
+        // The new process's kernel stack is empty, so sp is its top.
+        "csrw sscratch, sp",
+        // argc and argv, left in s1 and s2 by create_process
+        "mv a0, s1",
+        "mv a1, s2",
         "li t0, {user_base}",
         "csrw sepc, t0",
         "li t0, {sstatus}",
@@ -158,102 +391,690 @@ pub extern "C" fn  user_entry() {
     )
 }
 
+#[unsafe(naked)]
+pub extern "C" fn thread_entry() {
+    naked_asm!(
+        // As user_entry, with the entry point, argument and user stack left in s1 to s3 by
+        // create_thread. A zero frame pointer ends a panic backtrace.
+        "csrw sscratch, sp",
+        "mv a0, s2",
+        "mv sp, s3",
+        "li s0, 0",
+        "csrw sepc, s1",
+        "li t0, {sstatus}",
+        "csrw sstatus, t0",
+        "sret",
+        sstatus = const SSTATUS_SPIE | SSTATUS_SUM,
+    )
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn handle_trap(f: &mut TrapFrame) {
-    let scause = read_csr!("scause");
+    LAST_TRAP.store(f, Ordering::Relaxed);
+    let scause = f.scause;
     let stval = read_csr!("stval");
-    let mut user_pc = read_csr!("sepc");
+    let pc = f.sepc;
+    let trap = Trap::from_scause(scause);
 
-    if scause == SCAUSE_ECALL {
-        handle_syscall(f);
-        user_pc += 4;
-    } else {
-            panic!("unexpected trap scause=0x{:x}, stval=0x{:x}, sepc=0x{:x}", scause, stval, user_pc);
+    // Interrupts are handled wherever they come from. Exceptions in the kernel are bugs.
+    let is_interrupt = scause & SCAUSE_INTERRUPT != 0;
+    if !f.is_user() && !is_interrupt {
+        panic!("kernel trap {:?} scause=0x{:x}, stval=0x{:x}, sepc=0x{:x}", trap, scause, stval, pc);
+    }
+
+    // Interrupts resume the interrupted instruction once handled.
+    match trap {
+        Trap::UserEcall => {
+            // Step past the ecall first, so that Syscall::SigReturn can set where to go back to.
+            f.sepc += 4;
+            handle_syscall(f);
+        },
+        Trap::SupervisorSoftware => handle_ipi(),
+        Trap::SupervisorTimer => timer_handle_interrupt(),
+        Trap::SupervisorExternal => handle_external_interrupt(),
+        Trap::PageFault(access) => handle_page_fault(access, stval, pc),
+        Trap::IllegalInstruction => handle_illegal_instruction(stval, pc),
+        Trap::Misaligned(access) => handle_misaligned(access, stval, pc),
+        Trap::Breakpoint => handle_breakpoint(f),
+        trap => panic!("unexpected trap {:?} scause=0x{:x}, stval=0x{:x}, sepc=0x{:x}", trap, scause, stval, pc),
     }
 
-    write_csr!("sepc", user_pc);
+    // Ctrl-C reaches the foreground process here, before it runs any more.
+    if f.is_user() && take_interrupt(running_pid()) {
+        deliver_interrupt(f);
+    }
+    // So does the end of the process a thread belongs to.
+    if f.is_user() && PROCS.with_current(|p| p.killed) {
+        exit_current(EXIT_KILLED);
+    }
+}
+
+// Calls the current process's handler for Ctrl-C, or ends the process if it has none. The
+// registers it was interrupted with are saved on its stack, and the handler is called with
+// Signal::Interrupt and returns to the restorer, which passes them to Syscall::SigReturn. A
+// process whose stack pointer is not in its own memory is killed.
+fn deliver_interrupt(f: &mut TrapFrame) {
+    let (handler, restorer) = PROCS.with_leader(|p| (p.interrupt_handler, p.signal_restorer));
+    if handler == 0 {
+        exit_current(EXIT_INTERRUPTED);
+    }
+    let saved_at = f.sp.wrapping_sub(size_of::<TrapFrame>()) & !0xf;   // Keep sp 16-byte aligned
+    if write_user(saved_at, *f).is_err() {
+        exit_current(EXIT_KILLED);
+    }
+    f.sp = saved_at;
+    f.sepc = handler;
+    f.ra = restorer;
+    f.a0 = Signal::Interrupt as usize;
+}
+
+fn handle_external_interrupt() {
+    let irq = plic_claim();
+    match irq {
+        0 => return,
+        UART_IRQ => uart_handle_interrupt(),
+        _ => virtio_handle_interrupt(irq),
+    }
+    plic_complete(irq);
+}
+
+// Processes only run on the boot hart, so that is where the IPI arrived.
+fn handle_ipi() {
+    ipi_handle(boot_hart());
+}
+
+// A fault in user code is the process's own bug, so only the process goes.
+fn handle_page_fault(access: Access, addr: usize, pc: usize) {
+    let current = PROCS.with_current(|p| p.pid);
+    log_warn!("process {} killed: page fault on {:?} at 0x{:x}, sepc=0x{:x}", current, access, addr, pc);
+    exit_current(EXIT_KILLED);
+}
+
+// stval holds the instruction's encoding, if the machine reports it, and 0 otherwise.
+fn handle_illegal_instruction(instruction: usize, pc: usize) {
+    let instruction = if instruction != 0 { instruction as u32 } else { read_user_instruction(pc) };
+    let current = PROCS.with_current(|p| p.pid);
+    log_warn!(
+        "process {} killed: illegal instruction {}, sepc=0x{:x}",
+        current, InstructionBytes(instruction), pc,
+    );
+    exit_current(EXIT_KILLED);
+}
+
+// For a misaligned fetch, `addr` is the jump target and `pc` the jump.
+fn handle_misaligned(access: Access, addr: usize, pc: usize) {
+    let current = PROCS.with_current(|p| p.pid);
+    log_warn!(
+        "process {} killed: misaligned {:?} at 0x{:x} by instruction {}, sepc=0x{:x}",
+        current, access, addr, InstructionBytes(read_user_instruction(pc)), pc,
+    );
+    exit_current(EXIT_KILLED);
+}
+
+// Stops the process at an `ebreak` and shows its registers on the console, then waits for the
+// user to continue past the breakpoint or kill the process. Other processes keep running.
+fn handle_breakpoint(f: &mut TrapFrame) {
+    let current = PROCS.with_current(|p| p.pid);
+    let pc = f.sepc;
+    println!("process {} hit a breakpoint, sepc=0x{:x}", current, pc);
+    print!("{}", f);
+    loop {
+        print!("debug: [c]ontinue, [r]egisters or [k]ill? ");
+        let command = read_char();
+        println!("{}", command as char);
+        match command {
+            b'c' => {
+                // Skip the ebreak, which is 2 bytes if compressed.
+                f.sepc += if read_user_instruction(pc) & 0b11 == 0b11 { 4 } else { 2 };
+                return;
+            },
+            b'r' => print!("{}", f),
+            b'k' => {
+                println!("process {} killed at a breakpoint", current);
+                exit_current(EXIT_KILLED);
+            },
+            _ => {},
+        }
+    }
+}
+
+// Reads the instruction at `pc` in the current process: 16 bits if it is compressed, else 32.
+fn read_user_instruction(pc: usize) -> u32 {
+    // Safety: the hart has just fetched the instruction at pc through the current page table,
+    // and SUM is set, so the kernel can read it.
+    let low = unsafe { (pc as *const u16).read_volatile() } as u32;
+    if low & 0b11 != 0b11 {
+        return low;
+    }
+    // Safety: as above, for the second half of a 32-bit instruction.
+    let high = unsafe { ((pc + 2) as *const u16).read_volatile() } as u32;
+    high << 16 | low
+}
+
+// Shows an instruction as hex, 4 digits if it is compressed and 8 otherwise.
+struct InstructionBytes(u32);
+
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = if self.0 & 0b11 == 0b11 { 8 } else { 4 };
+        write!(f, "0x{:0digits$x}", self.0)
+    }
 }
 
 fn handle_syscall(f: &mut TrapFrame) {
-    let sysno = f.a4;
+    let sysno = match Syscall::try_from(f.a4) {
+        Ok(sysno) => sysno,
+        Err(sysno) => {
+            log_warn!("process {} made unknown syscall {}", running_pid(), sysno);
+            f.a0 = OsError::Unsupported.to_usize();
+            return;
+        }
+    };
     match sysno {
-        SYS_PUTBYTE => {  // Match what user code sends
-            match put_byte(f.a0 as u8) {
+        Syscall::PutByte => {  // Match what user code sends
+            match write_byte(f.a0 as u8) {
                 Ok(_) => f.a0 = 0,     // Set return value to 0 (success)
-                Err(e) => f.a0 = e as usize,    // Set return value to error code
+                Err(_) => f.a0 = OsError::Unsupported.to_usize(),   // The console refused it
             }
         },
-        SYS_GETCHAR => {
-            loop {
-                if let Ok(ch) = get_char() {
-                    f.a0 = ch as usize;
-                    break;
-                }
-                yield_now();
-            }
+        Syscall::GetChar => {
+            f.a0 = read_char() as usize;
         },
-        SYS_EXIT => {
+        Syscall::Exit => {
             let current = CURRENT_PROC.lock()
                 .expect("current process should be running");
-            crate::println!("process {} exited", current);
-            if let Some(p) = PROCS.0.lock().iter_mut()
-                .find(|p| p.pid == current) {
-                    p.state = State::Exited
-                }
-            yield_now();
-            unreachable!("unreachable after SYS_EXIT");
+            log_info!("process {} exited with {}", current, f.a0 & 0xff);
+            exit_current(f.a0 & 0xff);
+        },
+        Syscall::ReadFile | Syscall::WriteFile => 'block: {
+            let offset = f.a5;
+            let flags = f.a6;
+
+            let args = user_str(f.a0, f.a1).and_then(|filename| Ok((filename, user_bytes_mut(f.a2, f.a3)?)));
+            let (filename, buf) = match args {
+                Ok(args) => args,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
+
+            // println!("handling syscall Syscall::ReadFile | Syscall::WriteFile for file {:?}", filename);
+
+            let mut file = match vfs::open(filename, flags) {
+                Ok(file) => file,
+                Err(e) => {
+                    log_warn!("could not open {:x?}: {:?}", filename, e);
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
+            file.offset = offset;
+
+            let result = match sysno {
+                // Written back to disk by Syscall::Fsync
+                Syscall::WriteFile => file.write(file.write_offset(), buf),
+                Syscall::ReadFile => file.fs.read(file.inode, file.offset, buf),
+                _ => unreachable!("sysno must be Syscall::ReadFile or Syscall::WriteFile"),
+            };
+
+            f.a0 = result.unwrap_or_else(OsError::to_usize);
         },
-        SYS_READFILE | SYS_WRITEFILE => 'block: {
-            let filename_ptr = f.a0 as *const u8;
+        Syscall::Fsync => 'block: {
             let filename_len = f.a1;
 
-            // Safety: Caller guarantees that filename_ptr points to valid memory
-            // of length filename_len that remains valid for the lifetime of this reference
-            let filename = unsafe {
-                str::from_utf8(slice::from_raw_parts(filename_ptr, filename_len))
-            }.expect("filename must be valid UTF-8");
+            // An empty filename flushes every file.
+            if filename_len == 0 {
+                vfs::sync_all();
+                f.a0 = 0;
+                break 'block;
+            }
 
-            let buf_ptr = f.a2 as *mut u8;
-            let buf_len = f.a3;
+            let found = user_str(f.a0, filename_len).and_then(|filename| {
+                vfs::lookup(filename).inspect_err(|e| log_warn!("could not sync {:x?}: {:?}", filename, e))
+            });
+            let (fs, inode) = match found {
+                Ok(found) => found,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
 
-            // Safety: Caller guarantees that buf_ptr points to valid memory
-            // of length buf_len that remains valid for the lifetime of this reference
-            let buf = unsafe {
-                slice::from_raw_parts_mut(buf_ptr, buf_len)
+            fs.sync(inode);
+            f.a0 = 0;
+        },
+        Syscall::Open => {
+            let flags = f.a2;
+
+            f.a0 = user_str(f.a0, f.a1)
+                .and_then(|path| vfs::open(path, flags))
+                .and_then(|file| PROCS.with_current(|p| {
+                    let fd = p.files.iter().position(Option::is_none).ok_or(OsError::NoSpace)?;
+                    p.files[fd] = Some(file);
+                    Ok(fd)
+                }))
+                .unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::Read | Syscall::Write => 'block: {
+            let fd = f.a0;
+            let buf = match user_bytes_mut(f.a1, f.a2) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
             };
 
-            // println!("handling syscall SYS_READFILE | SYS_WRITEFILE for file {:?}", filename);
+            // Copy the open file out: reading the console may yield, which needs PROCS.
+            let Some(file) = PROCS.with_current(|p| p.files.get(fd).copied().flatten()) else {
+                f.a0 = OsError::BadFd.to_usize();
+                break 'block;
+            };
 
-            let Some(file_i) = FILES.fs_lookup(filename) else {
-                println!("file not found {:x?}", filename);
-                f.a0 = usize::MAX; // 2's complement is -1
+            let (offset, result) = match sysno {
+                Syscall::Read => (file.offset, file.fs.read(file.inode, file.offset, buf)),
+                Syscall::Write => {
+                    let offset = file.write_offset();
+                    (offset, file.write(offset, buf))
+                },
+                _ => unreachable!("sysno must be Syscall::Read or Syscall::Write"),
+            };
+
+            let len = match result {
+                Ok(len) => len,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
+
+            PROCS.with_current(|p| {
+                if let Some(Some(file)) = p.files.get_mut(fd) {
+                    file.offset = offset + len;
+                }
+            });
+            f.a0 = len;
+        },
+        Syscall::Seek => 'block: {
+            let fd = f.a0;
+            let offset = f.a1 as isize;
+
+            let Some(file) = PROCS.with_current(|p| p.files.get(fd).copied().flatten()) else {
+                f.a0 = OsError::BadFd.to_usize();
                 break 'block;
             };
 
-            match sysno {
-                SYS_WRITEFILE => {
-                    let mut files = FILES.0.lock();
-                    // try_borrow_mut()
-                    // .expect("should be able to borrow FILES mutably to handle SYS_WRITEFILE");
-
-                    files[file_i].data[..buf.len()].copy_from_slice(buf);
-                    files[file_i].size = buf.len();
-                    drop(files);
-                    fs_flush();
+            let base = match f.a2 {
+                SEEK_SET => Ok(0),
+                SEEK_CUR => Ok(file.offset),
+                SEEK_END => file.fs.stat(file.inode).map(|stat| stat.size),
+                _ => Err(OsError::Invalid),
+            };
+            // Seeking past the end is allowed, and a write there grows the file.
+            let new_offset = base.and_then(|base| base.checked_add_signed(offset).ok_or(OsError::Invalid));
+            f.a0 = match new_offset {
+                Ok(new_offset) => {
+                    PROCS.with_current(|p| {
+                        if let Some(Some(file)) = p.files.get_mut(fd) {
+                            file.offset = new_offset;
+                        }
+                    });
+                    new_offset
+                },
+                Err(e) => e.to_usize(),
+            };
+        },
+        Syscall::Fstat => {
+            let fd = f.a0;
+            f.a0 = PROCS.with_current(|p| p.files.get(fd).copied().flatten())
+                .ok_or(OsError::BadFd)
+                .and_then(|file| Ok(Stat { size: file.fs.stat(file.inode)?.size as u64, inode: file.inode as u64 }))
+                .and_then(|stat| write_user::<Stat>(f.a1, stat))
+                .map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::Close => {
+            let fd = f.a0;
+            let closed = PROCS.with_current(|p| {
+                let file = p.files.get_mut(fd)?.take()?;
+                // The process's lock on the file goes with its last descriptor for it.
+                let still_open = p.files.iter().flatten()
+                    .any(|other| core::ptr::addr_eq(other.fs, file.fs) && other.inode == file.inode);
+                Some((p.pid, file, still_open))
+            });
+            f.a0 = match closed {
+                Some((pid, file, still_open)) => {
+                    file.close();
+                    if !still_open {
+                        flock::unlock(&file, pid);
+                    }
+                    0
                 },
-                SYS_READFILE => {
-                    let files = FILES.0.lock();
-                    // try_borrow()
-                    // .expect("should be able to borrow FILES to handle SYS_READFILE");
+                None => OsError::BadFd.to_usize(),
+            };
+        },
+        Syscall::Pipe => {
+            // The descriptors for the read and write ends go in the two words a0 points at.
+            let ends = check_user(f.a0, size_of::<[usize; 2]>()).and_then(|()| pipe::pipe()).and_then(|(read_end, write_end)| {
+                let fds = PROCS.with_current(|p| {
+                    let mut free = (0..FDS_MAX).filter(|&fd| p.files[fd].is_none());
+                    let fds = (free.next()?, free.next()?);
+                    p.files[fds.0] = Some(read_end);
+                    p.files[fds.1] = Some(write_end);
+                    Some(fds)
+                });
+                fds.ok_or_else(|| {
+                    read_end.close();
+                    write_end.close();
+                    OsError::NoSpace
+                })
+            });
+            f.a0 = ends
+                .and_then(|(read_fd, write_fd)| write_user(f.a0, [read_fd, write_fd]))
+                .map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::Dup | Syscall::Dup2 => {
+            let fd = f.a0;
+            // Syscall::Dup takes the lowest free descriptor, and Syscall::Dup2 the one in a1,
+            // closing whatever was open there.
+            let to = match sysno {
+                Syscall::Dup => None,
+                Syscall::Dup2 => Some(f.a1),
+                _ => unreachable!("sysno must be Syscall::Dup or Syscall::Dup2"),
+            };
+            let duped = PROCS.with_current(|p| {
+                let file = p.files.get(fd).copied().flatten().ok_or(OsError::BadFd)?;
+                let to = match to {
+                    Some(to) if to < FDS_MAX => to,
+                    Some(_) => return Err(OsError::BadFd),
+                    None => p.files.iter().position(Option::is_none).ok_or(OsError::NoSpace)?,
+                };
+                if to == fd {
+                    return Ok((to, None));
+                }
+                file.dup();
+                let replaced = p.files[to].replace(file).map(|old| {
+                    let still_open = p.files.iter().flatten()
+                        .any(|other| core::ptr::addr_eq(other.fs, old.fs) && other.inode == old.inode);
+                    (p.pid, old, still_open)
+                });
+                Ok((to, replaced))
+            });
+            f.a0 = match duped {
+                Ok((to, replaced)) => {
+                    // As for Syscall::Close, a lock goes with the process's last descriptor.
+                    if let Some((pid, old, still_open)) = replaced {
+                        old.close();
+                        if !still_open {
+                            flock::unlock(&old, pid);
+                        }
+                    }
+                    to
+                },
+                Err(e) => e.to_usize(),
+            };
+        },
+        Syscall::Flock => 'block: {
+            let fd = f.a0;
+            let op = f.a1;
+
+            let Some((pid, file)) = PROCS.with_current(|p| Some((p.pid, p.files.get(fd).copied().flatten()?))) else {
+                f.a0 = OsError::BadFd.to_usize();
+                break 'block;
+            };
 
-                    buf.copy_from_slice(&files[file_i].data[..buf.len()]);
+            if op & LOCK_UN != 0 {
+                flock::unlock(&file, pid);
+                f.a0 = 0;
+                break 'block;
+            }
+
+            let exclusive = match op & (LOCK_SH | LOCK_EX) {
+                LOCK_SH => false,
+                LOCK_EX => true,
+                _ => {
+                    f.a0 = OsError::Unsupported.to_usize();
+                    break 'block;
                 },
-                _ => unreachable!("sysno must be SYS_READFILE or SYS_WRITEFILE"),
+            };
+
+            // Wait for the other processes to release their locks, unless asked not to.
+            let locked = if op & LOCK_NB == 0 {
+                flock::lock(&file, pid, exclusive)
+            } else {
+                flock::try_lock(&file, pid, exclusive)
+            };
+            f.a0 = locked.map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::Mount | Syscall::Umount => 'block: {
+            if PROCS.with_current(|p| p.pid) != INIT_PID {
+                f.a0 = OsError::Permission.to_usize();
+                break 'block;
             }
 
-            f.a0 = buf_len;
+            let path = match user_str(f.a0, f.a1) {
+                Ok(path) => path,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
+
+            let result = match sysno {
+                // a2 and a3 are the file system type, and a5 and a6 the device.
+                Syscall::Mount => user_str(f.a2, f.a3)
+                    .and_then(|fstype| Ok((fstype, user_str(f.a5, f.a6)?)))
+                    .and_then(|(fstype, device)| fstype::open_fs(fstype, device))
+                    .and_then(|fs| vfs::mount(path, fs).inspect_err(|_| fstype::close_fs(fs))),
+                // Files still open on the file system keep working, but cannot be found by path.
+                Syscall::Umount => vfs::umount(path).map(fstype::close_fs),
+                _ => unreachable!("sysno must be Syscall::Mount or Syscall::Umount"),
+            };
+            f.a0 = result.map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::SendTo | Syscall::RecvFrom => 'block: {
+            let Ok(port) = u16::try_from(f.a0) else {
+                f.a0 = OsError::Unsupported.to_usize();
+                break 'block;
+            };
+            let pid = PROCS.with_current(|p| p.pid);
+
+            let addr = f.a3;
+            let buf = match check_user(addr, size_of::<SockAddr>()).and_then(|()| user_bytes_mut(f.a1, f.a2)) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    f.a0 = e.to_usize();
+                    break 'block;
+                },
+            };
+
+            f.a0 = match sysno {
+                Syscall::SendTo => read_user::<SockAddr>(addr)
+                    .and_then(|to| net::send_udp(port, pid, to, buf))
+                    .unwrap_or_else(OsError::to_usize),
+                Syscall::RecvFrom => net::recv_udp(port, pid, buf)
+                    .and_then(|(len, from)| write_user(addr, from).map(|()| len))
+                    .unwrap_or_else(OsError::to_usize),
+                _ => unreachable!("sysno must be Syscall::SendTo or Syscall::RecvFrom"),
+            };
+        },
+        Syscall::ClockGettime => {
+            f.a0 = match f.a0 {
+                CLOCK_MONOTONIC => write_user(f.a1, TimeSpec::from_nanos(monotonic_ns()))
+                    .map_or_else(OsError::to_usize, |()| 0),
+                _ => OsError::Unsupported.to_usize(),
+            };
+        },
+        Syscall::Futex => {
+            let vaddr = f.a0;
+            f.a0 = match f.a1 {
+                FUTEX_WAIT => futex_wait(vaddr, f.a2 as u32).map_or_else(OsError::to_usize, |()| 0),
+                FUTEX_WAKE => futex_wake(vaddr, f.a2).unwrap_or_else(OsError::to_usize),
+                _ => OsError::Unsupported.to_usize(),
+            };
+        },
+        Syscall::Dmesg => {
+            f.a0 = user_bytes_mut(f.a0, f.a1)
+                .map_or_else(OsError::to_usize, |buf| log_read(f.a2, buf));
+        },
+        Syscall::ReadLine => {
+            // Only the console has a line discipline, so a program reading a redirected STDIN
+            // reads it with Syscall::Read instead.
+            let console = PROCS.with_current(|p| p.files[STDIN].is_some_and(|file| is_console(&file)));
+            f.a0 = if console {
+                user_bytes_mut(f.a0, f.a1).map_or_else(OsError::to_usize, read_line)
+            } else {
+                OsError::Unsupported.to_usize()
+            };
+        },
+        Syscall::Spawn => {
+            let spawned = read_user::<SpawnArgs>(f.a0).and_then(|spawn_args| {
+                let path = user_str(spawn_args.path as usize, spawn_args.path_len)?;
+                let args = user_str(spawn_args.argv as usize, spawn_args.argv_len)?;
+                // Each argument ends with a NUL.
+                let args: Vec<&str> = args.split_terminator('\0').collect();
+                spawn(path, &args)
+            });
+            f.a0 = spawned.unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::Wait => {
+            f.a0 = wait(f.a0, f.a1).unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::Sleep => {
+            f.a0 = sleep_ms(f.a0 as u64).map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::GetRandom => {
+            f.a0 = user_bytes_mut(f.a0, f.a1).map_or_else(OsError::to_usize, |buf| {
+                fill_random(buf);
+                buf.len()
+            });
+        },
+        Syscall::GetEnv => {
+            f.a0 = user_str(f.a0, f.a1)
+                .and_then(|name| getenv(name, user_bytes_mut(f.a2, f.a3)?))
+                .unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::SetEnv => {
+            // A null value removes the variable.
+            let value = (f.a2 != 0).then(|| user_str(f.a2, f.a3)).transpose();
+            f.a0 = user_str(f.a0, f.a1)
+                .and_then(|name| setenv(name, value?))
+                .map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::ListEnv => {
+            f.a0 = user_bytes_mut(f.a1, f.a2)
+                .and_then(|buf| listenv(f.a0, buf))
+                .unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::ReadDir => {
+            // Returns 1 with the entry filled in, or 0 after the last one.
+            f.a0 = match user_str(f.a0, f.a1).and_then(|path| vfs::readdir(path, f.a2)) {
+                Ok(Some(entry)) => match DirEnt::new(entry.inode as u32, &entry.name) {
+                    Some(dirent) => write_user(f.a3, dirent).map_or_else(OsError::to_usize, |()| 1),
+                    None => OsError::InvalidName.to_usize(),
+                },
+                Ok(None) => 0,
+                Err(e) => e.to_usize(),
+            };
+        },
+        Syscall::Unlink => {
+            // An open file would be left with an inode that may be reused, so it cannot be removed.
+            let result = user_str(f.a0, f.a1).and_then(|path| match vfs::lookup(path) {
+                Ok((fs, inode)) if PROCS.is_open(fs, inode) => Err(OsError::Busy),
+                _ => vfs::unlink(path),
+            });
+            f.a0 = result.map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::Rename => {
+            f.a0 = user_str(f.a0, f.a1)
+                .and_then(|from| vfs::rename(from, user_str(f.a2, f.a3)?))
+                .map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::GetVersion => {
+            let version = AbiVersion { version: ABI_VERSION, features: FEATURES };
+            f.a0 = write_user(f.a0, version).map_or_else(OsError::to_usize, |()| 0);
+        },
+        Syscall::TestReport => {
+            let (passed, failed) = (f.a0, f.a1);
+            log_info!("process {} ran user tests: {} passed, {} failed", running_pid(), passed, failed);
+            // In self-test mode the first process is the test runner, and its result is QEMU's.
+            #[cfg(feature = "self-test")]
+            if running_pid() == INIT_PID {
+                power::exit(if failed == 0 { 0 } else { 1 });
+            }
+            f.a0 = 0;
+        },
+        Syscall::SigAction => {
+            // a1 is the handler, or 0 for the default, and a2 the restorer it returns to.
+            let (handler, restorer) = (f.a1, f.a2);
+            f.a0 = match Signal::try_from(f.a0) {
+                Ok(Signal::Interrupt) => {
+                    PROCS.with_leader(|p| {
+                        p.interrupt_handler = handler;
+                        p.signal_restorer = restorer;
+                    });
+                    0
+                },
+                Err(_) => OsError::Invalid.to_usize(),
+            };
+        },
+        Syscall::SigReturn => {
+            // a0 points at the registers deliver_interrupt saved, which must be in the process's
+            // memory.
+            let Ok(saved) = read_user::<TrapFrame>(f.a0) else {
+                exit_current(EXIT_KILLED);
+            };
+            // Only the registers and pc come back, so a process cannot make itself privileged.
+            let (scause, sstatus) = (f.scause, f.sstatus);
+            *f = saved;
+            f.scause = scause;
+            f.sstatus = sstatus;
+        },
+        Syscall::ThreadCreate => {
+            f.a0 = create_thread(f.a0, f.a1, f.a2).unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::Sbrk => {
+            f.a0 = sbrk(f.a0).unwrap_or_else(OsError::to_usize);
+        },
+        Syscall::LogLevel => {
+            f.a0 = if f.a0 <= LOG_TRACE { set_log_level(f.a0) } else { OsError::Invalid.to_usize() };
+        },
+        Syscall::SetGroup => {
+            // a0 is the process to move, or 0 for the caller, and a1 the group, or 0 for a group
+            // of its own. Only the shell may move other processes.
+            let current = PROCS.with_leader(|p| p.pid);
+            let pid = if f.a0 == 0 { current } else { f.a0 };
+            let group = if f.a1 == 0 { pid } else { f.a1 };
+            f.a0 = match pid == current || current == INIT_PID {
+                true => set_group(pid, group).map_or_else(OsError::to_usize, |()| 0),
+                false => OsError::Permission.to_usize(),
+            };
+        },
+        // Job control is the shell's.
+        Syscall::SetForeground | Syscall::Continue => 'block: {
+            if PROCS.with_current(|p| p.pid) != INIT_PID {
+                f.a0 = OsError::Permission.to_usize();
+                break 'block;
+            }
+            match sysno {
+                Syscall::SetForeground => set_foreground(f.a0),
+                Syscall::Continue => continue_group(f.a0),
+                _ => unreachable!("sysno must be Syscall::SetForeground or Syscall::Continue"),
+            }
+            f.a0 = 0;
+        },
+        Syscall::Shutdown => 'block: {
+            if PROCS.with_current(|p| p.pid) != INIT_PID {
+                f.a0 = OsError::Permission.to_usize();
+                break 'block;
+            }
+            match f.a0 {
+                SHUTDOWN_POWEROFF => power::shutdown(false),
+                SHUTDOWN_REBOOT => power::shutdown(true),
+                SHUTDOWN_EXIT => power::exit(f.a1 as u16),
+                _ => f.a0 = OsError::Unsupported.to_usize(),
+            }
         },
-        _ => {panic!("unexpected syscall sysno={:x}", sysno);},
     }
 }
 
@@ -0,0 +1,70 @@
+//! Environment variables for os1k
+//!
+//! Each process has its own list of NAME=value variables, which a process it spawns starts with a
+//! copy of. The first process starts with none.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::process::PROCS;
+use crate::vfs::OsError;
+
+const ENV_SIZE_MAX: usize = 4096;   // Bytes of names and values a process can hold
+
+pub type Env = Vec<(String, String)>;
+
+fn check_name(name: &str) -> Result<(), OsError> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Err(OsError::InvalidName);
+    }
+    Ok(())
+}
+
+// Copies the value of variable `name` into `buf`, cut short to fit, and returns its full length.
+pub fn getenv(name: &str, buf: &mut [u8]) -> Result<usize, OsError> {
+    PROCS.with_current(|p| {
+        let (_, value) = p.env.iter().find(|(n, _)| n == name).ok_or(OsError::NotFound)?;
+        let len = value.len().min(buf.len());
+        buf[..len].copy_from_slice(&value.as_bytes()[..len]);
+        Ok(value.len())
+    })
+}
+
+// Sets variable `name` to `value`, or removes it if `value` is None.
+pub fn setenv(name: &str, value: Option<&str>) -> Result<(), OsError> {
+    check_name(name)?;
+    PROCS.with_current(|p| {
+        let index = p.env.iter().position(|(n, _)| n == name);
+        let Some(value) = value else {
+            if let Some(index) = index {
+                p.env.remove(index);
+            }
+            return Ok(());
+        };
+
+        let others: usize = p.env.iter()
+            .filter(|(n, _)| n != name)
+            .map(|(n, v)| n.len() + v.len())
+            .sum();
+        if others + name.len() + value.len() > ENV_SIZE_MAX {
+            return Err(OsError::NoSpace);
+        }
+        match index {
+            Some(index) => p.env[index].1 = String::from(value),
+            None => p.env.push((String::from(name), String::from(value))),
+        }
+        Ok(())
+    })
+}
+
+// Copies the `index`th variable into `buf` as NAME=value, cut short to fit, and returns its full
+// length. Fails with NotFound after the last one.
+pub fn listenv(index: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+    PROCS.with_current(|p| {
+        let (name, value) = p.env.get(index).ok_or(OsError::NotFound)?;
+        let entry = [name.as_bytes(), b"=", value.as_bytes()].concat();
+        let len = entry.len().min(buf.len());
+        buf[..len].copy_from_slice(&entry[..len]);
+        Ok(entry.len())
+    })
+}
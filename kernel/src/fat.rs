@@ -0,0 +1,525 @@
+//! FAT12/16 file system for os1k
+//!
+//! Only the root directory is supported, which matches the flat namespace of the tar file system.
+
+use core::ops::Range;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+
+use crate::{log_info, log_warn};
+use crate::block::{BlockDevice, Disk, SECTOR_SIZE};
+use crate::cache;
+use crate::vfs::{DirEntry as VfsDirEntry, FileSystem, OsError, Inode, Stat};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const FAT12_MAX_CLUSTERS: usize = 4085;     // FAT12 has fewer clusters than this
+const FAT16_MAX_CLUSTERS: usize = 65525;    // FAT16 has fewer clusters than this, FAT32 has more
+const FIRST_CLUSTER: usize = 2;             // Clusters 0 and 1 are reserved
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_ARCHIVE: u8 = 0x20;              // Set on new and modified files
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;            // Long file name entries combine several attribute bits
+const ENTRY_DELETED: u8 = 0xE5;             // First name byte of a deleted entry
+const ENTRY_END: u8 = 0x00;                 // First name byte of the entry after the last one
+
+// BIOS Parameter Block at the start of the boot sector.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct BootSector {
+    jump: [u8; 3],
+    oem_name: [u8; 8],
+    bytes_per_sector: u16,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    fat_count: u8,
+    root_entries: u16,
+    total_sectors_16: u16,
+    media: u8,
+    fat_sectors: u16,       // 0 on FAT32
+    sectors_per_track: u16,
+    heads: u16,
+    hidden_sectors: u32,
+    total_sectors_32: u32,  // Used if total_sectors_16 is 0
+    // Extended boot record and boot code follow
+}
+
+// Short (8.3) directory entry.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct DirEntry {
+    name: [u8; 11],         // Space padded name and extension, without the dot
+    attr: u8,
+    _reserved: u8,
+    _create_time_tenths: u8,
+    _create_time: u16,
+    _create_date: u16,
+    _access_date: u16,
+    _cluster_high: u16,     // Always 0 on FAT12/16
+    _write_time: u16,
+    _write_date: u16,
+    cluster: u16,           // First cluster of the file, 0 for an empty file
+    size: u32,
+}
+
+impl DirEntry {
+    fn new(name: [u8; 11]) -> Self {
+        Self {
+            name,
+            attr: ATTR_ARCHIVE,
+            _reserved: 0,
+            _create_time_tenths: 0,
+            _create_time: 0,
+            _create_date: 0,
+            _access_date: 0,
+            _cluster_high: 0,
+            _write_time: 0,
+            _write_date: 0,
+            cluster: 0,
+            size: 0,
+        }
+    }
+
+    fn is_file(&self) -> bool {
+        !matches!(self.name[0], ENTRY_END | ENTRY_DELETED)
+            && self.attr & ATTR_LONG_NAME != ATTR_LONG_NAME
+            && self.attr & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == 0
+    }
+
+    // Formats the 8.3 name as "NAME.EXT" into `buf`.
+    fn display_name<'a>(&self, buf: &'a mut [u8; 12]) -> &'a str {
+        let base = self.name[..8].trim_ascii_end();
+        let ext = self.name[8..].trim_ascii_end();
+        let mut len = base.len();
+        buf[..len].copy_from_slice(base);
+        if !ext.is_empty() {
+            buf[len] = b'.';
+            buf[len + 1..len + 1 + ext.len()].copy_from_slice(ext);
+            len += 1 + ext.len();
+        }
+        str::from_utf8(&buf[..len]).unwrap_or("<invalid name>")
+    }
+}
+
+// Converts "hello.txt" into the space padded, upper case 8.3 form "HELLO   TXT".
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short.make_ascii_uppercase();
+    Some(short)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FatFs {
+    disk: Disk,
+    fat_type: FatType,
+    cluster_size: usize,    // Bytes per cluster
+    fat_offset: usize,      // Byte offset of the first FAT
+    fat_size: usize,        // Bytes per FAT
+    fat_count: usize,
+    root_offset: usize,     // Byte offset of the root directory
+    root_entries: usize,
+    data_offset: usize,     // Byte offset of cluster 2
+    cluster_count: usize,
+}
+
+impl FatFs {
+    // Reads the boot sector of `disk` and returns the file system layout if it holds a FAT12/16 file system.
+    fn probe(disk: Disk) -> Option<Self> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        cache::read_sector(disk, &mut buf, 0);
+
+        if buf[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE {
+            return None;
+        }
+
+        // Safety:
+        // * data is aligned to single byte alignment - BootSector is packed
+        // * buf is initialised, valid for reading, and larger than a BootSector
+        let boot = unsafe { &*(buf.as_ptr() as *const BootSector) };
+
+        let bytes_per_sector = u16::from_le(boot.bytes_per_sector) as usize;
+        let sectors_per_cluster = boot.sectors_per_cluster as usize;
+        let fat_sectors = u16::from_le(boot.fat_sectors) as usize;
+        if bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || boot.fat_count == 0
+            || fat_sectors == 0 {
+            return None;
+        }
+
+        let total_sectors = match u16::from_le(boot.total_sectors_16) {
+            0 => u32::from_le(boot.total_sectors_32) as usize,
+            n => n as usize,
+        };
+        let reserved_sectors = u16::from_le(boot.reserved_sectors) as usize;
+        let root_entries = u16::from_le(boot.root_entries) as usize;
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(SECTOR_SIZE);
+        let root_sector = reserved_sectors + boot.fat_count as usize * fat_sectors;
+        let data_sector = root_sector + root_sectors;
+        let cluster_count = total_sectors.checked_sub(data_sector)? / sectors_per_cluster;
+
+        let fat_type = match cluster_count {
+            n if n < FAT12_MAX_CLUSTERS => FatType::Fat12,
+            n if n < FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => {
+                log_warn!("FAT32 is not supported");
+                return None;
+            },
+        };
+
+        Some(Self {
+            disk,
+            fat_type,
+            cluster_size: sectors_per_cluster * SECTOR_SIZE,
+            fat_offset: reserved_sectors * SECTOR_SIZE,
+            fat_size: fat_sectors * SECTOR_SIZE,
+            fat_count: boot.fat_count as usize,
+            root_offset: root_sector * SECTOR_SIZE,
+            root_entries,
+            data_offset: data_sector * SECTOR_SIZE,
+            cluster_count,
+        })
+    }
+
+    // Reads bytes starting `offset` bytes into the disk through the sector cache.
+    fn read_disk(&self, offset: usize, buf: &mut [u8]) {
+        let mut sector_buf = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - start).min(buf.len() - done);
+            cache::read_sector(self.disk, &mut sector_buf, (pos / SECTOR_SIZE) as u64);
+            buf[done..done + len].copy_from_slice(&sector_buf[start..start + len]);
+            done += len;
+        }
+    }
+
+    // Writes bytes starting `offset` bytes into the disk through the sector cache.
+    fn write_disk(&self, offset: usize, buf: &[u8]) {
+        let mut sector_buf = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % SECTOR_SIZE;
+            let len = (SECTOR_SIZE - start).min(buf.len() - done);
+            // Partial sector writes keep the rest of the sector.
+            if len < SECTOR_SIZE {
+                cache::read_sector(self.disk, &mut sector_buf, (pos / SECTOR_SIZE) as u64);
+            }
+            sector_buf[start..start + len].copy_from_slice(&buf[done..done + len]);
+            cache::write_sector(self.disk, &sector_buf, (pos / SECTOR_SIZE) as u64);
+            done += len;
+        }
+    }
+
+    fn zero_disk(&self, offset: usize, len: usize) {
+        const ZEROES: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(SECTOR_SIZE);
+            self.write_disk(offset + done, &ZEROES[..n]);
+            done += n;
+        }
+    }
+
+    // Any FAT entry at or above this value marks the end of a cluster chain.
+    fn end_of_chain(&self) -> usize {
+        match self.fat_type {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+        }
+    }
+
+    fn is_cluster(&self, cluster: usize) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: usize) -> usize {
+        self.data_offset + (cluster - FIRST_CLUSTER) * self.cluster_size
+    }
+
+    fn fat_entry(&self, cluster: usize) -> usize {
+        let mut bytes = [0u8; 2];
+        match self.fat_type {
+            FatType::Fat12 => {
+                // 12-bit entries are packed in pairs into 3 bytes.
+                self.read_disk(self.fat_offset + cluster + cluster / 2, &mut bytes);
+                let pair = u16::from_le_bytes(bytes) as usize;
+                if cluster.is_multiple_of(2) { pair & 0xFFF } else { pair >> 4 }
+            },
+            FatType::Fat16 => {
+                self.read_disk(self.fat_offset + cluster * 2, &mut bytes);
+                u16::from_le_bytes(bytes) as usize
+            },
+        }
+    }
+
+    // Updates the entry in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: usize, value: usize) {
+        for fat in 0..self.fat_count {
+            let fat_offset = self.fat_offset + fat * self.fat_size;
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = fat_offset + cluster + cluster / 2;
+                    let mut bytes = [0u8; 2];
+                    self.read_disk(offset, &mut bytes);
+                    let pair = u16::from_le_bytes(bytes);
+                    let pair = if cluster.is_multiple_of(2) {
+                        (pair & 0xF000) | (value as u16 & 0xFFF)
+                    } else {
+                        (pair & 0x000F) | ((value as u16) << 4)
+                    };
+                    self.write_disk(offset, &pair.to_le_bytes());
+                },
+                FatType::Fat16 => {
+                    self.write_disk(fat_offset + cluster * 2, &(value as u16).to_le_bytes());
+                },
+            }
+        }
+    }
+
+    // Finds a free cluster, zeroes it and marks it as the end of a chain.
+    fn alloc_cluster(&self) -> Option<usize> {
+        let cluster = (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count)
+            .find(|&c| self.fat_entry(c) == 0)?;
+        self.set_fat_entry(cluster, self.end_of_chain());
+        self.zero_disk(self.cluster_offset(cluster), self.cluster_size);
+        Some(cluster)
+    }
+
+    // Marks every cluster of the chain starting at `first` as free.
+    fn free_chain(&self, first: usize) {
+        let mut cluster = first;
+        while self.is_cluster(cluster) {
+            let next = self.fat_entry(cluster);
+            self.set_fat_entry(cluster, 0);
+            cluster = next;
+        }
+    }
+
+    fn dir_entry(&self, index: usize) -> DirEntry {
+        let mut buf = [0u8; DIR_ENTRY_SIZE];
+        self.read_disk(self.root_offset + index * DIR_ENTRY_SIZE, &mut buf);
+        // Safety: DirEntry is packed and every bit pattern is valid, and buf is DIR_ENTRY_SIZE bytes
+        unsafe { core::ptr::read(buf.as_ptr() as *const DirEntry) }
+    }
+
+    fn set_dir_entry(&self, index: usize, entry: &DirEntry) {
+        // Safety: DirEntry is packed, so it is exactly DIR_ENTRY_SIZE initialised bytes
+        let buf = unsafe {
+            core::slice::from_raw_parts(entry as *const DirEntry as *const u8, DIR_ENTRY_SIZE)
+        };
+        self.write_disk(self.root_offset + index * DIR_ENTRY_SIZE, buf);
+    }
+
+    // Iterates over (index, entry) for every file in the root directory.
+    fn files(&self) -> impl Iterator<Item = (usize, DirEntry)> + '_ {
+        (0..self.root_entries)
+            .map(|i| (i, self.dir_entry(i)))
+            .take_while(|(_, e)| e.name[0] != ENTRY_END)
+            .filter(|(_, e)| e.is_file())
+    }
+
+    // Calls `f(disk_offset, buf_range)` for each piece of the byte `range` of the cluster chain
+    // starting at `first`, where `buf_range` is relative to `range.start`.
+    // Returns the number of bytes covered before the chain ended.
+    fn for_each_extent(&self, first: usize, range: Range<usize>, mut f: impl FnMut(usize, Range<usize>)) -> usize {
+        let mut cluster = first;
+        for _ in 0..range.start / self.cluster_size {
+            cluster = self.fat_entry(cluster);
+        }
+
+        let mut pos = range.start;
+        while pos < range.end && self.is_cluster(cluster) {
+            let in_cluster = pos % self.cluster_size;
+            let len = (self.cluster_size - in_cluster).min(range.end - pos);
+            f(self.cluster_offset(cluster) + in_cluster, pos - range.start..pos - range.start + len);
+            pos += len;
+            cluster = self.fat_entry(cluster);
+        }
+        pos - range.start
+    }
+
+    // Reads up to `buf.len()` bytes at `offset` into the file. Returns the number of bytes read.
+    fn read_at(&self, index: usize, offset: usize, buf: &mut [u8]) -> usize {
+        let entry = self.dir_entry(index);
+        let size = u32::from_le(entry.size) as usize;
+        let len = buf.len().min(size.saturating_sub(offset));
+        if len == 0 {
+            return 0;
+        }
+
+        let read = self.for_each_extent(u16::from_le(entry.cluster) as usize, offset..offset + len, |offset, range| {
+            self.read_disk(offset, &mut buf[range]);
+        });
+        if read < len {
+            log_warn!("cluster chain of {} is shorter than its size", index);
+        }
+        read
+    }
+
+    // Writes `buf` at `offset` into the file, growing it if needed.
+    // Returns the number of bytes written, which is short if the disk is full.
+    fn write_at(&self, index: usize, offset: usize, buf: &[u8]) -> usize {
+        let mut entry = self.dir_entry(index);
+        let size = u32::from_le(entry.size) as usize;
+        let end = offset + buf.len();
+
+        // Grow the cluster chain to cover the end of the write.
+        let needed = end.div_ceil(self.cluster_size);
+        let mut clusters = 0;
+        let mut last = 0;
+        let mut cluster = u16::from_le(entry.cluster) as usize;
+        while clusters < needed {
+            if !self.is_cluster(cluster) {
+                let Some(new) = self.alloc_cluster() else {
+                    log_warn!("disk is full");
+                    break;
+                };
+                match last {
+                    0 => entry.cluster = (new as u16).to_le(),
+                    _ => self.set_fat_entry(last, new),
+                }
+                cluster = new;
+            }
+            last = cluster;
+            cluster = self.fat_entry(cluster);
+            clusters += 1;
+        }
+
+        let first = u16::from_le(entry.cluster) as usize;
+        let end = end.min(clusters * self.cluster_size);
+
+        // Zero any gap between the old end of file and the write offset.
+        if offset > size {
+            self.for_each_extent(first, size..offset.min(end), |offset, range| {
+                self.zero_disk(offset, range.len());
+            });
+        }
+
+        let written = if offset < end {
+            self.for_each_extent(first, offset..end, |offset, range| {
+                self.write_disk(offset, &buf[range]);
+            })
+        } else {
+            0
+        };
+
+        entry.size = (size.max(offset + written) as u32).to_le();
+        self.set_dir_entry(index, &entry);
+        written
+    }
+}
+
+impl FileSystem for FatFs {
+    // Inodes are root directory entry indexes.
+    fn lookup(&self, name: &str) -> Option<Inode> {
+        let short = short_name(name)?;
+        self.files()
+            .find(|(_, e)| e.name == short)
+            .map(|(i, _)| i)
+    }
+
+    fn create(&self, name: &str) -> Result<Inode, OsError> {
+        let short = short_name(name).ok_or(OsError::InvalidName)?;
+        let index = (0..self.root_entries)
+            .find(|&i| matches!(self.dir_entry(i).name[0], ENTRY_END | ENTRY_DELETED))
+            .ok_or(OsError::NoSpace)?;
+        self.set_dir_entry(index, &DirEntry::new(short));
+        Ok(index)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), OsError> {
+        let index = self.lookup(name).ok_or(OsError::NotFound)?;
+        let mut entry = self.dir_entry(index);
+        self.free_chain(u16::from_le(entry.cluster) as usize);
+        entry.name[0] = ENTRY_DELETED;
+        self.set_dir_entry(index, &entry);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), OsError> {
+        let index = self.lookup(from).ok_or(OsError::NotFound)?;
+        let short = short_name(to).ok_or(OsError::InvalidName)?;
+        if self.lookup(to).is_some() {
+            return Err(OsError::Exists);
+        }
+        let mut entry = self.dir_entry(index);
+        entry.name = short;
+        self.set_dir_entry(index, &entry);
+        Ok(())
+    }
+
+    fn read(&self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        Ok(self.read_at(inode, offset, buf))
+    }
+
+    fn write(&self, inode: Inode, offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        Ok(self.write_at(inode, offset, buf))
+    }
+
+    // Frees the file's clusters. The next write allocates a new chain.
+    fn truncate(&self, inode: Inode) -> Result<(), OsError> {
+        let mut entry = self.dir_entry(inode);
+        if !entry.is_file() {
+            return Err(OsError::NotFound);
+        }
+        self.free_chain(u16::from_le(entry.cluster) as usize);
+        entry.cluster = 0;
+        entry.size = 0;
+        self.set_dir_entry(inode, &entry);
+        Ok(())
+    }
+
+    fn readdir(&self, index: usize) -> Option<VfsDirEntry> {
+        let (inode, entry) = self.files().nth(index)?;
+        let mut name = [0u8; 12];
+        Some(VfsDirEntry { name: String::from(entry.display_name(&mut name)), inode })
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        let entry = self.dir_entry(inode);
+        if !entry.is_file() {
+            return Err(OsError::NotFound);
+        }
+        Ok(Stat { size: u32::from_le(entry.size) as usize })
+    }
+
+    // FAT writes go straight into the sector cache, so flushing it syncs every file.
+    fn sync(&self, _inode: Inode) {
+        cache::flush();
+    }
+
+    fn sync_all(&self) {
+        cache::flush();
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+}
+
+/// Probes `disk` for a FAT12/16 file system, returning it ready to mount if found.
+/// Returns None if the disk holds something else, such as a tar archive.
+pub fn fat_init(disk: Disk) -> Option<&'static FatFs> {
+    let fat = FatFs::probe(disk)?;
+    log_info!("found {:?} file system with {} clusters of {} bytes", fat.fat_type, fat.cluster_count, fat.cluster_size);
+    Some(Box::leak(Box::new(fat)))
+}
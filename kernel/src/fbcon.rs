@@ -0,0 +1,142 @@
+//! Framebuffer text console for os1k
+//!
+//! Draws console output on the virtio-gpu display, a character cell at a time, scrolling the
+//! whole screen up when the cursor passes the last row. Only printable ASCII has glyphs: other
+//! characters are drawn as '?', once per UTF-8 sequence. ANSI escape sequences, such as colors,
+//! are skipped.
+
+use crate::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::spinlock::SpinLock;
+use crate::virtio_gpu::{virtio_gpu_draw, Framebuffer, Rect};
+
+const SCALE: usize = 2;     // Screen pixels per font pixel
+const CELL_WIDTH: usize = GLYPH_WIDTH * SCALE;
+const CELL_HEIGHT: usize = GLYPH_HEIGHT * SCALE;
+const FOREGROUND: u32 = 0x00cc_cccc;
+const BACKGROUND: u32 = 0x0000_0000;
+
+// How far into an escape sequence the output is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Escape {
+    None,
+    Started,    // After ESC
+    Csi,        // After ESC [, until a final byte
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Cursor {
+    col: usize,
+    row: usize,
+    escape: Escape,
+}
+
+static CURSOR: SpinLock<Cursor> = SpinLock::new(Cursor { col: 0, row: 0, escape: Escape::None });
+
+fn cell_rect(col: usize, row: usize) -> Rect {
+    Rect {
+        x: (col * CELL_WIDTH) as u32,
+        y: (row * CELL_HEIGHT) as u32,
+        width: CELL_WIDTH as u32,
+        height: CELL_HEIGHT as u32,
+    }
+}
+
+fn screen_rect(fb: &Framebuffer) -> Rect {
+    Rect { x: 0, y: 0, width: fb.width as u32, height: fb.height as u32 }
+}
+
+fn draw_glyph(fb: &mut Framebuffer, col: usize, row: usize, c: u8) {
+    let glyph = glyph(c);
+    for y in 0..CELL_HEIGHT {
+        let bits = glyph[y / SCALE];
+        let start = (row * CELL_HEIGHT + y) * fb.width + col * CELL_WIDTH;
+        for (x, pixel) in fb.pixels[start..start + CELL_WIDTH].iter_mut().enumerate() {
+            *pixel = if bits & (1 << (x / SCALE)) != 0 { FOREGROUND } else { BACKGROUND };
+        }
+    }
+}
+
+// Moves every text row up by one and clears the last.
+fn scroll(fb: &mut Framebuffer, rows: usize) {
+    let row_pixels = CELL_HEIGHT * fb.width;
+    let text_pixels = rows * row_pixels;
+    fb.pixels.copy_within(row_pixels..text_pixels, 0);
+    fb.pixels[text_pixels - row_pixels..text_pixels].fill(BACKGROUND);
+}
+
+// Starts a new line, scrolling if the cursor is on the last row. Returns whether it scrolled.
+fn new_line(fb: &mut Framebuffer, cursor: &mut Cursor, rows: usize) -> bool {
+    cursor.col = 0;
+    if cursor.row + 1 < rows {
+        cursor.row += 1;
+        return false;
+    }
+    scroll(fb, rows);
+    true
+}
+
+// The smallest rectangle covering both `a` and `b`.
+fn union(a: Rect, b: Rect) -> Rect {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    let right = (a.x + a.width).max(b.x + b.width);
+    let bottom = (a.y + a.height).max(b.y + b.height);
+    Rect { x, y, width: right - x, height: bottom - y }
+}
+
+// Draws `b` at the cursor, and returns the part of the screen that changed.
+fn put_byte(fb: &mut Framebuffer, cursor: &mut Cursor, b: u8) -> Option<Rect> {
+    let cols = fb.width / CELL_WIDTH;
+    let rows = fb.height / CELL_HEIGHT;
+    match cursor.escape {
+        Escape::None => {},
+        Escape::Started => {
+            cursor.escape = if b == b'[' { Escape::Csi } else { Escape::None };
+            return None;
+        },
+        Escape::Csi => {
+            if (0x40..=0x7e).contains(&b) {
+                cursor.escape = Escape::None;
+            }
+            return None;
+        },
+    }
+    match b {
+        0x1b => {
+            cursor.escape = Escape::Started;
+            None
+        },
+        b'\n' => new_line(fb, cursor, rows).then(|| screen_rect(fb)),
+        b'\r' => {
+            cursor.col = 0;
+            None
+        },
+        // Backspace moves the cursor back without erasing, as on a terminal.
+        0x08 => {
+            cursor.col = cursor.col.saturating_sub(1);
+            None
+        },
+        // UTF-8 continuation bytes: the lead byte already drew the '?'.
+        0x80..=0xbf => None,
+        _ => {
+            let mut scrolled = false;
+            if cursor.col == cols {
+                scrolled = new_line(fb, cursor, rows);
+            }
+            draw_glyph(fb, cursor.col, cursor.row, b);
+            let rect = if scrolled { screen_rect(fb) } else { cell_rect(cursor.col, cursor.row) };
+            cursor.col += 1;
+            Some(rect)
+        },
+    }
+}
+
+// Shows `bytes` on the display, if there is one, sending the device one update for all of them.
+pub fn fbcon_write(bytes: &[u8]) {
+    let mut cursor = CURSOR.lock();
+    virtio_gpu_draw(|fb| {
+        bytes.iter()
+            .filter_map(|&b| put_byte(fb, &mut cursor, b))
+            .reduce(union)
+    });
+}
@@ -0,0 +1,18 @@
+//! QEMU test finisher for os1k
+//!
+//! The virt machine's sifive_test device stops QEMU when written: with exit status 0 for
+//! FINISHER_PASS, or with the status in the upper 16 bits for FINISHER_FAIL. Automated runs use
+//! it to end with a meaningful status rather than hang.
+
+use core::ptr;
+
+pub const FINISHER_PADDR: usize = 0x10_0000;
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+// Stops QEMU, which exits with status `code`. Takes no locks, so the panic handler can use it.
+pub fn finisher_exit(code: u16) {
+    let value = if code == 0 { FINISHER_PASS } else { (code as u32) << 16 | FINISHER_FAIL };
+    // Safety: FINISHER_PADDR is the test device's only register, mapped in every page table.
+    unsafe { ptr::write_volatile(FINISHER_PADDR as *mut u32, value) };
+}
@@ -0,0 +1,105 @@
+//! Advisory file locks for os1k
+//!
+//! Locks are held by a process on a file, however many times the process has opened it.
+//! They are advisory: reads and writes ignore them, and only Syscall::Flock waits for them.
+//! A process waiting for a lock sleeps on the queue of the entry holding it, until the entry is
+//! released, Ctrl-C is typed for it, or it is a thread whose process exits.
+
+use crate::process::PROCS;
+use crate::scheduler::{yield_now, WaitQueue};
+use crate::spinlock::SpinLock;
+use crate::tty::interrupt_pending;
+use crate::vfs::{FileSystem, OsError, Inode, OpenFile};
+
+const LOCKS_MAX: usize = 16;
+
+#[derive(Clone, Copy)]
+struct FileLock {
+    fs: &'static dyn FileSystem,
+    inode: Inode,
+    pid: usize,         // Process holding the lock
+    exclusive: bool,    // No other process may hold any lock on the file
+}
+
+impl FileLock {
+    fn is_on(&self, file: &OpenFile) -> bool {
+        core::ptr::addr_eq(self.fs, file.fs) && self.inode == file.inode
+    }
+}
+
+static LOCKS: SpinLock<[Option<FileLock>; LOCKS_MAX]> = SpinLock::new([None; LOCKS_MAX]);
+
+// Processes waiting for the entry in LOCKS at the same index to be released.
+static WAITERS: [WaitQueue; LOCKS_MAX] = [const { WaitQueue::new() }; LOCKS_MAX];
+
+// Takes a shared or exclusive lock on the file for `pid`, converting any lock it already holds.
+// Fails with WouldBlock if another process holds a conflicting lock.
+pub fn try_lock(file: &OpenFile, pid: usize, exclusive: bool) -> Result<(), OsError> {
+    take(file, pid, exclusive).map_err(|held| held.map_or(OsError::NoSpace, |_| OsError::WouldBlock))
+}
+
+// Takes the lock as try_lock does, sleeping while another process holds a conflicting one.
+// Fails with Interrupted if Ctrl-C is typed for the current process or it is killed meanwhile.
+pub fn lock(file: &OpenFile, pid: usize, exclusive: bool) -> Result<(), OsError> {
+    loop {
+        let held = match take(file, pid, exclusive) {
+            Ok(()) => return Ok(()),
+            Err(None) => return Err(OsError::NoSpace),
+            Err(Some(held)) => held,
+        };
+        let (leader, killed) = PROCS.with_current(|p| (p.leader, p.killed));
+        if killed || interrupt_pending(leader) {
+            return Err(OsError::Interrupted);
+        }
+        if !WAITERS[held].wait() {
+            yield_now();
+        }
+    }
+}
+
+// Takes the lock, or fails with the index of a conflicting entry, or None if LOCKS is full.
+fn take(file: &OpenFile, pid: usize, exclusive: bool) -> Result<(), Option<usize>> {
+    let mut locks = LOCKS.lock();
+
+    let conflict = locks.iter()
+        .position(|l| l.is_some_and(|l| l.is_on(file) && l.pid != pid && (l.exclusive || exclusive)));
+    if conflict.is_some() {
+        return Err(conflict);
+    }
+
+    let slot = match locks.iter().position(|l| l.is_some_and(|l| l.is_on(file) && l.pid == pid)) {
+        Some(held) => &mut locks[held],
+        None => locks.iter_mut().find(|l| l.is_none()).ok_or(None)?,
+    };
+    *slot = Some(FileLock { fs: file.fs, inode: file.inode, pid, exclusive });
+    Ok(())
+}
+
+// Releases the lock `pid` holds on the file, if any.
+pub fn unlock(file: &OpenFile, pid: usize) {
+    release(|l| l.is_on(file) && l.pid == pid);
+}
+
+// Releases every lock held by `pid`, when it exits.
+pub fn unlock_all(pid: usize) {
+    release(|l| l.pid == pid);
+}
+
+// Clears the matching entries, then wakes the processes waiting for them, with LOCKS unlocked as
+// waking takes PROCS.
+fn release(matches: impl Fn(&FileLock) -> bool) {
+    let mut released = [false; LOCKS_MAX];
+    for (lock, released) in LOCKS.lock().iter_mut().zip(&mut released) {
+        if lock.is_some_and(|l| matches(&l)) {
+            *lock = None;
+            *released = true;
+        }
+    }
+    WAITERS.iter().zip(released).filter(|&(_, r)| r).for_each(|(w, _)| w.wake_all());
+}
+
+// Wakes every process waiting for a lock, for Ctrl-C or a process exiting, so an interrupted or
+// killed one can give up.
+pub fn wake_all() {
+    WAITERS.iter().for_each(WaitQueue::wake_all);
+}
@@ -0,0 +1,75 @@
+//! File system types for os1k
+//!
+//! Maps the file system type names used by Syscall::Mount to file systems. "fat" and "tar" mount a
+//! disk, named as in `block`, and each disk can only be mounted once at a time. There is only
+//! one tar file system, so only one disk can be mounted as "tar" at a time.
+//! A disk the device will not write to is mounted read-only.
+//! "9p" mounts the directory shared by the host, once at a time.
+
+use crate::block::{Disk, DISKS_MAX};
+use crate::devfs::DEVFS;
+use crate::fat::fat_init;
+use crate::log_info;
+use crate::procfs::PROCFS;
+use crate::spinlock::SpinLock;
+use crate::tar::{fs_init, FILES};
+use crate::v9fs::{v9fs_attach, v9fs_detach, V9FS};
+use crate::vfs::{FileSystem, OsError};
+
+// The file system using each disk, if it is mounted.
+static DISK_FS: SpinLock<[Option<&'static dyn FileSystem>; DISKS_MAX]> = SpinLock::new([None; DISKS_MAX]);
+
+// Opens a file system of type `fstype` ready to mount. The disk types use the disk called
+// `device`, or disk0 if it is empty. "auto" probes the disk for its format.
+pub fn open_fs(fstype: &str, device: &str) -> Result<&'static dyn FileSystem, OsError> {
+    match fstype {
+        "devfs" => Ok(&DEVFS),
+        "proc" => Ok(&PROCFS),
+        "9p" => Ok(v9fs_attach()?),
+        "auto" | "fat" | "tar" => {
+            let disk = match device {
+                "" => Disk::ROOT,
+                name => Disk::find(name).ok_or(OsError::NotFound)?,
+            };
+            let mut disk_fs = DISK_FS.lock();
+            if disk_fs[disk.index()].is_some() {
+                return Err(OsError::Busy);
+            }
+            let tar_in_use = disk_fs.iter().flatten().any(|fs| core::ptr::addr_eq(*fs, &FILES));
+
+            // Only probe for FAT when it could be used: a FAT found on a "tar" mount would be leaked.
+            let fat = match fstype {
+                "auto" | "fat" => fat_init(disk),
+                _ => None,
+            };
+            let fs: &'static dyn FileSystem = match (fstype, fat) {
+                (_, Some(fat)) => fat,
+                ("fat", None) => return Err(OsError::Unsupported),
+                _ if tar_in_use => return Err(OsError::Busy),
+                _ => {
+                    fs_init(disk)?;
+                    &FILES
+                },
+            };
+            if fs.read_only() {
+                log_info!("disk{} is read-only, so its file system is too", disk.index());
+            }
+            disk_fs[disk.index()] = Some(fs);
+            Ok(fs)
+        },
+        _ => Err(OsError::NotFound),
+    }
+}
+
+// Called once `fs` is unmounted, so its disk can be mounted again.
+pub fn close_fs(fs: &'static dyn FileSystem) {
+    if core::ptr::addr_eq(fs, &V9FS) {
+        v9fs_detach();
+        return;
+    }
+    for disk_fs in DISK_FS.lock().iter_mut() {
+        if disk_fs.is_some_and(|disk| core::ptr::addr_eq(disk, fs)) {
+            *disk_fs = None;
+        }
+    }
+}
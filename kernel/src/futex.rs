@@ -0,0 +1,69 @@
+//! Futexes for os1k
+//!
+//! Syscall::Futex lets a process sleep on a 32-bit word of its memory until another wakes it, the
+//! building block for user mutexes and, with threads or shared memory, for waiting on each other.
+//! FUTEX_WAIT only blocks if the word still holds the value the caller last saw, so a wake that
+//! comes between the caller's check and the syscall is not lost. Waiters queue on a wait queue
+//! per (address space, address).
+
+use crate::entry::check_user;
+use crate::page::PageTable;
+use crate::process::{PROCS, PROCS_MAX};
+use crate::scheduler::WaitQueue;
+use crate::spinlock::SpinLock;
+use crate::vfs::OsError;
+
+const FUTEX_QUEUES: usize = PROCS_MAX;  // A process waits on one futex at a time
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FutexKey {
+    space: usize,   // Root page table of the address space
+    vaddr: usize,
+}
+
+// The futex each queue is for. A queue with no waiters is free for another futex.
+static KEYS: SpinLock<[Option<FutexKey>; FUTEX_QUEUES]> = SpinLock::new([None; FUTEX_QUEUES]);
+static QUEUES: [WaitQueue; FUTEX_QUEUES] = [const { WaitQueue::new() }; FUTEX_QUEUES];
+
+// The key for the word at `vaddr` in the current process, which must be an aligned address in its
+// memory.
+fn current_key(vaddr: usize) -> Result<FutexKey, OsError> {
+    if !vaddr.is_multiple_of(align_of::<u32>()) {
+        return Err(OsError::Invalid);
+    }
+    check_user(vaddr, size_of::<u32>())?;
+    let space = PROCS.with_leader(|p| p.page_table.as_deref().map_or(0, |pt| pt as *const PageTable as usize));
+    Ok(FutexKey { space, vaddr })
+}
+
+// Blocks the current process until the futex at `vaddr` is woken, if the word there holds
+// `expected`. Fails with WouldBlock if it does not. Wake-ups can be spurious.
+pub fn futex_wait(vaddr: usize, expected: u32) -> Result<(), OsError> {
+    let key = current_key(vaddr)?;
+    let queue = {
+        let mut keys = KEYS.lock();
+        // Safety: vaddr is an aligned user address in the current address space, and SUM is set,
+        // so the kernel can read it.
+        if unsafe { (vaddr as *const u32).read_volatile() } != expected {
+            return Err(OsError::WouldBlock);
+        }
+        let queue = keys.iter().position(|&k| k == Some(key))
+            .or_else(|| QUEUES.iter().position(WaitQueue::is_empty))
+            .ok_or(OsError::NoSpace)?;
+        keys[queue] = Some(key);
+        queue
+    };
+    // The kernel is not preempted, so no wake can come between unlocking KEYS and joining the queue.
+    QUEUES[queue].wait();
+    Ok(())
+}
+
+// Wakes up to `count` processes waiting on the futex at `vaddr`, returning how many it woke.
+pub fn futex_wake(vaddr: usize, count: usize) -> Result<usize, OsError> {
+    let key = current_key(vaddr)?;
+    let keys = KEYS.lock();
+    let Some(queue) = keys.iter().position(|&k| k == Some(key)) else {
+        return Ok(0);
+    };
+    Ok((0..count).take_while(|_| QUEUES[queue].wake_one()).count())
+}
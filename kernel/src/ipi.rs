@@ -0,0 +1,72 @@
+//! Inter-processor interrupts for os1k
+//!
+//! A hart pokes another by setting message bits in the target's pending word and asking SBI to
+//! raise a supervisor software interrupt there. The target clears the interrupt, then takes every
+//! message at once, so messages sent together or before it gets round to them are merged.
+//!
+//! The only message is IPI_TLB_FLUSH: processes only run on the boot hart, so there is nothing
+//! for another hart to reschedule. A message's bit stays set until the target has acted on it,
+//! which is how tlb_shootdown waits for the flushes. A hart that never answers is given up on after FLUSH_WAIT_LIMIT tries.
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::log_warn;
+use crate::sbi::sbi_send_ipi;
+use crate::smp::{boot_hart, harts_online, HARTS_MAX};
+
+// Messages, one bit each
+pub const IPI_TLB_FLUSH: usize = 1 << 0;    // Page tables changed: drop cached translations
+
+const SIP_SSIP: usize = 1 << 1;             // Supervisor software interrupt pending
+const FLUSH_WAIT_LIMIT: usize = 10_000_000;
+
+static PENDING: [AtomicUsize; HARTS_MAX] = [const { AtomicUsize::new(0) }; HARTS_MAX];
+
+// Sends `message` to hart `hartid`. Returns the SBI error code on failure.
+pub fn send_ipi(hartid: usize, message: usize) -> Result<(), isize> {
+    PENDING[hartid].fetch_or(message, Ordering::Release);
+    sbi_send_ipi(1 << hartid)
+}
+
+// Has every other online hart drop its cached translations, after a page table changed, and
+// waits until they have. Page tables only change on the boot hart, which runs the processes.
+pub fn tlb_shootdown() {
+    let online = harts_online();
+    let harts = (0..HARTS_MAX).filter(|&h| h != boot_hart() && online & 1 << h != 0);
+    // Send to them all before waiting, so they flush at the same time.
+    let mut sent = 0;
+    for hartid in harts {
+        match send_ipi(hartid, IPI_TLB_FLUSH) {
+            Ok(()) => sent |= 1 << hartid,
+            Err(e) => log_warn!("could not flush the TLB of hart {}: {}", hartid, e),
+        }
+    }
+
+    for hartid in (0..HARTS_MAX).filter(|&h| sent & 1 << h != 0) {
+        let mut spins = 0;
+        while PENDING[hartid].load(Ordering::Acquire) & IPI_TLB_FLUSH != 0 {
+            spins += 1;
+            if spins == FLUSH_WAIT_LIMIT {
+                log_warn!("hart {} did not flush its TLB", hartid);
+                break;
+            }
+            spin_loop();
+        }
+    }
+}
+
+// Handles a software interrupt on hart `hartid`: acts on every message received, then clears
+// them so the sender sees they are done.
+pub fn ipi_handle(hartid: usize) {
+    // Clear the interrupt before taking the messages, so one sent in between raises it again.
+    // Safety: clearing SSIP only acknowledges the software interrupt
+    unsafe { asm!("csrc sip, {}", in(reg) SIP_SSIP) };
+    let messages = PENDING[hartid].load(Ordering::Acquire);
+    if messages & IPI_TLB_FLUSH != 0 {
+        // Safety: sfence.vma only drops cached translations
+        unsafe { asm!("sfence.vma") };
+    }
+    PENDING[hartid].fetch_and(!messages, Ordering::Release);
+}
@@ -0,0 +1,148 @@
+//! Write-ahead journal for os1k
+//!
+//! The last sectors of each disk are reserved for the journal: one header sector followed by
+//! copies of the sectors being written. A flush first writes every sector to the journal, then
+//! the header as the commit record, and only then the sectors in place. If the machine stops
+//! before the commit record is written the disk is unchanged, and if it stops after, `recover`
+//! replays the journal on the next mount. Either way a flush happens completely or not at all.
+
+use alloc::vec::Vec;
+
+use crate::{log_info, log_warn};
+use crate::block::{BlockDevice, Disk, SECTOR_SIZE};
+use crate::cache;
+
+pub const JOURNAL_DATA_SECTORS: usize = 8;  // Most sectors a single transaction can write
+pub const JOURNAL_SECTORS: usize = 1 + JOURNAL_DATA_SECTORS;
+
+const JOURNAL_MAGIC: u32 = 0x4a4b_3130;  // "01KJ" little endian
+
+// Header sector layout, all little endian: magic, count, checksum, then `count` target sectors.
+const HEADER_MAGIC: usize = 0;
+const HEADER_COUNT: usize = 4;
+const HEADER_CHECKSUM: usize = 8;
+const HEADER_SECTORS: usize = 12;
+
+// First sector of the journal on `disk`. Everything before it is available to the file system.
+pub fn journal_start(disk: Disk) -> usize {
+    (disk.sector_count() as usize).saturating_sub(JOURNAL_SECTORS)
+}
+
+// FNV-1a over the target sectors and their data, so a torn journal is never replayed.
+fn checksum(targets: &[u64], data: &[[u8; SECTOR_SIZE]]) -> u32 {
+    targets.iter()
+        .flat_map(|sector| sector.to_le_bytes())
+        .chain(data.iter().flatten().copied())
+        .fold(0x811c_9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().expect("slice should be 4 bytes"))
+}
+
+// Clears the commit record so the journal is not replayed again.
+fn clear(mut disk: Disk, start: usize) {
+    disk.write_sector(&[0u8; SECTOR_SIZE], start as u64);
+}
+
+// A set of sector writes that reach a disk together.
+#[derive(Debug)]
+pub struct Transaction {
+    disk: Disk,
+    targets: Vec<u64>,
+    data: Vec<[u8; SECTOR_SIZE]>,
+}
+
+impl Transaction {
+    pub fn new(disk: Disk) -> Self {
+        Self { disk, targets: Vec::new(), data: Vec::new() }
+    }
+
+    // Adds a sector write. A later write to the same sector replaces the earlier one.
+    pub fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        let index = match self.targets.iter().position(|&s| s == sector) {
+            Some(index) => index,
+            None => {
+                assert!(self.targets.len() < JOURNAL_DATA_SECTORS, "transaction is larger than the journal");
+                self.targets.push(sector);
+                self.data.push([0; SECTOR_SIZE]);
+                self.targets.len() - 1
+            },
+        };
+        self.data[index].copy_from_slice(buf);
+    }
+
+    // Writes the transaction to the journal, then to its place on the disk.
+    pub fn commit(self) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let mut disk = self.disk;
+        let start = journal_start(disk);
+
+        // The journal is written straight to the disk, in one request: the cache could reorder it.
+        // The device could too, so each step is flushed before the next is written.
+        disk.write_sectors(&[self.data.as_flattened()], (start + 1) as u64);
+        disk.flush();
+
+        let mut header = [0u8; SECTOR_SIZE];
+        header[HEADER_MAGIC..HEADER_MAGIC + 4].copy_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+        header[HEADER_COUNT..HEADER_COUNT + 4].copy_from_slice(&(self.targets.len() as u32).to_le_bytes());
+        header[HEADER_CHECKSUM..HEADER_CHECKSUM + 4].copy_from_slice(&checksum(&self.targets, &self.data).to_le_bytes());
+        for (i, sector) in self.targets.iter().enumerate() {
+            let offset = HEADER_SECTORS + i * size_of::<u64>();
+            header[offset..offset + size_of::<u64>()].copy_from_slice(&sector.to_le_bytes());
+        }
+        // Commit point: from here on the transaction survives a crash.
+        disk.write_sector(&header, start as u64);
+        disk.flush();
+
+        for (data, &sector) in self.data.iter().zip(&self.targets) {
+            cache::write_sector(disk, data, sector);
+        }
+        cache::flush();
+
+        clear(disk, start);
+    }
+}
+
+// Replays a committed transaction left in the journal of `disk` by an interrupted flush.
+// Must run before the file system reads the disk.
+pub fn recover(mut disk: Disk) {
+    let start = journal_start(disk);
+    let mut header = [0u8; SECTOR_SIZE];
+    disk.read_sector(&mut header, start as u64);
+
+    if read_u32(&header, HEADER_MAGIC) != JOURNAL_MAGIC {
+        return;
+    }
+
+    let count = read_u32(&header, HEADER_COUNT) as usize;
+    if count > JOURNAL_DATA_SECTORS {
+        log_warn!("invalid sector count {}, discarding", count);
+        clear(disk, start);
+        return;
+    }
+
+    let targets: Vec<u64> = (0..count)
+        .map(|i| {
+            let offset = HEADER_SECTORS + i * size_of::<u64>();
+            u64::from_le_bytes(header[offset..offset + size_of::<u64>()].try_into().expect("slice should be 8 bytes"))
+        })
+        .collect();
+    let mut data = alloc::vec![[0u8; SECTOR_SIZE]; count];
+    disk.read_sectors(&mut [data.as_flattened_mut()], (start + 1) as u64);
+
+    if checksum(&targets, &data) != read_u32(&header, HEADER_CHECKSUM) {
+        log_warn!("checksum mismatch, discarding");
+        clear(disk, start);
+        return;
+    }
+
+    for (buf, &sector) in data.iter().zip(&targets) {
+        disk.write_sector(buf, sector);
+    }
+    disk.flush();
+    clear(disk, start);
+    log_info!("replayed {} sectors", count);
+}
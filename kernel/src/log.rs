@@ -0,0 +1,96 @@
+//! Kernel logging for os1k
+//!
+//! log_error! to log_trace! print a message tagged with the module it comes from, as in
+//! "virtio: slot 1: device 2 at 0x10002000". Debug and trace messages are compiled out unless
+//! the `log-debug` or `log-trace` feature is on. Of the rest, only those at or above the level
+//! set with Syscall::LogLevel are printed; it starts at the most detailed level compiled in.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use common::print::{RED, YELLOW};
+
+pub use common::{LOG_DEBUG, LOG_ERROR, LOG_INFO, LOG_TRACE, LOG_WARN};
+
+// The most detailed level compiled in
+pub const LOG_MAX: usize = if cfg!(feature = "log-trace") {
+    LOG_TRACE
+} else if cfg!(feature = "log-debug") {
+    LOG_DEBUG
+} else {
+    LOG_INFO
+};
+
+static LEVEL: AtomicUsize = AtomicUsize::new(LOG_MAX);
+
+pub fn log_enabled(level: usize) -> bool {
+    level <= LOG_MAX && level <= LEVEL.load(Ordering::Relaxed)
+}
+
+// Prints only messages at `level` or above from now on, returning the previous level.
+pub fn set_log_level(level: usize) -> usize {
+    LEVEL.swap(level, Ordering::Relaxed)
+}
+
+// The tag for a module path: its last part, such as "virtio" for "kernel::virtio".
+pub fn module_tag(path: &'static str) -> &'static str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+pub fn level_prefix(level: usize) -> &'static str {
+    match level {
+        LOG_ERROR => "error: ",
+        LOG_WARN => "warn: ",
+        LOG_DEBUG => "debug: ",
+        LOG_TRACE => "trace: ",
+        _ => "",
+    }
+}
+
+// Errors are printed in red and warnings in yellow.
+pub fn level_style(level: usize) -> &'static str {
+    match level {
+        LOG_ERROR => RED,
+        LOG_WARN => YELLOW,
+        _ => "",
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ( $level:expr, $($arg:tt)* ) => {
+        if $crate::log::log_enabled($level) {
+            $crate::println_color!(
+                $crate::log::level_style($level),
+                "{}: {}{}",
+                $crate::log::module_tag(module_path!()),
+                $crate::log::level_prefix($level),
+                format_args!($($arg)*),
+            );
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ( $($arg:tt)* ) => { $crate::log!($crate::log::LOG_ERROR, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ( $($arg:tt)* ) => { $crate::log!($crate::log::LOG_WARN, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ( $($arg:tt)* ) => { $crate::log!($crate::log::LOG_INFO, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ( $($arg:tt)* ) => { $crate::log!($crate::log::LOG_DEBUG, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ( $($arg:tt)* ) => { $crate::log!($crate::log::LOG_TRACE, $($arg)*) };
+}
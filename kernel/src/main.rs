@@ -9,26 +9,84 @@ use core::arch::naked_asm;
 use core::ptr::write_bytes;
 
 #[allow(unused_imports)]
-use common::{print, println};
+use common::{print, println, println_color};
 
 mod address;
 mod allocator;
+mod block;
+mod cache;
+mod condvar;
+mod console;
+mod devfs;
+mod dmesg;
 #[macro_use]
 mod entry;
+mod env;
+mod fat;
+mod fbcon;
+mod finisher;
+mod flock;
+mod font;
+mod fstype;
+mod futex;
+mod ipi;
+mod journal;
+mod log;
+mod mutex;
+mod net;
+mod once;
 mod page;
 mod panic;
+mod pipe;
+mod plic;
+mod power;
 mod process;
+mod procfs;
+#[cfg(feature = "profile")]
+mod profile;
+mod ramdisk;
+mod random;
 mod tar;
+mod timer;
+mod tty;
+mod uart;
+mod v9fs;
 mod sbi;
 mod scheduler;
+mod semaphore;
+mod smp;
 mod spinlock;
+#[cfg(feature = "lock-stress")]
+mod stress;
+mod vfs;
 mod virtio;
-
-use crate::entry::kernel_entry;
+mod virtio_9p;
+mod virtio_console;
+mod virtio_gpu;
+mod virtio_net;
+mod volatile;
+mod watchdog;
+
+use crate::block::block_init;
+use crate::devfs::DEVFS;
 use crate::process::create_process;
-use crate::tar::fs_init;
+use crate::procfs::PROCFS;
+use crate::sbi::sbi_init;
 use crate::scheduler::yield_now;
-use crate::virtio::virtio_blk_init;
+use crate::smp::{hart_init, smp_init, SIE_SSIE};
+use crate::timer::timer_init;
+use crate::vfs::mount;
+use crate::virtio::virtio_probe;
+use crate::virtio_9p::virtio_9p_init;
+use crate::virtio_gpu::virtio_gpu_init;
+use crate::virtio_net::virtio_net_init;
+use crate::watchdog::watchdog_init;
+
+const SIE_STIE: usize = 1 << 5;    // Supervisor timer interrupts, taken in user mode
+const SIE_SEIE: usize = 1 << 9;    // Supervisor external interrupts, taken in user mode
+
+#[cfg(feature = "self-test")]
+const SELF_TEST_PATH: &str = "/selftest.bin";  // The user tests, run in place of the shell
 
 // Safety: Symbols created by linker script
 unsafe extern "C" {
@@ -42,12 +100,6 @@ unsafe extern "C" {
     static _binary_shell_bin_size: u8;
 }
 
-// fn delay() {
-//     for _ in 0..300_000_000usize {
-//         unsafe{asm!("nop");} // do nothing
-//     }
-// }
-//
 // static PROC_A: SpinLock<Option<usize>> = SpinLock::new(None);
 // static PROC_B: SpinLock<Option<usize>> = SpinLock::new(None);
 //
@@ -56,7 +108,7 @@ unsafe extern "C" {
 //     loop {
 //         print!("🐈");
 //         yield_now();
-//         delay()
+//         timer::delay_ms(1000);
 //     }
 // }
 //
@@ -65,13 +117,13 @@ unsafe extern "C" {
 //     loop {
 //         print!("🐕");
 //         yield_now();
-//         delay()
+//         timer::delay_ms(1000);
 //     }
 // }
 
 
 #[unsafe(no_mangle)]
-fn kernel_main() -> ! {
+extern "C" fn kernel_main(hartid: usize) -> ! {
     let bss = &raw const __bss;
     let bss_end = &raw const __bss_end;
     // Safety: from linker script bss is aligned and bss segment is valid for writes up to bss_end
@@ -79,25 +131,51 @@ fn kernel_main() -> ! {
         write_bytes(bss as *mut u8, 0, bss_end as usize - bss as usize);
     }
 
-    write_csr!("stvec", kernel_entry as *const () as usize);
-
-    virtio_blk_init();
-    fs_init();
+    hart_init();
+
+    sbi_init();
+    smp_init(hartid);
+    virtio_probe();
+    block_init();
+    virtio_net_init();
+    virtio_gpu_init();
+    virtio_9p_init();
+    console::console_init();
+    timer_init();
+    watchdog_init();
+    #[cfg(feature = "profile")]
+    profile::profile_init();
+    write_csr!("sie", SIE_STIE | SIE_SEIE | SIE_SSIE);
+    // The disk format is selected when mounting: FAT if the boot sector says so, otherwise tar.
+    let root_fs = fstype::open_fs("auto", "").expect("disk should have a file system");
+    mount("/", root_fs).expect("root file system should mount");
+    mount("/dev", &DEVFS).expect("devfs should mount");
+    mount("/proc", &PROCFS).expect("procfs should mount");
+    vfs::list();
 
 
     common::println!("Hello World! 🦀");
 
-    // PROC_A.lock().get_or_insert_with(|| {
+    // PROC_A.get_or_init(|| {
     //     create_process(proc_a_entry as usize)
     // });
-    // PROC_B.lock().get_or_insert_with(|| {
+    // PROC_B.get_or_init(|| {
     //     create_process(proc_b_entry as usize)
     // });
 
     // new!
-    let shell_start = &raw const _binary_shell_bin_start as *mut u8;
-    let shell_size = &raw const _binary_shell_bin_size as usize;  // The symbol _address_ is the size of the binary
-    let _ = create_process(shell_start, shell_size);
+    #[cfg(not(feature = "self-test"))]
+    {
+        let shell_start = &raw const _binary_shell_bin_start as *mut u8;
+        let shell_size = &raw const _binary_shell_bin_size as usize;  // The symbol _address_ is the size of the binary
+        create_process(shell_start, shell_size, &["shell"]).expect("the shell should start");
+    }
+    // The tests take the shell's place as the first process, and end QEMU with Syscall::TestReport.
+    #[cfg(feature = "self-test")]
+    {
+        let image = process::read_image(SELF_TEST_PATH).expect("the tests should be on the disk");
+        create_process(image.as_ptr(), image.len(), &[SELF_TEST_PATH]).expect("the tests should start");
+    }
 
     yield_now();
 
@@ -109,8 +187,10 @@ fn kernel_main() -> ! {
 #[unsafe(naked)]
 unsafe extern "C" fn boot() -> ! {
     naked_asm!(
-        "la a0, {stack_top}",
-        "mv sp, a0",
+        // The firmware passes the hart ID in a0, which kernel_main takes as its argument. A zero
+        // frame pointer ends a panic backtrace.
+        "la sp, {stack_top}",
+        "li s0, 0",
         "j {kernel_main}",
         stack_top = sym __stack_top,
         kernel_main = sym kernel_main,
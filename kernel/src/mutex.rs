@@ -0,0 +1,76 @@
+//! Sleeping lock for os1k
+//!
+//! A Mutex is for resources held across disk I/O or other long waits. A process that finds it
+//! locked blocks on the mutex's wait queue and other processes run; unlocking wakes the next
+//! waiter. Unlike a SpinLock, a Mutex may be held while the process blocks. It must not be taken
+//! with a SpinLock held, since the process then cannot block to wait for it.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering::{Acquire, Relaxed, Release}};
+
+use crate::scheduler::WaitQueue;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // Only one hart runs processes, and the kernel is not preempted, so the holder cannot
+        // unlock between the failed exchange and joining the queue.
+        while self.locked.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+            if !self.waiters.wait() {
+                panic!("mutex wanted at {} is held, and the caller cannot block", Location::caller());
+            }
+        }
+        MutexGuard { lock: self }
+    }
+}
+
+impl<T> core::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Mutex").field("locked", &self.locked.load(Relaxed)).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The existence of this guard guarantees exclusive access
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The existence of this guard guarantees exclusive access
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Release);
+        self.lock.waiters.wake_one();
+    }
+}
@@ -0,0 +1,334 @@
+//! UDP/IPv4 networking for os1k
+//!
+//! Just enough Ethernet, ARP, IPv4 and UDP to exchange datagrams with the host through QEMU
+//! user networking, which puts the guest at 10.0.2.15 behind a gateway at 10.0.2.2.
+//! There are no sockets to create: a local port belongs to the first process that sends or
+//! receives on it, until that process exits. Datagrams for ports nobody owns are dropped.
+//! A process receiving on a port with nothing queued sleeps until a datagram arrives for it, or
+//! Ctrl-C is typed for it, or it is a thread whose process exits.
+
+use common::net::{checksum, SockAddr};
+
+use crate::process::PROCS;
+use crate::scheduler::{yield_now, WaitQueue};
+use crate::timer::{read_time, TIMEBASE_FREQUENCY};
+use crate::spinlock::SpinLock;
+use crate::tty::interrupt_pending;
+use crate::vfs::OsError;
+use crate::virtio_net::{virtio_net_mac, virtio_net_poll, virtio_net_send, FRAME_MAX};
+
+const IP_ADDR: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY: [u8; 4] = [10, 0, 2, 2];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ETH_HDR: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_LEN: usize = 28;              // ARP packet for IPv4 over Ethernet
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const IP_HDR: usize = 20;               // Without options, which are never sent
+const IP_PROTO_UDP: u8 = 17;
+const IP_TTL: u8 = 64;
+const UDP_HDR: usize = 8;
+const UDP_DATA_MAX: usize = FRAME_MAX - ETH_HDR - IP_HDR - UDP_HDR;
+
+const ARP_ENTRIES: usize = 4;
+const ARP_RETRY_TICKS: u64 = TIMEBASE_FREQUENCY / 10;  // Ask again every 100ms
+const ARP_TIMEOUT_TICKS: u64 = TIMEBASE_FREQUENCY;     // Give up after a second
+const PORTS_MAX: usize = 4;
+const QUEUE_LEN: usize = 4;             // Datagrams waiting on each port
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+// An IPv4 address and its Ethernet address.
+type ArpEntry = ([u8; 4], [u8; 6]);
+
+// Ethernet addresses learnt from ARP packets.
+static ARP_TABLE: SpinLock<[Option<ArpEntry>; ARP_ENTRIES]> = SpinLock::new([None; ARP_ENTRIES]);
+
+fn arp_lookup(ip: [u8; 4]) -> Option<[u8; 6]> {
+    ARP_TABLE.lock().iter().flatten().find(|(i, _)| *i == ip).map(|(_, mac)| *mac)
+}
+
+fn arp_learn(ip: [u8; 4], mac: [u8; 6]) {
+    let mut table = ARP_TABLE.lock();
+    let slot = match table.iter().position(|e| e.is_some_and(|(i, _)| i == ip)) {
+        Some(index) => index,
+        // Replace the oldest entry when the table is full.
+        None => table.iter().position(Option::is_none).unwrap_or_else(|| {
+            table.rotate_left(1);
+            ARP_ENTRIES - 1
+        }),
+    };
+    table[slot] = Some((ip, mac));
+}
+
+// Sends an Ethernet frame whose payload is `header` followed by `data`.
+fn send_frame(dst: [u8; 6], ethertype: u16, header: &[u8], data: &[u8]) -> Result<(), OsError> {
+    let mac = virtio_net_mac().ok_or(OsError::NotFound)?;
+    let mut eth = [0u8; ETH_HDR];
+    eth[0..6].copy_from_slice(&dst);
+    eth[6..12].copy_from_slice(&mac);
+    write_u16(&mut eth, 12, ethertype);
+    virtio_net_send(&[&eth, header, data])
+}
+
+fn send_arp(op: u16, dst_mac: [u8; 6], target_mac: [u8; 6], target_ip: [u8; 4]) -> Result<(), OsError> {
+    let mac = virtio_net_mac().ok_or(OsError::NotFound)?;
+    let mut arp = [0u8; ARP_LEN];
+    write_u16(&mut arp, 0, 1);                  // Hardware type: Ethernet
+    write_u16(&mut arp, 2, ETHERTYPE_IPV4);     // Protocol type
+    arp[4] = 6;                                 // Hardware address length
+    arp[5] = 4;                                 // Protocol address length
+    write_u16(&mut arp, 6, op);
+    arp[8..14].copy_from_slice(&mac);
+    arp[14..18].copy_from_slice(&IP_ADDR);
+    arp[18..24].copy_from_slice(&target_mac);
+    arp[24..28].copy_from_slice(&target_ip);
+    send_frame(dst_mac, ETHERTYPE_ARP, &arp, &[])
+}
+
+// Returns the Ethernet address to send to `ip`, asking for it with ARP if needed.
+// Hosts outside the local network are reached through the gateway.
+fn resolve(ip: [u8; 4]) -> Result<[u8; 6], OsError> {
+    if ip == [255; 4] {
+        return Ok(BROADCAST_MAC);
+    }
+    let local = (0..4).all(|i| ip[i] & NETMASK[i] == IP_ADDR[i] & NETMASK[i]);
+    let next_hop = if local { ip } else { GATEWAY };
+
+    let start = read_time();
+    let mut asked = None;
+    loop {
+        virtio_net_poll();
+        if let Some(mac) = arp_lookup(next_hop) {
+            return Ok(mac);
+        }
+        let now = read_time();
+        if now - start > ARP_TIMEOUT_TICKS {
+            return Err(OsError::Unreachable);
+        }
+        if asked.is_none_or(|at| now - at > ARP_RETRY_TICKS) {
+            send_arp(ARP_REQUEST, BROADCAST_MAC, [0; 6], next_hop)?;
+            asked = Some(now);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn receive_arp(arp: &[u8]) {
+    if arp.len() < ARP_LEN || read_u16(arp, 0) != 1 || read_u16(arp, 2) != ETHERTYPE_IPV4 {
+        return;
+    }
+    let sender_mac: [u8; 6] = arp[8..14].try_into().expect("slice should be 6 bytes");
+    let sender_ip: [u8; 4] = arp[14..18].try_into().expect("slice should be 4 bytes");
+    arp_learn(sender_ip, sender_mac);
+
+    if read_u16(arp, 6) == ARP_REQUEST && arp[24..28] == IP_ADDR {
+        let _ = send_arp(ARP_REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Datagram {
+    from: SockAddr,
+    len: usize,
+    data: [u8; UDP_DATA_MAX],
+}
+
+// A local port and the datagrams received on it, oldest first.
+// Ports are updated in place: a queue is too big to build on the kernel stack.
+struct Port {
+    port: u16,                      // 0 if unused
+    pid: usize,                     // Process owning the port
+    queue: [Datagram; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+const UNUSED_PORT: Port = Port {
+    port: 0,
+    pid: 0,
+    queue: [Datagram { from: SockAddr { ip: [0; 4], port: 0 }, len: 0, data: [0; UDP_DATA_MAX] }; QUEUE_LEN],
+    head: 0,
+    len: 0,
+};
+
+static PORTS: SpinLock<[Port; PORTS_MAX]> = SpinLock::new([UNUSED_PORT; PORTS_MAX]);
+
+// Processes waiting for a datagram on the port in PORTS at the same index.
+static RECEIVED: [WaitQueue; PORTS_MAX] = [const { WaitQueue::new() }; PORTS_MAX];
+
+// Gives `port` to `pid`, unless another process owns it. Returns its index in `ports`.
+fn bind(ports: &mut [Port; PORTS_MAX], port: u16, pid: usize) -> Result<usize, OsError> {
+    if port == 0 {
+        return Err(OsError::Unsupported);
+    }
+    if let Some(index) = ports.iter().position(|p| p.port == port) {
+        return match ports[index].pid == pid {
+            true => Ok(index),
+            false => Err(OsError::Busy),
+        };
+    }
+    let index = ports.iter().position(|p| p.port == 0).ok_or(OsError::NoSpace)?;
+    let p = &mut ports[index];
+    p.port = port;
+    p.pid = pid;
+    p.head = 0;
+    p.len = 0;
+    Ok(index)
+}
+
+// Releases every port owned by `pid`, when it exits.
+pub fn release_all(pid: usize) {
+    for port in PORTS.lock().iter_mut().filter(|p| p.port != 0 && p.pid == pid) {
+        port.port = 0;
+    }
+}
+
+fn receive_udp(from_ip: [u8; 4], udp: &[u8]) {
+    if udp.len() < UDP_HDR {
+        return;
+    }
+    let len = (read_u16(udp, 4) as usize).clamp(UDP_HDR, udp.len());
+    let from = SockAddr { ip: from_ip, port: read_u16(udp, 0) };
+    let data = &udp[UDP_HDR..len];
+
+    let dst_port = read_u16(udp, 2);
+    let index = {
+        let mut ports = PORTS.lock();
+        let Some(index) = ports.iter().position(|p| p.port != 0 && p.port == dst_port) else {
+            return;
+        };
+        let port = &mut ports[index];
+        // A full queue drops the new datagram, as UDP allows.
+        if port.len == QUEUE_LEN {
+            return;
+        }
+        let datagram = &mut port.queue[(port.head + port.len) % QUEUE_LEN];
+        datagram.from = from;
+        datagram.len = data.len();
+        datagram.data[..data.len()].copy_from_slice(data);
+        port.len += 1;
+        index
+    };
+    // Woken with PORTS unlocked, as waking takes PROCS.
+    RECEIVED[index].wake_all();
+}
+
+fn receive_ipv4(ip: &[u8]) {
+    if ip.len() < IP_HDR || ip[0] >> 4 != 4 {
+        return;
+    }
+    let hdr_len = (ip[0] & 0xf) as usize * 4;
+    let total_len = read_u16(ip, 2) as usize;
+    if hdr_len < IP_HDR || total_len < hdr_len || total_len > ip.len() || checksum(&ip[..hdr_len]) != 0 {
+        return;
+    }
+    // Fragments are not reassembled.
+    if read_u16(ip, 6) & 0x3fff != 0 {
+        return;
+    }
+    let dst = &ip[16..20];
+    if dst != IP_ADDR && dst != [255; 4] {
+        return;
+    }
+    if ip[9] == IP_PROTO_UDP {
+        let src = ip[12..16].try_into().expect("slice should be 4 bytes");
+        receive_udp(src, &ip[hdr_len..total_len]);
+    }
+}
+
+// Handles an Ethernet frame from the network device.
+pub fn receive(frame: &[u8]) {
+    if frame.len() < ETH_HDR {
+        return;
+    }
+    match read_u16(frame, 12) {
+        ETHERTYPE_ARP => receive_arp(&frame[ETH_HDR..]),
+        ETHERTYPE_IPV4 => receive_ipv4(&frame[ETH_HDR..]),
+        _ => {},
+    }
+}
+
+// Sends `data` from local port `port`, owned by `pid`, to `to`. Returns the number of bytes sent.
+pub fn send_udp(port: u16, pid: usize, to: SockAddr, data: &[u8]) -> Result<usize, OsError> {
+    if data.len() > UDP_DATA_MAX {
+        return Err(OsError::NoSpace);
+    }
+    bind(&mut PORTS.lock(), port, pid)?;
+    let dst_mac = resolve(to.ip)?;
+
+    let mut headers = [0u8; IP_HDR + UDP_HDR];
+    let total_len = IP_HDR + UDP_HDR + data.len();
+    let (ip, udp) = headers.split_at_mut(IP_HDR);
+    ip[0] = 0x45;                               // Version 4, 5 words of header
+    write_u16(ip, 2, total_len as u16);
+    ip[8] = IP_TTL;
+    ip[9] = IP_PROTO_UDP;
+    ip[12..16].copy_from_slice(&IP_ADDR);
+    ip[16..20].copy_from_slice(&to.ip);
+    let sum = checksum(ip);
+    write_u16(ip, 10, sum);
+
+    write_u16(udp, 0, port);
+    write_u16(udp, 2, to.port);
+    write_u16(udp, 4, (UDP_HDR + data.len()) as u16);
+    // The UDP checksum is optional over IPv4 and left as zero.
+
+    send_frame(dst_mac, ETHERTYPE_IPV4, &headers, data)?;
+    Ok(data.len())
+}
+
+// Takes the oldest datagram received on `port`, owned by `pid`, into `buf`, truncating it to fit,
+// sleeping until one arrives. Returns its length and sender. Fails with Interrupted if Ctrl-C is
+// typed for the current process or it is killed meanwhile.
+pub fn recv_udp(port: u16, pid: usize, buf: &mut [u8]) -> Result<(usize, SockAddr), OsError> {
+    loop {
+        // Where the process cannot sleep, this polls the device instead of the interrupt.
+        virtio_net_poll();
+        let index = {
+            let mut ports = PORTS.lock();
+            let index = bind(&mut ports, port, pid)?;
+            if let Some(received) = take_datagram(&mut ports[index], buf) {
+                return Ok(received);
+            }
+            index
+        };
+        let (leader, killed) = PROCS.with_current(|p| (p.leader, p.killed));
+        if killed || interrupt_pending(leader) {
+            return Err(OsError::Interrupted);
+        }
+        if !RECEIVED[index].wait() {
+            yield_now();
+        }
+    }
+}
+
+// Takes the oldest datagram queued on `port` into `buf`, or gives None if there is none.
+fn take_datagram(port: &mut Port, buf: &mut [u8]) -> Option<(usize, SockAddr)> {
+    if port.len == 0 {
+        return None;
+    }
+    let datagram = &port.queue[port.head];
+    let len = datagram.len.min(buf.len());
+    buf[..len].copy_from_slice(&datagram.data[..len]);
+    let from = datagram.from;
+    port.head = (port.head + 1) % QUEUE_LEN;
+    port.len -= 1;
+    Some((len, from))
+}
+
+// Wakes every process waiting for a datagram, for Ctrl-C or a process exiting, so an interrupted
+// or killed one can give up.
+pub fn wake_all() {
+    RECEIVED.iter().for_each(WaitQueue::wake_all);
+}
@@ -0,0 +1,120 @@
+//! One-time initialisation for os1k
+//!
+//! Once holds a value that is set at most once. After that, get() is a single atomic load, so
+//! readers never take a lock, and an interrupt handler or the panic path can read it freely.
+//! LazyLock is a Once that makes its value on first use.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering::{Acquire, Release}};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;     // Being initialised; other harts wait for it
+const COMPLETE: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Safety: the value is written once, before state becomes COMPLETE, and only read after.
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Returns the value, or None if it has not been set yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Acquire) == COMPLETE {
+            // Safety: state is COMPLETE, so the value was written and will not change
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    // Returns the value, calling `f` to make it if it has not been set yet. `f` must not use
+    // this Once itself.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Acquire, Acquire).is_ok() {
+            // Safety: winning the exchange gives this hart the only access to the value
+            unsafe { (*self.value.get()).write(f()) };
+            self.state.store(COMPLETE, Release);
+        }
+        self.wait()
+    }
+
+    // Sets the value. Fails, handing `value` back, if it was already set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.state.compare_exchange(INCOMPLETE, RUNNING, Acquire, Acquire).is_err() {
+            return Err(value);
+        }
+        // Safety: as in get_or_init
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(COMPLETE, Release);
+        Ok(())
+    }
+
+    // Waits for another hart to finish initialising.
+    fn wait(&self) -> &T {
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            // Safety: the value was written, and nothing can borrow it any more
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Once").field(&self.get()).finish()
+    }
+}
+
+// A value made by `init` the first time it is used.
+pub struct LazyLock<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+impl<T, F: Fn() -> T> LazyLock<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self { once: Once::new(), init }
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.once.get_or_init(&self.init)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for LazyLock<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("LazyLock").field(&self.once.get()).finish()
+    }
+}
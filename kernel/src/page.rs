@@ -79,3 +79,24 @@ pub fn map_page(table1: &mut PageTable, vaddr: VAddr, paddr: PAddr, flags: usize
     table0[vaddr.vpn0()] = paddr.ppn() | flags | PAGE_V;
 }
 
+
+// Returns the physical page `vaddr` is mapped to, if it is.
+pub fn translate(table1: &PageTable, vaddr: VAddr) -> Option<PAddr> {
+    let pte1 = table1[vaddr.vpn1()];
+    if pte1 & PAGE_V == 0 {
+        return None;
+    }
+    // Safety: map_page makes every valid first level entry point to a leaked second level table
+    let table0 = unsafe { &*(PAddr::from_ppn(pte1).as_usize() as *const PageTable) };
+    let pte0 = table0[vaddr.vpn0()];
+    (pte0 & PAGE_V != 0).then(|| PAddr::from_ppn(pte0))
+}
+
+// Frees a page table and the second level tables map_page made for it. The pages they map are
+// left alone.
+pub fn free_table(table1: Box<PageTable>) {
+    for &pte1 in table1.0.iter().filter(|&&pte| pte & PAGE_V != 0) {
+        // Safety: map_page made the table with Box::new, and nothing else points to it
+        drop(unsafe { Box::from_raw(PAddr::from_ppn(pte1).as_usize() as *mut PageTable) });
+    }
+}
@@ -1,15 +1,74 @@
 //! Panic for os1k
+//!
+//! Prints the panic message, the trap CSRs, the registers at the most recent trap, and a
+//! backtrace found by following frame pointers. Look the addresses up in kernel/kernel.map, or
+//! with `llvm-addr2line -e kernel.elf`. Then QEMU is stopped, exiting with status 101.
 
 use core::arch::asm;
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::println;
+use common::backtrace::backtrace;
+use common::print::{Styled, RED};
+
+use crate::entry::last_trap;
+use crate::power::panic_exit;
+use crate::sbi::sbi_debug_put_byte;
+
+const PANIC_EXIT_CODE: u16 = 101;   // QEMU's exit status after a panic, as for a Rust program
+
+unsafe extern "C" {
+    static __kernel_base: u8;
+    static __free_ram_end: u8;
+}
+
+// Set by the first panic. A panic while printing the report only prints its message.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// Writes straight to the SBI debug console. A panic can happen with the console's locks held,
+// and taking them again would panic once more.
+struct DebugConsole;
+
+impl Write for DebugConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            sbi_debug_put_byte(b).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+// Prints the return addresses of the calls leading here, innermost first.
+fn kernel_backtrace(out: &mut impl Write) -> fmt::Result {
+    let fp: usize;
+    // Safety: only reads s0, the frame pointer
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+
+    // Every stack is in the kernel image or in free RAM.
+    let memory = (&raw const __kernel_base as usize)..(&raw const __free_ram_end as usize);
+    // Safety: kernel memory is mapped in every page table
+    unsafe { backtrace(out, fp, memory) }
+}
+
+fn report(out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "sepc=0x{:08x} sstatus=0x{:08x} scause=0x{:08x} stval=0x{:08x}",
+        read_csr!("sepc"), read_csr!("sstatus"), read_csr!("scause"), read_csr!("stval"),
+    )?;
+    if let Some(frame) = last_trap() {
+        writeln!(out, "registers at the last trap:")?;
+        write!(out, "{}", frame)?;
+    }
+    kernel_backtrace(out)
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("⚠️ Panic: {}", info);
-
-    loop {
-        unsafe {asm!("wfi")};
+    let _ = writeln!(DebugConsole, "{}", Styled(RED, format_args!("⚠️ Panic: {}", info)));
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let _ = report(&mut DebugConsole);
     }
+    panic_exit(PANIC_EXIT_CODE)
 }
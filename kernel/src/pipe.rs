@@ -0,0 +1,183 @@
+//! Pipes for os1k
+//!
+//! Syscall::Pipe gives a process two descriptors, and the bytes written to the second are read
+//! from the first, in order. Pipes are files of a file system of their own that is never
+//! mounted, so they are read, written and closed like any other file. Each pipe has two inodes,
+//! one for each end.
+//!
+//! Reading an empty pipe waits for a write, and reads 0 once every descriptor for the write end
+//! is closed. Writing to a full pipe waits for a read, and fails with BrokenPipe once every
+//! descriptor for the read end is closed. Either wait ends early if Ctrl-C is typed for the
+//! process, so it can be ended.
+//!
+//! Waiting processes sleep on the pipe's Condvar, and are woken by a read, a write, a close, or
+//! Ctrl-C.
+
+use core::fmt;
+
+use common::ring::SpscRing;
+
+use crate::condvar::Condvar;
+use crate::process::PROCS;
+use crate::spinlock::SpinLock;
+use crate::tty::interrupt_pending;
+use crate::vfs::{DirEntry, FileSystem, Inode, OpenFile, OsError, Stat};
+
+const PIPES_MAX: usize = 8;
+const PIPE_SIZE: usize = 4096;  // Bytes a pipe holds before writers wait
+
+struct Pipe {
+    buf: SpscRing<u8, PIPE_SIZE>,  // Written but not read yet. Only used with the pipes locked.
+    readers: usize,     // Descriptors open for the read end
+    writers: usize,     // Descriptors open for the write end
+}
+
+impl fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pipe {{ len: {}, readers: {}, writers: {} }}", self.buf.len(), self.readers, self.writers)
+    }
+}
+
+#[derive(Debug)]
+pub struct PipeFs(SpinLock<[Option<Pipe>; PIPES_MAX]>);
+
+pub static PIPES: PipeFs = PipeFs(SpinLock::new([const { None }; PIPES_MAX]));
+
+// Notified whenever pipe `i` is read, written or closed.
+static PIPE_CHANGED: [Condvar; PIPES_MAX] = [const { Condvar::new() }; PIPES_MAX];
+
+// The read end of pipe `i` is inode 2 * i, and the write end 2 * i + 1.
+fn pipe_index(inode: Inode) -> (usize, bool) {
+    (inode / 2, inode % 2 == 1)
+}
+
+// Makes a pipe, returning its read and write ends, each open once.
+pub fn pipe() -> Result<(OpenFile, OpenFile), OsError> {
+    let mut pipes = PIPES.0.lock();
+    let i = pipes.iter().position(Option::is_none).ok_or(OsError::NoSpace)?;
+    pipes[i] = Some(Pipe { buf: SpscRing::new(), readers: 1, writers: 1 });
+    let end = |inode| OpenFile { fs: &PIPES, inode, offset: 0, append: false };
+    Ok((end(2 * i), end(2 * i + 1)))
+}
+
+// Whether Ctrl-C was typed for the current process, which a wait gives up for.
+fn interrupted() -> bool {
+    interrupt_pending(PROCS.with_leader(|p| p.pid))
+}
+
+// Wakes every process waiting on a pipe, for Ctrl-C, so an interrupted one can give up.
+pub fn wake_all() {
+    PIPE_CHANGED.iter().for_each(Condvar::notify_all);
+}
+
+impl FileSystem for PipeFs {
+    // Pipes have no names: they are only reached through their descriptors.
+    fn lookup(&self, _name: &str) -> Option<Inode> {
+        None
+    }
+
+    fn create(&self, _name: &str) -> Result<Inode, OsError> {
+        Err(OsError::Unsupported)
+    }
+
+    fn read(&self, inode: Inode, _offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        let (i, write_end) = pipe_index(inode);
+        if write_end {
+            return Err(OsError::BadFd);
+        }
+        let mut pipes = self.0.lock();
+        loop {
+            let pipe = pipes.get_mut(i).and_then(Option::as_mut).ok_or(OsError::BadFd)?;
+            if !pipe.buf.is_empty() || buf.is_empty() {
+                let len = buf.len().min(pipe.buf.len());
+                for dst in &mut buf[..len] {
+                    // Safety: the pipes are locked, so this is the only context using the ring.
+                    *dst = unsafe { pipe.buf.pop() }.unwrap_or_default();
+                }
+                PIPE_CHANGED[i].notify_all();
+                return Ok(len);
+            }
+            if pipe.writers == 0 {
+                return Ok(0);
+            }
+            if interrupted() {
+                return Err(OsError::Interrupted);
+            }
+            pipes = PIPE_CHANGED[i].wait(pipes);
+        }
+    }
+
+    // Writes as much as fits, which is short if the pipe fills.
+    fn write(&self, inode: Inode, _offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        let (i, write_end) = pipe_index(inode);
+        if !write_end {
+            return Err(OsError::BadFd);
+        }
+        let mut pipes = self.0.lock();
+        loop {
+            let pipe = pipes.get_mut(i).and_then(Option::as_mut).ok_or(OsError::BadFd)?;
+            if pipe.readers == 0 {
+                return Err(OsError::BrokenPipe);
+            }
+            let len = buf.len().min(PIPE_SIZE - pipe.buf.len());
+            if len > 0 || buf.is_empty() {
+                for &b in &buf[..len] {
+                    // Safety: the pipes are locked, so this is the only context using the ring,
+                    // and it has room for `len` bytes.
+                    let _ = unsafe { pipe.buf.push(b) };
+                }
+                PIPE_CHANGED[i].notify_all();
+                return Ok(len);
+            }
+            if interrupted() {
+                return Err(OsError::Interrupted);
+            }
+            pipes = PIPE_CHANGED[i].wait(pipes);
+        }
+    }
+
+    fn readdir(&self, _index: usize) -> Option<DirEntry> {
+        None
+    }
+
+    // The size of a pipe is what is waiting to be read.
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        let pipes = self.0.lock();
+        let pipe = pipes.get(pipe_index(inode).0).and_then(Option::as_ref).ok_or(OsError::BadFd)?;
+        Ok(Stat { size: pipe.buf.len() })
+    }
+
+    fn sync(&self, _inode: Inode) {}
+
+    fn sync_all(&self) {}
+
+    fn dup(&self, inode: Inode) {
+        let (i, write_end) = pipe_index(inode);
+        if let Some(Some(pipe)) = self.0.lock().get_mut(i) {
+            match write_end {
+                true => pipe.writers += 1,
+                false => pipe.readers += 1,
+            }
+        }
+    }
+
+    // The pipe is freed once both of its ends are closed.
+    fn close(&self, inode: Inode) {
+        let (i, write_end) = pipe_index(inode);
+        let mut pipes = self.0.lock();
+        let Some(slot) = pipes.get_mut(i) else {
+            return;
+        };
+        if let Some(pipe) = slot {
+            match write_end {
+                true => pipe.writers = pipe.writers.saturating_sub(1),
+                false => pipe.readers = pipe.readers.saturating_sub(1),
+            }
+            if pipe.readers == 0 && pipe.writers == 0 {
+                *slot = None;
+            }
+            // A reader waiting for the last writer, or a writer for the last reader, can go on.
+            PIPE_CHANGED[i].notify_all();
+        }
+    }
+}
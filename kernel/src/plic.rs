@@ -0,0 +1,38 @@
+//! Platform-level interrupt controller for os1k
+
+use core::ptr;
+
+pub const PLIC_PADDR: usize = 0x0c00_0000;
+pub const PLIC_SIZE: usize = 0x40_0000;
+const PLIC_PRIORITY: usize =  0x0000;    // One u32 per interrupt source
+const PLIC_ENABLE: usize =    0x2080;    // Hart 0 supervisor context
+const PLIC_THRESHOLD: usize = 0x20_1000; // Hart 0 supervisor context
+const PLIC_CLAIM: usize =     0x20_1004; // Hart 0 supervisor context, also used to complete
+
+fn plic_write32(offset: usize, value: u32) {
+    // Safety: PLIC_PADDR + offset is a 32-bit aligned PLIC register, mapped in every page table.
+    unsafe { ptr::write_volatile((PLIC_PADDR + offset) as *mut u32, value) }
+}
+
+fn plic_read32(offset: usize) -> u32 {
+    // Safety: PLIC_PADDR + offset is a 32-bit aligned PLIC register, mapped in every page table.
+    unsafe { ptr::read_volatile((PLIC_PADDR + offset) as *const u32) }
+}
+
+// Routes interrupt source `irq` to supervisor mode on hart 0.
+pub fn plic_enable(irq: u32) {
+    plic_write32(PLIC_PRIORITY + irq as usize * 4, 1);
+    let enable = PLIC_ENABLE + (irq as usize / 32) * 4;
+    plic_write32(enable, plic_read32(enable) | 1 << (irq % 32));
+    plic_write32(PLIC_THRESHOLD, 0);
+}
+
+// Returns the highest priority pending interrupt, or 0 if there is none.
+pub fn plic_claim() -> u32 {
+    plic_read32(PLIC_CLAIM)
+}
+
+// Tells the PLIC that `irq` has been handled.
+pub fn plic_complete(irq: u32) {
+    plic_write32(PLIC_CLAIM, irq);
+}
@@ -0,0 +1,57 @@
+//! Power off and reboot for os1k
+//!
+//! Before handing over to the firmware, every file system is written back to its disk and the
+//! devices are stopped, so nothing is lost and no device is left writing to memory. `exit` also
+//! powers off, but through QEMU's test finisher first, so that QEMU exits with a status an
+//! automated run can check.
+
+use core::arch::asm;
+
+use crate::{log_error, log_info};
+use crate::finisher::finisher_exit;
+use crate::sbi::sbi_system_reset;
+use crate::vfs;
+use crate::virtio::virtio_reset_all;
+
+// Writes every file system back and stops interrupts and devices.
+fn stop() {
+    vfs::sync_all();
+    write_csr!("sie", 0usize);
+    virtio_reset_all();
+}
+
+fn halt() -> ! {
+    loop {
+        // Safety: wfi only waits, and with no interrupts enabled it waits forever
+        unsafe { asm!("wfi") };
+    }
+}
+
+// Powers the machine off, or reboots it if `reboot`.
+pub fn shutdown(reboot: bool) -> ! {
+    log_info!("{}", if reboot { "rebooting" } else { "powering off" });
+    stop();
+
+    let error = sbi_system_reset(reboot, false);
+    log_error!("SBI could not reset the system: {}", error);
+    halt()
+}
+
+// Powers the machine off so that QEMU exits with status `code`: 0 for a pass, anything else for
+// a failure. For the end of a test run.
+pub fn exit(code: u16) -> ! {
+    log_info!("exiting with status {}", code);
+    stop();
+
+    finisher_exit(code);
+    let error = sbi_system_reset(false, code != 0);
+    log_error!("SBI could not reset the system: {}", error);
+    halt()
+}
+
+// As `exit`, for the panic handler: nothing is written back, and no lock is taken.
+pub fn panic_exit(code: u16) -> ! {
+    finisher_exit(code);
+    let _ = sbi_system_reset(false, true);
+    halt()
+}
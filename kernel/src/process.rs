@@ -1,16 +1,33 @@
 //! Process
 
+use alloc::alloc::{alloc_zeroed, dealloc};
 use alloc::slice;
 use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use core::arch::naked_asm;
+use core::alloc::Layout;
+use core::arch::{asm, naked_asm};
 
 use crate::address::{align_up, PAddr, VAddr};
-use crate::allocator::PAGE_SIZE;
-use crate::entry::{user_entry, USER_BASE};
-use crate::page::{map_page, PageTable, PAGE_R, PAGE_W, PAGE_X, PAGE_U};
-use crate::spinlock::SpinLock;
-use crate::virtio::VIRTIO_BLK_PADDR;
+use crate::allocator::{memory_stats, PAGE_SIZE};
+use crate::console::flush_process_output;
+use crate::entry::{thread_entry, user_entry, USER_BASE};
+use crate::env::Env;
+use crate::finisher::FINISHER_PADDR;
+use crate::flock;
+use crate::ipi::tlb_shootdown;
+use crate::net;
+use crate::page::{free_table, map_page, translate, PageTable, PAGE_R, PAGE_W, PAGE_X, PAGE_U};
+use crate::plic::{PLIC_PADDR, PLIC_SIZE};
+use crate::scheduler::{running_pid, yield_now, CURRENT_PROC};
+use crate::spinlock::RwSpinLock;
+use crate::tty;
+use crate::uart::UART_PADDR;
+use crate::vfs::{self, FileSystem, Inode, OsError, OpenFile};
+use crate::virtio::{VIRTIO_MMIO_PADDR, VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SLOTS};
+
+use common::{EXIT_STOPPED, STDIN, STDERR, WAIT_NOHANG, WAIT_STOPPED};
 
 unsafe extern "C" {
     static __kernel_base: u8;
@@ -18,20 +35,35 @@ unsafe extern "C" {
 }
 
 pub const PROCS_MAX: usize = 8;         // Maximum number of processes
+pub const FDS_MAX: usize = 8;           // Maximum number of open files per process
+pub const INIT_PID: usize = 1;          // The first process (the shell), the only privileged one
+const HEAP_MAX: usize = 16 * 1024 * 1024;   // Largest heap a process can grow with Syscall::Sbrk
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum State {
     Unused,     // Unused process control structure
     Runnable,   // Runnable process
-    Exited,
+    Blocked,    // Waiting on a wait queue
+    Exited,     // Exited, until another process waits for it
 }
 
 #[derive(Clone, Debug)]
 pub struct Process {
     pub pid: usize,            // Process ID
+    pub leader: usize,         // The process whose memory it runs in: its own pid, unless a thread
+    pub group: usize,          // Process group, for job control: its parent's, unless moved
+    pub killed: bool,          // A thread whose process exited, to exit on its next trap
     pub state: State,          // Process state: Unused or Runnable
     pub sp: VAddr,             // Stack pointer
     pub page_table: Option<Box<PageTable>>,
+    pub memory: Option<Box<[u8]>>,  // The image followed by the arguments, mapped at USER_BASE
+    pub files: [Option<OpenFile>; FDS_MAX], // Open files indexed by file descriptor
+    pub heap_start: usize,     // Start of the heap, the page after the image and arguments
+    pub heap_end: usize,       // The break: the end of the heap, moved by Syscall::Sbrk
+    pub exit_status: usize,    // Set on exit, for Syscall::Wait
+    pub env: Env,              // Environment variables
+    pub interrupt_handler: usize,  // User function run for Ctrl-C, or 0 to be ended by it
+    pub signal_restorer: usize,    // User function a signal handler returns to
     pub stack: [u8; 8192],     // Kernel stack
 }
 
@@ -39,32 +71,77 @@ impl Process {
     const fn empty() -> Self {
         Self {
             pid: 0,
+            leader: 0,
+            group: 0,
+            killed: false,
             state: State::Unused,
             sp: VAddr::new(0),
             page_table: None,
+            memory: None,
+            files: [None; FDS_MAX],
+            heap_start: 0,
+            heap_end: 0,
+            exit_status: 0,
+            env: Vec::new(),
+            interrupt_handler: 0,
+            signal_restorer: 0,
             stack: [0; 8192],
         }
     }
 }
 
-pub struct Procs(pub SpinLock<[Process; PROCS_MAX]>);
+pub struct Procs(pub RwSpinLock<[Process; PROCS_MAX]>);
 
 impl Procs {
     const fn new() -> Self {
         Self(
-            SpinLock::new([const { Process::empty() }; PROCS_MAX])
+            RwSpinLock::new([const { Process::empty() }; PROCS_MAX])
         )
     }
 
     pub fn try_get_index(&self, pid: usize) -> Option<usize> {
-        self.0.lock().iter().position(|p| p.pid == pid)
+        self.0.read().iter().position(|p| p.pid == pid)
+    }
+
+    // Runs `f` on the process whose memory the current one runs in: the current process, or for
+    // a thread, the process that started it. Its page table, heap and signal handlers are shared.
+    pub fn with_leader<R>(&self, f: impl FnOnce(&mut Process) -> R) -> R {
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        let mut procs = self.0.write();
+        let leader = procs.iter()
+            .find(|p| p.pid == current)
+            .expect("current process should have a process control structure")
+            .leader;
+        let process = procs.iter_mut()
+            .find(|p| p.pid == leader)
+            .expect("a thread's process should outlive it");
+        f(process)
+    }
+
+    // Whether any process has a descriptor open for the file.
+    pub fn is_open(&self, fs: &'static dyn FileSystem, inode: Inode) -> bool {
+        self.0.read().iter()
+            .flat_map(|p| p.files.iter().flatten())
+            .any(|file| core::ptr::addr_eq(file.fs, fs) && file.inode == inode)
+    }
+
+    // Runs `f` on the currently running process while holding the lock.
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut Process) -> R) -> R {
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        let mut procs = self.0.write();
+        let process = procs.iter_mut()
+            .find(|p| p.pid == current)
+            .expect("current process should have a process control structure");
+        f(process)
     }
 }
 
 // Optional - but vital for debugging if you want to print the contents of PROCS.
 // impl fmt::Display for Procs {
 //     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-//         let procs = PROCS.0.lock();
+//         let procs = PROCS.0.read();
 //         for (i, process) in procs.iter().enumerate() {
 //             write!(f, "Addr: {:x?} ", &raw const *process as usize)?;
 //             writeln!(f, "PROC[{i}]")?;
@@ -79,22 +156,51 @@ impl Procs {
 
 pub static PROCS: Procs = Procs::new();  // All process control structures.
 
-pub fn create_process(image: *const u8, image_size: usize) -> usize {
-    let mut procs = PROCS.0.lock();
+// Lays out `args` as the process will find them: an argv array of pointers to NUL-terminated
+// strings, ending with a null pointer, followed by the strings. `vaddr` is where the returned
+// pages will be mapped, and argv is at its start.
+fn build_args(args: &[&str], vaddr: usize) -> Vec<u8> {
+    let ptr_size = size_of::<usize>();
+    let strings_start = (args.len() + 1) * ptr_size;
+    let size = strings_start + args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+    let mut data = Vec::with_capacity(align_up(size, PAGE_SIZE));
+
+    let mut string_addr = vaddr + strings_start;
+    for arg in args {
+        data.extend_from_slice(&string_addr.to_ne_bytes());
+        string_addr += arg.len() + 1;
+    }
+    data.extend_from_slice(&0usize.to_ne_bytes());
+    for arg in args {
+        data.extend_from_slice(arg.as_bytes());
+        data.push(0);
+    }
+    data.resize(align_up(size, PAGE_SIZE), 0);
+    data
+}
+
+// Starts a process running `image`, with `args` passed to its entry point as argc and argv.
+// Fails with NoSpace if every process slot is taken.
+pub fn create_process(image: *const u8, image_size: usize, args: &[&str]) -> Result<usize, OsError> {
+    let mut procs = PROCS.0.write();
 
     // Find an unused process control structure.
     let (i, process) = procs.iter_mut()
         .enumerate()
         .find(|(_, p)| p.state == State::Unused)
-        .expect("no free process slots");
+        .ok_or(OsError::NoSpace)?;
+
+    // The arguments follow the image, and the heap follows them.
+    let aligned_size = align_up(image_size, PAGE_SIZE);
+    let args_base = USER_BASE + aligned_size;
 
     // Stack callee-saved registers. These register values will be restored in
     // the first context switch in switch_context.
-    let callee_saved_regs: [usize; 13] = [
+    process.sp = push_callee_saved(&mut process.stack, [
         user_entry as *const () as usize,            // ra
         0,             // s0
-        0,             // s1
-        0,             // s2
+        args.len(),    // s1: argc, passed on by user_entry
+        args_base,     // s2: argv
         0,             // s3
         0,             // s4
         0,             // s5
@@ -104,16 +210,7 @@ pub fn create_process(image: *const u8, image_size: usize) -> usize {
         0,             // s9
         0,             // s10
         0,             // s11
-    ];
-
-    // Place the callee-saved registers at the end of the stack
-    let callee_saved_regs_start = process.stack.len() - callee_saved_regs.len() * size_of::<usize>();
-    let mut offset = callee_saved_regs_start;
-    for reg in &callee_saved_regs {
-        let bytes = reg.to_ne_bytes(); // native endian
-        process.stack[offset..offset + size_of::<usize>()].copy_from_slice(&bytes);
-        offset += size_of::<usize>();
-    }
+    ]);
 
     // Map kernel pages.
     let mut page_table = Box::new(PageTable::new());
@@ -124,39 +221,301 @@ pub fn create_process(image: *const u8, image_size: usize) -> usize {
         map_page(page_table.as_mut(), VAddr::new(paddr), PAddr::new(paddr), PAGE_R | PAGE_W | PAGE_X);
     }
 
-    map_page(page_table.as_mut(), VAddr::new(VIRTIO_BLK_PADDR as usize), PAddr::new(VIRTIO_BLK_PADDR as usize), PAGE_R | PAGE_W);
+    for slot in 0..VIRTIO_MMIO_SLOTS {
+        let paddr = VIRTIO_MMIO_PADDR as usize + slot * VIRTIO_MMIO_SIZE as usize;
+        map_page(page_table.as_mut(), VAddr::new(paddr), PAddr::new(paddr), PAGE_R | PAGE_W);
+    }
+
+    map_page(page_table.as_mut(), VAddr::new(UART_PADDR), PAddr::new(UART_PADDR), PAGE_R | PAGE_W);
+    map_page(page_table.as_mut(), VAddr::new(FINISHER_PADDR), PAddr::new(FINISHER_PADDR), PAGE_R | PAGE_W);
+
+    for paddr in (PLIC_PADDR..PLIC_PADDR + PLIC_SIZE).step_by(PAGE_SIZE) {
+        map_page(page_table.as_mut(), VAddr::new(paddr), PAddr::new(paddr), PAGE_R | PAGE_W);
+    }
 
     process.page_table = Some(page_table);
 
-    // Map user pages.
-    let aligned_size = align_up(image_size, PAGE_SIZE);
+    // Map user pages: the image, then the arguments, from one allocation the process keeps
+    // until it is reaped.
     let image_slice = unsafe {
         slice::from_raw_parts(image, image_size)
     };
-    let mut image_vec = image_slice.to_vec();
-    image_vec.resize(aligned_size, 0);
-    let image_data = Box::leak(image_vec.into_boxed_slice());
+    let mut memory = image_slice.to_vec();
+    memory.resize(aligned_size, 0);
+    memory.extend_from_slice(&build_args(args, args_base));
+    let memory = process.memory.insert(memory.into_boxed_slice());
     let page_table = process.page_table.as_mut()
     .expect("page table must be initialized before mapping user pages");
 
-    for (i, page_chunk) in image_data.chunks_mut(PAGE_SIZE).enumerate() {
-        let vaddr = VAddr::new(USER_BASE + i * PAGE_SIZE);
+    for (i, page_chunk) in memory.chunks_mut(PAGE_SIZE).enumerate() {
+        let vaddr = USER_BASE + i * PAGE_SIZE;
         let paddr = PAddr::new(page_chunk.as_mut_ptr() as usize);
+        let flags = if vaddr < args_base { PAGE_X } else { 0 };
 
         map_page(
             page_table,
-            vaddr,
+            VAddr::new(vaddr),
             paddr,
-            PAGE_U | PAGE_R | PAGE_W | PAGE_X,
+            PAGE_U | PAGE_R | PAGE_W | flags,
         );
     }
 
+    // Standard input, output and error start on the console, if /dev is mounted.
+    process.files = [None; FDS_MAX];
+    process.files[STDIN..=STDERR].fill(vfs::open("/dev/console", 0).ok());
+
+    // The heap starts empty, after the image and arguments.
+    process.heap_start = USER_BASE + memory.len();
+    process.heap_end = process.heap_start;
+    process.exit_status = 0;
+    process.env = Vec::new();
+    process.interrupt_handler = 0;
+    process.signal_restorer = 0;
+
     // Initialise fields.
     process.pid = i + 1;
+    process.leader = process.pid;
+    process.group = process.pid;
+    process.killed = false;
+    tty::clear_slot(i);
     process.state = State::Runnable;
-    process.sp = VAddr::new(&raw const process.stack[callee_saved_regs_start] as usize);
 
-    process.pid
+    Ok(process.pid)
+}
+
+// Places the callee-saved registers at the end of a new process's kernel stack, where the first
+// switch_context to it restores them from, and returns the stack pointer.
+fn push_callee_saved(stack: &mut [u8], regs: [usize; 13]) -> VAddr {
+    let start = stack.len() - regs.len() * size_of::<usize>();
+    let mut offset = start;
+    for reg in &regs {
+        let bytes = reg.to_ne_bytes(); // native endian
+        stack[offset..offset + size_of::<usize>()].copy_from_slice(&bytes);
+        offset += size_of::<usize>();
+    }
+    VAddr::new(&raw const stack[start] as usize)
+}
+
+// Starts a thread in the current process: a process of its own to the scheduler, with its own
+// kernel stack, that runs in the same memory. It calls `entry` with `arg` on the user stack
+// ending at `stack_top`. It starts with a copy of the open files and environment, but shares
+// the heap and signal handlers. Returns its pid, which Syscall::Wait joins.
+pub fn create_thread(entry: usize, arg: usize, stack_top: usize) -> Result<usize, OsError> {
+    if entry < USER_BASE || stack_top < USER_BASE || !stack_top.is_multiple_of(16) {
+        return Err(OsError::Invalid);
+    }
+    let current = running_pid();
+    let mut procs = PROCS.0.write();
+    let parent = procs.iter()
+        .find(|p| p.pid == current)
+        .expect("current process should have a process control structure");
+    let (leader, group, files, env) = (parent.leader, parent.group, parent.files, parent.env.clone());
+
+    let (i, thread) = procs.iter_mut()
+        .enumerate()
+        .find(|(_, p)| p.state == State::Unused)
+        .ok_or(OsError::NoSpace)?;
+
+    thread.sp = push_callee_saved(&mut thread.stack, [
+        thread_entry as *const () as usize,          // ra
+        0,             // s0
+        entry,         // s1: where the thread starts, passed on by thread_entry
+        arg,           // s2: its argument
+        stack_top,     // s3: its user stack
+        0,             // s4
+        0,             // s5
+        0,             // s6
+        0,             // s7
+        0,             // s8
+        0,             // s9
+        0,             // s10
+        0,             // s11
+    ]);
+    // The page table is the leader's, which the scheduler switches to.
+    thread.page_table = None;
+    thread.memory = None;
+    files.iter().flatten().for_each(OpenFile::dup);
+    thread.files = files;
+    thread.heap_start = 0;
+    thread.heap_end = 0;
+    thread.exit_status = 0;
+    thread.env = env;
+    thread.interrupt_handler = 0;
+    thread.signal_restorer = 0;
+    thread.pid = i + 1;
+    thread.leader = leader;
+    thread.group = group;
+    thread.killed = false;
+    tty::clear_slot(i);
+    thread.state = State::Runnable;
+    Ok(thread.pid)
+}
+
+// Starts the program at `path`, a flat binary linked at USER_BASE like the shell, as a new
+// process with `args`. Returns its pid.
+pub fn spawn(path: &str, args: &[&str]) -> Result<usize, OsError> {
+    let image = read_image(path)?;
+    // The new process starts with a copy of its parent's environment, and of its standard input,
+    // output and error, so the parent can point them at files or pipes first. It joins its
+    // parent's process group.
+    let (env, files, group) = PROCS.with_current(|p| (p.env.clone(), p.files, p.group));
+    let pid = create_process(image.as_ptr(), image.len(), args)?;
+    if let Some(p) = PROCS.0.write().iter_mut().find(|p| p.pid == pid) {
+        p.env = env;
+        p.group = group;
+        for (slot, &file) in p.files[STDIN..=STDERR].iter_mut().zip(&files[STDIN..=STDERR]) {
+            file.iter().for_each(OpenFile::dup);
+            if let Some(console) = core::mem::replace(slot, file) {
+                console.close();
+            }
+        }
+    }
+    Ok(pid)
+}
+
+// Reads the program at `path` into memory, for create_process.
+pub fn read_image(path: &str) -> Result<Vec<u8>, OsError> {
+    let (fs, inode) = vfs::lookup(path)?;
+    let mut image = vec![0u8; fs.stat(inode)?.size];
+    let mut len = 0;
+    while len < image.len() {
+        match fs.read(inode, len, &mut image[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    if len == 0 {
+        return Err(OsError::Invalid);
+    }
+    image.truncate(len);
+    Ok(image)
+}
+
+// Waits for process `pid` to exit and returns its exit status. Its slot is then free for a new
+// process, which may be given the same pid. `flags` are WAIT_NOHANG, to fail with WouldBlock
+// rather than wait, and WAIT_STOPPED, to return EXIT_STOPPED for a process Ctrl-Z stopped.
+pub fn wait(pid: usize, flags: usize) -> Result<usize, OsError> {
+    if pid <= INIT_PID || pid == running_pid() {
+        return Err(OsError::NotFound);
+    }
+    loop {
+        {
+            let mut procs = PROCS.0.write();
+            let (i, p) = procs.iter()
+                .enumerate()
+                .find(|(_, p)| p.pid == pid && p.state != State::Unused)
+                .ok_or(OsError::NotFound)?;
+            // A process's threads run in its memory, so it is only reaped once they have exited,
+            // and its threads that were never waited for go with it.
+            let threads_running = procs.iter()
+                .any(|t| t.leader == pid && t.pid != pid && matches!(t.state, State::Runnable | State::Blocked));
+            if p.state == State::Exited && !threads_running {
+                let status = p.exit_status;
+                for p in procs.iter_mut().filter(|p| (p.pid == pid || p.leader == pid) && p.state == State::Exited) {
+                    free_memory(p);
+                    p.state = State::Unused;
+                }
+                return Ok(status);
+            }
+            if flags & WAIT_STOPPED != 0 && tty::is_stopped(i) {
+                return Ok(EXIT_STOPPED);
+            }
+            if flags & WAIT_NOHANG != 0 {
+                return Err(OsError::WouldBlock);
+            }
+        }
+        yield_now();
+    }
+}
+
+// Frees the memory of a process being reaped: its heap pages, image and arguments, page table
+// and environment. A thread only has its environment, as the rest is its leader's.
+fn free_memory(p: &mut Process) {
+    p.env = Vec::new();
+    p.memory = None;
+    let Some(page_table) = p.page_table.take() else {
+        return;
+    };
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).expect("a page is a valid layout");
+    for vaddr in (p.heap_start..align_up(p.heap_end, PAGE_SIZE)).step_by(PAGE_SIZE) {
+        if let Some(mut paddr) = translate(&page_table, VAddr::new(vaddr)) {
+            // Safety: sbrk allocated the page with this layout, and the process no longer runs
+            unsafe { dealloc(paddr.as_ptr_mut() as *mut u8, layout) };
+        }
+    }
+    free_table(page_table);
+    // The tables' memory may be reused, so no hart can keep translations read from them.
+    tlb_shootdown();
+}
+
+// Moves process `pid` and its threads into process group `group`.
+pub fn set_group(pid: usize, group: usize) -> Result<(), OsError> {
+    let mut procs = PROCS.0.write();
+    if !procs.iter().any(|p| p.pid == pid && p.pid == p.leader && p.state != State::Unused) {
+        return Err(OsError::NotFound);
+    }
+    for p in procs.iter_mut().filter(|p| p.leader == pid && p.state != State::Unused) {
+        p.group = group;
+    }
+    Ok(())
+}
+
+// Moves the current process's break up by `increment` bytes, mapping zeroed pages to cover the
+// heap. Returns the old break. The heap cannot shrink: its pages are freed when the process is reaped.
+pub fn sbrk(increment: usize) -> Result<usize, OsError> {
+    PROCS.with_leader(|p| {
+        let old_end = p.heap_end;
+        let new_end = old_end.checked_add(increment)
+            .filter(|&end| end <= p.heap_start + HEAP_MAX)
+            .ok_or(OsError::NoSpace)?;
+        let grown = align_up(new_end, PAGE_SIZE) - align_up(old_end, PAGE_SIZE);
+        let (used, total) = memory_stats();
+        if used + grown > total {
+            return Err(OsError::NoMem);
+        }
+
+        let page_table = p.page_table.as_mut().expect("a user process should have a page table");
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).expect("a page is a valid layout");
+        for vaddr in (align_up(old_end, PAGE_SIZE)..align_up(new_end, PAGE_SIZE)).step_by(PAGE_SIZE) {
+            // Safety: layout has a non-zero size
+            let page = unsafe { alloc_zeroed(layout) };
+            map_page(page_table, VAddr::new(vaddr), PAddr::new(page as usize), PAGE_U | PAGE_R | PAGE_W);
+        }
+        // Safety: sfence.vma only drops cached translations
+        unsafe { asm!("sfence.vma") };
+        tlb_shootdown();
+        p.heap_end = new_end;
+        Ok(old_end)
+    })
+}
+
+// Ends the current process with `status`, for Syscall::Wait, releasing its files, locks and ports,
+// and runs the next one.
+pub fn exit_current(status: usize) -> ! {
+    flush_process_output();
+    let current = CURRENT_PROC.lock()
+        .expect("current process should be running");
+    let files = {
+        let mut procs = PROCS.0.write();
+        // The process's threads follow it the next time they trap.
+        for p in procs.iter_mut().filter(|p| p.leader == current && p.pid != current) {
+            p.killed = true;
+        }
+        procs.iter_mut().find(|p| p.pid == current).map(|p| {
+            p.state = State::Exited;
+            p.exit_status = status;
+            core::mem::replace(&mut p.files, [None; FDS_MAX])
+        })
+    };
+    // Closed with PROCS unlocked, as closing a pipe wakes the processes waiting on it.
+    files.iter().flatten().flatten().for_each(OpenFile::close);
+    flock::unlock_all(current);
+    net::release_all(current);
+    // The killed threads may be waiting for a lock another process holds, or a datagram.
+    flock::wake_all();
+    net::wake_all();
+    yield_now();
+    unreachable!("an exited process is never scheduled");
 }
 
 #[unsafe(naked)]
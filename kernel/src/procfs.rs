@@ -0,0 +1,114 @@
+//! Process file system for os1k
+//!
+//! Files are generated on every read from the current kernel state.
+
+use alloc::format;
+use alloc::string::String;
+
+use crate::allocator::memory_stats;
+use crate::process::{PROCS, State};
+use crate::spinlock::lock_stats;
+use crate::timer::{read_time, TIMEBASE_FREQUENCY};
+use crate::vfs::{DirEntry, FileSystem, OsError, Inode, Stat};
+
+const PROC_MEMINFO: Inode = 0;
+const PROC_UPTIME: Inode = 1;
+const PROC_LOCKS: Inode = 2;
+#[cfg(feature = "profile")]
+const PROC_PROFILE: Inode = 3;
+const PROC_STATUS: Inode = 4;  // Inode of <pid>/status is PROC_STATUS + pid
+
+// Files that are always there, before the per-process ones
+const FILES: &[(&str, Inode)] = &[
+    ("meminfo", PROC_MEMINFO),
+    ("uptime", PROC_UPTIME),
+    ("locks", PROC_LOCKS),
+    #[cfg(feature = "profile")]
+    ("profile", PROC_PROFILE),
+];
+
+// Returns the state and number of open files of a process.
+fn process_status(pid: usize) -> Option<(State, usize)> {
+    PROCS.0.read().iter()
+        .find(|p| p.pid == pid && p.state != State::Unused)
+        .map(|p| (p.state, p.files.iter().flatten().count()))
+}
+
+#[derive(Debug)]
+pub struct ProcFs;
+
+impl ProcFs {
+    // Generates the contents of a file.
+    fn generate(&self, inode: Inode) -> Result<String, OsError> {
+        match inode {
+            PROC_MEMINFO => {
+                let (used, total) = memory_stats();
+                Ok(format!("MemTotal: {} kB\nMemUsed: {} kB\nMemFree: {} kB\n", total / 1024, used / 1024, (total - used) / 1024))
+            },
+            PROC_UPTIME => {
+                let ticks = read_time();
+                let seconds = ticks / TIMEBASE_FREQUENCY;
+                let hundredths = ticks % TIMEBASE_FREQUENCY * 100 / TIMEBASE_FREQUENCY;
+                Ok(format!("{}.{:02}\n", seconds, hundredths))
+            },
+            PROC_LOCKS => {
+                let stats = lock_stats();
+                Ok(format!("Acquired: {}\nContended: {}\nSpins: {}\n", stats.acquired, stats.contended, stats.spins))
+            },
+            #[cfg(feature = "profile")]
+            PROC_PROFILE => Ok(crate::profile::profile_report()),
+            _ => {
+                let pid = inode - PROC_STATUS;
+                let (state, files) = process_status(pid).ok_or(OsError::NotFound)?;
+                Ok(format!("Pid: {}\nState: {:?}\nFiles: {}\n", pid, state, files))
+            },
+        }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn lookup(&self, name: &str) -> Option<Inode> {
+        if let Some(&(_, inode)) = FILES.iter().find(|(n, _)| *n == name) {
+            return Some(inode);
+        }
+        let pid = name.strip_suffix("/status")?.parse::<usize>().ok()?;
+        process_status(pid).map(|_| PROC_STATUS + pid)
+    }
+
+    fn create(&self, _name: &str) -> Result<Inode, OsError> {
+        Err(OsError::Unsupported)
+    }
+
+    fn read(&self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        let contents = self.generate(inode)?;
+        let data = contents.as_bytes().get(offset..).unwrap_or(&[]);
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write(&self, _inode: Inode, _offset: usize, _buf: &[u8]) -> Result<usize, OsError> {
+        Err(OsError::Unsupported)
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        if let Some(&(name, inode)) = FILES.get(index) {
+            return Some(DirEntry { name: String::from(name), inode });
+        }
+        let pid = PROCS.0.read().iter()
+            .filter(|p| p.state != State::Unused)
+            .nth(index - FILES.len())
+            .map(|p| p.pid)?;
+        Some(DirEntry { name: format!("{}/status", pid), inode: PROC_STATUS + pid })
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        Ok(Stat { size: self.generate(inode)?.len() })
+    }
+
+    fn sync(&self, _inode: Inode) {}
+
+    fn sync_all(&self) {}
+}
+
+pub static PROCFS: ProcFs = ProcFs;
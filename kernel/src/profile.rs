@@ -0,0 +1,76 @@
+//! Sampling profiler for os1k
+//!
+//! Built with the `profile` feature (`./os1k.sh profile`), every timer tick records where the
+//! running process was interrupted. Samples are counted per process and per PROFILE_BUCKET bytes
+//! of code, and /proc/profile lists the hottest. Ticks are only taken in user mode, so every
+//! sample is a user address: look it up in user/user.map, or disassemble with ./dis.sh.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::scheduler::CURRENT_PROC;
+use crate::spinlock::SpinLock;
+use crate::timer::timer_periodic;
+
+const PROFILE_BUCKET: usize = 16;   // Bytes of code counted together
+const PROFILE_ENTRIES: usize = 256;
+const PROFILE_TOP: usize = 20;      // Entries listed in /proc/profile
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    pid: usize,
+    addr: usize,        // Start of the bucket
+    count: usize,
+}
+
+#[derive(Debug)]
+struct Profile {
+    entries: [Option<Entry>; PROFILE_ENTRIES],
+    samples: usize,
+    dropped: usize,     // Samples with no free entry for their bucket
+}
+
+static PROFILE: SpinLock<Profile> = SpinLock::new(Profile {
+    entries: [None; PROFILE_ENTRIES],
+    samples: 0,
+    dropped: 0,
+});
+
+pub fn profile_init() {
+    timer_periodic(1, profile_tick, 0).expect("profiler should have a timer");
+}
+
+fn profile_tick(_: usize) {
+    let Some(pid) = *CURRENT_PROC.lock() else {
+        return;
+    };
+    // Called from the timer interrupt, so sepc is where the process was interrupted.
+    let addr = read_csr!("sepc") & !(PROFILE_BUCKET - 1);
+
+    let mut profile = PROFILE.lock();
+    profile.samples += 1;
+    if let Some(entry) = profile.entries.iter_mut().flatten().find(|e| e.pid == pid && e.addr == addr) {
+        entry.count += 1;
+    } else if let Some(slot) = profile.entries.iter_mut().find(|e| e.is_none()) {
+        *slot = Some(Entry { pid, addr, count: 1 });
+    } else {
+        profile.dropped += 1;
+    }
+}
+
+// Lists the hottest buckets, most samples first, for /proc/profile.
+pub fn profile_report() -> String {
+    let (mut entries, samples, dropped) = {
+        let profile = PROFILE.lock();
+        (profile.entries, profile.samples, profile.dropped)
+    };
+    entries.sort_unstable_by_key(|e| core::cmp::Reverse(e.map_or(0, |e| e.count)));
+
+    let mut report = format!("samples: {}, dropped: {}\n", samples, dropped);
+    for e in entries.iter().flatten().take(PROFILE_TOP) {
+        let permille = e.count * 1000 / samples;
+        let _ = writeln!(report, "{:>6} {:>3}.{}% pid {} 0x{:08x}", e.count, permille / 10, permille % 10, e.pid, e.addr);
+    }
+    report
+}
@@ -0,0 +1,81 @@
+//! RAM disk for os1k
+//!
+//! A disk held in memory, for running the file systems without a virtio block device. Built with
+//! the `ramdisk` feature, the kernel links in a disk image (ramdisk.img.o, made by
+//! `./os1k.sh ramdisk`) and the RAM disk starts as a copy of it. Otherwise it starts zeroed,
+//! which is an empty tar archive.
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::{log_info, log_warn};
+use crate::spinlock::SpinLock;
+
+const RAMDISK_EMPTY_SECTORS: usize = 128;   // Size without an image
+const RAMDISK_SPARE_SECTORS: usize = 64;    // Room after the image for new files and the journal
+
+#[cfg(feature = "ramdisk")]
+// Safety: Symbols created by objcopy from the disk image
+unsafe extern "C" {
+    static _binary_ramdisk_img_start: u8;
+    static _binary_ramdisk_img_size: u8;
+}
+
+// Returns the disk image linked into the kernel, if there is one.
+fn image() -> &'static [u8] {
+    #[cfg(feature = "ramdisk")]
+    {
+        let start = &raw const _binary_ramdisk_img_start;
+        let size = &raw const _binary_ramdisk_img_size as usize;  // The symbol _address_ is the size of the image
+        // Safety: objcopy places `size` bytes of the image at `start`, in memory that is never written
+        unsafe { core::slice::from_raw_parts(start, size) }
+    }
+    #[cfg(not(feature = "ramdisk"))]
+    {
+        &[]
+    }
+}
+
+static SECTORS: SpinLock<Option<Box<[[u8; SECTOR_SIZE]]>>> = SpinLock::new(None);
+
+// Creates the RAM disk, filled from the linked image if there is one.
+pub fn ramdisk_init() {
+    let image = image();
+    let sectors = match image.len() {
+        0 => RAMDISK_EMPTY_SECTORS,
+        len => len.div_ceil(SECTOR_SIZE) + RAMDISK_SPARE_SECTORS,
+    };
+    let mut disk = vec![[0; SECTOR_SIZE]; sectors].into_boxed_slice();
+    for (sector, chunk) in disk.iter_mut().zip(image.chunks(SECTOR_SIZE)) {
+        sector[..chunk.len()].copy_from_slice(chunk);
+    }
+    log_info!("{} sectors, {} bytes from the image", sectors, image.len());
+    *SECTORS.lock() = Some(disk);
+}
+
+// The RAM disk. Its sectors live in SECTORS, so this is only a handle.
+#[derive(Debug)]
+pub struct RamDisk;
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        SECTORS.lock().as_ref().map_or(0, |disk| disk.len() as u64)
+    }
+
+    fn read_sector(&mut self, buf: &mut [u8], sector: u64) {
+        let disk = SECTORS.lock();
+        match disk.as_ref().and_then(|disk| disk.get(sector as usize)) {
+            Some(data) => buf.copy_from_slice(data),
+            None => log_warn!("tried to read sector {} past the end", sector),
+        }
+    }
+
+    fn write_sector(&mut self, buf: &[u8], sector: u64) {
+        let mut disk = SECTORS.lock();
+        match disk.as_mut().and_then(|disk| disk.get_mut(sector as usize)) {
+            Some(data) => data.copy_from_slice(buf),
+            None => log_warn!("tried to write sector {} past the end", sector),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//! Random numbers for os1k
+//!
+//! Not cryptographically secure: a xorshift generator seeded from the time CSR on first use,
+//! behind /dev/random and Syscall::GetRandom.
+
+use crate::spinlock::SpinLock;
+
+static RANDOM_STATE: SpinLock<u32> = SpinLock::new(0);
+
+fn next_random(state: &mut u32) -> u32 {
+    if *state == 0 {
+        *state = (read_csr!("time") as u32) | 1;  // xorshift needs a nonzero seed
+    }
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+// Fills `buf` with random bytes.
+pub fn fill_random(buf: &mut [u8]) {
+    let mut state = RANDOM_STATE.lock();
+    for chunk in buf.chunks_mut(size_of::<u32>()) {
+        chunk.copy_from_slice(&next_random(&mut state).to_ne_bytes()[..chunk.len()]);
+    }
+}
@@ -1,53 +1,212 @@
 //! SBI Interface
+//!
+//! SBI v0.2 and later calls put the extension ID in a7, the function ID in a6 and return an
+//! error code in a0 and a value in a1. `sbi_init` asks the Base extension which extensions the
+//! firmware has; where one is missing, or the firmware only speaks v0.1, the legacy call with the
+//! same effect is used instead.
 
 use core::arch::asm;
-use core::ffi::{c_long, c_int};
+use core::ffi::c_long;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub const EID_CONSOLE_PUTCHAR: c_long = 1;
-pub const EID_CONSOLE_GETCHAR: c_long = 2;
+use crate::log_info;
 
+// Legacy (v0.1) extensions, one function each
+const EID_LEGACY_SET_TIMER: c_long = 0;
+const EID_LEGACY_CONSOLE_PUTCHAR: c_long = 1;
+const EID_LEGACY_SEND_IPI: c_long = 4;
+const EID_LEGACY_SHUTDOWN: c_long = 8;
 
-// Safety: Caller must ensure that SBI call does not change machine state, memory mappings etc.
-pub unsafe fn sbi_call(mut arg0: c_int, eid: c_long) -> Result<isize, isize> {
+const EID_BASE: c_long = 0x10;
+const FID_GET_SPEC_VERSION: c_long = 0;
+const FID_PROBE_EXTENSION: c_long = 3;
+const EID_TIME: c_long = 0x5449_4d45;   // "TIME"
+const FID_SET_TIMER: c_long = 0;
+const EID_DBCN: c_long = 0x4442_434e;   // "DBCN"
+const FID_CONSOLE_WRITE_BYTE: c_long = 2;
+const EID_SRST: c_long = 0x5352_5354;   // "SRST"
+const FID_SYSTEM_RESET: c_long = 0;
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_REASON_NONE: usize = 0;
+const RESET_REASON_SYSTEM_FAILURE: usize = 1;
+const EID_HSM: c_long = 0x48_534d;      // "HSM"
+const FID_HART_START: c_long = 0;
+const FID_HART_GET_STATUS: c_long = 2;
+pub const HART_STOPPED: isize = 1;
+const EID_IPI: c_long = 0x73_5049;      // "sPI"
+const FID_SEND_IPI: c_long = 0;
+
+const SBI_SUCCESS: isize = 0;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+
+// Which extensions the firmware has, set once by `sbi_init`. Atomics rather than a lock, so the
+// panic handler can write to the debug console from anywhere.
+static HAS_TIME: AtomicBool = AtomicBool::new(false);
+static HAS_DBCN: AtomicBool = AtomicBool::new(false);
+static HAS_SRST: AtomicBool = AtomicBool::new(false);
+static HAS_HSM: AtomicBool = AtomicBool::new(false);
+static HAS_IPI: AtomicBool = AtomicBool::new(false);
+
+// The pair every v0.2+ call returns
+#[derive(Clone, Copy, Debug)]
+struct SbiRet {
+    error: isize,
+    value: isize,
+}
+
+impl SbiRet {
+    fn into_result(self) -> Result<isize, isize> {
+        if self.error == SBI_SUCCESS { Ok(self.value) } else { Err(self.error) }
+    }
+}
+
+// Safety: Caller must ensure that the call does not change machine state, memory mappings etc.
+// in a way the kernel does not expect.
+unsafe fn sbi_call(eid: c_long, fid: c_long, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let error: isize;
+    let value: isize;
     unsafe {
         asm!(
             "ecall",
-            inlateout("a0") arg0,
+            inlateout("a0") arg0 as isize => error,
+            inlateout("a1") arg1 as isize => value,
+            in("a2") arg2,
+            in("a6") fid,
             in("a7") eid,
         );
     }
+    SbiRet { error, value }
+}
 
-    match eid {
-        EID_CONSOLE_PUTCHAR => {
-            if arg0 == 0 {
-                Ok(0)
-            } else {
-                Err(arg0 as isize)
-            }
-        },
-        EID_CONSOLE_GETCHAR => {
-            if arg0 != -1 {
-                Ok(arg0 as isize)
-            } else {
-                Err(-1)
-            }
+// Legacy calls take their arguments in a0 and a1 and return a single value in a0.
+// Safety: as for `sbi_call`.
+unsafe fn sbi_legacy_call(eid: c_long, arg0: usize, arg1: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 as isize => ret,
+            in("a1") arg1,
+            in("a7") eid,
+        );
+    }
+    ret
+}
+
+fn sbi_base_call(fid: c_long, arg0: usize) -> Result<isize, isize> {
+    // Safety: Base extension calls only report on the firmware
+    unsafe { sbi_call(EID_BASE, fid, arg0, 0, 0) }.into_result()
+}
+
+// Returns true if the firmware implements extension `eid`.
+fn sbi_probe_extension(eid: c_long) -> bool {
+    matches!(sbi_base_call(FID_PROBE_EXTENSION, eid as usize), Ok(value) if value != 0)
+}
+
+// Finds out which extensions the firmware has. Before this only the legacy calls are used.
+pub fn sbi_init() {
+    // v0.1 firmware does not have the Base extension, so the call fails.
+    let version = match sbi_base_call(FID_GET_SPEC_VERSION, 0) {
+        Ok(version) => version as usize,
+        Err(_) => {
+            log_info!("v0.1, using legacy calls");
+            return;
         },
-        _ => {
-            panic!("Unknown SBI EID");
-        }
+    };
+    HAS_TIME.store(sbi_probe_extension(EID_TIME), Ordering::Relaxed);
+    HAS_DBCN.store(sbi_probe_extension(EID_DBCN), Ordering::Relaxed);
+    HAS_SRST.store(sbi_probe_extension(EID_SRST), Ordering::Relaxed);
+    HAS_HSM.store(sbi_probe_extension(EID_HSM), Ordering::Relaxed);
+    HAS_IPI.store(sbi_probe_extension(EID_IPI), Ordering::Relaxed);
+    log_info!(
+        "v{}.{}, TIME {}, DBCN {}, SRST {}, HSM {}, sPI {}",
+        version >> 24 & 0x7f,
+        version & 0xff_ffff,
+        if HAS_TIME.load(Ordering::Relaxed) { "yes" } else { "no" },
+        if HAS_DBCN.load(Ordering::Relaxed) { "yes" } else { "no" },
+        if HAS_SRST.load(Ordering::Relaxed) { "yes" } else { "no" },
+        if HAS_HSM.load(Ordering::Relaxed) { "yes" } else { "no" },
+        if HAS_IPI.load(Ordering::Relaxed) { "yes" } else { "no" },
+    );
+}
+
+// Asks for a supervisor timer interrupt once the `time` CSR reaches `deadline`, and clears any
+// pending one. Returns the SBI error code on failure.
+pub fn sbi_set_timer(deadline: u64) -> Result<(), isize> {
+    let low = deadline as u32 as usize;
+    let high = (deadline >> 32) as u32 as usize;
+    if HAS_TIME.load(Ordering::Relaxed) {
+        // Safety: set_timer only changes the timer deadline and the pending timer interrupt
+        return unsafe { sbi_call(EID_TIME, FID_SET_TIMER, low, high, 0) }.into_result().map(|_| ());
     }
+    // Safety: as above. The legacy call has no error to return.
+    unsafe { sbi_legacy_call(EID_LEGACY_SET_TIMER, low, high) };
+    Ok(())
 }
 
-#[unsafe(no_mangle)]
-pub fn put_byte(b: u8) -> Result<isize, isize> {
-    // Safety: EID_CONSOLE_PUTCHAR is a safe SBI call that only writes to console
-    unsafe {
-        sbi_call(b as c_int, EID_CONSOLE_PUTCHAR)
+// Writes `b` to the firmware's debug console. This takes no locks and needs no driver, so it
+// works however broken the kernel is.
+pub fn sbi_debug_put_byte(b: u8) -> Result<isize, isize> {
+    if HAS_DBCN.load(Ordering::Relaxed) {
+        // Safety: console_write_byte only writes to the debug console
+        return unsafe { sbi_call(EID_DBCN, FID_CONSOLE_WRITE_BYTE, b as usize, 0, 0) }.into_result();
+    }
+    // Safety: console_putchar only writes to the debug console
+    match unsafe { sbi_legacy_call(EID_LEGACY_CONSOLE_PUTCHAR, b as usize, 0) } {
+        0 => Ok(0),
+        error => Err(error),
     }
 }
 
-pub fn get_char() -> Result<isize, isize> {
-    unsafe {
-        sbi_call(0, EID_CONSOLE_GETCHAR)
+// Powers the machine off, or reboots it if `reboot`. `failed` tells the firmware the system is
+// stopping because of a failure. Returns the SBI error code if the firmware cannot: legacy
+// firmware can only power off.
+pub fn sbi_system_reset(reboot: bool, failed: bool) -> isize {
+    if HAS_SRST.load(Ordering::Relaxed) {
+        let reset_type = if reboot { RESET_TYPE_COLD_REBOOT } else { RESET_TYPE_SHUTDOWN };
+        let reason = if failed { RESET_REASON_SYSTEM_FAILURE } else { RESET_REASON_NONE };
+        // Safety: system_reset only returns if it fails
+        return unsafe { sbi_call(EID_SRST, FID_SYSTEM_RESET, reset_type, reason, 0) }.error;
+    }
+    if reboot {
+        return SBI_ERR_NOT_SUPPORTED;
+    }
+    // Safety: shutdown does not return
+    unsafe { sbi_legacy_call(EID_LEGACY_SHUTDOWN, 0, 0) }
+}
+
+// Returns the HSM state of `hartid`, such as HART_STOPPED, or the SBI error code if there is no
+// such hart. Legacy firmware starts every hart at boot and cannot report on them.
+pub fn sbi_hart_get_status(hartid: usize) -> Result<isize, isize> {
+    if !HAS_HSM.load(Ordering::Relaxed) {
+        return Err(SBI_ERR_NOT_SUPPORTED);
+    }
+    // Safety: hart_get_status only reports on the hart
+    unsafe { sbi_call(EID_HSM, FID_HART_GET_STATUS, hartid, 0, 0) }.into_result()
+}
+
+// Starts the stopped hart `hartid` in supervisor mode at physical address `start`, with
+// translation and interrupts off, its hart ID in a0 and `opaque` in a1.
+// Safety: `start` must be code that sets up the hart from just those registers.
+pub unsafe fn sbi_hart_start(hartid: usize, start: usize, opaque: usize) -> Result<(), isize> {
+    if !HAS_HSM.load(Ordering::Relaxed) {
+        return Err(SBI_ERR_NOT_SUPPORTED);
+    }
+    unsafe { sbi_call(EID_HSM, FID_HART_START, hartid, start, opaque) }.into_result().map(|_| ())
+}
+
+// Raises a supervisor software interrupt on every hart in `hart_mask`, where bit 0 is hart 0.
+pub fn sbi_send_ipi(hart_mask: usize) -> Result<(), isize> {
+    if HAS_IPI.load(Ordering::Relaxed) {
+        // Safety: send_ipi only sets the harts' pending software interrupt
+        return unsafe { sbi_call(EID_IPI, FID_SEND_IPI, hart_mask, 0, 0) }.into_result().map(|_| ());
+    }
+    // The legacy call takes the address of the mask. The kernel is mapped at its physical
+    // address, so this is also where the firmware finds it.
+    // Safety: as above, and the mask outlives the call.
+    match unsafe { sbi_legacy_call(EID_LEGACY_SEND_IPI, &raw const hart_mask as usize, 0) } {
+        0 => Ok(()),
+        error => Err(error),
     }
 }
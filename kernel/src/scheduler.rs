@@ -1,43 +1,165 @@
 //! Round-robin scheduler
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::allocator::PAGE_SIZE;
 use crate::page::{SATP_SV32, PageTable};
+use crate::once::Once;
 use crate::process::{create_process, PROCS, PROCS_MAX, State, switch_context};
-use crate::spinlock::SpinLock;
+use crate::spinlock::{locks_held, SpinLock};
+use crate::timer::{monotonic_ns, timer_oneshot, timer_poll, TICK_HZ};
+use crate::tty::is_stopped;
+use crate::uart::uart_poll;
+use crate::vfs::OsError;
+use crate::virtio::virtio_blk_poll;
+use crate::watchdog::watchdog_pet;
 
-static IDLE_PROC: SpinLock<Option<usize>> = SpinLock::new(None);    // Idle process
+static IDLE_PROC: Once<usize> = Once::new();    // Idle process
 pub static CURRENT_PROC: SpinLock<Option<usize>> = SpinLock::new(None); // Currently running process
 const IDLE_PID: usize = 0; // idle
 
+// CURRENT_PROC, readable without a lock, for code that must not take one.
+static RUNNING_PID: AtomicUsize = AtomicUsize::new(IDLE_PID);
+
+pub fn running_pid() -> usize {
+    RUNNING_PID.load(Ordering::Relaxed)
+}
+
+// Processes blocked until an event, such as a disk request completing.
+pub struct WaitQueue(SpinLock<[Option<usize>; PROCS_MAX]>);
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self(SpinLock::new([None; PROCS_MAX]))
+    }
+
+    // Blocks the current process until the queue is woken. Returns false without blocking
+    // when called outside a process or with a lock held, and the caller should poll instead.
+    pub fn wait(&self) -> bool {
+        let current = match *CURRENT_PROC.lock() {
+            Some(pid) if pid != IDLE_PID => pid,
+            _ => return false,
+        };
+        if locks_held() {
+            return false;
+        }
+
+        {
+            let mut waiters = self.0.lock();
+            let Some(slot) = waiters.iter_mut().find(|w| w.is_none()) else {
+                return false;
+            };
+            *slot = Some(current);
+        }
+        if let Some(p) = PROCS.0.write().iter_mut().find(|p| p.pid == current) {
+            p.state = State::Blocked;
+        }
+        yield_now();
+        true
+    }
+
+    // Makes every waiting process runnable again.
+    pub fn wake_all(&self) {
+        let mut waiters = self.0.lock();
+        let mut procs = PROCS.0.write();
+        for pid in waiters.iter_mut().filter_map(Option::take) {
+            if let Some(p) = procs.iter_mut().find(|p| p.pid == pid && p.state == State::Blocked) {
+                p.state = State::Runnable;
+            }
+        }
+    }
+
+    // Makes one waiting process runnable again. Returns false if there was none.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.0.lock();
+        let Some(pid) = waiters.iter_mut().find_map(Option::take) else {
+            return false;
+        };
+        if let Some(p) = PROCS.0.write().iter_mut().find(|p| p.pid == pid && p.state == State::Blocked) {
+            p.state = State::Runnable;
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().iter().all(Option::is_none)
+    }
+}
+
+// Makes a sleeping process runnable again, from its timer.
+fn wake_sleeper(pid: usize) {
+    if let Some(p) = PROCS.0.write().iter_mut().find(|p| p.pid == pid && p.state == State::Blocked) {
+        p.state = State::Runnable;
+    }
+}
+
+// Blocks the current process for at least `ms` milliseconds. Fails with NoSpace if every timer
+// is in use.
+pub fn sleep_ms(ms: u64) -> Result<(), OsError> {
+    let deadline = monotonic_ns() + ms * 1_000_000;
+    loop {
+        let now = monotonic_ns();
+        if now >= deadline {
+            return Ok(());
+        }
+        // A tick more than the remaining time, as the current tick is already partly over.
+        let ticks = (deadline - now).div_ceil(1_000_000_000 / TICK_HZ) as usize + 1;
+        let pid = running_pid();
+        timer_oneshot(ticks, wake_sleeper, pid).ok_or(OsError::NoSpace)?;
+        if let Some(p) = PROCS.0.write().iter_mut().find(|p| p.pid == pid) {
+            p.state = State::Blocked;
+        }
+        yield_now();
+    }
+}
+
 pub fn yield_now() {
+    watchdog_pet();
+
     // Initialse IDLE_PROC if not yet initialised
-    let idle_pid = { *IDLE_PROC.lock().get_or_insert_with(|| {
-            let idle_pid = create_process(core::ptr::null(), 0);
-            if let Some(p) = PROCS.0.lock().iter_mut()
-                .find(|p| p.pid == idle_pid) {
-                    p.pid = IDLE_PID;
-                }
-            *CURRENT_PROC.lock() = Some(IDLE_PID);
-            IDLE_PID
-        })
-    };
+    let idle_pid = *IDLE_PROC.get_or_init(|| {
+        let idle_pid = create_process(core::ptr::null(), 0, &[])
+            .expect("the idle process should have a slot");
+        if let Some(p) = PROCS.0.write().iter_mut()
+            .find(|p| p.pid == idle_pid) {
+                p.pid = IDLE_PID;
+                p.leader = IDLE_PID;
+            }
+        *CURRENT_PROC.lock() = Some(IDLE_PID);
+        RUNNING_PID.store(IDLE_PID, Ordering::Relaxed);
+        IDLE_PID
+    });
 
     let current_pid = CURRENT_PROC.lock()
         .expect("CURRENT_PROC initialised before use");
 
     // Search for a runnable process
-    let next_pid = {
+    let next_pid = loop {
         let current_index = PROCS.try_get_index(current_pid)
             .expect("current process PID should have an index");
-        PROCS.0.lock().iter()
-            .cycle()
-            .skip(current_index + 1)
-            .take(PROCS_MAX)
-            .find(|p| p.state == State::Runnable && p.pid != idle_pid)
-            .map(|p| p.pid)
-            .unwrap_or(idle_pid)
+        let (next_pid, blocked) = {
+            let procs = PROCS.0.read();
+            let next_pid = procs.iter()
+                .enumerate()
+                .cycle()
+                .skip(current_index + 1)
+                .take(PROCS_MAX)
+                .find(|(i, p)| p.state == State::Runnable && p.pid != idle_pid && !is_stopped(*i))
+                .map(|(_, p)| p.pid)
+                .unwrap_or(idle_pid);
+            (next_pid, procs.iter().any(|p| p.state == State::Blocked))
+        };
+        // Interrupts are off in the kernel, so with every process blocked, poll the disk, the
+        // UART and the timer until one of them can run.
+        if next_pid == idle_pid && blocked {
+            virtio_blk_poll();
+            uart_poll();
+            timer_poll();
+            watchdog_pet();
+            continue;
+        }
+        break next_pid;
     };
 
     // If there's no runnable process other than the current one, return and continue processing
@@ -45,39 +167,41 @@ pub fn yield_now() {
         return;
     }
 
-    let (next_sp_ptr, current_sp_ptr, satp, sscratch) = {
+    let (next_sp_ptr, current_sp_ptr, satp) = {
         let next_index = PROCS.try_get_index(next_pid)
             .expect("should find next by pid");
         let current_index = PROCS.try_get_index(current_pid)
             .expect("should find current by pid");
-        let mut procs = PROCS.0.lock();
+        let mut procs = PROCS.0.write();
+
+        // A thread runs in the page table of the process it belongs to.
+        let leader = procs[next_index].leader;
+        let page_table = procs.iter()
+            .find(|p| p.pid == leader)
+            .and_then(|p| p.page_table.as_deref())
+            .expect("page_table should exist");
+        let page_table_addr = page_table as *const PageTable as usize;
+        let satp = SATP_SV32 | (page_table_addr / PAGE_SIZE);
+
         let [next, current] = procs.get_disjoint_mut([next_index, current_index])
             .expect("indices should be valid and distinct");
-
         let next_sp_ptr = next.sp.field_raw_ptr();
         let current_sp_ptr = current.sp.field_raw_ptr();
-
-        let page_table = next.page_table.as_ref().expect("page_table should exist");
-        // Double deref on page_table for both ref and Box.
-        let page_table_addr = &**page_table as *const PageTable as usize;
-        let satp = SATP_SV32 | (page_table_addr / PAGE_SIZE);
-        //Safety: sscratch points to the end of next.stack, which is a valid stack allocation.
-
-        let sscratch = unsafe { next.stack.as_ptr().add(next.stack.len()) };
-        (next_sp_ptr, current_sp_ptr, satp, sscratch)
+        (next_sp_ptr, current_sp_ptr, satp)
     };
 
+    // sscratch stays 0 while in the kernel. The next process gets its kernel stack back in
+    // sscratch on its way out to user mode.
     unsafe{asm!(
         "sfence.vma",
         "csrw satp, {satp}",
         "sfence.vma",
-        "csrw sscratch, {sscratch}",
         satp = in(reg) satp,
-        sscratch = in(reg) sscratch,
     )};
 
     // Context switch
     *CURRENT_PROC.lock() = Some(next_pid);
+    RUNNING_PID.store(next_pid, Ordering::Relaxed);
     unsafe {
         switch_context(current_sp_ptr, next_sp_ptr);
     }
@@ -0,0 +1,60 @@
+//! Counting semaphore for os1k
+//!
+//! A Semaphore counts something a process can wait for, such as finished disk requests or bytes
+//! typed. up() adds one and wakes a waiter, and may be called from an interrupt handler. down()
+//! takes one, blocking until there is one.
+
+use core::hint::spin_loop;
+
+use crate::scheduler::WaitQueue;
+use crate::spinlock::SpinLock;
+
+pub struct Semaphore {
+    count: SpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: SpinLock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn up(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    // Takes one if there is one, without blocking.
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    // Takes one, blocking until there is one. `poll` is called before each try: interrupts are
+    // only taken in user mode, so where the process cannot block (at boot, or with a SpinLock
+    // held) polling the device is the only way up() gets called.
+    pub fn down(&self, poll: impl Fn()) {
+        loop {
+            poll();
+            if self.try_down() {
+                return;
+            }
+            if !self.waiters.wait() {
+                spin_loop();
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Semaphore").field("count", &*self.count.lock()).finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! Secondary harts for os1k
+//!
+//! The firmware starts the kernel on one hart. `smp_init` asks SBI to start every other stopped
+//! hart at `secondary_boot`, on a stack of its own. The scheduler only runs on the boot hart, so
+//! once a secondary hart has set up its trap registers it waits, with interrupts off.
+//!
+//! A secondary hart touches no lock, since it is not running a process: the boot hart allocates
+//! its stack, and the hart reports that it is up through an atomic. The exception is the
+//! `lock-stress` test, which runs on every hart before any process does.
+
+use alloc::vec;
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::entry::kernel_entry;
+use crate::ipi::ipi_handle;
+use crate::{log_info, log_warn};
+use crate::sbi::{sbi_hart_get_status, sbi_hart_start, HART_STOPPED};
+#[cfg(feature = "lock-stress")]
+use crate::stress::{lock_stress, lock_stress_hart};
+
+pub const HARTS_MAX: usize = 8;         // The QEMU virt machine has at most 8 harts
+const HART_STACK_SIZE: usize = 16 * 1024;
+pub const SIE_SSIE: usize = 1 << 1;     // Supervisor software interrupts, for IPIs
+
+// Bit n is set once hart n is running the kernel.
+static HARTS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// The hart the kernel booted on, which is the one that runs processes.
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
+
+// Where a secondary hart starts, with its hart ID in a0 and the top of its stack in a1.
+#[unsafe(naked)]
+unsafe extern "C" fn secondary_boot() -> ! {
+    naked_asm!(
+        "mv sp, a1",
+        "li s0, 0",
+        "j {secondary_main}",
+        secondary_main = sym secondary_main,
+    );
+}
+
+extern "C" fn secondary_main(hartid: usize) -> ! {
+    hart_init();
+    write_csr!("sie", SIE_SSIE);
+    HARTS_ONLINE.fetch_or(1 << hartid, Ordering::Release);
+    #[cfg(feature = "lock-stress")]
+    lock_stress_hart(hartid);
+    loop {
+        // Safety: wfi only waits. Interrupts are off in sstatus, so it returns on an IPI without
+        // taking it, and the IPI is handled here.
+        unsafe { asm!("wfi") };
+        // There is nothing to schedule here yet, so the only IPIs are TLB flushes.
+        ipi_handle(hartid);
+    }
+}
+
+// Sets up the trap registers of the calling hart. Traps go to `kernel_entry`, and sscratch is 0
+// because the hart is running the kernel.
+pub fn hart_init() {
+    write_csr!("stvec", kernel_entry as *const () as usize);
+    write_csr!("sscratch", 0usize);
+}
+
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Relaxed)
+}
+
+// Bit n is set if hart n is running the kernel.
+pub fn harts_online() -> usize {
+    HARTS_ONLINE.load(Ordering::Acquire)
+}
+
+// Starts every hart other than `boot_hartid` that SBI reports as stopped, and waits for them.
+pub fn smp_init(boot_hartid: usize) {
+    BOOT_HART.store(boot_hartid, Ordering::Relaxed);
+    HARTS_ONLINE.fetch_or(1 << boot_hartid, Ordering::Relaxed);
+    let mut started = 1 << boot_hartid;
+    for hartid in (0..HARTS_MAX).filter(|&h| h != boot_hartid) {
+        if sbi_hart_get_status(hartid) != Ok(HART_STOPPED) {
+            continue;
+        }
+        let stack = vec![0u8; HART_STACK_SIZE].leak();
+        let stack_top = stack.as_ptr_range().end as usize;
+        // Safety: secondary_boot sets up the stack from a1 before running any Rust code
+        match unsafe { sbi_hart_start(hartid, secondary_boot as *const () as usize, stack_top) } {
+            Ok(()) => started |= 1 << hartid,
+            Err(e) => log_warn!("could not start hart {}: {}", hartid, e),
+        }
+    }
+
+    while HARTS_ONLINE.load(Ordering::Acquire) != started {
+        core::hint::spin_loop();
+    }
+    log_info!("{} hart(s) online, booted on hart {}", started.count_ones(), boot_hartid);
+    #[cfg(feature = "lock-stress")]
+    lock_stress(boot_hartid, started);
+}
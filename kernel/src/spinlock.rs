@@ -1,13 +1,73 @@
 //! Spinlock for os1k
+//!
+//! SpinLock is a ticket lock: each locker takes the next ticket with an atomic add (amoadd.w) and
+//! waits until it is served, so harts get the lock in the order they asked for it. Taking the lock
+//! is an acquire and serving the next ticket a release, so what one holder wrote is seen by the
+//! next on any hart. A waiter backs off exponentially, up to how far it is from the front of the
+//! queue, with `pause` hints, so waiters do not flood the lock's cache line. A lock that is still
+//! held after LOCK_SPIN_LIMIT tries is taken to be deadlocked, and the kernel panics with where
+//! the lock was wanted. Built with the `lock-debug` feature, a SpinLock also records the pid and
+//! location of its holder, so the panic names both sides.
 
+use core::arch::asm;
 use core::cell::UnsafeCell;
+use core::panic::Location;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering::{Acquire, Release}};
+use core::sync::atomic::{AtomicUsize, Ordering::{Acquire, Relaxed, Release}};
+
+#[cfg(feature = "lock-debug")]
+use crate::scheduler::running_pid;
+
+// Number of locks currently held. Context switches only happen with no locks held,
+// so this is always the count for the running process.
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+const LOCK_SPIN_LIMIT: usize = 10_000_000;
+const BACKOFF_MAX: usize = 256;     // Most pauses between looks at the lock, per ticket ahead
+
+// Tells the hart it is spinning. pause is a hint, a no-op on harts without Zihintpause.
+#[inline(always)]
+fn cpu_relax() {
+    // Safety: pause changes no state
+    unsafe { asm!(".insn i 0x0f, 0, x0, x0, 0x010", options(nomem, nostack)) };
+}
+
+// Counts for every SpinLock since boot, for /proc/locks
+static ACQUIRED: AtomicUsize = AtomicUsize::new(0);
+static CONTENDED: AtomicUsize = AtomicUsize::new(0);     // Acquisitions that had to wait
+static SPINS: AtomicUsize = AtomicUsize::new(0);         // Pauses spent waiting
+
+#[derive(Clone, Copy, Debug)]
+pub struct LockStats {
+    pub acquired: usize,
+    pub contended: usize,
+    pub spins: usize,
+}
+
+pub fn lock_stats() -> LockStats {
+    LockStats {
+        acquired: ACQUIRED.load(Relaxed),
+        contended: CONTENDED.load(Relaxed),
+        spins: SPINS.load(Relaxed),
+    }
+}
+
+// Returns whether the caller holds any lock, and so must not block.
+pub fn locks_held() -> bool {
+    HELD.load(Relaxed) != 0
+}
+
+// Who holds a lock: the pid, and where in the kernel it was locked.
+#[cfg(feature = "lock-debug")]
+type Owner = (usize, &'static Location<'static>);
 
 #[derive(Debug)]
 pub struct SpinLock<T> {
-    locked: AtomicBool,
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
     value: UnsafeCell<T>,
+    #[cfg(feature = "lock-debug")]
+    owner: UnsafeCell<Option<Owner>>,
 }
 
 unsafe impl<T> Sync for SpinLock<T> where T: Send {}
@@ -15,20 +75,61 @@ unsafe impl<T> Sync for SpinLock<T> where T: Send {}
 impl<T> SpinLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
-            locked: AtomicBool::new(false),
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
             value: UnsafeCell::new(value),
+            #[cfg(feature = "lock-debug")]
+            owner: UnsafeCell::new(None),
         }
     }
 
-    #[allow(clippy::never_loop)]
+    #[track_caller]
     pub fn lock(&self) -> Guard<'_, T> {
-        while self.locked.swap(true, Acquire) {
-            core::hint::spin_loop();
-            // crate::print!(".");
-            panic!("locked");
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        let mut spins = 0;
+        let mut backoff = 1;
+        loop {
+            let ahead = ticket.wrapping_sub(self.now_serving.load(Acquire));
+            if ahead == 0 {
+                break;
+            }
+            for _ in 0..backoff {
+                cpu_relax();
+            }
+            spins += backoff;
+            if spins >= LOCK_SPIN_LIMIT {
+                self.deadlock(Location::caller());
+            }
+            backoff = (backoff * 2).min(ahead * BACKOFF_MAX);
+        }
+        ACQUIRED.fetch_add(1, Relaxed);
+        if spins != 0 {
+            CONTENDED.fetch_add(1, Relaxed);
+            SPINS.fetch_add(spins, Relaxed);
         }
+        #[cfg(feature = "lock-debug")]
+        // Safety: the lock is held, so no one else writes the owner.
+        unsafe { *self.owner.get() = Some((running_pid(), Location::caller())) };
+        HELD.fetch_add(1, Relaxed);
         Guard { lock: self }
     }
+
+    #[cfg(not(feature = "lock-debug"))]
+    fn deadlock(&self, waiter: &Location) -> ! {
+        panic!("deadlock: lock wanted at {} is still held", waiter);
+    }
+
+    #[cfg(feature = "lock-debug")]
+    fn deadlock(&self, waiter: &Location) -> ! {
+        // Safety: a racy read, but the holder has not released the lock in all this time.
+        match unsafe { *self.owner.get() } {
+            Some((pid, holder)) => panic!(
+                "deadlock: process {} wants the lock at {}, held by process {} since {}",
+                running_pid(), waiter, pid, holder,
+            ),
+            None => panic!("deadlock: lock wanted at {} is still held", waiter),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -36,6 +137,15 @@ pub struct Guard<'a, T> {
     lock: &'a SpinLock<T>,
 }
 
+impl<'a, T> Guard<'a, T> {
+    // Releases the lock, returning it so it can be taken again.
+    pub fn unlock(guard: Self) -> &'a SpinLock<T> {
+        let lock = guard.lock;
+        drop(guard);
+        lock
+    }
+}
+
 impl<T> Deref for Guard<'_, T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -53,7 +163,121 @@ impl<T> DerefMut for Guard<'_, T> {
 
 impl<T> Drop for Guard<'_, T> {
     fn drop(&mut self) {
-        self.lock.locked.store(false, Release);
+        #[cfg(feature = "lock-debug")]
+        // Safety: the lock is still held.
+        unsafe { *self.lock.owner.get() = None };
+        HELD.fetch_sub(1, Relaxed);
+        // Serve the next ticket. Only the holder changes now_serving, so this needs no AMO.
+        let serving = self.lock.now_serving.load(Relaxed);
+        self.lock.now_serving.store(serving.wrapping_add(1), Release);
     }
 }
 
+// Set in RwSpinLock::state while a writer holds the lock. The other bits count the readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// A lock held by any number of readers or by one writer, for tables that are mostly looked up.
+// Like SpinLock, it spins on a conflicting holder, and panics with where the lock was wanted
+// once it is still held after LOCK_SPIN_LIMIT pauses.
+#[derive(Debug)]
+pub struct RwSpinLock<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for RwSpinLock<T> where T: Send + Sync {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let mut spins = 0;
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state & WRITER != 0 {
+                spins = spin(spins, Location::caller());
+                state = self.state.load(Relaxed);
+                continue;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Acquire, Relaxed) {
+                Ok(_) => break,
+                Err(current) => state = current,
+            }
+        }
+        HELD.fetch_add(1, Relaxed);
+        ReadGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let mut spins = 0;
+        while self.state.compare_exchange_weak(0, WRITER, Acquire, Relaxed).is_err() {
+            spins = spin(spins, Location::caller());
+        }
+        HELD.fetch_add(1, Relaxed);
+        WriteGuard { lock: self }
+    }
+}
+
+// Pauses before another look at a RwSpinLock, returning the pauses so far. Panics once they
+// reach LOCK_SPIN_LIMIT, as the lock is then taken to be deadlocked.
+fn spin(spins: usize, waiter: &Location) -> usize {
+    cpu_relax();
+    if spins + 1 >= LOCK_SPIN_LIMIT {
+        panic!("deadlock: lock wanted at {} is still held", waiter);
+    }
+    spins + 1
+}
+
+#[derive(Debug)]
+pub struct ReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        //Safety: The existance of this guard guarantees no writer
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        HELD.fetch_sub(1, Relaxed);
+        self.lock.state.fetch_sub(1, Release);
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        //Safety: The existance of this guard guarantees exclusive lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        //Safety: The existance of this guard guarantees exclusive lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        HELD.fetch_sub(1, Relaxed);
+        self.lock.state.store(0, Release);
+    }
+}
@@ -0,0 +1,91 @@
+//! SpinLock stress test for os1k
+//!
+//! Built with the `lock-stress` feature, every hart takes one SpinLock STRESS_ROUNDS times at
+//! boot, updating shared counts while it holds it. A lost update shows in the total. A fair lock
+//! serves the harts in turn, so no hart gets the lock many times in a row, and each waits about
+//! as long as the others. Run it with `./os1k.sh stress`.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::log_info;
+use crate::power;
+use crate::smp::HARTS_MAX;
+use crate::spinlock::SpinLock;
+use crate::timer::read_time;
+
+const STRESS_ROUNDS: usize = 10_000;
+
+struct Stress {
+    total: usize,
+    last_hart: usize,
+    streak: usize,                  // Acquisitions in a row by last_hart
+    longest_streak: usize,
+    total_wait: [u64; HARTS_MAX],   // Time each hart spent waiting for the lock, in time ticks
+    longest_wait: [u64; HARTS_MAX],
+}
+
+static STRESS: SpinLock<Stress> = SpinLock::new(Stress {
+    total: 0,
+    last_hart: HARTS_MAX,
+    streak: 0,
+    longest_streak: 0,
+    total_wait: [0; HARTS_MAX],
+    longest_wait: [0; HARTS_MAX],
+});
+
+// Set by the boot hart, so the harts start together.
+static START: AtomicBool = AtomicBool::new(false);
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+// Runs the test on the calling hart. Every hart online calls this.
+pub fn lock_stress_hart(hartid: usize) {
+    while !START.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    for _ in 0..STRESS_ROUNDS {
+        let asked = read_time();
+        let mut s = STRESS.lock();
+        let waited = read_time() - asked;
+
+        // Read, then write, so that two holders at once would lose an update.
+        let total = s.total;
+        s.total = total + 1;
+
+        if s.last_hart == hartid {
+            s.streak += 1;
+        } else {
+            s.last_hart = hartid;
+            s.streak = 1;
+        }
+        s.longest_streak = s.longest_streak.max(s.streak);
+        s.total_wait[hartid] += waited;
+        s.longest_wait[hartid] = s.longest_wait[hartid].max(waited);
+    }
+    FINISHED.fetch_add(1, Ordering::Release);
+}
+
+// Starts the test on the harts in `harts`, a mask of hart IDs, joins in, and reports once all
+// of them are done. Then QEMU exits with status 0, or the kernel panics if an update was lost.
+pub fn lock_stress(boot_hartid: usize, harts: usize) {
+    let count = harts.count_ones() as usize;
+    log_info!("{} hart(s), {} rounds each", count, STRESS_ROUNDS);
+    START.store(true, Ordering::Release);
+    lock_stress_hart(boot_hartid);
+    while FINISHED.load(Ordering::Acquire) != count {
+        spin_loop();
+    }
+
+    let s = STRESS.lock();
+    for hartid in (0..HARTS_MAX).filter(|h| harts & 1 << h != 0) {
+        log_info!(
+            "hart {}: mean wait {} ticks, longest {}",
+            hartid, s.total_wait[hartid] / STRESS_ROUNDS as u64, s.longest_wait[hartid],
+        );
+    }
+    log_info!("longest run by one hart: {} acquisitions", s.longest_streak);
+    assert_eq!(s.total, count * STRESS_ROUNDS, "lock-stress: lost updates");
+    log_info!("passed");
+    drop(s);
+    power::exit(0);
+}
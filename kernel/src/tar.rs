@@ -1,249 +1,797 @@
 //! Tar as a file system
 
-use core::ffi::CStr;
 use core::fmt::Debug;
+use core::iter::Enumerate;
+use core::ops::Range;
+use core::slice;
 
-use common::println;
+use alloc::string::String;
 
+use common::array::ArrayString;
+use common::tar::{crc32, int2oct, oct2int, HeaderError, TarHeader};
+
+use crate::{log_debug, log_trace, log_warn};
 use crate::address::align_up;
-use crate::spinlock::SpinLock;
-use crate::virtio::{read_write_disk, SECTOR_SIZE};
+use crate::block::{BlockDevice, Disk, SECTOR_SIZE};
+use crate::spinlock::{RwSpinLock, SpinLock};
+use crate::cache::{self, CacheDisk};
+use crate::journal::{self, Transaction, JOURNAL_DATA_SECTORS};
+use crate::mutex::Mutex;
+use crate::vfs::{DirEntry, FileSystem, OsError, Inode, Stat};
 
 pub const FILES_MAX: usize = 2;
-const DISK_MAX_SIZE: usize = align_up(size_of::<File>() * FILES_MAX, SECTOR_SIZE);
-
-#[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
-struct TarHeader {
-    name: [u8; 100],
-    mode: [u8; 8],
-    uid: [u8; 8],
-    gid: [u8; 8],
-    size: [u8; 12],
-    mtime: [u8; 12],
-    checksum: [u8; 8],
-    typeflag: u8,
-    linkname: [u8; 100],
-    magic: [u8; 6],
-    version: [u8; 2],
-    uname: [u8; 32],
-    gname: [u8; 32],
-    devmajor: [u8; 8],
-    devminor: [u8; 8],
-    prefix: [u8; 155],
-    _padding: [u8; 12],
-    // data follows as a byte array size `size`
-}
-
-impl TarHeader {
-    fn zeroed() -> Self {
-        // SAFETY: VirtioVirtq contains only structs/arrays of integers and pointers.
-        // All-zero bytes is a valid representation: integers become 0, pointer becomes null.
-        unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
-    }
+const FILE_DATA_MAX: usize = 1024;
+
+// Not part of ustar: a sparse file keeps its real size and a bitmap of the data sectors stored
+// in the archive in the (otherwise unused) link name, as octal strings. The `size` field only
+// counts the stored sectors, so tar tools can still walk the archive.
+const SPARSE_SIZE: Range<usize> = 0..12;
+const SPARSE_MAP: Range<usize> = 12..24;
+
+// Most links followed to resolve a name, so a loop of links cannot hang lookups.
+const LINK_DEPTH_MAX: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FileKind {
+    Regular,
+    HardLink,   // Another name for the regular file named by `File::link`
+    Symlink,    // Refers to a file by name, which may not exist
+}
 
-    /// SAFETY: It is UB to mutate the underlying memory while this byte array exists
-    unsafe fn as_bytes(&self) -> &[u8] {
-        // Safety:
-        // * self pointer is non_null as this can only be called on an existing struct,
-        // * self is valid for reads for entire struct
-        // * self is properly aligned to 1 byte for u8
-        // * the entire memory range is within size_of::<Self>()
-        // * header pointer points to size_of::<Self>() properly initialised bytes thanks to empty()
-        // * we do not mutate the underlying memory of the slice once header is created
-        // * total size of TarHeader is smaller than isize::MAX
-        unsafe {
-            core::slice::from_raw_parts(
-                self as *const Self as *const u8,
-                self.size())
+impl FileKind {
+    fn typeflag(self) -> u8 {
+        match self {
+            FileKind::Regular => b'0',
+            FileKind::HardLink => b'1',
+            FileKind::Symlink => b'2',
         }
     }
-
-    fn size(&self) -> usize {
-        size_of::<Self>()
-    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct File {
     in_use: bool,
-    pub name: [u8; 100],
-    pub data: [u8; 1024],
+    kind: FileKind,
+    link: ArrayString<100>, // Link target, for hard links and symlinks
+    pub name: ArrayString<100>,
+    pub data: [u8; FILE_DATA_MAX],
     pub size: usize,
+    on_disk: bool,          // The file has been written to disk at `sector`
+    sector: usize,          // Sector holding the tar header on disk
+    sector_map: u32,        // Bit `i` is set if data sector `i` is stored on disk. The rest are holes.
+    corrupt: bool,          // The data read from disk did not match the CRC32 in the header
+    dirty_header: bool,     // The header (i.e. the size or CRC32) changed since the last flush
+    dirty_sectors: u32,     // Bit `i` is set if data sector `i` changed since the last flush
 }
 
+// Every data sector needs a bit in `File::dirty_sectors` and `File::sector_map`.
+const _: () = assert!(FILE_DATA_MAX / SECTOR_SIZE <= u32::BITS as usize);
+
+// Rewriting every file has to fit in one journal transaction, leaving a sector for the end marker.
+const _: () = assert!(FILES_MAX * (1 + sectors_for(FILE_DATA_MAX)) < JOURNAL_DATA_SECTORS);
+
 impl File {
     const fn zeroed() -> Self {
         // SAFETY: VirtioVirtq contains only structs/arrays of integers and pointers.
         // All-zero bytes is a valid representation: integers become 0, pointer becomes null.
         unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
     }
+
+    fn name_str(&self) -> &str {
+        &self.name
+    }
+
+    // The name of the file a link refers to. All files are in the archive root,
+    // so leading "/" and "./" are ignored.
+    fn link_str(&self) -> &str {
+        self.link
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+    }
+
+    // Record that the bytes in `range` changed in memory, and whether that changed the file size.
+    fn mark_dirty(&mut self, range: Range<usize>, size_changed: bool) {
+        if !range.is_empty() {
+            let first = range.start / SECTOR_SIZE;
+            let last = (range.end - 1) / SECTOR_SIZE;
+            self.dirty_sectors |= (first..=last).fold(0, |mask, i| mask | 1 << i);
+            self.dirty_header = true;  // The CRC32 in the header covers the data
+        }
+        self.dirty_header |= size_changed;
+    }
+
+    // Bit `i` is set for each data sector holding a nonzero byte. Sectors of zeros are holes:
+    // they take no space on disk and read back as zeros.
+    fn data_map(&self) -> u32 {
+        self.data[..self.size].chunks(SECTOR_SIZE)
+        .enumerate()
+        .filter(|(_, chunk)| chunk.iter().any(|&byte| byte != 0))
+        .fold(0, |map, (i, _)| map | 1 << i)
+    }
+
+    // Number of data sectors stored on disk after the header.
+    fn stored_sectors(&self) -> usize {
+        self.sector_map.count_ones() as usize
+    }
+
+    // Sector on disk holding data sector `i`. Holes before it take no space.
+    fn data_sector(&self, i: usize) -> usize {
+        let before = self.sector_map & ((1 << i) - 1);
+        self.sector + 1 + before.count_ones() as usize
+    }
+
+    // Whether the file has changes that have not been written to disk yet.
+    fn is_dirty(&self) -> bool {
+        self.dirty_header || self.dirty_sectors != 0
+    }
+
+    // Whether the data can be written over the stored sectors without moving the file: none of
+    // it is in a hole, and no stored sector is past the end. Sectors of zeros stay stored.
+    fn fits_in_place(&self) -> bool {
+        self.data_map() & !self.sector_map == 0 && self.sector_map >> sectors_for(self.size) == 0
+    }
+
+    // Whether flushing the file rewrites the archive from it onwards, rather than only its own
+    // sectors. A file before `pinned_end` cannot move, so it never turns zeroed sectors into holes.
+    fn needs_rewrite(&self, pinned_end: usize) -> bool {
+        if !self.on_disk {
+            true
+        } else if self.sector < pinned_end {
+            !self.fits_in_place()
+        } else {
+            self.data_map() != self.sector_map
+        }
+    }
 }
 
+// Slots in the name index. Kept at most half full so probe sequences stay short.
+const INDEX_SLOTS: usize = (2 * FILES_MAX).next_power_of_two();
+
+// Hash table from file name to inode, using linear probing. Removing or renaming a file rebuilds
+// the whole index, so there are no tombstones: a probe stops at the first empty slot.
 #[derive(Debug)]
-pub struct Files(pub SpinLock<[File; FILES_MAX]>);
+struct NameIndex([Option<Inode>; INDEX_SLOTS]);
 
-impl Files {
-    pub fn fs_lookup(&self, name: &str) -> Option<usize> {
-        let files = self.0.lock();
+impl NameIndex {
+    const fn new() -> Self {
+        Self([None; INDEX_SLOTS])
+    }
 
-        println!("looking up filename {}", name);
+    // FNV-1a of the name.
+    fn hash(name: &str) -> usize {
+        name.bytes().fold(0x811c_9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193)) as usize
+    }
 
-        files.iter()
-        .position(|f| {  // `position` returns the index based on the closure result being true
-            CStr::from_bytes_until_nul(&f.name)
-            .ok() // Converts Result<> into Option<>
-            .and_then(|cstr| cstr.to_str().ok()) // Returns None if cstr is None, otherwise calls closure
-            .is_some_and(|s| s == name) // Evaluates closure if receiving Some
-        })
+    // Slots to try for `name`, in order.
+    fn probe(name: &str) -> impl Iterator<Item = usize> {
+        let start = Self::hash(name);
+        (0..INDEX_SLOTS).map(move |i| (start + i) % INDEX_SLOTS)
+    }
+
+    fn insert(&mut self, name: &str, inode: Inode) {
+        let slot = Self::probe(name)
+            .find(|&slot| self.0[slot].is_none())
+            .expect("name index should have more slots than files");
+        self.0[slot] = Some(inode);
     }
+
+    // Finds the entry (of any kind) called `name`.
+    fn get(&self, files: &[File; FILES_MAX], name: &str) -> Option<Inode> {
+        Self::probe(name)
+            .map_while(|slot| self.0[slot])
+            .find(|&inode| files[inode].in_use && files[inode].name_str() == name)
+    }
+
+    fn clear(&mut self) {
+        self.0.fill(None);
+    }
+
+    // Indexes the files in use from scratch, after one is removed or renamed.
+    fn rebuild(&mut self, files: &[File; FILES_MAX]) {
+        self.clear();
+        for (inode, file) in files.iter().enumerate().filter(|(_, f)| f.in_use) {
+            self.insert(file.name_str(), inode);
+        }
+    }
+}
+
+// The file table, and an index of it by name. The index is only locked with the table locked.
+#[derive(Debug)]
+pub struct Files(pub RwSpinLock<[File; FILES_MAX]>, RwSpinLock<NameIndex>);
+
+// A file in use, borrowed from FILES while it is locked.
+#[derive(Clone, Copy, Debug)]
+pub struct FileHandle<'a> {
+    inode: Inode,
+    file: &'a File,
 }
 
-pub static FILES: Files = Files(SpinLock::new([File::zeroed(); FILES_MAX]));
+impl<'a> FileHandle<'a> {
+    pub fn inode(&self) -> Inode {
+        self.inode
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.file.name_str()
+    }
+
+    pub fn size(&self) -> usize {
+        self.file.size
+    }
+
+    // The file contents, or Corrupt if they failed their CRC32 check when loaded.
+    pub fn data(&self) -> Result<&'a [u8], OsError> {
+        if self.file.corrupt {
+            return Err(OsError::Corrupt);
+        }
+        Ok(&self.file.data[..self.file.size])
+    }
+}
 
+// Iterator over the files in use, from `Files::iter_in_use`.
 #[derive(Debug)]
-pub struct Disk(SpinLock<[u8; DISK_MAX_SIZE]>);
+pub struct InUse<'a>(Enumerate<slice::Iter<'a, File>>);
 
-impl Disk {
-    const fn empty() -> Self {
-        Self(SpinLock::new([0u8; DISK_MAX_SIZE]))
+impl<'a> Iterator for InUse<'a> {
+    type Item = FileHandle<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.find(|(_, file)| file.in_use)
+            .map(|(inode, file)| FileHandle { inode, file })
     }
 }
 
-pub static DISK: Disk = Disk::empty();
+impl Files {
+    // Calls `f` with an iterator over the files in use, with FILES locked until it returns.
+    pub fn iter_in_use<R>(&self, f: impl FnOnce(InUse<'_>) -> R) -> R {
+        let files = self.0.read();
+        f(InUse(files.iter().enumerate()))
+    }
+
+    // Calls `f` with the file at `inode`, or returns NotFound if it is not in use.
+    pub fn with_file<R>(&self, inode: Inode, f: impl FnOnce(FileHandle<'_>) -> R) -> Result<R, OsError> {
+        let files = self.0.read();
+        let file = files.get(inode).filter(|file| file.in_use).ok_or(OsError::NotFound)?;
+        Ok(f(FileHandle { inode, file }))
+    }
 
-fn oct2int(oct: &[u8]) -> Result<usize, ()> {
-    oct.iter()
-    .take_while(|&&b | b != 0)  // Nul terminated octal slice so stop here
-    .try_fold(0, | dec, &b | {
-        match b {
-            b'0'..=b'7' => Ok(dec * 8 + (b - b'0') as usize),
-              _ => Err(())
+    // As `with_file`, for changing the file. The change is undone if the archive could not be
+    // flushed with it, failing with NoSpace.
+    fn with_file_mut<R>(&self, inode: Inode, f: impl FnOnce(&mut File) -> Result<R, OsError>) -> Result<R, OsError> {
+        let mut files = self.0.write();
+        let file = files.get_mut(inode).filter(|file| file.in_use).ok_or(OsError::NotFound)?;
+        let saved = *file;
+        let result = f(file)?;
+        if let Err(err) = check_flush(&files) {
+            files[inode] = saved;
+            return Err(err);
         }
-    })
+        Ok(result)
+    }
+
+    pub fn fs_lookup(&self, name: &str) -> Option<usize> {
+        log_trace!("looking up filename {}", name);
+        self.resolve(name)
+    }
+
+    // Finds the regular file called `name`, following hard links and symlinks.
+    fn resolve(&self, name: &str) -> Option<Inode> {
+        let files = self.0.read();
+        let index = self.1.read();
+        let mut name = name;
+
+        for _ in 0..=LINK_DEPTH_MAX {
+            let inode = index.get(&files, name)?;
+            let file = &files[inode];
+            match file.kind {
+                FileKind::Regular => return Some(inode),
+                FileKind::HardLink | FileKind::Symlink => name = file.link_str(),
+            }
+        }
+
+        log_warn!("too many levels of links looking up {}", name);
+        None
+    }
 }
 
-// Turn the file size into a nul terminated octal string.
-fn int2oct(dec: usize, oct: &mut [u8]) {
-    let mut num = dec;
-    oct.fill(b' ');  // Fill with spaces
-    if let Some(last_byte) = oct.last_mut() {
-        *last_byte = b'\0'; // Set last byte to nul terminator
+impl FileSystem for Files {
+    fn lookup(&self, name: &str) -> Option<Inode> {
+        self.fs_lookup(name)
+    }
+
+    fn create(&self, name: &str) -> Result<Inode, OsError> {
+        check_name(name)?;
+
+        let mut files = self.0.write();
+        let mut index = self.1.write();
+
+        // The name may belong to a link to a file that does not exist.
+        if index.get(&files, name).is_some() {
+            return Err(OsError::Exists);
+        }
+
+        // New files go after the last entry in the archive. They reach the disk on the next flush.
+        let sector = files.iter()
+            .filter(|f| f.in_use)
+            .map(|f| f.sector + 1 + f.stored_sectors())
+            .max()
+            .unwrap_or(0)
+            .max(*PINNED_END.lock());
+
+        let (i, file) = files.iter_mut()
+            .enumerate()
+            .find(|(_, f)| !f.in_use)
+            .ok_or(OsError::NoSpace)?;
+
+        *file = File::zeroed();
+        file.in_use = true;
+        file.name = ArrayString::from(name).map_err(|_| OsError::InvalidName)?;
+        file.sector = sector;
+        file.dirty_header = true;
+        if let Err(err) = check_flush(&files) {
+            files[i] = File::zeroed();
+            return Err(err);
+        }
+        index.insert(name, i);
+        Ok(i)
+    }
+
+    // The archive is rewritten from the removed file onwards straight away, closing the gap it
+    // leaves. Links to the file are left dangling.
+    fn unlink(&self, name: &str) -> Result<(), OsError> {
+        let _flushing = FLUSH.lock();
+        let (txn, written) = {
+            let mut files = self.0.write();
+            let mut index = self.1.write();
+            let inode = index.get(&files, name).ok_or(OsError::NotFound)?;
+            let sector = files[inode].sector;
+            // Removing the file only shrinks the archive, but not if it cannot be moved up.
+            check_rewrite(&files, sector)?;
+            files[inode] = File::zeroed();
+            index.rebuild(&files);
+
+            let mut txn = Transaction::new(*TAR_DISK.lock());
+            let written = rewrite_from(&mut txn, &mut files, sector)?;
+            (txn, written)
+        };
+        txn.commit();
+        log_debug!("removed {}, wrote {} sectors to disk", name, written);
+        Ok(())
+    }
+
+    // Only the header changes, so the new name reaches the disk on the next flush. Links to the
+    // old name are left dangling.
+    fn rename(&self, from: &str, to: &str) -> Result<(), OsError> {
+        check_name(to)?;
+
+        let mut files = self.0.write();
+        let mut index = self.1.write();
+        let inode = index.get(&files, from).ok_or(OsError::NotFound)?;
+        if index.get(&files, to).is_some() {
+            return Err(OsError::Exists);
+        }
+
+        let file = &mut files[inode];
+        file.name = ArrayString::from(to).map_err(|_| OsError::InvalidName)?;
+        file.dirty_header = true;
+        index.rebuild(&files);
+        Ok(())
+    }
+
+    fn read(&self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.with_file(inode, |file| {
+            let data = file.data()?.get(offset..).unwrap_or(&[]);
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        })?
+    }
+
+    fn write(&self, inode: Inode, offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        self.with_file_mut(inode, |file| {
+            if file.corrupt {
+                return Err(OsError::Corrupt);
+            }
+            // Links are resolved by lookup, so this is only reached through a dangling link's inode.
+            if file.kind != FileKind::Regular {
+                return Err(OsError::Unsupported);
+            }
+            let old_size = file.size;
+
+            // Writes past the end of the file grow it, up to the data capacity.
+            let len = file.data.len().checked_sub(offset)
+                .ok_or(OsError::NoSpace)?
+                .min(buf.len());
+
+            // Zero any gap between the old end of file and the write offset.
+            if offset > file.size {
+                file.data[file.size..offset].fill(0);
+            }
+            file.data[offset..offset + len].copy_from_slice(&buf[..len]);
+            file.size = file.size.max(offset + len);
+            // Written back to disk by fs_flush
+            file.mark_dirty(old_size.min(offset)..offset + len, file.size != old_size);
+            Ok(len)
+        })
+    }
+
+    // The file stores no data sectors any more, so the next flush rewrites the archive from it.
+    // Its old contents are gone, so a corrupt file is usable again.
+    fn truncate(&self, inode: Inode) -> Result<(), OsError> {
+        self.with_file_mut(inode, |file| {
+            if file.kind != FileKind::Regular {
+                return Err(OsError::Unsupported);
+            }
+            file.data.fill(0);
+            file.mark_dirty(0..0, file.size != 0);
+            file.size = 0;
+            file.corrupt = false;
+            Ok(())
+        })
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        let (name, inode) = self.iter_in_use(|mut files| {
+            files.nth(index)
+                .map(|f| (String::from(f.name()), f.inode()))
+        })?;
+        // Links are listed with the inode of the file they lead to, unless they dangle.
+        let inode = self.resolve(&name).unwrap_or(inode);
+        Some(DirEntry { name, inode })
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        self.with_file(inode, |file| Stat { size: file.size() })
+    }
+
+    fn sync(&self, inode: Inode) {
+        let dirty = self.with_file(inode, |file| file.file.is_dirty());
+        if dirty == Ok(true) {
+            fs_flush(inode);
+        }
+    }
+
+    fn sync_all(&self) {
+        fs_sync();
+    }
+
+    fn read_only(&self) -> bool {
+        TAR_DISK.lock().read_only()
     }
-    oct.iter_mut()
-    .rev()
-    .skip(1) // Skip the last byte to leave as nul terminator
-    .for_each(|byte| {
-        *byte = (num % 8) as u8 + b'0';
-    num /= 8;
-    });
 }
 
-pub fn fs_init() {
-    // Load into DISK by sector
-    for sector in 0..(size_of::<[u8; DISK_MAX_SIZE]>() / SECTOR_SIZE) {
-        let mut disk = DISK.0.lock();
-        // let ptr = &raw mut disk[sector * SECTOR_SIZE];
-        let offset = sector * SECTOR_SIZE;
-        read_write_disk(&mut disk[offset..offset + SECTOR_SIZE], sector as u64, false);
+// The name is stored nul terminated in the header, and every file is in the archive root.
+fn check_name(name: &str) -> Result<(), OsError> {
+    if name.is_empty() || name.len() >= size_of::<[u8; 100]>() || name.contains('/') {
+        return Err(OsError::InvalidName);
     }
+    Ok(())
+}
+
+pub static FILES: Files = Files(RwSpinLock::new([File::zeroed(); FILES_MAX]), RwSpinLock::new(NameIndex::new()));
+
+// The disk FILES was loaded from. There is only one FILES, so only one disk can hold a mounted archive.
+static TAR_DISK: SpinLock<Disk> = SpinLock::new(Disk::ROOT);
+
+// Sector after the last entry fs_init could not load. Those entries are not in FILES, so a
+// rewrite would overwrite them: the archive is only rewritten from here onwards.
+static PINNED_END: SpinLock<usize> = SpinLock::new(0);
+
+// Held while the journal is in use, so flushes reach the disk one at a time. Taken before FILES.
+static FLUSH: Mutex<()> = Mutex::new(());
+
+// Number of sectors needed to hold `size` bytes of file data.
+const fn sectors_for(size: usize) -> usize {
+    align_up(size, SECTOR_SIZE) / SECTOR_SIZE
+}
 
-    // Load into FILES from DISK
-    let mut off = 0;
-    let mut files = FILES.0.lock();
+// Map of a file with no holes: every data sector is stored.
+const fn file_map(size: usize) -> u32 {
+    (1 << sectors_for(size)) - 1
+}
+
+// Sectors available to the archive. The journal takes the rest of the disk.
+fn disk_sectors() -> usize {
+    journal::journal_start(*TAR_DISK.lock())
+}
 
-    for file in files.iter_mut() {
-        let disk = DISK.0.lock();
-        assert!(disk.len() >= off + size_of::<TarHeader>());
-        // Safety:
-        // * data is aligned to single byte alignment - not using larger types
-        // * disk is initialised and valid for reading
-        let header = unsafe {
-            &*(disk.as_ptr().add(off) as *const TarHeader)
+// Fails with Unsupported if the disk does not start with a tar header, and with Corrupt if a later
+// entry cannot be walked past. FILES is left empty then.
+pub fn fs_init(mut disk: Disk) -> Result<(), OsError> {
+    // Load into FILES by reading each header sector, then the data sectors that follow it
+    let mut sector = 0;
+    let mut error = None;
+    let _flushing = FLUSH.lock();
+    let mut files = FILES.0.write();
+    let mut index = FILES.1.write();
+    // Forget any files from an earlier mount of the disk.
+    files.fill(File::zeroed());
+    index.clear();
+    *PINNED_END.lock() = 0;
+    let mut free_files = files.iter_mut().enumerate();
+
+    *TAR_DISK.lock() = disk;
+
+    // Finish any flush that was interrupted, then start from what is on the disk,
+    // not from anything cached before.
+    journal::recover(disk);
+    cache::invalidate_all(disk);
+
+    while sector < disk_sectors() {
+        // A header that fails its checksum cannot be trusted for the file size either,
+        // so there is no way to find the next header: stop loading there.
+        let header = match TarHeader::read(&mut CacheDisk(disk), sector as u64) {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(HeaderError::BadMagic) => {
+                log_warn!("invalid tar header at sector {}: magic is not ustar", sector);
+                error = Some(if sector == 0 { OsError::Unsupported } else { OsError::Corrupt });
+                break;
+            },
+            Err(HeaderError::BadChecksum { stored, computed }) => {
+                log_warn!("corrupt header at sector {}: checksum={} but computed {}, ignoring remaining entries", sector, stored, computed);
+                break;
+            },
+            Err(HeaderError::ChecksumNotOctal) => {
+                log_warn!("corrupt header at sector {}: checksum is not octal, ignoring remaining entries", sector);
+                break;
+            },
         };
 
-        if header.name[0] == b'\0' { // name is a c string with nul terminator
+        let Ok(stored_size) = oct2int(&header.size) else {
+            log_warn!("invalid tar header at sector {}: size is not octal", sector);
+            error = Some(OsError::Corrupt);
             break;
+        };
+
+        let header_sector = sector;
+        let data_sectors = sectors_for(stored_size);
+        sector += 1 + data_sectors;
+
+        // Only regular files and links are loaded. Other entries (directories, devices...) are
+        // skipped, and left where they are by later rewrites.
+        let kind = match header.typeflag {
+            b'0' | b'\0' => FileKind::Regular,
+            b'1' => FileKind::HardLink,
+            b'2' => FileKind::Symlink,
+            _ => {
+                log_warn!("skipping {} with unsupported typeflag {:?}", header.name_str(), header.typeflag as char);
+                *PINNED_END.lock() = sector;
+                continue;
+            },
+        };
+
+        // The link name of a regular file holds its sparse map, if it has one.
+        let (filesz, sector_map) = if kind != FileKind::Regular || header.linkname[0] == b'\0' {
+            (stored_size, None)
+        } else {
+            match (oct2int(&header.linkname[SPARSE_SIZE]), oct2int(&header.linkname[SPARSE_MAP])) {
+                (Ok(size), Ok(map)) if map.count_ones() as usize == data_sectors => (size, Some(map)),
+                _ => {
+                    log_warn!("skipping {}: invalid sparse map", header.name_str());
+                    *PINNED_END.lock() = sector;
+                    continue;
+                },
+            }
+        };
+
+        if filesz > FILE_DATA_MAX || sector > disk_sectors() {
+            log_warn!("skipping {}: size={} is too large", header.name_str(), filesz);
+            *PINNED_END.lock() = sector;
+            continue;
         }
 
-        match core::ffi::CStr::from_bytes_with_nul(&header.magic) {
-            Ok(magic) if magic == c"ustar" => {},
-            Ok(magic) => panic!("invalid tar header: magic={:?}", magic),
-            Err(_) => panic!("invalid tar header: magic is not a valid c string"),
+        // Without a sparse map every data sector is stored.
+        let sector_map = sector_map.unwrap_or(file_map(stored_size) as usize);
+        if sector_map >> sectors_for(filesz) != 0 {
+            log_warn!("skipping {}: sparse map is larger than the file", header.name_str());
+            *PINNED_END.lock() = sector;
+            continue;
         }
 
-        let filesz = oct2int(&header.size)
-        .expect("file size should be valid");
+        let Some((inode, file)) = free_files.next() else {
+            log_warn!("too many files, skipping {}", header.name_str());
+            *PINNED_END.lock() = sector;
+            continue;
+        };
 
         file.in_use = true;
-        file.name = header.name;
+        file.kind = kind;
+        // A header's strings are shorter than its 100 byte fields, so they fit.
+        file.link = if kind == FileKind::Regular {
+            ArrayString::new()
+        } else {
+            ArrayString::from(header.linkname_str()).unwrap_or_default()
+        };
+        file.name = ArrayString::from(header.name_str()).unwrap_or_default();
+        index.insert(file.name_str(), inode);
         file.size = filesz;
+        file.on_disk = true;
+        file.sector = header_sector;
+        file.sector_map = sector_map as u32;
+
+        // Read the stored data sectors straight into the file in one request, leaving holes zeroed.
+        // They are consecutive on the disk, and the cache holds nothing newer after invalidate_all.
+        file.data.fill(0);
+        let mut stored: [&mut [u8]; FILE_DATA_MAX / SECTOR_SIZE] = Default::default();
+        let mut count = 0;
+        let chunks = file.data.chunks_mut(SECTOR_SIZE)
+            .enumerate()
+            .filter(|(i, _)| sector_map & 1 << i != 0);
+        for ((_, chunk), buf) in chunks.zip(stored.iter_mut()) {
+            *buf = chunk;
+            count += 1;
+        }
+        disk.read_sectors(&mut stored[..count], header_sector as u64 + 1);
+
+        // Files written by tar tools have no CRC32, so they cannot be checked.
+        if header.crc32[0] != b'\0' {
+            let computed = crc32(&file.data[..filesz]);
+            match oct2int(&header.crc32) {
+                Ok(crc) if crc as u32 == computed => {},
+                _ => {
+                    log_warn!("{} is corrupt: data does not match its CRC32", header.name_str());
+                    file.corrupt = true;
+                },
+            }
+        }
+    }
 
-        let data_offset = off + header.size();
-
-        file.data[..filesz].copy_from_slice(&disk[data_offset..data_offset + filesz]);
-
-        let file_name_str = str::from_utf8(&file.name)
-        .expect("file name text should be valid UTF8")
-        .trim();
-        crate::println!("file: {}, size={}", file_name_str, filesz);
-
-        off += align_up( header.size() + filesz, SECTOR_SIZE );
+    if let Some(err) = error {
+        files.fill(File::zeroed());
+        index.clear();
+        *PINNED_END.lock() = 0;
+        return Err(err);
     }
 
     // println!("at the end of fs_init, FILES is {:?}", FILES);
+    Ok(())
 }
 
-pub fn fs_flush() {
-    // Copy all file contents into `disk` buffer.
-    let mut disk = DISK.0.lock();
-    disk.fill(0);
+// Add a file's header and data sectors at its recorded location to a transaction.
+// Unless `all` is set, only the header and sectors marked dirty are written.
+// Returns the number of sectors written.
+fn write_file(txn: &mut Transaction, file: &mut File, all: bool) -> usize {
+    let mut written = 0;
+    let mut buf = [0u8; SECTOR_SIZE];
+
+    if all || file.dirty_header {
+        let mut header = TarHeader::new(&file.name, file.kind.typeflag());
+        if file.kind != FileKind::Regular {
+            // Links have no data, so are never sparse
+            header.linkname[..file.link.len()].copy_from_slice(file.link.as_bytes());
+        }
+        if file.sector_map == file_map(file.size) {
+            int2oct(file.size, &mut header.size);
+        } else {
+            int2oct(file.stored_sectors() * SECTOR_SIZE, &mut header.size);
+            int2oct(file.size, &mut header.linkname[SPARSE_SIZE]);
+            int2oct(file.sector_map as usize, &mut header.linkname[SPARSE_MAP]);
+        }
+        // A corrupt file moved by a flush must stay corrupt, so it gets a CRC32 that cannot match.
+        let crc = crc32(&file.data[..file.size]);
+        int2oct(if file.corrupt { !crc } else { crc } as usize, &mut header.crc32);
 
-    let files = FILES.0.lock();
+        header.seal();
+        txn.write_sector(&header.to_sector(), file.sector as u64);
+        written += 1;
+    }
 
-    let mut off = 0;
-    for file in files.iter() {
-        if !file.in_use {
-            break;
+    // Write stored file data after the header, zero padding the final sector. Holes are skipped.
+    for (i, chunk) in file.data[..file.size].chunks(SECTOR_SIZE).enumerate() {
+        if file.sector_map & (1 << i) == 0 || (!all && file.dirty_sectors & (1 << i) == 0) {
+            continue;
         }
+        buf.fill(0);
+        buf[..chunk.len()].copy_from_slice(chunk);
+        txn.write_sector(&buf, file.data_sector(i) as u64);
+        written += 1;
+    }
 
-        // Create header
-        let mut header = TarHeader::zeroed();
-        header.name.copy_from_slice(&file.name);
-        header.mode.copy_from_slice("00000644".as_bytes()); // Read and write permissions
-        header.magic.copy_from_slice("ustar\0".as_bytes());
-        header.version.copy_from_slice("00".as_bytes());
-        header.typeflag = b'0'; // Regular file
-        int2oct(file.size, &mut header.size);
-        header.checksum.fill(b' '); // Checksum is calculated with checksum field set to spaces
-
-        // Calculate the checksum
-        let checksum = {
-            // Safety: We drop buf on checksum creation to avoid mutating underlying data
-            let buf = unsafe { header.as_bytes() };
-            buf.iter().fold(0, | checksum, byte | checksum + *byte as usize )
-        };
-        int2oct(checksum, &mut header.checksum);
+    file.on_disk = true;
+    file.dirty_header = false;
+    file.dirty_sectors = 0;
+    written
+}
 
-        // Safety: We do not mutate header in the remainder of this loop
-        let buf = unsafe { header.as_bytes() };
-        disk[off..off + header.size()].copy_from_slice(buf);
+/// Write the changes to the file at `file_i` back to disk.
+///
+/// Only the file's own header and dirty data sectors are written, unless the file is new or
+/// has changed which data sectors are stored (because it grew, shrank, or a hole was filled or
+/// zeroed). Then every later file has to move and the end of the archive has to be marked again,
+/// so the archive is rewritten from this file onwards.
+///
+/// The sectors go through the journal, so an interrupted flush never leaves a corrupt archive.
+pub fn fs_flush(file_i: usize) {
+    let _flushing = FLUSH.lock();
+    let flushed = {
+        let mut files = FILES.0.write();
+        flush_transaction(&mut files, file_i)
+    };
+    // Changes that would not fit are refused when they are made, so this is not expected.
+    let Ok((txn, written)) = flushed else {
+        log_warn!("cannot flush file {}: the archive is out of space", file_i);
+        return;
+    };
+    // FILES is unlocked while the transaction is written, so other processes can use it.
+    txn.commit();
+    log_debug!("wrote {} sectors to disk", written);
+}
 
-        // Copy file data immediately after the header.
-        let data_offset = off + header.size();
-        let data_size = size_of_val(&file.data);
-        disk[data_offset..data_offset + data_size].copy_from_slice(&file.data);
+// Builds the transaction that fs_flush writes, returning it with the number of sectors in it.
+fn flush_transaction(files: &mut [File; FILES_MAX], file_i: usize) -> Result<(Transaction, usize), OsError> {
+    let mut txn = Transaction::new(*TAR_DISK.lock());
 
-        off += align_up(header.size() + file.size, SECTOR_SIZE);
+    if !files[file_i].needs_rewrite(*PINNED_END.lock()) {
+        let written = write_file(&mut txn, &mut files[file_i], false);
+        return Ok((txn, written));
     }
 
-    // println!("tar: fs_flush just before write DISK is {:?}", disk);
+    let written = rewrite_from(&mut txn, files, files[file_i].sector)?;
+    Ok((txn, written))
+}
 
-    // Write `disk` buffer into the vitio-blk.
-    for sector in 0..(DISK_MAX_SIZE / SECTOR_SIZE) {
-        let offset = sector * SECTOR_SIZE;
-        read_write_disk(&mut disk[offset..offset + SECTOR_SIZE], sector as u64, true);
+// Checks that every file with changes can be flushed. Flushes rewrite the archive from the
+// first file that needs it, so that is the only rewrite that has to fit.
+fn check_flush(files: &[File; FILES_MAX]) -> Result<(), OsError> {
+    let pinned_end = *PINNED_END.lock();
+    match files.iter().filter(|f| f.in_use && f.needs_rewrite(pinned_end)).map(|f| f.sector).min() {
+        Some(start) => check_rewrite(files, start),
+        None => Ok(()),
     }
+}
 
-    println!("wrote {} bytes to disk", DISK_MAX_SIZE);
+// Fails with NoSpace if rewriting the archive from `start` would move an entry fs_init skipped,
+// or not fit before the journal.
+fn check_rewrite(files: &[File; FILES_MAX], start: usize) -> Result<(), OsError> {
+    let end = start + files.iter()
+        .filter(|f| f.in_use && f.sector >= start)
+        .map(|f| 1 + f.data_map().count_ones() as usize)
+        .sum::<usize>();
+    if start < *PINNED_END.lock() || end > disk_sectors() {
+        return Err(OsError::NoSpace);
+    }
+    Ok(())
+}
+
+// Adds every file at or after `start` in the archive to a transaction, packed together from
+// `start` onwards in their archive order, and marks the new end of the archive. A file removed
+// or created since a slot was freed may be anywhere in the table, so it is sorted by sector.
+// Returns the number of sectors written, or NoSpace if check_rewrite fails.
+fn rewrite_from(txn: &mut Transaction, files: &mut [File; FILES_MAX], start: usize) -> Result<usize, OsError> {
+    check_rewrite(files, start)?;
+
+    let mut order: [Inode; FILES_MAX] = core::array::from_fn(|i| i);
+    order.sort_unstable_by_key(|&i| files[i].sector);
+
+    let mut sector = start;
+    let mut written = 0;
+    for i in order {
+        let file = &mut files[i];
+        if !file.in_use || file.sector < start {
+            continue;
+        }
+        file.sector = sector;
+        file.sector_map = file.data_map();
+        written += write_file(txn, file, true);
+        sector += 1 + file.stored_sectors();
+    }
+
+    // Mark the end of the archive in case it has shrunk.
+    if sector < disk_sectors() {
+        txn.write_sector(&[0u8; SECTOR_SIZE], sector as u64);
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Write every file changed since it was last flushed back to disk.
+pub fn fs_sync() {
+    for file_i in 0..FILES_MAX {
+        // fs_flush may rewrite later files too, which clears their dirty flag.
+        let dirty = FILES.0.read()[file_i].is_dirty();
+        if dirty {
+            fs_flush(file_i);
+        }
+    }
 }
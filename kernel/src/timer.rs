@@ -0,0 +1,156 @@
+//! Timer interrupts for os1k
+//!
+//! The supervisor timer interrupt fires TICK_HZ times a second. Each one counts a tick, asks SBI
+//! for the next deadline and fires the software timers that are due. Deadlines fall on multiples
+//! of the tick interval, so a late interrupt does not push the later ones back.
+//!
+//! A software timer calls its callback once, after a delay, or every period until cancelled.
+//! Both are counted in ticks. Ticks are only taken in user mode, or polled for while every
+//! process is blocked, so a timer can fire late, but never early.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sbi::sbi_set_timer;
+use crate::spinlock::SpinLock;
+
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;  // `time` CSR ticks per second on the QEMU virt machine
+pub const TICK_HZ: u64 = 100;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const TICK_INTERVAL: u64 = TIMEBASE_FREQUENCY / TICK_HZ;  // In `time` CSR ticks
+const TIMERS_MAX: usize = 16;
+const SIP_STIP: usize = 1 << 5;     // Supervisor timer interrupt pending
+
+// Timer interrupts since boot. At TICK_HZ it takes over a year to wrap.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+// Called from the timer interrupt with the argument the timer was set up with.
+pub type TimerCallback = fn(usize);
+
+// Names a software timer, to cancel it. Ids are not reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy, Debug)]
+struct SoftTimer {
+    id: TimerId,
+    deadline: usize,    // The tick to fire on
+    period: usize,      // Ticks between firings, or 0 to fire once
+    callback: TimerCallback,
+    arg: usize,
+}
+
+static TIMERS: SpinLock<[Option<SoftTimer>; TIMERS_MAX]> = SpinLock::new([None; TIMERS_MAX]);
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Reads the 64-bit `time` CSR as two halves, retrying if the low half wrapped in between.
+pub fn read_time() -> u64 {
+    loop {
+        let high = read_csr!("timeh");
+        let low = read_csr!("time");
+        if high == read_csr!("timeh") {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+// Returns the time since boot in nanoseconds. Whole seconds and the remainder are scaled
+// separately, so the product does not overflow.
+pub fn monotonic_ns() -> u64 {
+    let time = read_time();
+    time / TIMEBASE_FREQUENCY * NANOS_PER_SEC + time % TIMEBASE_FREQUENCY * NANOS_PER_SEC / TIMEBASE_FREQUENCY
+}
+
+// Spins for at least `us` microseconds, measured on the `time` CSR, so the wait is the same
+// however fast the host is. Interrupts are not taken meanwhile.
+#[allow(dead_code)] // For drivers' short waits
+pub fn delay_us(us: u64) {
+    let end = read_time() + us * TIMEBASE_FREQUENCY / 1_000_000;
+    while read_time() < end {
+        core::hint::spin_loop();
+    }
+}
+
+#[allow(dead_code)] // For drivers' short waits
+pub fn delay_ms(ms: u64) {
+    delay_us(ms * 1000);
+}
+
+// Asks for an interrupt at the start of the next tick.
+fn set_next_deadline() {
+    let deadline = (read_time() / TICK_INTERVAL + 1) * TICK_INTERVAL;
+    sbi_set_timer(deadline).expect("SBI should set the timer");
+}
+
+// Starts the tick. The interrupt is taken once it is enabled in `sie`.
+pub fn timer_init() {
+    set_next_deadline();
+}
+
+// Returns the number of ticks since boot.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn timer_add(delay: usize, period: usize, callback: TimerCallback, arg: usize) -> Option<TimerId> {
+    let mut timers = TIMERS.lock();
+    let slot = timers.iter_mut().find(|t| t.is_none())?;
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    *slot = Some(SoftTimer { id, deadline: ticks() + delay.max(1), period, callback, arg });
+    Some(id)
+}
+
+// Calls `callback(arg)` once, `delay` ticks from now. Returns None if every timer is in use.
+pub fn timer_oneshot(delay: usize, callback: TimerCallback, arg: usize) -> Option<TimerId> {
+    timer_add(delay, 0, callback, arg)
+}
+
+// Calls `callback(arg)` every `period` ticks until cancelled. Returns None if every timer is in
+// use.
+pub fn timer_periodic(period: usize, callback: TimerCallback, arg: usize) -> Option<TimerId> {
+    timer_add(period, period.max(1), callback, arg)
+}
+
+// Stops a timer. Returns false if it has already fired for the last time or been cancelled.
+#[allow(dead_code)] // For timeouts and alarms
+pub fn timer_cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.iter_mut().find(|t| t.is_some_and(|t| t.id == id)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        },
+        None => false,
+    }
+}
+
+// Takes the next timer due by `tick`, rescheduling it if it is periodic.
+fn next_due(tick: usize) -> Option<SoftTimer> {
+    let mut timers = TIMERS.lock();
+    let slot = timers.iter_mut().find(|t| t.is_some_and(|t| t.deadline <= tick))?;
+    let timer = slot.take()?;
+    if timer.period != 0 {
+        *slot = Some(SoftTimer { deadline: timer.deadline + timer.period, ..timer });
+    }
+    Some(timer)
+}
+
+// Handles the supervisor timer interrupt.
+pub fn timer_handle_interrupt() {
+    let tick = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // Setting the deadline also clears the pending interrupt.
+    set_next_deadline();
+
+    // One timer at a time, with the lock released, so callbacks can add and cancel timers. A
+    // callback that does not return, because it killed the process, leaves the rest due for
+    // the next tick.
+    while let Some(timer) = next_due(tick) {
+        (timer.callback)(timer.arg);
+    }
+}
+
+// Handles a timer interrupt left pending while interrupts are off in the kernel.
+pub fn timer_poll() {
+    if read_csr!("sip") & SIP_STIP != 0 {
+        timer_handle_interrupt();
+    }
+}
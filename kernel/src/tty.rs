@@ -0,0 +1,189 @@
+//! Line discipline for os1k
+//!
+//! Reads a line of console input for a process, echoing what is typed and handling the usual
+//! editing keys, so user programs get whole lines without each reimplementing line editing.
+//! Backspace (or Delete) erases a character, Ctrl-U the whole line and Ctrl-W the word before
+//! the cursor. Ctrl-D on an empty line reads as the end of input. Escape sequences, such as the
+//! arrow keys, are ignored.
+//!
+//! Ctrl-C ends the foreground processes: the drivers pass every byte received to `tty_input`, and
+//! each process exits on its way back to user mode, unless it has a handler for
+//! Signal::Interrupt, which runs instead. The byte is still delivered as input, so a
+//! process reading the console wakes up to be ended, and Ctrl-C in the shell cancels the line.
+//!
+//! The foreground processes are the process group the shell sets with Syscall::SetForeground
+//! for the job it waits for, or with none set, the processes in the shell's own group. Ctrl-Z
+//! stops them, along with their threads: the scheduler passes them over until
+//! Syscall::Continue, and the shell sees them stopped through Syscall::Wait with WAIT_STOPPED.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::console::{flush_process_output, read_char, write_bytes};
+use crate::flock;
+use crate::net;
+use crate::pipe;
+use crate::process::{State, INIT_PID, PROCS};
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const CTRL_Z: u8 = 0x1a;
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+// The foreground process group, or 0 for the shell's.
+static FOREGROUND: AtomicUsize = AtomicUsize::new(0);
+
+// The processes, a bit for each slot of PROCS, to be ended for Ctrl-C, and stopped by Ctrl-Z.
+// Bits rather than fields of Process, so input is handled without taking PROCS for writing.
+static INTERRUPTED: AtomicUsize = AtomicUsize::new(0);
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+// The slots of the foreground processes, or just the processes rather than their threads for
+// `leaders`. The shell itself is never among them.
+fn foreground(leaders: bool) -> usize {
+    let group = match FOREGROUND.load(Ordering::Relaxed) {
+        0 => INIT_PID,
+        group => group,
+    };
+    PROCS.0.read().iter()
+        .enumerate()
+        .filter(|(_, p)| matches!(p.state, State::Runnable | State::Blocked) && p.pid > INIT_PID)
+        .filter(|(_, p)| p.group == group && (!leaders || p.pid == p.leader))
+        .fold(0, |slots, (i, _)| slots | 1 << i)
+}
+
+// Looks at each byte of console input as it arrives.
+pub fn tty_input(b: u8) {
+    match b {
+        CTRL_C => {
+            INTERRUPTED.fetch_or(foreground(true), Ordering::Relaxed);
+            // A process waiting on a pipe, a lock or a port sleeps until it changes, so wake it to
+            // give up.
+            pipe::wake_all();
+            flock::wake_all();
+            net::wake_all();
+        },
+        CTRL_Z => {
+            STOPPED.fetch_or(foreground(false), Ordering::Relaxed);
+        },
+        _ => {},
+    }
+}
+
+// Makes process group `group` the foreground one, or the shell's for 0.
+pub fn set_foreground(group: usize) {
+    FOREGROUND.store(group, Ordering::Relaxed);
+}
+
+// Lets the processes of `group` that Ctrl-Z stopped run again.
+pub fn continue_group(group: usize) {
+    let slots = PROCS.0.read().iter()
+        .enumerate()
+        .filter(|(_, p)| p.state != State::Unused && p.group == group)
+        .fold(0, |slots, (i, _)| slots | 1 << i);
+    STOPPED.fetch_and(!slots, Ordering::Relaxed);
+}
+
+// Whether the process in slot `i` of PROCS is stopped, for the scheduler to pass it over.
+pub fn is_stopped(i: usize) -> bool {
+    STOPPED.load(Ordering::Relaxed) & 1 << i != 0
+}
+
+// Forgets any Ctrl-C or Ctrl-Z for slot `i`, which a new process is taking.
+pub fn clear_slot(i: usize) {
+    INTERRUPTED.fetch_and(!(1 << i), Ordering::Relaxed);
+    STOPPED.fetch_and(!(1 << i), Ordering::Relaxed);
+}
+
+// Whether process `pid` is to be ended for Ctrl-C, for a syscall waiting on something else to
+// give up, leaving the request for the return to user mode.
+pub fn interrupt_pending(pid: usize) -> bool {
+    match PROCS.try_get_index(pid) {
+        Some(i) if pid != 0 => INTERRUPTED.load(Ordering::Relaxed) & 1 << i != 0,
+        _ => false,
+    }
+}
+
+// Returns whether process `pid` is to be ended for Ctrl-C, clearing the request.
+pub fn take_interrupt(pid: usize) -> bool {
+    match PROCS.try_get_index(pid) {
+        Some(i) if pid != 0 => INTERRUPTED.fetch_and(!(1 << i), Ordering::Relaxed) & 1 << i != 0,
+        _ => false,
+    }
+}
+
+fn echo(bytes: &[u8]) {
+    let _ = write_bytes(bytes);
+    flush_process_output();
+}
+
+// Removes the last character of `line`, a UTF-8 sequence, from the screen and from the line.
+// Returns the new length.
+fn erase(line: &[u8], mut len: usize) -> usize {
+    while len > 0 {
+        len -= 1;
+        if line[len] & 0xc0 != 0x80 {   // Not a continuation byte, so the start of the character
+            break;
+        }
+    }
+    echo(b"\x08 \x08");
+    len
+}
+
+// Reads the rest of an escape sequence, whose ESC has been read.
+fn skip_escape() {
+    if read_char() != b'[' {
+        return;
+    }
+    while !(0x40..=0x7e).contains(&read_char()) {}
+}
+
+// Reads a line into `buf`, ending it with '\n'. The line is cut short to fit. Returns its
+// length, which is 0 only at the end of input.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let Some(max) = buf.len().checked_sub(1) else {
+        return 0;
+    };
+    let mut len = 0;
+    loop {
+        match read_char() {
+            // On the debug console the newline is \r
+            b'\r' | b'\n' => {
+                echo(b"\n");
+                buf[len] = b'\n';
+                return len + 1;
+            },
+            CTRL_C => {
+                echo(b"^C\n");
+                buf[0] = b'\n';
+                return 1;
+            },
+            CTRL_D if len == 0 => return 0,
+            BACKSPACE | DELETE if len > 0 => len = erase(buf, len),
+            CTRL_U => {
+                while len > 0 {
+                    len = erase(buf, len);
+                }
+            },
+            CTRL_W => {
+                while len > 0 && buf[len - 1] == b' ' {
+                    len = erase(buf, len);
+                }
+                while len > 0 && buf[len - 1] != b' ' {
+                    len = erase(buf, len);
+                }
+            },
+            ESCAPE => skip_escape(),
+            // Other control characters are dropped, as is anything past the end of the buffer.
+            b if b < b' ' || b == DELETE || len == max => {},
+            b => {
+                buf[len] = b;
+                len += 1;
+                echo(&[b]);
+            },
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! NS16550 UART for os1k
+//!
+//! The QEMU virt machine's serial port. Output waits for room in the transmitter and writes the
+//! holding register. Input raises an interrupt, and the handler moves every received byte into
+//! a lock-free ring buffer and wakes the processes waiting for input. The UART is usable for output before
+//! `uart_init`, as the firmware has already set it up.
+
+use common::ring::SpscRing;
+
+use crate::plic::plic_enable;
+use crate::scheduler::WaitQueue;
+use crate::tty::tty_input;
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
+
+pub const UART_PADDR: usize = 0x1000_0000;
+pub const UART_IRQ: u32 = 10;
+const INPUT_MAX: usize = 1024;      // Bytes received but not yet read, a power of two
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const FCR_ENABLE_AND_CLEAR: u8 = 0x07;  // Enable the FIFOs and empty both
+const LCR_8N1: u8 = 0x03;               // 8 data bits, no parity, 1 stop bit
+const MCR_OUT2: u8 = 1 << 3;            // Connects the interrupt output on a real 16550
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+// The UART registers, one byte apart.
+#[repr(C)]
+struct UartRegs {
+    data: Volatile<u8>,     // Receive buffer when read, transmit holding register when written
+    ier: Volatile<u8>,      // Interrupt enable
+    fcr: WriteOnly<u8>,     // FIFO control
+    lcr: Volatile<u8>,      // Line control
+    mcr: Volatile<u8>,      // Modem control
+    lsr: ReadOnly<u8>,      // Line status
+}
+
+fn regs() -> &'static UartRegs {
+    // Safety: UART_PADDR is the UART, which is mapped in every page table.
+    unsafe { &*(UART_PADDR as *const UartRegs) }
+}
+
+// Received bytes, oldest first. Lock-free, so the interrupt handler never waits for a reader.
+static INPUT: SpscRing<u8, INPUT_MAX> = SpscRing::new();
+
+// Processes waiting for input.
+static INPUT_WAIT: WaitQueue = WaitQueue::new();
+
+// Turns on the receive interrupt.
+pub fn uart_init() {
+    let regs = regs();
+    regs.ier.write(0);
+    regs.lcr.write(LCR_8N1);
+    regs.fcr.write(FCR_ENABLE_AND_CLEAR);
+    regs.mcr.write(MCR_OUT2);
+    regs.ier.write(IER_RX_AVAILABLE);
+    plic_enable(UART_IRQ);
+}
+
+fn uart_put_byte(b: u8) -> Result<isize, isize> {
+    let regs = regs();
+    while regs.lsr.read() & LSR_THR_EMPTY == 0 {
+        core::hint::spin_loop();
+    }
+    regs.data.write(b);
+    Ok(0)
+}
+
+pub fn uart_write(bytes: &[u8]) -> Result<isize, isize> {
+    for &b in bytes {
+        uart_put_byte(b)?;
+    }
+    Ok(0)
+}
+
+// Moves everything the UART has received into INPUT and wakes the processes waiting for it.
+// Input that does not fit is dropped.
+pub fn uart_poll() {
+    let regs = regs();
+    let mut received = false;
+    while regs.lsr.read() & LSR_DATA_READY != 0 {
+        let byte = regs.data.read();
+        tty_input(byte);
+        // Safety: only the boot hart polls the UART, from the interrupt handler or with interrupts
+        // off, so there is one producer.
+        let _ = unsafe { INPUT.push(byte) };
+        received = true;
+    }
+    if received {
+        INPUT_WAIT.wake_all();
+    }
+}
+
+// Handles the UART interrupt. Reading every received byte clears it.
+pub fn uart_handle_interrupt() {
+    uart_poll();
+}
+
+// Returns the next byte received, or Err(-1) if there is none yet.
+pub fn uart_get_char() -> Result<isize, isize> {
+    pop_input().map(|b| b as isize).ok_or(-1)
+}
+
+// Returns the next byte received, blocking until there is one. Interrupts are only taken in
+// user mode, so where the process cannot block the UART is polled instead.
+pub fn uart_read_char() -> u8 {
+    loop {
+        uart_poll();
+        if let Some(b) = pop_input() {
+            return b;
+        }
+        if !INPUT_WAIT.wait() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn pop_input() -> Option<u8> {
+    // Safety: input is only read by the running process, in the kernel where it is not
+    // preempted, so there is one consumer.
+    unsafe { INPUT.pop() }
+}
@@ -0,0 +1,468 @@
+//! 9P file system for os1k
+//!
+//! Mounts a directory shared by the host over virtio-9p, using 9P2000.L. Files are read and
+//! written straight on the host, so changes on either side show up on the other without
+//! rebuilding the disk image. Names below the mount point may contain slashes to reach files in
+//! subdirectories, but only regular files in the shared directory itself are listed.
+//!
+//! Every file looked up gets a node, and its fid is fixed by the node's index. Nodes are kept
+//! until the file system is unmounted, so inodes stay valid for open files.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+
+use common::p9::{
+    parse_reply,
+    Reader,
+    ReplyError,
+    Writer,
+    NOFID,
+    NOTAG,
+    QID_SIZE,
+    TATTACH,
+    TCLUNK,
+    TFSYNC,
+    TGETATTR,
+    TLCREATE,
+    TLOPEN,
+    TREAD,
+    TREADDIR,
+    TSETATTR,
+    TVERSION,
+    TWALK,
+    TWRITE,
+};
+
+use crate::{log_info, log_warn};
+use crate::spinlock::SpinLock;
+use crate::vfs::{DirEntry, FileSystem, OsError, Inode, Stat};
+use crate::virtio_9p::{virtio_9p_present, virtio_9p_rpc};
+
+const MSIZE: usize = 8192;          // Largest message, offered to the server in Tversion
+const IOHDRSZ: usize = 24;          // Room for the header of Tread, Twrite and Rread
+const VERSION: &str = "9P2000.L";
+const TAG: u16 = 0;                 // Only one request is in flight at a time
+const ROOT_FID: u32 = 0;            // The shared directory
+const DIR_FID: u32 = 1;             // The shared directory, while it is being listed
+const FIRST_NODE_FID: u32 = 2;
+const NODES_MAX: usize = 32;
+const WALK_NAMES_MAX: usize = 16;   // Path components per Twalk
+const UNAME: &str = "root";
+const UID: u32 = 0;
+const GID: u32 = 0;
+const FILE_MODE: u32 = 0o644;       // Permissions of new files
+
+// Linux open flags, as used by Tlopen and Tlcreate
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+
+// Tgetattr request mask and the mode bits it returns
+const GETATTR_MODE: u64 = 0x1;
+const GETATTR_SIZE: u64 = 0x200;
+const SETATTR_SIZE: u32 = 0x8;      // Tsetattr valid bit for the size
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const DT_REG: u8 = 8;               // Directory entry type of a regular file
+
+// Linux errno values returned in Rlerror
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const EROFS: u32 = 30;
+const ENAMETOOLONG: u32 = 36;
+
+fn reply_error(err: ReplyError) -> OsError {
+    match err {
+        ReplyError::Lerror(ENOENT | ENOTDIR) => OsError::NotFound,
+        ReplyError::Lerror(EEXIST) => OsError::Exists,
+        ReplyError::Lerror(ENOSPC) => OsError::NoSpace,
+        ReplyError::Lerror(EINVAL | ENAMETOOLONG) => OsError::InvalidName,
+        ReplyError::Lerror(EPERM | EACCES | EROFS) => OsError::Permission,
+        ReplyError::Lerror(_) => OsError::Unsupported,
+        ReplyError::Malformed => {
+            log_warn!("malformed reply");
+            OsError::Corrupt
+        },
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    path: String,   // Below the shared directory, without leading or trailing slashes
+    open: bool,     // Opened with Tlopen or Tlcreate, so it can be read and written
+}
+
+fn node_fid(inode: Inode) -> u32 {
+    FIRST_NODE_FID + inode as u32
+}
+
+#[derive(Debug)]
+struct Session {
+    tx: Box<[u8]>,
+    rx: Box<[u8]>,
+    msize: usize,       // Agreed with the server in Tversion
+    attached: bool,
+    nodes: [Option<Node>; NODES_MAX],
+}
+
+// Created on the first mount and kept, as memory is never freed.
+static SESSION: SpinLock<Option<Session>> = SpinLock::new(None);
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            tx: vec![0; MSIZE].into_boxed_slice(),
+            rx: vec![0; MSIZE].into_boxed_slice(),
+            msize: MSIZE,
+            attached: false,
+            nodes: [const { None }; NODES_MAX],
+        }
+    }
+
+    // Sends the request built by `build` and returns a reader over the fields of the reply.
+    fn rpc(&mut self, msg_type: u8, tag: u16, build: impl FnOnce(&mut Writer)) -> Result<Reader<'_>, OsError> {
+        let mut writer = Writer::new(&mut self.tx[..self.msize], msg_type, tag);
+        build(&mut writer);
+        let len = writer.finish().ok_or(OsError::NoSpace)?;
+        virtio_9p_rpc(&self.tx[..len], &mut self.rx[..self.msize])?;
+        parse_reply(&self.rx, msg_type, tag).map_err(reply_error)
+    }
+
+    fn attach(&mut self) -> Result<(), OsError> {
+        self.msize = MSIZE;
+        let mut reply = self.rpc(TVERSION, NOTAG, |w| {
+            w.u32(MSIZE as u32).str(VERSION);
+        })?;
+        let msize = reply.u32().ok_or(OsError::Corrupt)? as usize;
+        if reply.str() != Some(VERSION) {
+            log_warn!("server does not speak {}", VERSION);
+            return Err(OsError::Unsupported);
+        }
+        self.msize = msize.min(MSIZE);
+
+        self.rpc(TATTACH, TAG, |w| {
+            w.u32(ROOT_FID).u32(NOFID).str(UNAME).str("").u32(UID);
+        })?;
+        self.attached = true;
+        Ok(())
+    }
+
+    // Forgets every fid, which also forgets every node.
+    fn detach(&mut self) {
+        for inode in 0..NODES_MAX {
+            if self.nodes[inode].take().is_some() {
+                self.clunk(node_fid(inode));
+            }
+        }
+        self.clunk(ROOT_FID);
+        self.attached = false;
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        // The server forgets the fid even if it reports an error.
+        let _ = self.rpc(TCLUNK, TAG, |w| {
+            w.u32(fid);
+        });
+    }
+
+    // Makes `newfid` refer to `path` below the shared directory.
+    fn walk(&mut self, newfid: u32, path: &str) -> Result<(), OsError> {
+        let count = path.split('/').filter(|name| !name.is_empty()).count();
+        if count > WALK_NAMES_MAX {
+            return Err(OsError::InvalidName);
+        }
+        let mut reply = self.rpc(TWALK, TAG, |w| {
+            w.u32(ROOT_FID).u32(newfid).u16(count as u16);
+            path.split('/').filter(|name| !name.is_empty()).for_each(|name| {
+                w.str(name);
+            });
+        })?;
+        // A walk that stops part of the way leaves `newfid` unused.
+        match reply.u16() {
+            Some(walked) if walked as usize == count => Ok(()),
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    // Returns the mode and size of the file `fid` refers to.
+    fn getattr(&mut self, fid: u32) -> Result<(u32, u64), OsError> {
+        let mut reply = self.rpc(TGETATTR, TAG, |w| {
+            w.u32(fid).u64(GETATTR_MODE | GETATTR_SIZE);
+        })?;
+        // valid[8] qid[13] mode[4] uid[4] gid[4] nlink[8] rdev[8] size[8] ...
+        reply.bytes(8 + QID_SIZE).ok_or(OsError::Corrupt)?;
+        let mode = reply.u32().ok_or(OsError::Corrupt)?;
+        reply.bytes(4 + 4 + 8 + 8).ok_or(OsError::Corrupt)?;
+        let size = reply.u64().ok_or(OsError::Corrupt)?;
+        Ok((mode, size))
+    }
+
+    fn lopen(&mut self, fid: u32, flags: u32) -> Result<(), OsError> {
+        self.rpc(TLOPEN, TAG, |w| {
+            w.u32(fid).u32(flags);
+        })?;
+        Ok(())
+    }
+
+    fn free_node(&self) -> Option<Inode> {
+        let free = self.nodes.iter().position(Option::is_none);
+        if free.is_none() {
+            log_warn!("too many files");
+        }
+        free
+    }
+
+    fn lookup(&mut self, path: &str) -> Option<Inode> {
+        if path.is_empty() {
+            return None;    // The shared directory itself
+        }
+        if let Some(inode) = self.nodes.iter().position(|n| n.as_ref().is_some_and(|n| n.path == path)) {
+            return Some(inode);
+        }
+
+        let inode = self.free_node()?;
+        let fid = node_fid(inode);
+        self.walk(fid, path).ok()?;
+        match self.getattr(fid) {
+            Ok((mode, _)) if mode & S_IFMT == S_IFREG => {},
+            _ => {
+                self.clunk(fid);
+                return None;
+            },
+        }
+        self.nodes[inode] = Some(Node { path: String::from(path), open: false });
+        Some(inode)
+    }
+
+    fn create(&mut self, path: &str) -> Result<Inode, OsError> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(OsError::InvalidName);
+        }
+        let inode = self.free_node().ok_or(OsError::NoSpace)?;
+        let fid = node_fid(inode);
+
+        // Tlcreate turns the fid of the directory into the fid of the new, open file.
+        self.walk(fid, dir)?;
+        let created = self.rpc(TLCREATE, TAG, |w| {
+            w.u32(fid).str(name).u32(O_RDWR).u32(FILE_MODE).u32(GID);
+        });
+        if let Err(err) = created {
+            self.clunk(fid);
+            return Err(err);
+        }
+        self.nodes[inode] = Some(Node { path: String::from(path), open: true });
+        Ok(inode)
+    }
+
+    // Opens the file for reading and writing on first use, or only for reading if it is read-only on the host.
+    fn open(&mut self, inode: Inode) -> Result<u32, OsError> {
+        let node = self.nodes.get(inode).and_then(Option::as_ref).ok_or(OsError::NotFound)?;
+        let fid = node_fid(inode);
+        if !node.open {
+            match self.lopen(fid, O_RDWR) {
+                Err(OsError::Permission) => self.lopen(fid, O_RDONLY),
+                opened => opened,
+            }?;
+            if let Some(node) = self.nodes[inode].as_mut() {
+                node.open = true;
+            }
+        }
+        Ok(fid)
+    }
+
+    fn read(&mut self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        let fid = self.open(inode)?;
+        let chunk_max = self.msize - IOHDRSZ;
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(chunk_max);
+            let mut reply = self.rpc(TREAD, TAG, |w| {
+                w.u32(fid).u64((offset + done) as u64).u32(count as u32);
+            })?;
+            let len = (reply.u32().ok_or(OsError::Corrupt)? as usize).min(count);
+            let data = reply.bytes(len).ok_or(OsError::Corrupt)?;
+            buf[done..done + len].copy_from_slice(data);
+            done += len;
+            if len == 0 {
+                break;  // End of file
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, inode: Inode, offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        let fid = self.open(inode)?;
+        let chunk_max = self.msize - IOHDRSZ;
+        let mut done = 0;
+        while done < buf.len() {
+            let chunk = &buf[done..(done + chunk_max).min(buf.len())];
+            let mut reply = self.rpc(TWRITE, TAG, |w| {
+                w.u32(fid).u64((offset + done) as u64).u32(chunk.len() as u32).bytes(chunk);
+            })?;
+            let len = (reply.u32().ok_or(OsError::Corrupt)? as usize).min(chunk.len());
+            done += len;
+            if len == 0 {
+                break;  // The host file system is full
+            }
+        }
+        Ok(done)
+    }
+
+    fn truncate(&mut self, inode: Inode) -> Result<(), OsError> {
+        if self.nodes.get(inode).and_then(Option::as_ref).is_none() {
+            return Err(OsError::NotFound);
+        }
+        // valid[4] mode[4] uid[4] gid[4] size[8] atime[16] mtime[16], only the size applies.
+        self.rpc(TSETATTR, TAG, |w| {
+            w.u32(node_fid(inode)).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(0)
+            .u64(0).u64(0).u64(0).u64(0);
+        })?;
+        Ok(())
+    }
+
+    // Returns the name of the `index`th regular file in the shared directory.
+    fn dir_entry_name(&mut self, index: usize) -> Result<Option<String>, OsError> {
+        self.walk(DIR_FID, "")?;
+        let found = self.lopen(DIR_FID, O_RDONLY).and_then(|()| {
+            let count = (self.msize - IOHDRSZ) as u32;
+            let mut seen = 0;
+            let mut offset = 0;
+            loop {
+                let mut reply = self.rpc(TREADDIR, TAG, |w| {
+                    w.u32(DIR_FID).u64(offset).u32(count);
+                })?;
+                let len = reply.u32().ok_or(OsError::Corrupt)? as usize;
+                let mut entries = Reader::new(reply.bytes(len).ok_or(OsError::Corrupt)?);
+                if entries.is_empty() {
+                    return Ok(None);
+                }
+                // qid[13] offset[8] type[1] name[s]
+                while !entries.is_empty() {
+                    entries.bytes(QID_SIZE).ok_or(OsError::Corrupt)?;
+                    offset = entries.u64().ok_or(OsError::Corrupt)?;
+                    let entry_type = entries.u8().ok_or(OsError::Corrupt)?;
+                    // Names that are not UTF-8 cannot be looked up, so they are skipped.
+                    let Some(name) = entries.str() else {
+                        continue;
+                    };
+                    if entry_type == DT_REG {
+                        if seen == index {
+                            return Ok(Some(String::from(name)));
+                        }
+                        seen += 1;
+                    }
+                }
+            }
+        });
+        self.clunk(DIR_FID);
+        found
+    }
+
+    fn stat(&mut self, inode: Inode) -> Result<Stat, OsError> {
+        if self.nodes.get(inode).and_then(Option::as_ref).is_none() {
+            return Err(OsError::NotFound);
+        }
+        let (_, size) = self.getattr(node_fid(inode))?;
+        Ok(Stat { size: size as usize })
+    }
+
+    fn fsync(&mut self, inode: Inode) {
+        if self.nodes.get(inode).and_then(Option::as_ref).is_some_and(|n| n.open) {
+            let _ = self.rpc(TFSYNC, TAG, |w| {
+                w.u32(node_fid(inode)).u32(0);
+            });
+        }
+    }
+}
+
+// Runs `f` on the session, failing with NotFound if the file system is not mounted.
+fn with_session<R>(f: impl FnOnce(&mut Session) -> Result<R, OsError>) -> Result<R, OsError> {
+    let mut session = SESSION.lock();
+    match session.as_mut() {
+        Some(s) if s.attached => f(s),
+        _ => Err(OsError::NotFound),
+    }
+}
+
+#[derive(Debug)]
+pub struct V9fs;
+
+impl FileSystem for V9fs {
+    fn lookup(&self, name: &str) -> Option<Inode> {
+        with_session(|s| s.lookup(name).ok_or(OsError::NotFound)).ok()
+    }
+
+    fn create(&self, name: &str) -> Result<Inode, OsError> {
+        with_session(|s| s.create(name))
+    }
+
+    fn read(&self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+        with_session(|s| s.read(inode, offset, buf))
+    }
+
+    fn write(&self, inode: Inode, offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        with_session(|s| s.write(inode, offset, buf))
+    }
+
+    fn truncate(&self, inode: Inode) -> Result<(), OsError> {
+        with_session(|s| s.truncate(inode))
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        with_session(|s| {
+            let name = s.dir_entry_name(index)?.ok_or(OsError::NotFound)?;
+            let inode = s.lookup(&name).ok_or(OsError::NotFound)?;
+            Ok(DirEntry { name, inode })
+        }).ok()
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError> {
+        with_session(|s| s.stat(inode))
+    }
+
+    // Writes go straight to the host, so syncing only asks the host to write them to its disk.
+    fn sync(&self, inode: Inode) {
+        let _ = with_session(|s| {
+            s.fsync(inode);
+            Ok(())
+        });
+    }
+
+    fn sync_all(&self) {
+        let _ = with_session(|s| {
+            (0..NODES_MAX).for_each(|inode| s.fsync(inode));
+            Ok(())
+        });
+    }
+}
+
+pub static V9FS: V9fs = V9fs;
+
+// Connects to the shared directory, returning the file system ready to mount.
+// Fails with NotFound without a 9P device, and with Busy if it is already mounted.
+pub fn v9fs_attach() -> Result<&'static V9fs, OsError> {
+    if !virtio_9p_present() {
+        return Err(OsError::NotFound);
+    }
+    let mut session = SESSION.lock();
+    let session = session.get_or_insert_with(Session::new);
+    if session.attached {
+        return Err(OsError::Busy);
+    }
+    session.attach()?;
+    log_info!("attached to the shared directory");
+    Ok(&V9FS)
+}
+
+// Called once the file system is unmounted. Files still open on it stop working.
+pub fn v9fs_detach() {
+    if let Some(session) = SESSION.lock().as_mut().filter(|s| s.attached) {
+        session.detach();
+    }
+}
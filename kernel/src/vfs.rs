@@ -0,0 +1,280 @@
+//! Virtual file system for os1k
+//!
+//! File systems are mounted at a path, and every path-taking syscall goes through the mount table
+//! to find the file system responsible for the path.
+
+use core::fmt;
+
+use alloc::string::String;
+use alloc::vec;
+
+use common::{OPEN_APPEND, OPEN_CREATE, OPEN_TRUNC};
+
+pub use common::OsError;
+
+use crate::log_debug;
+use crate::spinlock::SpinLock;
+
+const MOUNTS_MAX: usize = 8;
+
+// Identifies a file within its file system.
+pub type Inode = usize;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub size: usize,
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: Inode,
+}
+
+pub trait FileSystem: Sync {
+    // File names within a file system have no slashes: the path up to the mount point is removed.
+    fn lookup(&self, name: &str) -> Option<Inode>;
+    fn create(&self, name: &str) -> Result<Inode, OsError>;
+    // Removes the entry called `name`. Removing a link leaves the file it leads to.
+    fn unlink(&self, _name: &str) -> Result<(), OsError> {
+        Err(OsError::Unsupported)
+    }
+    // Gives the entry called `from` the name `to`, which must not exist yet.
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), OsError> {
+        Err(OsError::Unsupported)
+    }
+    // Reads are bounded by the file size, so reading at or past the end returns 0.
+    fn read(&self, inode: Inode, offset: usize, buf: &mut [u8]) -> Result<usize, OsError>;
+    // Writes past the end grow the file. The returned length is short if the file system is full.
+    fn write(&self, inode: Inode, offset: usize, buf: &[u8]) -> Result<usize, OsError>;
+    // Empties the file, so it can be written again from the start without keeping an old tail.
+    fn truncate(&self, _inode: Inode) -> Result<(), OsError> {
+        Err(OsError::Unsupported)
+    }
+    // Returns the `index`th entry of the directory, or None after the last one.
+    fn readdir(&self, index: usize) -> Option<DirEntry>;
+    fn stat(&self, inode: Inode) -> Result<Stat, OsError>;
+    // Writes any changes to the file back to the disk.
+    fn sync(&self, inode: Inode);
+    // Writes any changes to every file back to the disk.
+    fn sync_all(&self);
+    // Called when a descriptor for the file is copied, and when one is closed, for file systems
+    // that need to know whether a file is still open. The first descriptor comes from opening
+    // the file, or for a pipe, from making it.
+    fn dup(&self, _inode: Inode) {}
+    fn close(&self, _inode: Inode) {}
+    // Whether the file system refuses changes: creating or writing a file fails with ReadOnly.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+struct Mount {
+    path: String,   // Mount point without leading or trailing slashes, "" for the root
+    fs: &'static dyn FileSystem,
+}
+
+static MOUNTS: SpinLock<[Option<Mount>; MOUNTS_MAX]> = SpinLock::new([const { None }; MOUNTS_MAX]);
+
+// Resolves `.`, `..`, repeated slashes and trailing slashes, returning the path below the root
+// without leading or trailing slashes.
+pub fn normalize(path: &str) -> String {
+    // Normalizing never makes a path longer.
+    let mut buf = vec![0u8; path.len()];
+    String::from(common::path::normalize(path, &mut buf).expect("normalized path should fit"))
+}
+
+pub fn mount(path: &str, fs: &'static dyn FileSystem) -> Result<(), OsError> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.iter().flatten().any(|m| m.path == path) {
+        return Err(OsError::Exists);
+    }
+
+    let slot = mounts.iter_mut()
+        .find(|m| m.is_none())
+        .ok_or(OsError::NoSpace)?;
+    *slot = Some(Mount { path, fs });
+    Ok(())
+}
+
+// Removes the file system mounted at `path` and writes its changes to the disk.
+// The root cannot be unmounted.
+pub fn umount(path: &str) -> Result<&'static dyn FileSystem, OsError> {
+    let path = normalize(path);
+    if path.is_empty() {
+        return Err(OsError::Busy);
+    }
+
+    let fs = MOUNTS.lock().iter_mut()
+        .find(|m| m.as_ref().is_some_and(|m| m.path == path))
+        .and_then(Option::take)
+        .map(|m| m.fs)
+        .ok_or(OsError::NotFound)?;
+    // Synced with the mount table unlocked, as it may do disk I/O.
+    fs.sync_all();
+    Ok(fs)
+}
+
+// Finds the file system with the longest mount point containing `path`,
+// and returns it with the rest of the path below the mount point.
+fn resolve(path: &str) -> Result<(&'static dyn FileSystem, String), OsError> {
+    let path = normalize(path);
+    let path = path.as_str();
+    let mounts = MOUNTS.lock();
+
+    mounts.iter()
+        .flatten()
+        .filter_map(|m| {
+            let rest = if m.path.is_empty() {
+                path
+            } else {
+                let rest = path.strip_prefix(m.path.as_str())?;
+                // Only match whole path components: /dev matches /dev/null but not /device
+                match rest {
+                    "" => rest,
+                    _ => rest.strip_prefix('/')?,
+                }
+            };
+            Some((m.path.len(), m.fs, rest))
+        })
+        .max_by_key(|(len, _, _)| *len)
+        .map(|(_, fs, rest)| (fs, String::from(rest)))
+        .ok_or(OsError::NotFound)
+}
+
+pub fn lookup(path: &str) -> Result<(&'static dyn FileSystem, Inode), OsError> {
+    let (fs, name) = resolve(path)?;
+    let inode = fs.lookup(&name).ok_or(OsError::NotFound)?;
+    Ok((fs, inode))
+}
+
+pub fn create(path: &str) -> Result<(&'static dyn FileSystem, Inode), OsError> {
+    let (fs, name) = resolve(path)?;
+    if fs.lookup(&name).is_some() {
+        return Err(OsError::Exists);
+    }
+    if fs.read_only() {
+        return Err(OsError::ReadOnly);
+    }
+    let inode = fs.create(&name)?;
+    Ok((fs, inode))
+}
+
+// Removes the file at `path`. Checking that no process has it open is up to the caller.
+pub fn unlink(path: &str) -> Result<(), OsError> {
+    let (fs, name) = resolve(path)?;
+    if fs.read_only() {
+        return Err(OsError::ReadOnly);
+    }
+    fs.unlink(&name)
+}
+
+// Moves the file at `from` to `to`. Files cannot move between file systems.
+pub fn rename(from: &str, to: &str) -> Result<(), OsError> {
+    let (fs, from) = resolve(from)?;
+    let (to_fs, to) = resolve(to)?;
+    if !core::ptr::addr_eq(fs, to_fs) {
+        return Err(OsError::Unsupported);
+    }
+    if fs.read_only() {
+        return Err(OsError::ReadOnly);
+    }
+    fs.rename(&from, &to)
+}
+
+// Returns the `index`th entry of the directory at `path`, or None after the last one. Only the
+// root of a file system is a directory.
+pub fn readdir(path: &str, index: usize) -> Result<Option<DirEntry>, OsError> {
+    let (fs, name) = resolve(path)?;
+    if !name.is_empty() {
+        return Err(if fs.lookup(&name).is_some() { OsError::Invalid } else { OsError::NotFound });
+    }
+    Ok(fs.readdir(index))
+}
+
+// An open file in a process's file descriptor table.
+#[derive(Clone, Copy)]
+pub struct OpenFile {
+    pub fs: &'static dyn FileSystem,
+    pub inode: Inode,
+    pub offset: usize,      // Where the next read or write starts
+    pub append: bool,       // Writes ignore the offset and go to the end of the file
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpenFile {{ inode: {}, offset: {}, append: {} }}", self.inode, self.offset, self.append)
+    }
+}
+
+impl OpenFile {
+    // The offset the next write goes to.
+    pub fn write_offset(&self) -> usize {
+        if self.append {
+            self.fs.stat(self.inode).map_or(self.offset, |stat| stat.size)
+        } else {
+            self.offset
+        }
+    }
+
+    // Tells the file system a descriptor for the file was copied to another.
+    pub fn dup(&self) {
+        self.fs.dup(self.inode);
+    }
+
+    // Tells the file system a descriptor for the file was closed.
+    pub fn close(&self) {
+        self.fs.close(self.inode);
+    }
+
+    // Writes `buf` at `offset` into the file, unless its file system is read-only.
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, OsError> {
+        if self.fs.read_only() {
+            return Err(OsError::ReadOnly);
+        }
+        self.fs.write(self.inode, offset, buf)
+    }
+}
+
+// Looks up `path`, creating it if it does not exist and `flags` has OPEN_CREATE, and emptying
+// it if `flags` has OPEN_TRUNC.
+pub fn open(path: &str, flags: usize) -> Result<OpenFile, OsError> {
+    let (fs, inode) = match lookup(path) {
+        Err(OsError::NotFound) if flags & OPEN_CREATE != 0 => create(path),
+        found => found,
+    }?;
+    if flags & OPEN_TRUNC != 0 {
+        if fs.read_only() {
+            return Err(OsError::ReadOnly);
+        }
+        fs.truncate(inode)?;
+    }
+    Ok(OpenFile { fs, inode, offset: 0, append: flags & OPEN_APPEND != 0 })
+}
+
+pub fn sync_all() {
+    // Copy the file systems out so the mount table is not locked during disk I/O.
+    let mut mounted: [Option<&'static dyn FileSystem>; MOUNTS_MAX] = [None; MOUNTS_MAX];
+    for (fs, m) in mounted.iter_mut().zip(MOUNTS.lock().iter()) {
+        *fs = m.as_ref().map(|m| m.fs);
+    }
+    mounted.iter().flatten().for_each(|fs| fs.sync_all());
+}
+
+// Prints every mount point and the files below it.
+pub fn list() {
+    let mut mounted: [Option<(String, &'static dyn FileSystem)>; MOUNTS_MAX] = [const { None }; MOUNTS_MAX];
+    for (entry, m) in mounted.iter_mut().zip(MOUNTS.lock().iter()) {
+        *entry = m.as_ref().map(|m| (m.path.clone(), m.fs));
+    }
+
+    for (path, fs) in mounted.iter().flatten() {
+        log_debug!("mount: /{}", path);
+        for entry in (0..).map_while(|i| fs.readdir(i)) {
+            let size = fs.stat(entry.inode).map_or(0, |s| s.size);
+            log_debug!("file: {}, size={}", entry.name, size);
+        }
+    }
+}
@@ -6,37 +6,50 @@ use core::ptr;
 
 use alloc::boxed::Box;
 
-use crate::println;
-use crate::spinlock::SpinLock;
-
-pub const SECTOR_SIZE: usize =       512;
-const VIRTQ_ENTRY_NUM: usize =       16;
+use crate::once::Once;
+use crate::plic::plic_enable;
+use crate::semaphore::Semaphore;
+use crate::{log_info, log_warn};
+use crate::condvar::Condvar;
+use crate::spinlock::{Guard, SpinLock};
+use crate::volatile::{ReadOnly, Volatile, WriteOnly};
+use crate::virtio_net::virtio_net_handle_interrupt;
+
+pub use common::block::SECTOR_SIZE;
+pub const VIRTQ_ENTRY_NUM: usize =   16;
+const SEGS_MAX: usize =              6;  // Data buffers per request
+const REQ_DESCS_MAX: usize =         SEGS_MAX + 2;  // Descriptors per request: header, data buffers and status
+pub const VIRTIO_DEVICE_NET: u32 =   1;
 const VIRTIO_DEVICE_BLK: u32 =       2;
-pub const VIRTIO_BLK_PADDR: u32 = 0x10001000;
-const VIRTIO_REG_MAGIC: u32 =         0x00;
-const VIRTIO_REG_VERSION: u32 =       0x04;
-const VIRTIO_REG_DEVICE_ID: u32 =     0x08;
-const VIRTIO_REG_QUEUE_SEL: u32 =     0x30;
-#[expect(dead_code)]
-const VIRTIO_REG_QUEUE_NUM_MAX: u32 = 0x34;
-const VIRTIO_REG_QUEUE_NUM: u32 =     0x38;
-const VIRTIO_REG_QUEUE_ALIGN: u32 =   0x3c;
-const VIRTIO_REG_QUEUE_PFN: u32 =     0x40;
-#[expect(dead_code)]
-const VIRTIO_REG_QUEUE_READY: u32 =   0x44;
-const VIRTIO_REG_QUEUE_NOTIFY: u32 =  0x50;
-const VIRTIO_REG_DEVICE_STATUS: u32 = 0x70;
-const VIRTIO_REG_DEVICE_CONFIG: u32 = 0x100;
+pub const VIRTIO_DEVICE_CONSOLE: u32 = 3;
+pub const VIRTIO_DEVICE_9P: u32 =    9;
+pub const VIRTIO_DEVICE_GPU: u32 =   16;
+pub const VIRTIO_MMIO_PADDR: u32 =   0x10001000;    // First virtio-mmio slot of the QEMU virt machine
+pub const VIRTIO_MMIO_SIZE: u32 =    0x1000;        // Size of each slot
+pub const VIRTIO_MMIO_SLOTS: usize = 8;
+const VIRTIO_MMIO_IRQ: u32 =         1;             // Interrupt of the first slot, the others follow it
+const VIRTIO_MAGIC: u32 =            0x74726976;    // "virt" little endian
+const VIRTIO_CONFIG_OFFSET: u32 = 0x100;   // Device-specific configuration space, after the registers
 const VIRTIO_STATUS_ACK: u32 =       1;
 const VIRTIO_STATUS_DRIVER: u32 =    2;
 const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
 const VIRTIO_STATUS_FEAT_OK: u32 =   8;
+const VIRTIO_BLK_F_SEG_MAX: u32 =    1 << 2;    // seg_max in the config space limits data buffers per request
+const VIRTIO_BLK_F_RO: u32 =         1 << 5;    // The disk is read-only
+const VIRTIO_BLK_F_FLUSH: u32 =      1 << 9;    // The device has a write cache, emptied by VIRTIO_BLK_T_FLUSH
+const VIRTIO_BLK_FEATURES: u32 =     VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH;  // Features the driver understands
+const VIRTIO_BLK_CONFIG_CAPACITY: u32 = 0;     // In sectors
+const VIRTIO_BLK_CONFIG_SEG_MAX: u32 = 12;
+const VIRTIO_F_VERSION_1: u32 =      1 << 0;    // In the second word of features: required by version 2 devices
+const VIRTIO_VERSION_LEGACY: u32 =   1;
+pub const VIRTIO_VERSION_MODERN: u32 = 2;
 const VIRTQ_DESC_F_NEXT: u32 =          1;
 const VIRTQ_DESC_F_WRITE: u32 =         2;
 #[expect(dead_code)]
 const VIRTQ_AVAIL_F_NO_INTERRUPT: u32 = 1;
 const VIRTIO_BLK_T_IN: u32 =  0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
 
 // Virtqueue Descriptor area entry.
 #[repr(C, packed)]
@@ -82,13 +95,16 @@ struct AlignedVirtqUsed(VirtqUsed);
 // Virtqueue.
 #[repr(C)]  // Not packed, as VirtqUsed is aligned to page size
 #[derive(Debug)]
-struct VirtioVirtq {
+pub struct VirtioVirtq {
     descs: [VirtqDesc; VIRTQ_ENTRY_NUM],
     avail: VirtqAvail,
     used: AlignedVirtqUsed,  // Needs align to page size
+    dev: VirtioDevice,       // Device the queue belongs to
     queue_index: u16,
     used_index: *mut u16, // Only access using ptr::read_volatile
-    last_used_index: u16,
+    last_used_index: u16, // Used ring entries already handled
+    free_head: u16,       // First free descriptor, the rest are chained through `next`
+    num_free: usize,      // Number of free descriptors
 }
 
 impl VirtioVirtq {
@@ -106,197 +122,500 @@ unsafe impl Sync for VirtioVirtq {}
 // no concurrent access occurs. The hardware is accessible from any CPU core.
 unsafe impl Send for VirtioVirtq {}
 
-// Virtio-blk request.
+// Virtio-blk request header and status. The data is read or written in place in the caller's buffers.
 #[repr(C, packed)]
 #[derive(Debug)]
 struct VirtioBlkReq {
     req_type: u32,
     reserved: u32,
     sector: u64,
-    data: [u8; 512],
     status: u8,
 }
 
 impl VirtioBlkReq {
     fn zeroed() -> Self {
-        // SAFETY: VirtioBlkReq is a packed C struct with only integer/array fields.
+        // SAFETY: VirtioBlkReq is a packed C struct with only integer fields.
         // All-zero bytes is a valid representation for this type.
         unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
     }
 }
 
-static BLK_REQUEST_VQ: SpinLock<Option<Box<VirtioVirtq>>> = SpinLock::new(None);
+pub const BLK_DEVICES_MAX: usize = 4;
 
-static BLK_REQ: SpinLock<Option<Box<VirtioBlkReq>>> = SpinLock::new(None);
+// A virtio-blk device's settings, fixed once it is set up.
+#[derive(Clone, Copy, Debug)]
+struct BlkInfo {
+    capacity: u64,      // In bytes
+    features: u32,      // Negotiated features
+    segs_max: usize,    // Most data buffers per request
+}
 
-static BLK_CAPACITY: SpinLock<Option<u64>> = SpinLock::new(None);
+// Block devices are numbered in the order they were found, from 0. Everything below is indexed by that unit.
+static BLK_INFO: SpinLock<[Option<BlkInfo>; BLK_DEVICES_MAX]> = SpinLock::new([None; BLK_DEVICES_MAX]);
 
-fn virtio_reg_read32(offset: u32) -> u32 {
-    // Safety:
-    // * VIRTIO_BLK_PADDR + offset is valid for reads
-    // * VIRTIO_BLK_PADDR is 32-bit aligned and offset is 32-bit aligned
-    // * VIRTIO_BLK_PADDR + offset points to a QEMU initialized `u32`
-    // * `u32` is Copy
-    assert_eq!((VIRTIO_BLK_PADDR + offset) % align_of::<u32>() as u32, 0);
-    unsafe {
-        ptr::read_volatile((VIRTIO_BLK_PADDR + offset) as *const u32)
-    }
+static BLK_REQUEST_VQ: [SpinLock<Option<Box<VirtioVirtq>>>; BLK_DEVICES_MAX] = [const { SpinLock::new(None) }; BLK_DEVICES_MAX];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReqState {
+    Free,
+    InFlight,   // Submitted to the device
+    Done,       // Completed by the device, not yet collected by the requester
 }
 
-fn virtio_reg_read64(offset: u32) -> u64 {
-    // Safety:
-    // * VIRTIO_BLK_PADDR + offset is valid for reads
-    // * VIRTIO_BLK_PADDR is 64-bit aligned and offset is 64-bit aligned
-    // * VIRTIO_BLK_PADDR + offset points to a QEMU initialized `u64`
-    // * `u64` is Copy
-    assert_eq!((VIRTIO_BLK_PADDR + offset) % align_of::<u64>() as u32, 0);
-    unsafe {
-        ptr::read_volatile((VIRTIO_BLK_PADDR + offset) as *const u64)
+// Virtio-blk requests, indexed by the head descriptor of their chain, which is also
+// the id the device puts in the used ring.
+#[derive(Debug)]
+struct BlkReqs {
+    reqs: Box<[VirtioBlkReq; VIRTQ_ENTRY_NUM]>,
+    state: [ReqState; VIRTQ_ENTRY_NUM],
+}
+
+// Lock a unit's BLK_REQ before its BLK_REQUEST_VQ.
+static BLK_REQ: [SpinLock<Option<BlkReqs>>; BLK_DEVICES_MAX] = [const { SpinLock::new(None) }; BLK_DEVICES_MAX];
+
+// Raised when a unit's request completes, indexed like BLK_REQ's requests.
+static BLK_DONE: [[Semaphore; VIRTQ_ENTRY_NUM]; BLK_DEVICES_MAX] =
+    [const { [const { Semaphore::new(0) }; VIRTQ_ENTRY_NUM] }; BLK_DEVICES_MAX];
+
+// Notified, with BLK_REQ unlocked, when a unit's descriptors are freed.
+static BLK_DESCS_FREED: [Condvar; BLK_DEVICES_MAX] = [const { Condvar::new() }; BLK_DEVICES_MAX];
+
+fn blk_info(unit: usize) -> BlkInfo {
+    BLK_INFO.lock()[unit].expect("virtio-blk unit should be set up")
+}
+
+// The virtio-mmio registers. Legacy devices use QueueAlign and QueuePFN to find a queue, modern
+// devices QueueReady and the queue address pairs, which take the low word first.
+#[repr(C)]
+struct VirtioMmioRegs {
+    magic: ReadOnly<u32>,                   // 0x00
+    version: ReadOnly<u32>,
+    device_id: ReadOnly<u32>,
+    vendor_id: ReadOnly<u32>,
+    device_features: ReadOnly<u32>,         // 0x10
+    device_features_sel: WriteOnly<u32>,
+    _reserved0: [u32; 2],
+    driver_features: WriteOnly<u32>,        // 0x20
+    driver_features_sel: WriteOnly<u32>,
+    _reserved1: [u32; 2],
+    queue_sel: WriteOnly<u32>,              // 0x30
+    queue_num_max: ReadOnly<u32>,
+    queue_num: WriteOnly<u32>,
+    queue_align: WriteOnly<u32>,            // Legacy only
+    queue_pfn: Volatile<u32>,               // 0x40, legacy only
+    queue_ready: Volatile<u32>,             // Modern only
+    _reserved2: [u32; 2],
+    queue_notify: WriteOnly<u32>,           // 0x50
+    _reserved3: [u32; 3],
+    interrupt_status: ReadOnly<u32>,        // 0x60
+    interrupt_ack: WriteOnly<u32>,
+    _reserved4: [u32; 2],
+    status: Volatile<u32>,                  // 0x70
+    _reserved5: [u32; 3],
+    queue_desc: [WriteOnly<u32>; 2],        // 0x80, modern only like the other queue addresses
+    _reserved6: [u32; 2],
+    queue_driver: [WriteOnly<u32>; 2],      // 0x90
+    _reserved7: [u32; 2],
+    queue_device: [WriteOnly<u32>; 2],      // 0xa0
+}
+
+const _: () = assert!(offset_of!(VirtioMmioRegs, queue_pfn) == 0x40);
+const _: () = assert!(offset_of!(VirtioMmioRegs, status) == 0x70);
+const _: () = assert!(offset_of!(VirtioMmioRegs, queue_device) == 0xa0);
+
+// Writes a 64-bit value to a pair of 32-bit registers, low word first.
+fn write_pair(regs: &[WriteOnly<u32>; 2], value: u64) {
+    regs[0].write(value as u32);
+    regs[1].write((value >> 32) as u32);
+}
+
+// A virtio-mmio device found by `virtio_probe`.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioDevice {
+    pub paddr: u32,         // Base of the device's registers
+    pub irq: u32,           // PLIC interrupt source
+    pub device_id: u32,     // Device type, such as VIRTIO_DEVICE_BLK
+    pub version: u32,       // VIRTIO_VERSION_LEGACY or VIRTIO_VERSION_MODERN
+}
+
+impl VirtioDevice {
+    fn regs(&self) -> &'static VirtioMmioRegs {
+        // Safety:
+        // * self.paddr is a virtio-mmio slot, which is mapped in every page table
+        // * the slot is 4KB aligned and starts with the registers
+        unsafe { &*(self.paddr as *const VirtioMmioRegs) }
+    }
+
+    fn set_status(&self, bit: u32) {
+        let regs = self.regs();
+        regs.status.write(regs.status.read() | bit);
+    }
+
+    // Reads a field of the device-specific configuration space, `offset` bytes in.
+    pub fn read_config<T: Copy>(&self, offset: u32) -> T {
+        let addr = self.paddr + VIRTIO_CONFIG_OFFSET + offset;
+        assert_eq!(addr % align_of::<T>() as u32, 0);
+        // Safety: the configuration space follows the registers, QEMU initialises it, and addr is aligned.
+        unsafe {
+            ptr::read_volatile(addr as *const T)
+        }
+    }
+
+    // Acknowledges every pending interrupt of the device.
+    pub fn ack_interrupt(&self) {
+        let regs = self.regs();
+        regs.interrupt_ack.write(regs.interrupt_status.read());
+    }
+
+    // Resets the device and negotiates features: steps 1 to 6 of device initialisation.
+    // Returns the features in `supported` that the device offers, which are now in use.
+    pub fn negotiate(&self, supported: u32) -> u32 {
+        let regs = self.regs();
+        // 1. Reset the device
+        regs.status.write(0);
+        // 2. Set the ACKNOWLEDGE status bit: the guest OS has noticed the device
+        self.set_status(VIRTIO_STATUS_ACK);
+        // 3. Set the DRIVER status bit.
+        self.set_status(VIRTIO_STATUS_DRIVER);
+        // 4. Read device feature bits, and write the subset understood by the driver to the device.
+        regs.device_features_sel.write(0);
+        let features = regs.device_features.read() & supported;
+        regs.driver_features_sel.write(0);
+        regs.driver_features.write(features);
+        if self.version == VIRTIO_VERSION_MODERN {
+            regs.device_features_sel.write(1);
+            if regs.device_features.read() & VIRTIO_F_VERSION_1 == 0 {
+                panic!("virtio: modern device does not offer VIRTIO_F_VERSION_1");
+            }
+            regs.driver_features_sel.write(1);
+            regs.driver_features.write(VIRTIO_F_VERSION_1);
+        }
+        // 5. Set the FEATURES_OK status bit
+        self.set_status(VIRTIO_STATUS_FEAT_OK);
+        // 6. Re-read device status to ensure the FEATURES_OK bit is still set
+        if regs.status.read() & VIRTIO_STATUS_FEAT_OK == 0 {
+            panic!("virtio: device rejected features 0x{:x}", features);
+        }
+        features
+    }
+
+    // Step 8 of device initialisation, once the virtqueues are set up: the device is live.
+    pub fn driver_ok(&self) {
+        self.regs().status.write(VIRTIO_STATUS_DRIVER_OK);
     }
 }
 
-fn virtio_reg_write32(offset: u32, value: u32) {
-    // Safety:
-    // * VIRTIO_BLK_PADDR + offset is valid for writes.
-    // * VIRTIO_BLK_PADDR + offset is properly 32-bit aligned.
-    assert_eq!((VIRTIO_BLK_PADDR + offset) % align_of::<u32>() as u32, 0);
-    unsafe {
-        ptr::write_volatile((VIRTIO_BLK_PADDR + offset) as *mut u32, value)
+// Devices found in the virtio-mmio slots, indexed by slot.
+// Set once by virtio_probe.
+static DEVICES: Once<[Option<VirtioDevice>; VIRTIO_MMIO_SLOTS]> = Once::new();
+
+// Scans the virtio-mmio slots and records the device in each.
+pub fn virtio_probe() {
+    let mut devices = [None; VIRTIO_MMIO_SLOTS];
+    for (i, slot) in devices.iter_mut().enumerate() {
+        let mut dev = VirtioDevice {
+            paddr: VIRTIO_MMIO_PADDR + i as u32 * VIRTIO_MMIO_SIZE,
+            irq: VIRTIO_MMIO_IRQ + i as u32,
+            device_id: 0,
+            version: 0,
+        };
+        let regs = dev.regs();
+        if regs.magic.read() != VIRTIO_MAGIC {
+            continue;
+        }
+        dev.version = regs.version.read();
+        dev.device_id = regs.device_id.read();
+        // Device ID 0 marks an empty slot.
+        if dev.device_id == 0 {
+            continue;
+        }
+        // Version 1 is the legacy interface, version 2 the modern one.
+        if dev.version != VIRTIO_VERSION_LEGACY && dev.version != VIRTIO_VERSION_MODERN {
+            log_warn!("slot {}: ignoring device {} with unsupported version {}", i, dev.device_id, dev.version);
+            continue;
+        }
+        log_info!("slot {}: device {} at 0x{:x}", i, dev.device_id, dev.paddr);
+        *slot = Some(dev);
+    }
+    if DEVICES.set(devices).is_err() {
+        panic!("virtio: probed twice");
     }
 }
 
-fn virtio_reg_fetch_and_or32(offset: u32, value: u32) {
-    // Safety:
-    // * Caller ensures VIRTIO_BLK_PADDR + offset is valid for reads and writes
-    virtio_reg_write32(offset, virtio_reg_read32(offset) | value);
+// Every device found, in slot order. None before virtio_probe.
+fn devices() -> impl Iterator<Item = &'static VirtioDevice> {
+    DEVICES.get().into_iter().flatten().flatten()
 }
 
-#[allow(clippy::identity_op)]
-pub fn virtio_blk_init() {
-    if virtio_reg_read32(VIRTIO_REG_MAGIC) != 0x74726976 {
-        panic!("virtio: invalid magic value");
-    };
-    if virtio_reg_read32(VIRTIO_REG_VERSION) != 1 {
-        panic!("virtio: invalid version");
-    };
+// Returns the first device of type `device_id`.
+pub fn virtio_find(device_id: u32) -> Option<VirtioDevice> {
+    devices().find(|d| d.device_id == device_id).copied()
+}
 
-    if virtio_reg_read32(VIRTIO_REG_DEVICE_ID) != VIRTIO_DEVICE_BLK {
-        panic!("virtio: invalid version");
+// Resets every device, so none of them reads or writes memory any more.
+pub fn virtio_reset_all() {
+    for dev in devices() {
+        dev.regs().status.write(0);
+    }
+}
+
+// Handles an interrupt from the PLIC, if it belongs to a virtio device.
+pub fn virtio_handle_interrupt(irq: u32) {
+    let Some(dev) = devices().find(|d| d.irq == irq).copied() else {
+        return;
     };
+    match dev.device_id {
+        VIRTIO_DEVICE_BLK => virtio_blk_handle_interrupt(&dev),
+        VIRTIO_DEVICE_NET => virtio_net_handle_interrupt(&dev),
+        _ => log_warn!("unexpected interrupt from device {}", dev.device_id),
+    }
+}
+
+// Sets up every block device, up to BLK_DEVICES_MAX. Returns how many there are.
+pub fn virtio_blk_init() -> usize {
+    let blk_devices = devices().filter(|d| d.device_id == VIRTIO_DEVICE_BLK);
+    let mut count = 0;
+    for (unit, dev) in blk_devices.enumerate() {
+        if unit == BLK_DEVICES_MAX {
+            log_warn!("blk: ignoring block devices after the first {}", BLK_DEVICES_MAX);
+            break;
+        }
+        virtio_blk_init_unit(unit, dev);
+        count += 1;
+    }
+    if count == 0 {
+        log_info!("blk: no block device");
+    }
+    count
+}
 
-    // 1. Reset the device
-    virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, 0);
-    // 2. Set the ACKNOWLEDGE status bit: the guest OS has noticed the device
-    virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
-    // 3. Set the DRIVER status bit.
-    virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
-    // 5. Set the FEATURES_OK status bit
-    virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
+#[allow(clippy::identity_op)]
+fn virtio_blk_init_unit(unit: usize, dev: &VirtioDevice) {
+    let features = dev.negotiate(VIRTIO_BLK_FEATURES);
     // 7. Perform device-specific setup, including discovery of virtqueues for the device
-    *BLK_REQUEST_VQ.lock() = Some(virtq_init(0));
+    *BLK_REQUEST_VQ[unit].lock() = Some(virtq_init(dev, 0));
     // 8. Set the DRIVER_OK status bit.
-    virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+    dev.driver_ok();
 
     // Get the disk capacity.
-    *BLK_CAPACITY.lock() = Some(virtio_reg_read64(VIRTIO_REG_DEVICE_CONFIG + 0) * SECTOR_SIZE as u64);
+    let capacity = dev.read_config::<u64>(VIRTIO_BLK_CONFIG_CAPACITY) * SECTOR_SIZE as u64;
+    log_info!("blk{}: capacity is {} bytes", unit, capacity);
 
-    match *BLK_CAPACITY.lock() {
-        Some(capacity) => println!("virtio-blk: capacity is {} bytes", capacity),
-        None => println!("virtio-blk: capacity is not initialized yet"),
+    let mut segs_max = SEGS_MAX;
+    if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+        let seg_max = dev.read_config::<u32>(VIRTIO_BLK_CONFIG_SEG_MAX) as usize;
+        segs_max = seg_max.clamp(1, SEGS_MAX);
     }
+    if features & VIRTIO_BLK_F_RO != 0 {
+        log_info!("blk{}: disk is read-only", unit);
+    }
+    BLK_INFO.lock()[unit] = Some(BlkInfo { capacity, features, segs_max });
 
     // Allocate a region to store requests to the device.
-    *BLK_REQ.lock() = Some(Box::new(VirtioBlkReq::zeroed()));
+    *BLK_REQ[unit].lock() = Some(BlkReqs {
+        reqs: Box::new(core::array::from_fn(|_| VirtioBlkReq::zeroed())),
+        state: [ReqState::Free; VIRTQ_ENTRY_NUM],
+    });
+
+    // Completed requests raise an interrupt.
+    plic_enable(dev.irq);
 }
 
-fn virtq_init(index: usize) ->  Box<VirtioVirtq> {
+pub fn virtq_init(dev: &VirtioDevice, index: usize) ->  Box<VirtioVirtq> {
     // Allocate a region for the virtqueue.
     let mut vq = Box::new(VirtioVirtq::zeroed());
 
+    vq.dev = *dev;
     vq.queue_index = index as u16;
     vq.used_index = &raw mut vq.used.0.index; // Create pointer for read_volatile
 
+    // Every descriptor starts on the free list.
+    for (i, desc) in vq.descs.iter_mut().enumerate() {
+        desc.next = (i + 1) as u16;
+    }
+    vq.free_head = 0;
+    vq.num_free = VIRTQ_ENTRY_NUM;
+
+    let regs = dev.regs();
     // 1. Select the queue writing its index (first queue is 0) to QueueSel.
-    virtio_reg_write32(VIRTIO_REG_QUEUE_SEL, index as u32);
+    regs.queue_sel.write(index as u32);
+    // 3. Read maximum queue size (number of elements) from QueueNumMax.
+    if (regs.queue_num_max.read() as usize) < VIRTQ_ENTRY_NUM {
+        panic!("virtio: queue {} is smaller than {} entries", index, VIRTQ_ENTRY_NUM);
+    }
     // 5. Notify the device about the queue size by writing the size to QueueNum.
-    virtio_reg_write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
-    // 6. Notify the device about the used alignment by writing its value in bytes to QueueAlign.
-    virtio_reg_write32(VIRTIO_REG_QUEUE_ALIGN, 0);
-    // 7. Write the physical number of the first page of the queue to the QueuePFN register.
-    virtio_reg_write32(VIRTIO_REG_QUEUE_PFN, &*vq as * const _ as u32); // In our OS the virtual address matches the physical address
+    regs.queue_num.write(VIRTQ_ENTRY_NUM as u32);
+
+    // In our OS the virtual address matches the physical address
+    if dev.version == VIRTIO_VERSION_LEGACY {
+        // 6. Notify the device about the used alignment by writing its value in bytes to QueueAlign.
+        regs.queue_align.write(0);
+        // 7. Write the physical number of the first page of the queue to the QueuePFN register.
+        regs.queue_pfn.write(&*vq as * const _ as u32);
+    } else {
+        // Modern devices take the address of each part of the queue, then QueueReady.
+        write_pair(&regs.queue_desc, &raw const vq.descs as u64);
+        write_pair(&regs.queue_driver, &raw const vq.avail as u64);
+        write_pair(&regs.queue_device, &raw const vq.used as u64);
+        regs.queue_ready.write(1);
+    }
 
     vq
 }
 
+// Takes a descriptor off the free list.
+pub fn virtq_alloc_desc(vq: &mut VirtioVirtq) -> Option<u16> {
+    if vq.num_free == 0 {
+        return None;
+    }
+    let desc = vq.free_head;
+    vq.free_head = vq.descs[desc as usize].next;
+    vq.num_free -= 1;
+    Some(desc)
+}
+
+// Returns the chain of descriptors starting at `head` to the free list.
+pub fn virtq_free_chain(vq: &mut VirtioVirtq, head: u16) {
+    let mut desc = head;
+    loop {
+        let VirtqDesc { flags, next, .. } = vq.descs[desc as usize];
+        vq.descs[desc as usize].next = vq.free_head;
+        vq.free_head = desc;
+        vq.num_free += 1;
+        if flags & VIRTQ_DESC_F_NEXT as u16 == 0 {
+            break;
+        }
+        desc = next;
+    }
+}
+
+// Points a descriptor at a single buffer of `len` bytes at `addr`, which the device fills if `device_writes`.
+pub fn virtq_set_buffer(vq: &mut VirtioVirtq, desc: u16, addr: usize, len: usize, device_writes: bool) {
+    vq.descs[desc as usize] = VirtqDesc {
+        addr: addr as u64,
+        len: len as u32,
+        flags: if device_writes { VIRTQ_DESC_F_WRITE as u16 } else { 0 },
+        next: 0,
+    };
+}
+
+// Links descriptor `desc` to `next`, so that they form one request.
+pub fn virtq_chain(vq: &mut VirtioVirtq, desc: u16, next: u16) {
+    vq.descs[desc as usize].flags |= VIRTQ_DESC_F_NEXT as u16;
+    vq.descs[desc as usize].next = next;
+}
+
 // Notifies the device that there is a new request. `desc_index` is the index of the head descriptor of the new request
-fn virtq_kick(vq: &mut VirtioVirtq, desc_index: u16) {
+pub fn virtq_kick(vq: &mut VirtioVirtq, desc_index: u16) {
     let index = vq.avail.index as usize % VIRTQ_ENTRY_NUM;
     vq.avail.ring[index] = desc_index;
-    vq.avail.index += 1;
+    vq.avail.index = vq.avail.index.wrapping_add(1);
 
     core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst); // Equivalent to __sync_synchronise();
 
-    virtio_reg_write32(VIRTIO_REG_QUEUE_NOTIFY, vq.queue_index.into());  // converting `u16` to `u32` cannot fail
-    vq.last_used_index += 1;
+    vq.dev.regs().queue_notify.write(vq.queue_index.into());  // converting `u16` to `u32` cannot fail
 }
 
-// Returns whether there are requests being processed by the device.
-fn virtq_is_busy(vq: &VirtioVirtq) -> bool {
+// Returns the head descriptor and written length of the next used ring entry, if any.
+pub fn virtq_pop_used(vq: &mut VirtioVirtq) -> Option<(u16, usize)> {
     // Safety:
     // * vq.used_index is valid for reads
     // * vq.used_index is 16-bit aligned
     // * vq.used_index points to a value properly initialised by QEMU
     // * `u16` is Copy
     assert_eq!(vq.used_index as usize % align_of::<u16>(), 0);
-    unsafe {
-        vq.last_used_index != core::ptr::read_volatile(vq.used_index)
+    if vq.last_used_index == unsafe { core::ptr::read_volatile(vq.used_index) } {
+        return None;
     }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+    let index = vq.last_used_index as usize % VIRTQ_ENTRY_NUM;
+    // Safety: the entry is in bounds, written by the device before it advanced the used index,
+    // and VirtqUsedElem is packed so needs no alignment.
+    let elem = unsafe { ptr::read_volatile(&raw const vq.used.0.ring[index]) };
+    vq.last_used_index = vq.last_used_index.wrapping_add(1);
+    Some((elem.id as u16, elem.len as usize))
 }
 
-// Reads/writes from/to virtio-blk device.
-pub fn read_write_disk(buf: &mut [u8], sector: u64, is_write: bool) {
-    let blk_capacity = BLK_CAPACITY.lock()
-        .expect("block capacity should be initialised before read_write_disk call.");
-    if sector >= (blk_capacity / SECTOR_SIZE as u64) {
-        println!("virtio: tried to read/write sector={}, but capacity is {}", sector, blk_capacity / SECTOR_SIZE as u64);
-        return;
-    }
-
-    let mut br_guard = BLK_REQ.lock();
-    let br = br_guard.as_mut()
-        .expect("BLK_REQ not initialised");
+// Returns the capacity in bytes of block device `unit`.
+pub fn virtio_blk_capacity(unit: usize) -> u64 {
+    blk_info(unit).capacity
+}
 
-    br.sector = sector;
-    br.req_type = if is_write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
+// Marks the requests the block devices have finished as done and wakes the processes waiting on them.
+pub fn virtio_blk_poll() {
+    for unit in 0..BLK_DEVICES_MAX {
+        let mut completed = [false; VIRTQ_ENTRY_NUM];
+        {
+            let mut br_guard = BLK_REQ[unit].lock();
+            let Some(br) = br_guard.as_mut() else {
+                continue;
+            };
+            let mut vq_guard = BLK_REQUEST_VQ[unit].lock();
+            let vq = vq_guard.as_mut().expect("BLK_REQUEST_VQ not initialised");
+
+            while let Some((head, _)) = virtq_pop_used(vq) {
+                br.state[head as usize] = ReqState::Done;
+                completed[head as usize] = true;
+            }
+        }
+        for (done, _) in BLK_DONE[unit].iter().zip(completed).filter(|&(_, c)| c) {
+            done.up();
+        }
+    }
+}
 
-    if is_write {
-        br.data.copy_from_slice(buf);
-    };
+// Handles a virtio-blk interrupt. Polling checks every device, so it does not matter which one raised it.
+fn virtio_blk_handle_interrupt(dev: &VirtioDevice) {
+    dev.ack_interrupt();
+    virtio_blk_poll();
+}
 
-    // Construct the virtqueue descriptors (using 3 descriptors).
-    let mut vq_guard = BLK_REQUEST_VQ.lock();
+// Submits a request of type `req_type` and returns its head descriptor, or None if there are not enough
+// free descriptors. `segs` holds the address and length of each data buffer.
+fn submit_request(br_guard: &mut Guard<'_, Option<BlkReqs>>, unit: usize, req_type: u32, segs: &[(usize, usize)], sector: u64) -> Option<u16> {
+    let br_reqs = br_guard.as_mut()
+        .expect("BLK_REQ not initialised");
+    let mut vq_guard = BLK_REQUEST_VQ[unit].lock();
     let vq = vq_guard.as_mut().expect("BLK_REQUEST_VQ not initialised");
 
-    let blk_req_paddr = &**br as *const VirtioBlkReq as usize; // Double deference to get address from heap, not of the Box
+    // Header, one descriptor per data buffer, then status.
+    let count = segs.len() + 2;
+    if vq.num_free < count {
+        return None;
+    }
+    let mut descs = [0u16; REQ_DESCS_MAX];
+    for desc in &mut descs[..count] {
+        *desc = virtq_alloc_desc(vq).expect("enough descriptors should be free");
+    }
+    let head = descs[0] as usize;
+
+    br_reqs.state[head] = ReqState::InFlight;
+    let br = &mut br_reqs.reqs[head];
+    br.sector = sector;
+    br.req_type = req_type;
+    let blk_req_paddr = &*br as *const VirtioBlkReq as usize;
 
-    // Descriptor 0: request header
-    vq.descs[0] = VirtqDesc {
+    // Request header
+    vq.descs[head] = VirtqDesc {
         addr: blk_req_paddr as u64,
         len: (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32,
         flags: VIRTQ_DESC_F_NEXT as u16,
-        next: 1,
+        next: descs[1],
     };
 
-    // Descriptor 1: data buffer
-    vq.descs[1] = VirtqDesc {
-        addr: (blk_req_paddr + offset_of!(VirtioBlkReq, data)) as u64,
-        len: SECTOR_SIZE as u32,
-        flags: (VIRTQ_DESC_F_NEXT | (if is_write {0} else {VIRTQ_DESC_F_WRITE})) as u16,
-        next: 2,
-    };
+    // Data buffers
+    for (i, &(addr, len)) in segs.iter().enumerate() {
+        vq.descs[descs[1 + i] as usize] = VirtqDesc {
+            addr: addr as u64,
+            len: len as u32,
+            flags: (VIRTQ_DESC_F_NEXT | (if req_type == VIRTIO_BLK_T_IN {VIRTQ_DESC_F_WRITE} else {0})) as u16,
+            next: descs[2 + i],
+        };
+    }
 
-    // Descriptor 2: status byte
-    vq.descs[2] = VirtqDesc {
+    // Status byte
+    vq.descs[descs[count - 1] as usize] = VirtqDesc {
         addr: (blk_req_paddr + offset_of!(VirtioBlkReq, status)) as u64,
         len: mem::size_of::<u8>() as u32,
         flags: VIRTQ_DESC_F_WRITE as u16,
@@ -304,22 +623,109 @@ pub fn read_write_disk(buf: &mut [u8], sector: u64, is_write: bool) {
     };
 
     // Notify the device that there is a new request.
-    virtq_kick(vq.as_mut(), 0);
+    virtq_kick(vq.as_mut(), head as u16);
+    Some(head as u16)
+}
+
+// Runs one request over `segs` starting at `sector` and waits for it. Returns the sector after the last one.
+fn transfer(unit: usize, req_type: u32, segs: &[(usize, usize)], sector: u64) -> u64 {
+    let end = sector + segs.iter().map(|&(_, len)| len / SECTOR_SIZE).sum::<usize>() as u64;
+    let blk_sectors = virtio_blk_capacity(unit) / SECTOR_SIZE as u64;
+    if end > blk_sectors {
+        log_warn!("tried to read/write sectors {}..{}, but capacity is {}", sector, end, blk_sectors);
+        return end;
+    }
+
+    let head = {
+        let mut br_guard = BLK_REQ[unit].lock();
+        loop {
+            if let Some(head) = submit_request(&mut br_guard, unit, req_type, segs, sector) {
+                break head;
+            }
+            br_guard = BLK_DESCS_FREED[unit].wait(br_guard);
+        }
+    };
 
     // Wait until the device finishes processing.
-    while virtq_is_busy(vq.as_ref()) {
-        core::hint::spin_loop();
-        common::print!(".");
+    BLK_DONE[unit][head as usize].down(virtio_blk_poll);
+    {
+        let mut br_guard = BLK_REQ[unit].lock();
+        let br_reqs = br_guard.as_mut()
+            .expect("BLK_REQ not initialised");
+        assert_eq!(br_reqs.state[head as usize], ReqState::Done);
+        br_reqs.state[head as usize] = ReqState::Free;
+        virtq_free_chain(BLK_REQUEST_VQ[unit].lock().as_mut().expect("BLK_REQUEST_VQ not initialised"), head);
+
+        // virtio-blk: If a non-zero value is returned, it's an error.
+        let status = br_reqs.reqs[head as usize].status;
+        if status != 0 {
+            log_warn!("failed to read/write sectors {}..{} status={}", sector, end, status);
+        }
+    }
+
+    // Let any process waiting for free descriptors retry.
+    BLK_DESCS_FREED[unit].notify_all();
+    end
+}
+
+// Returns whether block device `unit` only allows reads.
+pub fn virtio_blk_read_only(unit: usize) -> bool {
+    blk_info(unit).features & VIRTIO_BLK_F_RO != 0
+}
+
+// Reads/writes consecutive sectors from/to block device `unit`, starting at `sector` and scattered across
+// the buffers at `bufs`, given as (address, length). Each buffer holds a whole number of sectors.
+// Up to SEGS_MAX buffers (fewer if the device says so) go in each request, and buffers that follow
+// each other in memory count as one.
+fn transfer_bufs(unit: usize, bufs: impl Iterator<Item = (usize, usize)>, sector: u64, is_write: bool) {
+    let req_type = if is_write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
+    let segs_max = blk_info(unit).segs_max;
+    let mut segs = [(0, 0); SEGS_MAX];
+    let mut count = 0;
+    let mut sector = sector;
+
+    for (addr, len) in bufs {
+        assert_eq!(len % SECTOR_SIZE, 0, "virtio: buffer is not a whole number of sectors");
+        if len == 0 {
+            continue;
+        }
+        if count > 0 && segs[count - 1].0 + segs[count - 1].1 == addr {
+            segs[count - 1].1 += len;
+            continue;
+        }
+        if count == segs_max {
+            sector = transfer(unit, req_type, &segs[..count], sector);
+            count = 0;
+        }
+        segs[count] = (addr, len);
+        count += 1;
     }
 
-    // virtio-blk: If a non-zero value is returned, it's an error.
-    if br.status != 0 {
-        println!("virtio: warn: failed to read/write sector={} status={}", sector, br.status);
+    if count > 0 {
+        transfer(unit, req_type, &segs[..count], sector);
+    }
+}
+
+// Reads consecutive sectors from block device `unit`, starting at `sector`, into `bufs` in turn.
+pub fn virtio_blk_read(unit: usize, bufs: &mut [&mut [u8]], sector: u64) {
+    // In our OS the virtual address matches the physical address
+    transfer_bufs(unit, bufs.iter_mut().map(|buf| (buf.as_mut_ptr() as usize, buf.len())), sector, false);
+}
+
+// Writes `bufs` in turn to consecutive sectors of block device `unit`, starting at `sector`.
+// Writes to a read-only disk are refused.
+pub fn virtio_blk_write(unit: usize, bufs: &[&[u8]], sector: u64) {
+    if virtio_blk_read_only(unit) {
+        log_warn!("blk{}: disk is read-only, refusing write to sector={}", unit, sector);
         return;
     }
+    transfer_bufs(unit, bufs.iter().map(|buf| (buf.as_ptr() as usize, buf.len())), sector, true);
+}
 
-    // For read operations, copy the data into the buffer.
-    if !is_write {
-        buf.copy_from_slice(&br.data);
+// Waits until every write to block device `unit` so far is on stable storage. Devices without a
+// write cache put writes there before completing them, so only devices with one are sent a flush.
+pub fn virtio_blk_flush(unit: usize) {
+    if blk_info(unit).features & VIRTIO_BLK_F_FLUSH != 0 {
+        transfer(unit, VIRTIO_BLK_T_FLUSH, &[], 0);
     }
 }
@@ -0,0 +1,80 @@
+//! Virtio 9P transport for os1k
+//!
+//! Carries 9P messages between the kernel and a directory shared by the host. Each request is
+//! a buffer for the message and a buffer for the reply, and the file system waits for one
+//! reply before sending the next request, so replies are polled.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+
+use crate::log_info;
+use crate::spinlock::SpinLock;
+use crate::vfs::OsError;
+use crate::virtio::{
+    virtio_find,
+    virtq_alloc_desc,
+    virtq_chain,
+    virtq_free_chain,
+    virtq_init,
+    virtq_kick,
+    virtq_pop_used,
+    virtq_set_buffer,
+    VirtioVirtq,
+    VIRTIO_DEVICE_9P,
+};
+
+const VIRTIO_9P_MOUNT_TAG: u32 = 1 << 0;    // The configuration space holds the tag of the share
+const REQUESTQ: usize = 0;
+const CONFIG_TAG_LEN: u32 = 0;              // u16, followed by the tag without a nul
+
+static REQUEST: SpinLock<Option<Box<VirtioVirtq>>> = SpinLock::new(None);
+
+// Sets up the 9P device, if the machine has one.
+pub fn virtio_9p_init() {
+    let Some(dev) = virtio_find(VIRTIO_DEVICE_9P) else {
+        log_info!("no shared directory");
+        return;
+    };
+
+    let features = dev.negotiate(VIRTIO_9P_MOUNT_TAG);
+    let vq = virtq_init(&dev, REQUESTQ);
+    dev.driver_ok();
+
+    if features & VIRTIO_9P_MOUNT_TAG != 0 {
+        let len = u16::from_le_bytes([dev.read_config::<u8>(CONFIG_TAG_LEN), dev.read_config::<u8>(CONFIG_TAG_LEN + 1)]);
+        let tag: String = (0..len as u32)
+            .map(|i| dev.read_config::<u8>(CONFIG_TAG_LEN + 2 + i) as char)
+            .collect();
+        log_info!("shared directory tagged {}", tag);
+    }
+
+    *REQUEST.lock() = Some(vq);
+}
+
+// Returns whether there is a 9P device.
+pub fn virtio_9p_present() -> bool {
+    REQUEST.lock().is_some()
+}
+
+// Sends the message in `req` and waits for the reply to be written into `resp`.
+// Fails with NotFound without a 9P device.
+pub fn virtio_9p_rpc(req: &[u8], resp: &mut [u8]) -> Result<(), OsError> {
+    let mut request = REQUEST.lock();
+    let vq = request.as_mut().ok_or(OsError::NotFound)?;
+
+    let req_desc = virtq_alloc_desc(vq).expect("the request queue should be idle");
+    let resp_desc = virtq_alloc_desc(vq).expect("the request queue should be idle");
+    virtq_set_buffer(vq, req_desc, req.as_ptr() as usize, req.len(), false);
+    virtq_set_buffer(vq, resp_desc, resp.as_mut_ptr() as usize, resp.len(), true);
+    virtq_chain(vq, req_desc, resp_desc);
+    virtq_kick(vq, req_desc);
+
+    let head = loop {
+        if let Some((head, _)) = virtq_pop_used(vq) {
+            break head;
+        }
+        core::hint::spin_loop();
+    };
+    virtq_free_chain(vq, head);
+    Ok(())
+}
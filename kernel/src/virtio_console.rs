@@ -0,0 +1,135 @@
+//! Virtio console device for os1k
+//!
+//! Only the first port is used, without the multiport feature. The console is polled rather
+//! than interrupt driven: input is only wanted when a process asks for it. As with virtio-net,
+//! every descriptor owns the buffer with the same index.
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::spinlock::SpinLock;
+use crate::tty::tty_input;
+use crate::virtio::{
+    virtio_find,
+    virtq_alloc_desc,
+    virtq_free_chain,
+    virtq_init,
+    virtq_kick,
+    virtq_pop_used,
+    virtq_set_buffer,
+    VirtioDevice,
+    VirtioVirtq,
+    VIRTIO_DEVICE_CONSOLE,
+    VIRTQ_ENTRY_NUM,
+};
+
+const CONSOLE_BUF_SIZE: usize = 64;
+const INPUT_MAX: usize = 256;       // Bytes received but not yet read
+const RECEIVEQ: usize = 0;          // Port 0
+const TRANSMITQ: usize = 1;
+
+// A virtqueue and the buffers of its descriptors.
+struct ConsoleQueue {
+    vq: Box<VirtioVirtq>,
+    bufs: Box<[[u8; CONSOLE_BUF_SIZE]]>,
+}
+
+// Received bytes, oldest first.
+struct Input {
+    bytes: [u8; INPUT_MAX],
+    head: usize,
+    len: usize,
+}
+
+static RECEIVE: SpinLock<Option<ConsoleQueue>> = SpinLock::new(None);
+static TRANSMIT: SpinLock<Option<ConsoleQueue>> = SpinLock::new(None);
+static INPUT: SpinLock<Input> = SpinLock::new(Input { bytes: [0; INPUT_MAX], head: 0, len: 0 });
+
+fn console_queue(dev: &VirtioDevice, index: usize) -> ConsoleQueue {
+    ConsoleQueue {
+        vq: virtq_init(dev, index),
+        bufs: vec![[0; CONSOLE_BUF_SIZE]; VIRTQ_ENTRY_NUM].into_boxed_slice(),
+    }
+}
+
+// Sets up the console device. Returns false if the machine has none.
+pub fn virtio_console_init() -> bool {
+    let Some(dev) = virtio_find(VIRTIO_DEVICE_CONSOLE) else {
+        return false;
+    };
+
+    dev.negotiate(0);
+    let mut receive = console_queue(&dev, RECEIVEQ);
+    let transmit = console_queue(&dev, TRANSMITQ);
+
+    // Give the device every receive buffer.
+    while let Some(desc) = virtq_alloc_desc(&mut receive.vq) {
+        let addr = receive.bufs[desc as usize].as_ptr() as usize;
+        virtq_set_buffer(&mut receive.vq, desc, addr, CONSOLE_BUF_SIZE, true);
+        virtq_kick(&mut receive.vq, desc);
+    }
+    dev.driver_ok();
+
+    *RECEIVE.lock() = Some(receive);
+    *TRANSMIT.lock() = Some(transmit);
+    true
+}
+
+// Sends `bytes`, a descriptor's buffer at a time.
+pub fn virtio_console_write(bytes: &[u8]) -> Result<isize, isize> {
+    let mut transmit = TRANSMIT.lock();
+    let tx = transmit.as_mut().ok_or(-1isize)?;
+
+    for chunk in bytes.chunks(CONSOLE_BUF_SIZE) {
+        // Take back the buffers the device has sent, waiting for one if they are all in flight.
+        let desc = loop {
+            while let Some((desc, _)) = virtq_pop_used(&mut tx.vq) {
+                virtq_free_chain(&mut tx.vq, desc);
+            }
+            if let Some(desc) = virtq_alloc_desc(&mut tx.vq) {
+                break desc;
+            }
+            core::hint::spin_loop();
+        };
+
+        tx.bufs[desc as usize][..chunk.len()].copy_from_slice(chunk);
+        let addr = tx.bufs[desc as usize].as_ptr() as usize;
+        virtq_set_buffer(&mut tx.vq, desc, addr, chunk.len(), false);
+        virtq_kick(&mut tx.vq, desc);
+    }
+    Ok(0)
+}
+
+// Moves everything the device has received into INPUT. Input that does not fit is dropped.
+fn poll_input() {
+    let mut receive = RECEIVE.lock();
+    let Some(rx) = receive.as_mut() else {
+        return;
+    };
+    let mut input = INPUT.lock();
+    while let Some((desc, len)) = virtq_pop_used(&mut rx.vq) {
+        for &byte in &rx.bufs[desc as usize][..len.min(CONSOLE_BUF_SIZE)] {
+            tty_input(byte);
+            if input.len < INPUT_MAX {
+                let tail = (input.head + input.len) % INPUT_MAX;
+                input.bytes[tail] = byte;
+                input.len += 1;
+            }
+        }
+        // The descriptor still points at its buffer, so it can go straight back.
+        virtq_kick(&mut rx.vq, desc);
+    }
+}
+
+// Returns the next byte received, or Err(-1) if there is none yet.
+pub fn virtio_console_get_char() -> Result<isize, isize> {
+    poll_input();
+    let mut input = INPUT.lock();
+    if input.len == 0 {
+        return Err(-1);
+    }
+    let byte = input.bytes[input.head];
+    input.head = (input.head + 1) % INPUT_MAX;
+    input.len -= 1;
+    Ok(byte as isize)
+}
@@ -0,0 +1,276 @@
+//! Virtio GPU device for os1k
+//!
+//! Only 2D is used: one resource, backed by a framebuffer in kernel memory, is shown on the
+//! first scanout. Drawing happens in the framebuffer, then the changed rectangle is copied to
+//! the host and flushed to the display. Commands are few and small, so they are polled.
+
+use core::mem::size_of;
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::{log_error, log_info};
+use crate::spinlock::SpinLock;
+use crate::virtio::{
+    virtio_find,
+    virtq_alloc_desc,
+    virtq_chain,
+    virtq_free_chain,
+    virtq_init,
+    virtq_kick,
+    virtq_pop_used,
+    virtq_set_buffer,
+    VirtioVirtq,
+    VIRTIO_DEVICE_GPU,
+};
+
+const CONTROLQ: usize = 0;
+const SCANOUTS_MAX: usize = 16;
+const SCANOUT_ID: u32 = 0;
+const RESOURCE_ID: u32 = 1;
+const DEFAULT_WIDTH: u32 = 640;     // Used if the device does not report a display size
+const DEFAULT_HEIGHT: u32 = 480;
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;   // One u32 per pixel, 0x00rrggbb on a little endian machine
+
+// Header of every command and response.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct CtrlHdr {
+    cmd_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHdr {
+    fn new(cmd_type: u32) -> Self {
+        Self { cmd_type, ..Default::default() }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct RespDisplayInfo {
+    hdr: CtrlHdr,
+    pmodes: [DisplayOne; SCANOUTS_MAX],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ResourceCreate2d {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// The attach command with its single memory entry: the framebuffer is one allocation.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ResourceAttachBacking {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SetScanout {
+    hdr: CtrlHdr,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TransferToHost2d {
+    hdr: CtrlHdr,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ResourceFlush {
+    hdr: CtrlHdr,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+// Pixels in rows of `width`, each 0x00rrggbb.
+#[derive(Debug)]
+pub struct Framebuffer {
+    pub pixels: Box<[u32]>,
+    pub width: usize,
+    pub height: usize,
+}
+
+struct Gpu {
+    vq: Box<VirtioVirtq>,
+    fb: Framebuffer,
+}
+
+static GPU: SpinLock<Option<Gpu>> = SpinLock::new(None);
+
+// Sends one command and waits for the device to fill in `resp`.
+fn command<Req, Resp>(vq: &mut VirtioVirtq, req: &Req, resp: &mut Resp) {
+    let req_desc = virtq_alloc_desc(vq).expect("the control queue should be idle");
+    let resp_desc = virtq_alloc_desc(vq).expect("the control queue should be idle");
+    // Kernel memory is identity mapped, so references are physical addresses.
+    virtq_set_buffer(vq, req_desc, req as *const Req as usize, size_of::<Req>(), false);
+    virtq_set_buffer(vq, resp_desc, resp as *mut Resp as usize, size_of::<Resp>(), true);
+    virtq_chain(vq, req_desc, resp_desc);
+    virtq_kick(vq, req_desc);
+
+    let head = loop {
+        if let Some((head, _)) = virtq_pop_used(vq) {
+            break head;
+        }
+        core::hint::spin_loop();
+    };
+    virtq_free_chain(vq, head);
+}
+
+// Sends a command that has no reply beyond success or failure. Returns the response type on failure.
+fn command_nodata<Req>(vq: &mut VirtioVirtq, req: &Req) -> Result<(), u32> {
+    let mut resp = CtrlHdr::default();
+    command(vq, req, &mut resp);
+    match resp.cmd_type {
+        VIRTIO_GPU_RESP_OK_NODATA => Ok(()),
+        err => Err(err),
+    }
+}
+
+// Returns the size of the first scanout, or the default size if the device has no enabled display.
+fn display_size(vq: &mut VirtioVirtq) -> (u32, u32) {
+    let mut info = RespDisplayInfo::default();
+    command(vq, &CtrlHdr::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO), &mut info);
+    let mode = info.pmodes[SCANOUT_ID as usize];
+    if info.hdr.cmd_type == VIRTIO_GPU_RESP_OK_DISPLAY_INFO && mode.enabled != 0 {
+        (mode.rect.width, mode.rect.height)
+    } else {
+        (DEFAULT_WIDTH, DEFAULT_HEIGHT)
+    }
+}
+
+// Creates the framebuffer resource and shows it on the first scanout.
+fn setup_scanout(vq: &mut VirtioVirtq, fb: &Framebuffer) -> Result<(), u32> {
+    let (width, height) = (fb.width as u32, fb.height as u32);
+    let rect = Rect { x: 0, y: 0, width, height };
+
+    command_nodata(vq, &ResourceCreate2d {
+        hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+        resource_id: RESOURCE_ID,
+        format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+        width,
+        height,
+    })?;
+    command_nodata(vq, &ResourceAttachBacking {
+        hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+        resource_id: RESOURCE_ID,
+        nr_entries: 1,
+        addr: fb.pixels.as_ptr() as u64,
+        length: size_of_val(&*fb.pixels) as u32,
+        padding: 0,
+    })?;
+    command_nodata(vq, &SetScanout {
+        hdr: CtrlHdr::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+        rect,
+        scanout_id: SCANOUT_ID,
+        resource_id: RESOURCE_ID,
+    })?;
+    flush(vq, fb, rect)
+}
+
+// Copies `rect` of the framebuffer to the host and updates the display.
+fn flush(vq: &mut VirtioVirtq, fb: &Framebuffer, rect: Rect) -> Result<(), u32> {
+    command_nodata(vq, &TransferToHost2d {
+        hdr: CtrlHdr::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+        rect,
+        offset: ((rect.y as usize * fb.width + rect.x as usize) * size_of::<u32>()) as u64,
+        resource_id: RESOURCE_ID,
+        padding: 0,
+    })?;
+    command_nodata(vq, &ResourceFlush {
+        hdr: CtrlHdr::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+        rect,
+        resource_id: RESOURCE_ID,
+        padding: 0,
+    })
+}
+
+// Sets up the display, if the machine has one.
+pub fn virtio_gpu_init() {
+    let Some(dev) = virtio_find(VIRTIO_DEVICE_GPU) else {
+        log_info!("no display");
+        return;
+    };
+
+    dev.negotiate(0);
+    let mut vq = virtq_init(&dev, CONTROLQ);
+    dev.driver_ok();
+
+    let (width, height) = display_size(&mut vq);
+    let fb = Framebuffer {
+        // Allocated straight on the heap, which also makes it one physically contiguous block.
+        pixels: vec![0; width as usize * height as usize].into_boxed_slice(),
+        width: width as usize,
+        height: height as usize,
+    };
+    if let Err(err) = setup_scanout(&mut vq, &fb) {
+        log_error!("setting up the display failed with response {:#x}", err);
+        return;
+    }
+    log_info!("{}x{} display", width, height);
+
+    *GPU.lock() = Some(Gpu { vq, fb });
+}
+
+// Lets `draw` change the framebuffer, then shows the rectangle it returns. Returns false if
+// there is no display.
+pub fn virtio_gpu_draw(draw: impl FnOnce(&mut Framebuffer) -> Option<Rect>) -> bool {
+    let mut gpu = GPU.lock();
+    let Some(Gpu { vq, fb }) = gpu.as_mut() else {
+        return false;
+    };
+    if let Some(rect) = draw(fb) {
+        // A failed flush only leaves the display stale, and there is nowhere to report it:
+        // printing here would come straight back to the display.
+        let _ = flush(vq, fb, rect);
+    }
+    true
+}
@@ -0,0 +1,163 @@
+//! Virtio network device for os1k
+//!
+//! Every descriptor of both virtqueues owns the buffer with the same index. Receive buffers stay
+//! posted: once a frame has been copied out its descriptor goes straight back to the device.
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::net;
+use crate::plic::plic_enable;
+use crate::log_info;
+use crate::spinlock::SpinLock;
+use crate::vfs::OsError;
+use crate::virtio::{
+    virtio_find,
+    virtq_alloc_desc,
+    virtq_free_chain,
+    virtq_init,
+    virtq_kick,
+    virtq_pop_used,
+    virtq_set_buffer,
+    VirtioDevice,
+    VirtioVirtq,
+    VIRTIO_DEVICE_NET,
+    VIRTIO_VERSION_MODERN,
+    VIRTQ_ENTRY_NUM,
+};
+
+pub const FRAME_MAX: usize = 1514;          // Ethernet frame without the FCS
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;       // The device has a MAC address in its configuration space
+const VIRTIO_NET_HDR_LEGACY: usize = 10;    // struct virtio_net_hdr without num_buffers
+const VIRTIO_NET_HDR_MODERN: usize = 12;    // Version 2 devices always include num_buffers
+const NET_BUF_SIZE: usize = VIRTIO_NET_HDR_MODERN + FRAME_MAX;
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];  // QEMU's default
+
+// A virtqueue and the buffers of its descriptors.
+struct NetQueue {
+    vq: Box<VirtioVirtq>,
+    bufs: Box<[[u8; NET_BUF_SIZE]]>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct NetDevice {
+    mac: [u8; 6],
+    hdr_len: usize,     // Bytes of virtio_net_hdr before each frame
+}
+
+static RECEIVE: SpinLock<Option<NetQueue>> = SpinLock::new(None);
+static TRANSMIT: SpinLock<Option<NetQueue>> = SpinLock::new(None);
+static NET_DEVICE: SpinLock<Option<NetDevice>> = SpinLock::new(None);
+
+fn net_queue(dev: &VirtioDevice, index: usize) -> NetQueue {
+    NetQueue {
+        vq: virtq_init(dev, index),
+        // Allocated straight on the heap: the buffers are too big for the stack.
+        bufs: vec![[0; NET_BUF_SIZE]; VIRTQ_ENTRY_NUM].into_boxed_slice(),
+    }
+}
+
+// Sets up the network device, if the machine has one.
+pub fn virtio_net_init() {
+    let Some(dev) = virtio_find(VIRTIO_DEVICE_NET) else {
+        log_info!("no network device");
+        return;
+    };
+
+    let features = dev.negotiate(VIRTIO_NET_F_MAC);
+    let mut receive = net_queue(&dev, RECEIVEQ);
+    let transmit = net_queue(&dev, TRANSMITQ);
+
+    // Give the device every receive buffer.
+    while let Some(desc) = virtq_alloc_desc(&mut receive.vq) {
+        let addr = receive.bufs[desc as usize].as_ptr() as usize;
+        virtq_set_buffer(&mut receive.vq, desc, addr, NET_BUF_SIZE, true);
+        virtq_kick(&mut receive.vq, desc);
+    }
+    dev.driver_ok();
+
+    let mac = if features & VIRTIO_NET_F_MAC != 0 {
+        core::array::from_fn(|i| dev.read_config::<u8>(i as u32))
+    } else {
+        DEFAULT_MAC
+    };
+    let hdr_len = if dev.version == VIRTIO_VERSION_MODERN { VIRTIO_NET_HDR_MODERN } else { VIRTIO_NET_HDR_LEGACY };
+    log_info!("mac is {:02x?}", mac);
+
+    *RECEIVE.lock() = Some(receive);
+    *TRANSMIT.lock() = Some(transmit);
+    *NET_DEVICE.lock() = Some(NetDevice { mac, hdr_len });
+
+    // Received frames raise an interrupt.
+    plic_enable(dev.irq);
+}
+
+// Returns the MAC address of the network device, if there is one.
+pub fn virtio_net_mac() -> Option<[u8; 6]> {
+    NET_DEVICE.lock().map(|d| d.mac)
+}
+
+// Queues an Ethernet frame, made of `parts` one after another, for sending. Fails with NotFound
+// without a network device, and with NoSpace if the frame is too big or every transmit buffer is in use.
+pub fn virtio_net_send(parts: &[&[u8]]) -> Result<(), OsError> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    if len > FRAME_MAX {
+        return Err(OsError::NoSpace);
+    }
+    let hdr_len = NET_DEVICE.lock().ok_or(OsError::NotFound)?.hdr_len;
+    let mut transmit = TRANSMIT.lock();
+    let tx = transmit.as_mut().ok_or(OsError::NotFound)?;
+
+    // Take back the buffers the device has sent.
+    while let Some((desc, _)) = virtq_pop_used(&mut tx.vq) {
+        virtq_free_chain(&mut tx.vq, desc);
+    }
+
+    let desc = virtq_alloc_desc(&mut tx.vq).ok_or(OsError::NoSpace)?;
+    let buf = &mut tx.bufs[desc as usize];
+    // An all-zero header: no checksum offload and no segmentation.
+    buf[..hdr_len].fill(0);
+    let mut offset = hdr_len;
+    for part in parts {
+        buf[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+    let addr = buf.as_ptr() as usize;
+    virtq_set_buffer(&mut tx.vq, desc, addr, offset, false);
+    virtq_kick(&mut tx.vq, desc);
+    Ok(())
+}
+
+// Hands every frame the device has received to the network stack.
+pub fn virtio_net_poll() {
+    let Some(hdr_len) = NET_DEVICE.lock().map(|d| d.hdr_len) else {
+        return;
+    };
+    let mut frame = [0u8; FRAME_MAX];
+    loop {
+        // Copy the frame out so the stack can reply to it without holding RECEIVE.
+        let len = {
+            let mut receive = RECEIVE.lock();
+            let Some(rx) = receive.as_mut() else {
+                return;
+            };
+            let Some((desc, written)) = virtq_pop_used(&mut rx.vq) else {
+                return;
+            };
+            let len = written.saturating_sub(hdr_len).min(FRAME_MAX);
+            frame[..len].copy_from_slice(&rx.bufs[desc as usize][hdr_len..hdr_len + len]);
+            // The descriptor still points at its buffer, so it can go straight back.
+            virtq_kick(&mut rx.vq, desc);
+            len
+        };
+        net::receive(&frame[..len]);
+    }
+}
+
+// Handles the virtio-net interrupt.
+pub fn virtio_net_handle_interrupt(dev: &VirtioDevice) {
+    dev.ack_interrupt();
+    virtio_net_poll();
+}
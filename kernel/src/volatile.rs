@@ -0,0 +1,46 @@
+//! Volatile memory-mapped registers for os1k
+//!
+//! A register block is a `#[repr(C)]` struct of these wrappers laid over the device's
+//! registers, so each access has the register's type and direction. Every access is a single
+//! volatile load or store of the whole value, which the compiler will not merge, reorder with
+//! other volatile accesses, or remove.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+// A register that is read and written.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        // Safety: the register is mapped for as long as the reference lives, and is aligned
+        // for T because the register block is.
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+
+    pub fn write(&self, value: T) {
+        // Safety: as for `read`. Devices expect writes through shared references.
+        unsafe { ptr::write_volatile(self.0.get(), value) }
+    }
+}
+
+// A register the driver only reads, such as an ID or status set by the device.
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(Volatile<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    pub fn read(&self) -> T {
+        self.0.read()
+    }
+}
+
+// A register the driver only writes, such as a doorbell. Reading it is undefined for the device.
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(Volatile<T>);
+
+impl<T: Copy> WriteOnly<T> {
+    pub fn write(&self, value: T) {
+        self.0.write(value);
+    }
+}
@@ -0,0 +1,52 @@
+//! Watchdog for os1k
+//!
+//! Every call into the scheduler pets the watchdog. If a timer tick finds it has not been petted
+//! for WATCHDOG_TICKS, the running process is stuck in a loop that never gives up the CPU: the
+//! watchdog logs it once, with where it was, and kills it if KILL_RUNAWAY is set.
+//!
+//! Ticks are only taken in user mode, so a kernel that never gets back to user mode cannot be
+//! caught this way.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use common::EXIT_KILLED;
+
+use crate::log_warn;
+use crate::process::exit_current;
+use crate::scheduler::running_pid;
+use crate::timer::{ticks, timer_periodic, TICK_HZ};
+
+const WATCHDOG_TICKS: usize = 5 * TICK_HZ as usize;
+const KILL_RUNAWAY: bool = false;
+
+// The tick of the last call into the scheduler
+static LAST_PET: AtomicUsize = AtomicUsize::new(0);
+// Set once the current runaway has been logged, so it is logged only once.
+static BARKED: AtomicBool = AtomicBool::new(false);
+
+pub fn watchdog_init() {
+    timer_periodic(1, watchdog_tick, 0).expect("watchdog should have a timer");
+}
+
+// Records that the kernel got to the scheduler.
+pub fn watchdog_pet() {
+    LAST_PET.store(ticks(), Ordering::Relaxed);
+    BARKED.store(false, Ordering::Relaxed);
+}
+
+fn watchdog_tick(_: usize) {
+    let stuck = ticks().wrapping_sub(LAST_PET.load(Ordering::Relaxed));
+    if stuck < WATCHDOG_TICKS || BARKED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let current = running_pid();
+    // Called from the timer interrupt, so sepc is where the process was interrupted.
+    log_warn!(
+        "process {} has run for {} s without yielding, sepc=0x{:x}",
+        current, stuck / TICK_HZ as usize, read_csr!("sepc"),
+    );
+    if KILL_RUNAWAY {
+        log_warn!("killing process {}", current);
+        exit_current(EXIT_KILLED);
+    }
+}
@@ -13,10 +13,14 @@ if [ "$COMMAND" == "clean" ]; then
     cargo clean;
     rm -f kernel.elf;
     rm -f disk.tar;
+    rm -f disk.img;
     rm -f shell.bin;
     rm -f shell.bin.o;
+    rm -f ramdisk.img;
+    rm -f ramdisk.img.o;
     rm -f kernel/kernel.map;
     rm -f user/user.map;
+    rm -f disk/selftest.bin;
 fi
 
 
@@ -59,6 +63,43 @@ if [ "$COMMAND" == "run" ]; then
     fi
 fi
 
+if [ "$COMMAND" == "ramdisk" ]; then
+    # Run from a RAM disk holding the files in disk/, without a virtio disk
+    "./$0" build;
+    (cd disk && tar cf ../ramdisk.img --format=ustar *.txt);
+    $OBJCOPY -Ibinary -Oelf32-littleriscv ramdisk.img ramdisk.img.o;
+    cargo run --features kernel/ramdisk;
+fi
+
+if [ "$COMMAND" == "profile" ]; then
+    # Run with the sampling profiler: `cat /proc/profile` lists where processes spend their time
+    "./$0" build;
+    cargo run --features kernel/profile;
+fi
+
+if [ "$COMMAND" == "stress" ]; then
+    # Run the SpinLock stress test on 4 harts at boot. QEMU exits with status 0 if it passes
+    "./$0" build;
+    SMP=${SMP:-4} cargo run --features kernel/lock-stress;
+fi
+
+if [ "$COMMAND" == "selftest" ]; then
+    # Run the user library tests in place of the shell. QEMU exits with status 0 if they all pass.
+    # The tests are loaded from the disk, and only a FAT disk has room for them and their files.
+    "./$0" build;
+    cargo build -p user --bin selftest;
+    $OBJCOPY --set-section-flags=.bss=alloc,contents \
+        --output-target=binary \
+        $TARGET_DIR/selftest disk/selftest.bin;
+    DISK_FORMAT=fat cargo run --features kernel/self-test;
+fi
+
+if [ "$COMMAND" == "test" ]; then
+    # File system logic shared through common runs on the host, without QEMU
+    HOST=$(rustc -vV | sed -n 's/^host: //p')
+    cargo test -p common --features std --target $HOST;
+fi
+
 if [ "$COMMAND" == "cleanandrun" ]; then
     "./$0" clean;
     "./$0" run;
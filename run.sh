@@ -7,12 +7,69 @@ QEMU=qemu-system-riscv32
 #Cargo will provide a path to the built kernel in $1
 cp $1 kernel.elf
 
-(cd disk && tar cf ../disk.tar --format=ustar *.txt)
+# Set DISK_FORMAT=fat to boot from a FAT image instead of a tar archive (needs mtools)
+DISK_FORMAT=${DISK_FORMAT:-tar}
+if [ "$DISK_FORMAT" == "fat" ]; then
+    DISK=disk.img
+    rm -f $DISK
+    mkfs.fat -C -n OS1K $DISK 1024
+    mcopy -i $DISK disk/*.txt $(ls disk/*.bin 2>/dev/null) ::
+else
+    DISK=disk.tar
+    # Programs built into disk/, such as selftest.bin, are larger than the tar file system holds,
+    # so only FAT disks carry them
+    (cd disk && tar cf ../disk.tar --format=ustar *.txt)
+    # Reserve space after the archive for the kernel's journal (JOURNAL_SECTORS in journal.rs)
+    truncate -s +$((9 * 512)) $DISK
+fi
+
+# Set VIRTIO_MODERN=1 to give the kernel version 2 virtio-mmio devices instead of legacy ones
+VIRTIO_LEGACY=true
+if [ "${VIRTIO_MODERN:-0}" == "1" ]; then
+    VIRTIO_LEGACY=false
+fi
+
+# Set GPU=1 to open a QEMU window showing a virtio-gpu display, which mirrors the console.
+# virtio-gpu has no legacy interface, so this also gives the kernel version 2 devices.
+DISPLAY_ARGS="-nographic"
+if [ "${GPU:-0}" == "1" ]; then
+    VIRTIO_LEGACY=false
+    DISPLAY_ARGS="-device virtio-gpu-device,bus=virtio-mmio-bus.3"
+fi
+
+# Set READONLY=1 to attach the disk read-only: the kernel then mounts it read-only, and writes fail
+DISK_RO=""
+if [ "${READONLY:-0}" == "1" ]; then
+    DISK_RO=",readonly=on"
+fi
+
+# Set DISK2=<image> to attach a second disk, which the kernel names disk1: `mount auto /mnt disk1` in the shell to use it.
+DISK2_ARGS=""
+if [ -n "${DISK2:-}" ]; then
+    DISK2_ARGS="-drive id=drive1,file=$DISK2,format=raw,if=none -device virtio-blk-device,drive=drive1,bus=virtio-mmio-bus.5"
+fi
+
+# The disk directory is also shared over virtio-9p: `mount 9p /host` in the shell to use it.
 
 #     -d unimp,guest_errors,int,cpu_reset -D qemu.log \
 
+# Set CONSOLE=virtio to give the kernel a virtio console, which it then uses instead of the UART
+CONSOLE_ARGS="-serial mon:stdio"
+if [ "${CONSOLE:-uart}" == "virtio" ]; then
+    CONSOLE_ARGS="-chardev stdio,id=char0,mux=on,signal=off -serial chardev:char0 -mon chardev=char0
+        -device virtio-serial-device,bus=virtio-mmio-bus.2 -device virtconsole,chardev=char0"
+fi
+
+# Set SMP=<n> to give the machine n harts. The kernel starts them all, but only schedules on one.
+SMP_ARGS="-smp ${SMP:-1}"
+
 #Start QEMU
-$QEMU -machine virt -bios default -nographic -serial mon:stdio --no-reboot \
-    -drive id=drive0,file=disk.tar,format=raw,if=none \
-    -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
+$QEMU -machine virt -bios default $SMP_ARGS $DISPLAY_ARGS $CONSOLE_ARGS --no-reboot \
+    -global virtio-mmio.force-legacy=$VIRTIO_LEGACY \
+    -drive id=drive0,file=$DISK,format=raw,if=none$DISK_RO \
+    -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 $DISK2_ARGS \
+    -netdev user,id=net0,hostfwd=udp::5555-:5555 \
+    -device virtio-net-device,netdev=net0,bus=virtio-mmio-bus.1 \
+    -fsdev local,id=fs0,path=disk,security_model=none \
+    -device virtio-9p-device,fsdev=fs0,mount_tag=host,bus=virtio-mmio-bus.4 \
     -kernel kernel.elf
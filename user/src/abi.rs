@@ -0,0 +1,53 @@
+//! Syscall interface version for os1k programs
+//!
+//! `start` asks the kernel which version of the syscall interface it has, and which of the
+//! optional parts, before main. A program can check has_feature before relying on one, and fall
+//! back when the kernel lacks it, rather than failing with OsError::Unsupported part way through.
+//! A kernel from before Syscall::GetVersion counts as version 0, with none of the features.
+
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use common::abi::AbiVersion;
+use common::error::check;
+use common::Syscall;
+
+use crate::io;
+use crate::sys_call;
+
+pub use common::abi::{ABI_VERSION, FEATURE_FDS, FEATURE_MMAP, FEATURE_NET, FEATURE_SIGNALS};
+
+static KERNEL_VERSION: AtomicU32 = AtomicU32::new(0);
+static KERNEL_FEATURES: AtomicU32 = AtomicU32::new(0);
+
+// Called by `start`, before main.
+pub(crate) extern "C" fn init() {
+    let mut abi = AbiVersion::default();
+    if check(sys_call(Syscall::GetVersion, &mut abi as *mut AbiVersion as isize, 0, 0, 0, 0, 0)).is_err() {
+        abi = AbiVersion::default();
+    }
+    KERNEL_VERSION.store(abi.version, Ordering::Relaxed);
+    KERNEL_FEATURES.store(abi.features, Ordering::Relaxed);
+
+    if abi.version < ABI_VERSION {
+        let mut stderr = io::stderr();
+        let _ = writeln!(
+            stderr,
+            "warning: kernel syscall interface version {} is older than {}, some calls may fail",
+            abi.version,
+            ABI_VERSION,
+        );
+    }
+}
+
+/// The version of the syscall interface the kernel has, or 0 for a kernel too old to say.
+/// Programs are built for ABI_VERSION.
+pub fn kernel_version() -> u32 {
+    KERNEL_VERSION.load(Ordering::Relaxed)
+}
+
+/// Whether the kernel has every optional part of the syscall interface in `features`, a
+/// combination of the FEATURE_* bits.
+pub fn has_feature(features: u32) -> bool {
+    KERNEL_FEATURES.load(Ordering::Relaxed) & features == features
+}
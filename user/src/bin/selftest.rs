@@ -0,0 +1,217 @@
+//! os1k user library tests
+//!
+//! Run in place of the shell by `./os1k.sh selftest`, or from the shell as `selftest`.
+
+#![no_std]
+#![no_main]
+
+use user::abi::{self, ABI_VERSION, FEATURE_FDS, FEATURE_SIGNALS};
+use user::alloc::format;
+use user::alloc::string::String;
+use user::alloc::sync::Arc;
+use user::alloc::vec::Vec;
+use user::fs::{self, File, SeekFrom};
+use user::input::{self, InputError};
+use user::io::{self, Read as _, Write as _};
+use user::line::{LineEditor, HISTORY_MAX};
+use user::signal::{self, Signal};
+use user::sync::Mutex;
+use user::thread;
+use user::time::{sleep_ms, Instant};
+use user::{env, rand, scan, user_test, ArrayString, OsError, STDIN};
+
+const SCRATCH_FILE: &str = "/selftest.txt";
+
+fn kernel_has_this_abi() {
+    assert!(abi::kernel_version() >= ABI_VERSION);
+    assert!(abi::has_feature(FEATURE_FDS));
+}
+
+fn args_start_with_path() {
+    assert_eq!(env::arg(1), Some("--run"));
+    assert_eq!(env::args().len(), 3);
+}
+
+fn env_vars_set_and_remove() {
+    env::set_var("SELFTEST", "yes").unwrap();
+    assert_eq!(env::var("SELFTEST").as_deref(), Some("yes"));
+    env::remove_var("SELFTEST").unwrap();
+    assert_eq!(env::var("SELFTEST"), None);
+}
+
+fn files_write_read_and_seek() {
+    fs::write(SCRATCH_FILE, b"hello, world").unwrap();
+    assert_eq!(fs::read_to_string(SCRATCH_FILE).unwrap(), "hello, world");
+
+    let mut file = File::open(SCRATCH_FILE).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 12);
+    assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), 7);
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, b"world");
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"HELLO").unwrap();
+    assert_eq!(fs::read_to_string(SCRATCH_FILE).unwrap(), "HELLO, world");
+}
+
+fn write_replaces_longer_file() {
+    fs::write(SCRATCH_FILE, b"hello, world").unwrap();
+    fs::write(SCRATCH_FILE, b"bye").unwrap();
+    assert_eq!(fs::read_to_string(SCRATCH_FILE).unwrap(), "bye");
+}
+
+fn read_at_starts_at_offset() {
+    fs::write(SCRATCH_FILE, b"hello, world").unwrap();
+    let mut buf = [0u8; 16];
+    let len = fs::read_at(SCRATCH_FILE, 7, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"world");
+    assert_eq!(fs::read_at(SCRATCH_FILE, 12, &mut buf).unwrap(), 0);
+}
+
+fn read_dir_lists_scratch_file() {
+    fs::write(SCRATCH_FILE, b"listed").unwrap();
+    let names: Vec<String> = fs::read_dir("/")
+        .map(|entry| String::from(entry.unwrap().name()))
+        .collect();
+    assert!(names.iter().any(|name| name == "selftest.txt"), "not listed in {:?}", names);
+}
+
+fn files_rename_and_remove() {
+    const RENAMED: &str = "selftest.old";
+    fs::write(SCRATCH_FILE, b"moved").unwrap();
+    fs::rename(SCRATCH_FILE, RENAMED).unwrap();
+    assert_eq!(fs::metadata(SCRATCH_FILE).unwrap_err(), OsError::NotFound);
+    assert_eq!(fs::read_to_string(RENAMED).unwrap(), "moved");
+
+    // Open files cannot be removed.
+    let file = File::open(RENAMED).unwrap();
+    assert_eq!(fs::remove_file(RENAMED).unwrap_err(), OsError::Busy);
+    drop(file);
+    fs::remove_file(RENAMED).unwrap();
+    assert_eq!(fs::remove_file(RENAMED).unwrap_err(), OsError::NotFound);
+}
+
+fn pipes_carry_bytes_until_closed() {
+    let (read_fd, write_fd) = user::pipe().unwrap();
+    assert_eq!(user::write(write_fd, b"through").unwrap(), 7);
+    let copy = user::dup(write_fd).unwrap();
+    user::close(write_fd).unwrap();
+
+    let mut buf = [0u8; 16];
+    assert_eq!(user::read(read_fd, &mut buf).unwrap(), 7);
+    assert_eq!(&buf[..7], b"through");
+    // The end of input only comes once the last descriptor for the write end is closed.
+    user::close(copy).unwrap();
+    assert_eq!(user::read(read_fd, &mut buf).unwrap(), 0);
+
+    let (read_fd, write_fd) = user::pipe().unwrap();
+    user::close(read_fd).unwrap();
+    assert_eq!(user::write(write_fd, b"lost"), Err(OsError::BrokenPipe));
+    user::close(write_fd).unwrap();
+}
+
+fn input_reads_piped_lines() {
+    let (read_fd, write_fd) = user::pipe().unwrap();
+    user::write(write_fd, b"5\n3 apples\nno newline").unwrap();
+    user::close(write_fd).unwrap();
+    let console = user::dup(STDIN).unwrap();
+    user::dup2(read_fd, STDIN).unwrap();
+    io::discard_stdin();
+
+    assert_eq!(input::read_u32(), Ok(5));
+    let mut line = String::new();
+    input::read_line(&mut line).unwrap();
+    assert_eq!(scan!(line => u32, String), Some((3, String::from("apples"))));
+    let mut short = ArrayString::<4>::new();
+    input::read_line_array(&mut short).unwrap();
+    assert_eq!(short.as_str(), "no ");
+    assert_eq!(input::read_line(&mut line), Err(InputError::Eof));
+
+    user::dup2(console, STDIN).unwrap();
+    io::discard_stdin();
+    user::close(console).unwrap();
+    user::close(read_fd).unwrap();
+}
+
+fn interrupt_handler_can_be_set_and_reset() {
+    fn on_interrupt(_: Signal) {}
+    assert!(abi::has_feature(FEATURE_SIGNALS));
+    signal::on(Signal::Interrupt, on_interrupt).unwrap();
+    signal::reset(Signal::Interrupt).unwrap();
+}
+
+fn threads_share_memory_and_join() {
+    let count = Arc::new(Mutex::new(0));
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let count = Arc::clone(&count);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    *count.lock() += 1;
+                }
+                7
+            }).unwrap()
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().ok(), Some(7));
+    }
+    assert_eq!(*count.lock(), 200);
+}
+
+fn missing_file_is_not_found() {
+    assert_eq!(File::open("/no such file").unwrap_err(), OsError::NotFound);
+}
+
+fn array_string_fits_capacity() {
+    let mut s: ArrayString<8> = ArrayString::new();
+    s.push_str("os1k").unwrap();
+    assert!(s.push_str(" rocks").is_err());
+    assert_eq!(s.as_str(), "os1k");
+}
+
+fn history_skips_repeats_and_drops_oldest() {
+    let mut editor = LineEditor::new();
+    editor.add_history("ls");
+    editor.add_history("ls");
+    editor.add_history("  ");
+    assert_eq!(editor.history().collect::<Vec<_>>(), ["ls"]);
+
+    for i in 0..HISTORY_MAX {
+        editor.add_history(&format!("echo {}", i));
+    }
+    assert_eq!(editor.history().count(), HISTORY_MAX);
+    assert_eq!(editor.history().next(), Some("echo 0"));
+}
+
+fn random_range_stays_in_bounds() {
+    for _ in 0..1000 {
+        assert!((10..20).contains(&rand::range(10..20)));
+    }
+}
+
+fn sleep_waits_at_least_as_long() {
+    let start = Instant::now();
+    sleep_ms(20);
+    assert!(start.elapsed().as_millis() >= 20);
+}
+
+user_test!(
+    kernel_has_this_abi,
+    args_start_with_path,
+    env_vars_set_and_remove,
+    files_write_read_and_seek,
+    write_replaces_longer_file,
+    read_at_starts_at_offset,
+    read_dir_lists_scratch_file,
+    files_rename_and_remove,
+    pipes_carry_bytes_until_closed,
+    input_reads_piped_lines,
+    interrupt_handler_can_be_set_and_reset,
+    threads_share_memory_and_join,
+    missing_file_is_not_found,
+    array_string_fits_capacity,
+    history_skips_repeats_and_drops_oldest,
+    random_range_stays_in_bounds,
+    sleep_waits_at_least_as_long,
+);
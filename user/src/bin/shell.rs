@@ -3,70 +3,762 @@
 #![no_std]
 #![no_main]
 
-use core::ffi::CStr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use user::alloc::format;
+use user::alloc::string::String;
+use user::alloc::vec;
+use user::alloc::vec::Vec;
+use user::env;
+use user::fs::{self, File};
+use user::io::{self, Read as _, Write as _};
+use user::line::LineEditor;
+use user::process::{exit, resume, set_foreground, set_group, spawn, ExitStatus, Pid};
+use user::time::sleep_ms;
 use user::{
-    exit,
+    close,
+    dmesg,
+    dup2,
     print,
     println,
-    get_char,
-    put_byte,
-    readfile,
-    writefile,
+    log_level,
+    monotonic_ns,
+    mount,
+    open,
+    pipe,
+    poweroff,
+    reboot,
+    recvfrom,
+    sendto,
+    set_color,
+    sync,
+    test_exit,
+    umount,
+    write,
+    GREEN,
+    OsError,
+    Styled,
+    SockAddr,
+    OPEN_CREATE,
+    OPEN_TRUNC,
+    STDERR,
+    STDIN,
+    STDOUT,
 };
 
+const HOST: [u8; 4] = [10, 0, 2, 2];  // The host, as seen through QEMU user networking
+const UDP_PORT: u16 = 5555;
+
+// Where programs are looked for when $PATH is not set: directories separated by ':'.
+const PATH_DEFAULT: &str = "/bin:/";
+
+// Every builtin, for Tab to complete the first word of a line from. A program with the same
+// name as one is only run by its path, or with `run`.
+const COMMANDS: [&str; 32] = [
+    "appendfile", "bg", "cat", "color", "cp", "dmesg", "echo", "env", "exit", "export", "fg",
+    "hello", "hexdump", "history", "jobs", "loglevel", "ls", "mount", "mv", "poweroff",
+    "readfile", "reboot", "rm", "run", "sleep", "sync", "touch", "udprecv", "udpsend", "umount",
+    "uptime", "writefile",
+];
+
+// Set while a builtin runs with its input from a file or pipe rather than the console, which
+// commands that read stdin to its end need: the console never ends.
+static STDIN_REDIRECTED: AtomicBool = AtomicBool::new(false);
+
+// One command of a pipeline, and the files its input and output are redirected to.
+#[derive(Debug)]
+struct Stage<'a> {
+    words: Vec<&'a str>,
+    input: Option<&'a str>,     // < path
+    output: Option<&'a str>,    // > path
+}
+
+// Where a stage reads and writes: a file or pipe descriptor, or None for the shell's own.
+#[derive(Clone, Copy, Debug, Default)]
+struct Stdio {
+    input: Option<usize>,
+    output: Option<usize>,
+}
+
+impl Stdio {
+    fn close(&mut self) {
+        for fd in [self.input.take(), self.output.take()].into_iter().flatten() {
+            let _ = close(fd);
+        }
+    }
+}
+
+// The programs of a line started with `&`, or stopped by Ctrl-Z, that the shell has not waited
+// for. They are in a process group of their own, named by the first program, which fg makes
+// the foreground one. A job running in the background that reads the console competes with the
+// shell for what is typed.
+#[derive(Debug)]
+struct Job {
+    id: usize,                      // The number jobs lists it by, and fg and bg take
+    group: Pid,
+    programs: Vec<(String, Pid)>,   // Not waited for yet
+    line: String,
+    stopped: bool,
+}
+
 #[unsafe(no_mangle)]
 fn main() {
+    let mut editor = LineEditor::with_completer(complete);
+    let mut jobs = Vec::new();
+    let mut status = 0;     // Of the last line, for $?
     loop {
-        print!("> ");
-        let mut cmdline = [b'\n'; 128];
-        let mut pos = 0;
-        loop {
-            let Some(ch) = get_char() else {
-                break;
-            };
-            let byte = ch as u8;
-            match byte {
-                b'\r' => { // On the debug console the newline is \r
-                    println!();
-                    break;
-                },
+        reap_jobs(&mut jobs);
+        let Ok(cmdline) = editor.read_line(prompt(status)) else {
+            continue;
+        };
+        let cmdline = expand(&cmdline, status);
+        if !cmdline.trim().is_empty() {
+            status = run_line(cmdline.trim(), &editor, &mut jobs);
+        }
+    }
+}
+
+// The prompt: $PS1 expanded like a command line, so `export PS1=[\$?]` shows the last status,
+// and followed by a space. Without $PS1, a green "> ".
+fn prompt(status: u8) -> String {
+    match env::var("PS1") {
+        Some(ps1) => format!("{} ", expand(&ps1, status)),
+        None => format!("{}", Styled(GREEN, "> ")),
+    }
+}
+
+// Expands $NAME, ${NAME} and $?, the status of the last line, in `line`. Variables that are not
+// set expand to nothing. \$ is a $ that is not expanded, so `export` can store one for later.
+fn expand(line: &str, status: u8) -> String {
+    let mut expanded = String::new();
+    let mut rest = line;
+    while let Some(i) = rest.find(['$', '\\']) {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if rest[i..].starts_with('\\') {
+            let escaped = after.starts_with('$');
+            expanded.push(if escaped { '$' } else { '\\' });
+            rest = if escaped { &after[1..] } else { after };
+            continue;
+        }
+
+        let name_len = after.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(after.len());
+        let (name, next) = match after.strip_prefix('{').and_then(|braced| braced.split_once('}')) {
+            Some(braced) => braced,
+            None if after.starts_with('?') => after.split_at(1),
+            None => after.split_at(name_len),
+        };
+        match name {
+            "" => {
+                expanded.push('$');
+                rest = after;
+                continue;
+            },
+            "?" => expanded.push_str(&format!("{}", status)),
+            name => expanded.push_str(&env::var(name).unwrap_or_default()),
+        }
+        rest = next;
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Splits a line into the commands of a pipeline, `cmd1 | cmd2`, each with any `< path` and
+// `> path` taken out of its words.
+fn parse(line: &str) -> Result<Vec<Stage<'_>>, &'static str> {
+    line.split('|').map(|part| {
+        let mut stage = Stage { words: Vec::new(), input: None, output: None };
+        let mut words = part.split_whitespace();
+        while let Some(word) = words.next() {
+            let target = match word.as_bytes()[0] {
+                b'<' => &mut stage.input,
+                b'>' => &mut stage.output,
                 _ => {
-                    let _ = put_byte(byte);
-                    cmdline[pos] = byte;
-                    pos += 1;
-                }
-            }
+                    stage.words.push(word);
+                    continue;
+                },
+            };
+            // The path may follow the < or > straight away, or as the next word.
+            *target = match &word[1..] {
+                "" => Some(words.next().ok_or("missing file to redirect to")?),
+                path => Some(path),
+            };
+        }
+        if stage.words.is_empty() {
+            return Err("missing command");
+        }
+        Ok(stage)
+    }).collect()
+}
+
+// Opens the files a stage is redirected to, in place of any pipe ends. Output replaces the file,
+// as writing over a longer one would leave its tail in place.
+fn redirect<'a>(stage: &Stage<'a>, stdio: &mut Stdio) -> FileResult<'a> {
+    if let Some(path) = stage.input {
+        let fd = open(path, 0).map_err(|e| (path, e))?;
+        if let Some(pipe) = stdio.input.replace(fd) {
+            let _ = close(pipe);
+        }
+    }
+    if let Some(path) = stage.output {
+        let fd = open(path, OPEN_CREATE | OPEN_TRUNC).map_err(|e| (path, e))?;
+        if let Some(pipe) = stdio.output.replace(fd) {
+            let _ = close(pipe);
         }
+    }
+    Ok(())
+}
 
-        let cmdline_str = str::from_utf8(&cmdline)
-        .expect("command line text valid UTF8")
-        .trim();
+// Runs `f` with STDIN and STDOUT pointed at `stdio`. The shell's own are the console, like its
+// STDERR, which is never redirected, so they are put back from that.
+fn with_stdio<R>(stdio: Stdio, f: impl FnOnce() -> R) -> R {
+    let _ = io::stdout().flush();
+    if let Some(fd) = stdio.input {
+        let _ = dup2(fd, STDIN);
+        STDIN_REDIRECTED.store(true, Ordering::Relaxed);
+    }
+    if let Some(fd) = stdio.output {
+        let _ = dup2(fd, STDOUT);
+    }
+
+    let result = f();
 
-        match cmdline_str {
-            "hello" => {
-                println!("Hello world from the shell! 🐚");
+    let _ = io::stdout().flush();
+    if stdio.input.is_some() {
+        let _ = dup2(STDERR, STDIN);
+        io::discard_stdin();
+        STDIN_REDIRECTED.store(false, Ordering::Relaxed);
+    }
+    if stdio.output.is_some() {
+        let _ = dup2(STDERR, STDOUT);
+    }
+    result
+}
+
+// Runs each command of a pipeline with its input and output wired up. Programs are all
+// started first, then the builtins run in the shell one after another, and then the shell
+// waits for the programs, unless the line ends with `&`. So a builtin can only pass a pipe's
+// worth of output on to a builtin after it: beyond that, its writes wait for a reader that never
+// comes. Builtins always run to the end before the prompt comes back, `&` or not.
+//
+// Returns the status of the last program waited for, as $? shows it, or 1 if the line could
+// not be run. Builtins count as succeeding.
+fn run_line(line: &str, editor: &LineEditor, jobs: &mut Vec<Job>) -> u8 {
+    let (line, background) = match line.strip_suffix('&') {
+        Some(line) => (line.trim_end(), true),
+        None => (line, false),
+    };
+    let stages = match parse(line) {
+        Ok(stages) => stages,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        },
+    };
+
+    let mut stdio = vec![Stdio::default(); stages.len()];
+    let mut opened = Ok(());
+    for i in 1..stages.len() {
+        match pipe() {
+            Ok((read_fd, write_fd)) => {
+                stdio[i - 1].output = Some(write_fd);
+                stdio[i].input = Some(read_fd);
             },
-            "exit" => {
-                exit();
+            Err(e) => {
+                opened = Err(("pipe", e));
+                break;
             },
-            "readfile" => {
-                let mut buf = [0u8; 128];
-                readfile("hello.txt", &mut buf);
-                CStr::from_bytes_until_nul(&buf)
-                .ok()
-                .and_then(|cstr| cstr.to_str().ok())
-                .map(|s| println!("{}", s.trim_end()))
-                .unwrap_or_else(|| println!("could not read file contents"));
-            }
-            "writefile" => {
-                writefile(
-                    "meow.txt",
-                    b"Hello from the shell!");
+        }
+    }
+    let opened = opened.and_then(|()| {
+        stages.iter().zip(stdio.iter_mut()).try_for_each(|(stage, stdio)| redirect(stage, stdio))
+    });
+    if let Err((path, e)) = opened {
+        println!("{}: {}", path, e);
+        stdio.iter_mut().for_each(Stdio::close);
+        return 1;
+    }
+
+    // The shell closes its copy of each pipe end once the stage using it has it, so a reader
+    // sees the end of input when the writer finishes.
+    let programs: Vec<_> = stages.iter().map(|stage| program(&stage.words)).collect();
+    let mut running = Vec::new();
+    let mut status = 0;
+    for (program, stdio) in programs.iter().zip(stdio.iter_mut()) {
+        if let Some((path, args)) = program {
+            match with_stdio(*stdio, || spawn(path, args)) {
+                Ok(pid) => running.push((path.clone(), pid)),
+                Err(e) => {
+                    println!("{}: {}", path, e);
+                    status = 1;
+                },
+            }
+            stdio.close();
+        }
+    }
+    // The programs go in a process group of their own, for Ctrl-C and Ctrl-Z to reach them
+    // and not the background jobs.
+    let group = running.first().map(|&(_, pid)| pid);
+    if let Some(group) = group {
+        for &(_, pid) in &running {
+            let _ = set_group(pid, group);
+        }
+    }
+
+    for ((stage, program), stdio) in stages.iter().zip(&programs).zip(stdio.iter_mut()) {
+        if program.is_none() {
+            with_stdio(*stdio, || run_command(&stage.words, editor, jobs));
+            stdio.close();
+        }
+    }
+
+    let Some(group) = group else {
+        return status;
+    };
+    let id = jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    let job = Job { id, group, programs: running, line: String::from(line), stopped: false };
+    if background {
+        println!("[{}] {}", job.id, job.group);
+        jobs.push(job);
+        status
+    } else {
+        wait_job(job, jobs)
+    }
+}
+
+// Waits for a job's programs in the foreground, returning the status of the last, as $? shows
+// it. If Ctrl-Z stops them, the job goes on the list of jobs, for fg or bg to carry on with.
+fn wait_job(mut job: Job, jobs: &mut Vec<Job>) -> u8 {
+    let _ = set_foreground(Some(job.group));
+    let mut stopped = Vec::new();
+    let mut last = 0;
+    for (path, pid) in job.programs.drain(..) {
+        let status = pid.wait_or_stop();
+        match status {
+            Ok(status) if status.stopped() => stopped.push((path, pid)),
+            Ok(status) if status.success() => {},
+            Ok(status) => println!("{}: {}", path, status),
+            Err(e) => println!("{}: {}", path, e),
+        }
+        last = status.map_or(1, status_code);
+    }
+    let _ = set_foreground(None);
+
+    if !stopped.is_empty() {
+        job.programs = stopped;
+        job.stopped = true;
+        println!("[{}] stopped  {}", job.id, job.line);
+        jobs.push(job);
+    }
+    last
+}
+
+// A process's status as $? shows it: its exit code, or as in other shells, 128 plus the number
+// of the signal that would have ended or stopped it.
+fn status_code(status: ExitStatus) -> u8 {
+    match status.code() {
+        Some(code) => code,
+        None if status.interrupted() => 128 + 2,
+        None if status.stopped() => 128 + 20,
+        None => 128 + 9,
+    }
+}
+
+// Waits for the programs of background jobs that have ended, and drops the jobs that are done.
+fn reap_jobs(jobs: &mut Vec<Job>) {
+    jobs.retain_mut(|job| {
+        job.programs.retain(|(path, pid)| match pid.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) if status.success() => false,
+            Ok(Some(status)) => {
+                println!("{}: {}", path, status);
+                false
             },
-            _ => {
-                println!("unknown command: {}", cmdline_str);
+            Err(e) => {
+                println!("{}: {}", path, e);
+                false
             },
+        });
+        if job.programs.is_empty() {
+            println!("[{}] done  {}", job.id, job.line);
+        }
+        !job.programs.is_empty()
+    });
+}
+
+// Runs `words` if it is one of the job control commands, returning whether it was. fg and bg
+// take a job's number, with or without a '%', or carry on with the newest job.
+fn job_command(words: &[&str], jobs: &mut Vec<Job>) -> bool {
+    match words {
+        ["jobs"] => {
+            for job in jobs.iter() {
+                let state = if job.stopped { "stopped" } else { "running" };
+                println!("[{}] {}  {}", job.id, state, job.line);
+            }
+        },
+        [cmd @ ("fg" | "bg"), id @ ..] if id.len() <= 1 => {
+            let id = match id.first() {
+                Some(id) => id.trim_start_matches('%').parse().ok(),
+                None => jobs.last().map(|job| job.id),
+            };
+            let Some(i) = id.and_then(|id| jobs.iter().position(|job| job.id == id)) else {
+                println!("{}: no such job", cmd);
+                return true;
+            };
+            if let Err(e) = resume(jobs[i].group) {
+                println!("{} failed: {}", cmd, e);
+                return true;
+            }
+            if *cmd == "fg" {
+                let job = jobs.remove(i);
+                println!("{}", job.line);
+                wait_job(job, jobs);
+            } else {
+                jobs[i].stopped = false;
+                println!("[{}] {} &", jobs[i].id, jobs[i].line);
+            }
+        },
+        ["fg", ..] => println!("usage: fg [job]"),
+        ["bg", ..] => println!("usage: bg [job]"),
+        _ => return false,
+    }
+    true
+}
+
+// The path and arguments of a command that runs a program from the file system: `run <path>
+// [args...]`, a path to the program, or the name of one that is not a builtin.
+fn program<'a, 'b>(words: &'b [&'a str]) -> Option<(String, &'b [&'a str])> {
+    match words {
+        ["run", path, args @ ..] => Some((String::from(*path), args)),
+        [path, args @ ..] if path.contains('/') => Some((String::from(*path), args)),
+        [name, args @ ..] if !COMMANDS.contains(name) => find_program(name).map(|path| (path, args)),
+        _ => None,
+    }
+}
+
+// The directories in $PATH, where programs are looked for.
+fn search_path() -> String {
+    env::var("PATH").unwrap_or_else(|| String::from(PATH_DEFAULT))
+}
+
+// Finds the program `name` runs: `<name>.bin` in the first directory in $PATH that has it.
+fn find_program(name: &str) -> Option<String> {
+    search_path()
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| format!("{}/{}.bin", dir.trim_end_matches('/'), name))
+        .find(|path| fs::metadata(path).is_ok())
+}
+
+// Runs a builtin command.
+fn run_command(words: &[&str], editor: &LineEditor, jobs: &mut Vec<Job>) {
+    if file_command(words) || job_command(words, jobs) {
+        return;
+    }
+
+    let cmdline_str = words.join(" ");
+    match cmdline_str.as_str() {
+        "hello" => {
+            println!("Hello world from the shell! 🐚");
+        },
+        // The shell is the only process, so exiting it leaves nothing to do.
+        "exit" | "poweroff" => {
+            let e = poweroff();
+            println!("poweroff failed: {}", e);
+            exit(1);
+        },
+        cmd if cmd.starts_with("exit ") => {
+            // exit <status>: QEMU exits with the status, for scripted runs
+            match cmd["exit ".len()..].trim().parse() {
+                Ok(status) => {
+                    let e = test_exit(status);
+                    println!("exit failed: {}", e);
+                    exit(1);
+                },
+                Err(_) => println!("usage: exit [status]"),
+            }
+        },
+        "reboot" => {
+            let e = reboot();
+            println!("reboot failed: {}", e);
+        },
+        "readfile" => {
+            match fs::read_to_string("hello.txt") {
+                Ok(text) => println!("{}", text.trim_end_matches(['\0', '\n'])),
+                Err(OsError::Corrupt) => println!("hello.txt is corrupt"),
+                Err(e) => println!("could not read hello.txt: {}", e),
+            }
+        },
+        "writefile" => {
+            if let Err(e) = fs::write("meow.txt", b"Hello from the shell!") {
+                println!("writefile failed: {}", e);
+            }
+        },
+        "appendfile" => {
+            let appended = File::append("meow.txt")
+                .and_then(|mut file| file.write_all(b"\nAppended by the shell!"));
+            if let Err(e) = appended {
+                println!("appendfile failed: {}", e);
+            }
+        },
+        "uptime" => {
+            let ns = monotonic_ns();
+            println!("up {}.{:03} s", ns / 1_000_000_000, ns / 1_000_000 % 1000);
+        },
+        "history" => {
+            for (i, line) in editor.history().enumerate() {
+                println!("{:>4}  {}", i + 1, line);
+            }
+        },
+        "env" => {
+            for (name, value) in env::vars() {
+                println!("{}={}", name, value);
+            }
+        },
+        cmd if cmd.starts_with("export ") => {
+            // export NAME=value: set for the shell and the programs it runs
+            match cmd["export ".len()..].trim().split_once('=') {
+                Some((name, value)) => {
+                    if let Err(e) = env::set_var(name, value) {
+                        println!("export failed: {}", e);
+                    }
+                },
+                None => println!("usage: export NAME=value"),
+            }
+        },
+        cmd if cmd.starts_with("sleep ") => {
+            // sleep <ms>
+            match cmd["sleep ".len()..].trim().parse() {
+                Ok(ms) => sleep_ms(ms),
+                Err(_) => println!("usage: sleep <ms>"),
+            }
+        },
+        "dmesg" => {
+            let mut buf = [0u8; 128];
+            let mut offset = 0;
+            loop {
+                let len = match dmesg(offset, &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                let _ = write(STDOUT, &buf[..len]);
+                offset += len;
+            }
+        },
+        "sync" => {
+            if let Err(e) = sync() {
+                println!("sync failed: {}", e);
+            }
+        },
+        // color on|off: off for consoles that show escape sequences as text
+        "color on" => set_color(true),
+        "color off" => set_color(false),
+        cmd if cmd.starts_with("loglevel ") => {
+            // loglevel <0-5>: 0 is silent, 5 prints trace messages too
+            match cmd["loglevel ".len()..].trim().parse() {
+                Ok(level) if log_level(level).is_ok() => {},
+                _ => println!("usage: loglevel <0-5>"),
+            }
+        },
+        cmd if cmd.starts_with("mount ") => {
+            // mount <type> <path> [device]
+            let mut args = cmd["mount ".len()..].split_whitespace();
+            match (args.next(), args.next(), args.next().unwrap_or("")) {
+                (Some(fstype), Some(path), device) => {
+                    if let Err(e) = mount(fstype, path, device) {
+                        println!("mount failed: {}", e);
+                    }
+                },
+                _ => println!("usage: mount <type> <path> [device]"),
+            }
+        },
+        cmd if cmd.starts_with("umount ") => {
+            if let Err(e) = umount(cmd["umount ".len()..].trim()) {
+                println!("umount failed: {}", e);
+            }
+        },
+        "run" => println!("usage: run <path> [args...]"),
+        cmd if cmd.starts_with("udpsend ") => {
+            let to = SockAddr { ip: HOST, port: UDP_PORT };
+            if let Err(e) = sendto(UDP_PORT, &cmd.as_bytes()["udpsend ".len()..], &to) {
+                println!("udpsend failed: {}", e);
+            }
+        },
+        "udprecv" => {
+            let mut buf = [0u8; 128];
+            let mut from = SockAddr::default();
+            match recvfrom(UDP_PORT, &mut buf, &mut from) {
+                Ok(len) => match str::from_utf8(&buf[..len]) {
+                    Ok(text) => println!("{:?}:{}: {}", from.ip, from.port, text),
+                    Err(_) => println!("udprecv: not UTF-8"),
+                },
+                Err(e) => println!("udprecv failed: {}", e),
+            }
+        },
+        _ => {
+            println!("unknown command: {}", cmdline_str);
+        },
+    }
+}
+
+// Runs `args` if it is one of the file commands, returning whether it was.
+fn file_command(args: &[&str]) -> bool {
+    let result = match args {
+        ["ls"] => ls("/"),
+        ["ls", path] => ls(path),
+        ["cat"] if STDIN_REDIRECTED.load(Ordering::Relaxed) => cat_stdin(),
+        ["cat", paths @ ..] if !paths.is_empty() => paths.iter().try_for_each(|path| cat(path)),
+        ["echo", words @ ..] => {
+            println!("{}", words.join(" "));
+            Ok(())
+        },
+        ["rm", paths @ ..] if !paths.is_empty() => {
+            paths.iter().try_for_each(|path| fs::remove_file(path).map_err(|e| (*path, e)))
+        },
+        ["hexdump"] if STDIN_REDIRECTED.load(Ordering::Relaxed) => hexdump_stdin(),
+        ["hexdump", path] => hexdump(path),
+        ["cp", from, to] => cp(from, to),
+        ["mv", from, to] => fs::rename(from, to).map_err(|e| (*from, e)),
+        ["touch", paths @ ..] if !paths.is_empty() => {
+            // Creates missing files, leaving existing ones as they are.
+            paths.iter().try_for_each(|path| File::open_with(path, OPEN_CREATE).map(drop).map_err(|e| (*path, e)))
+        },
+        ["ls", ..] => usage("ls [path]"),
+        ["cat", ..] => usage("cat <path>..."),
+        ["rm", ..] => usage("rm <path>..."),
+        ["hexdump", ..] => usage("hexdump <path>"),
+        ["cp", ..] => usage("cp <from> <to>"),
+        ["mv", ..] => usage("mv <from> <to>"),
+        ["touch", ..] => usage("touch <path>..."),
+        _ => return false,
+    };
+    if let Err((path, e)) = result {
+        println!("{}: {}: {}", args[0], path, e);
+    }
+    true
+}
+
+// A failed file command's error, with the path it failed on.
+type FileResult<'a> = Result<(), (&'a str, OsError)>;
+
+fn usage(usage: &str) -> FileResult<'static> {
+    println!("usage: {}", usage);
+    Ok(())
+}
+
+// Lists the files at `path` with their sizes.
+fn ls(path: &str) -> FileResult<'_> {
+    for entry in fs::read_dir(path) {
+        let entry = entry.map_err(|e| (path, e))?;
+        let name = entry.name();
+        match fs::metadata(&format!("{}/{}", path.trim_end_matches('/'), name)) {
+            Ok(metadata) => println!("{:>8} {}", metadata.len(), name),
+            // A link to a file that does not exist has no size.
+            Err(_) => println!("{:>8} {}", "?", name),
+        }
+    }
+    Ok(())
+}
+
+fn cat(path: &str) -> FileResult<'_> {
+    let bytes = fs::read(path).map_err(|e| (path, e))?;
+    io::stdout().write_all(&bytes).map_err(|e| (path, e))
+}
+
+fn cat_stdin() -> FileResult<'static> {
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes).map_err(|e| ("stdin", e))?;
+    io::stdout().write_all(&bytes).map_err(|e| ("stdin", e))
+}
+
+// Prints the file at `path` as hexdump -C does, reading it a piece at a time by offset.
+fn hexdump(path: &str) -> FileResult<'_> {
+    let mut buf = [0u8; 256];   // A whole number of lines, so each piece starts a line
+    let mut offset = 0;
+    loop {
+        // Reads may stop short at a sector boundary, so fill the buffer before printing.
+        let mut len = 0;
+        while len < buf.len() {
+            match fs::read_at(path, offset + len, &mut buf[len..]).map_err(|e| (path, e))? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        hexdump_lines(offset, &buf[..len]);
+        offset += len;
+        if len < buf.len() {
+            break;
         }
     }
+    println!("{:08x}", offset);
+    Ok(())
+}
+
+fn hexdump_stdin() -> FileResult<'static> {
+    let mut bytes = Vec::new();
+    io::stdin().read_to_end(&mut bytes).map_err(|e| ("stdin", e))?;
+    hexdump_lines(0, &bytes);
+    println!("{:08x}", bytes.len());
+    Ok(())
+}
+
+// Prints `bytes`, which start `offset` bytes into a file, 16 to a line: the offset, the bytes in
+// hex in two groups of 8, then the bytes as ASCII, with '.' for those that are not printable.
+fn hexdump_lines(offset: usize, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        print!("{:08x} ", offset + i * 16);
+        for j in 0..16 {
+            if j % 8 == 0 {
+                print!(" ");
+            }
+            match line.get(j) {
+                Some(b) => print!("{:02x} ", b),
+                None => print!("   "),
+            }
+        }
+        let ascii: String = line.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        println!(" |{}|", ascii);
+    }
+}
+
+fn cp<'a>(from: &'a str, to: &'a str) -> FileResult<'a> {
+    let bytes = fs::read(from).map_err(|e| (from, e))?;
+    fs::write(to, &bytes).map_err(|e| (to, e))
+}
+
+// Completes the first word of a line as a builtin or the name of a program in $PATH, and any
+// later word, or a first word with a slash, as a file path.
+fn complete(before: &str) -> Vec<String> {
+    let (word, first) = match before.rsplit_once(' ') {
+        Some((start, word)) => (word, start.trim().is_empty()),
+        None => (before, true),
+    };
+    if first && !word.contains('/') {
+        let mut commands: Vec<String> = COMMANDS.iter()
+            .map(|&command| String::from(command))
+            .collect();
+        for dir in search_path().split(':').filter(|dir| !dir.is_empty()) {
+            commands.extend(fs::read_dir(dir)
+                .map_while(Result::ok)
+                .filter_map(|entry| entry.name().strip_suffix(".bin").map(String::from)));
+        }
+        commands.retain(|command| command.starts_with(word));
+        commands.sort();
+        commands.dedup();
+        return commands;
+    }
+
+    // Files are listed from the directory named before the last slash, or the root.
+    let (dir, name) = match word.rsplit_once('/') {
+        Some((dir, name)) => (&word[..dir.len() + 1], name),
+        None => ("", word),
+    };
+    let list = if dir.is_empty() { "/" } else { dir };
+    fs::read_dir(list)
+        .map_while(Result::ok)
+        .filter(|entry| entry.name().starts_with(name))
+        .map(|entry| format!("{}{}", dir, entry.name()))
+        .collect()
 }
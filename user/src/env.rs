@@ -0,0 +1,141 @@
+//! Program arguments and environment variables for os1k programs
+//!
+//! The kernel hands `start` argc and argv, as in C: a count and an array of pointers to
+//! NUL-terminated strings, the first of which is the program's name. They are kept here for
+//! args() and arg() to read.
+//!
+//! Environment variables are kept by the kernel, one set per process. A process starts with a
+//! copy of those of the process that spawned it, so setting one affects only the process and
+//! the ones it spawns afterwards.
+
+use alloc::string::String;
+use alloc::vec;
+use core::ffi::{c_char, CStr};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use common::error::check;
+use common::{OsError, Syscall};
+
+use crate::sys_call;
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(ptr::null_mut());
+
+// Called by `start` with the registers the kernel set, before main.
+pub(crate) extern "C" fn init(argc: usize, argv: *mut *const c_char) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+}
+
+/// Returns argument `n`, where argument 0 is the program's name.
+pub fn arg(n: usize) -> Option<&'static str> {
+    if n >= ARGC.load(Ordering::Relaxed) {
+        return None;
+    }
+    // Safety: the kernel maps argv, with argc pointers to NUL-terminated strings, for the life
+    // of the process
+    let arg = unsafe { CStr::from_ptr(*ARGV.load(Ordering::Relaxed).add(n)) };
+    // The kernel only passes str, so this is always UTF-8.
+    Some(arg.to_str().unwrap_or_default())
+}
+
+/// Returns an iterator over the arguments, starting with the program's name.
+pub fn args() -> Args {
+    Args { next: 0, end: ARGC.load(Ordering::Relaxed) }
+}
+
+/// The arguments the program was started with, from args().
+#[derive(Clone, Debug)]
+pub struct Args {
+    next: usize,
+    end: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next == self.end {
+            return None;
+        }
+        self.next += 1;
+        arg(self.next - 1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.next;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for Args {
+    fn next_back(&mut self) -> Option<&'static str> {
+        if self.next == self.end {
+            return None;
+        }
+        self.end -= 1;
+        arg(self.end)
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+// Runs a syscall that copies a string of unknown length into a buffer and returns the full
+// length, again with a bigger buffer until the string fits.
+fn read_string(call: impl Fn(&mut [u8]) -> isize) -> Result<String, OsError> {
+    let mut buf = vec![0u8; 64];
+    loop {
+        let len = check(call(&mut buf))?;
+        if len <= buf.len() {
+            buf.truncate(len);
+            // The kernel only holds str, so this is always UTF-8.
+            return Ok(String::from_utf8(buf).unwrap_or_default());
+        }
+        buf.resize(len, 0);
+    }
+}
+
+/// Returns the value of environment variable `name`, or None if it is not set.
+pub fn var(name: &str) -> Option<String> {
+    read_string(|buf| {
+        sys_call(Syscall::GetEnv, name.as_ptr() as isize, name.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0)
+    }).ok()
+}
+
+/// Sets environment variable `name` to `value`. Fails with OsError::InvalidName if `name` is
+/// empty or holds '=', or OsError::NoSpace if the process's variables would take over 4 KB.
+pub fn set_var(name: &str, value: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::SetEnv, name.as_ptr() as isize, name.len() as isize, value.as_ptr() as isize, value.len() as isize, 0, 0)).map(|_| ())
+}
+
+/// Removes environment variable `name`, if it is set.
+pub fn remove_var(name: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::SetEnv, name.as_ptr() as isize, name.len() as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Returns an iterator over the environment variables as (name, value) pairs, in the order they
+/// were first set.
+pub fn vars() -> Vars {
+    Vars { next: 0 }
+}
+
+/// The environment variables, from vars().
+#[derive(Clone, Debug)]
+pub struct Vars {
+    next: usize,
+}
+
+impl Iterator for Vars {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        let index = self.next;
+        let entry = read_string(|buf| {
+            sys_call(Syscall::ListEnv, index as isize, buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, 0)
+        }).ok()?;
+        self.next += 1;
+        let (name, value) = entry.split_once('=')?;
+        Some((String::from(name), String::from(value)))
+    }
+}
@@ -0,0 +1,207 @@
+//! Files for os1k programs
+//!
+//! A File is an open file descriptor, closed when the File is dropped. It reads and writes
+//! through io::Read and io::Write, from an offset that moves on as it goes and that seek sets.
+//! read, read_to_string and write do the whole job on a file by its path, and metadata,
+//! remove_file and rename work on it without reading it. read_dir lists the files at the root of
+//! a mounted file system.
+//!
+//! Writing at an offset leaves the rest of the file in place. write and File::create empty the
+//! file first, so they replace it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use common::abi::Stat;
+use common::error::check;
+use common::{OsError, Syscall, OPEN_APPEND, OPEN_CREATE, OPEN_TRUNC, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use crate::io::{Read, Write};
+use crate::sys_call;
+
+/// A file open on a file descriptor.
+#[derive(Debug)]
+pub struct File {
+    fd: usize,
+}
+
+/// Where to seek to, counted in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+pub use common::abi::DirEnt;
+
+/// What is known about a file.
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    stat: Stat,
+}
+
+impl Metadata {
+    /// The size of the file in bytes.
+    pub fn len(&self) -> usize {
+        self.stat.size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stat.size == 0
+    }
+
+    /// Identifies the file within its file system.
+    pub fn inode(&self) -> usize {
+        self.stat.inode as usize
+    }
+}
+
+/// The entries of a directory, from read_dir. Stops after the first error.
+#[derive(Debug)]
+pub struct ReadDir<'a> {
+    path: &'a str,
+    index: usize,
+    done: bool,
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Result<DirEnt, OsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut entry = DirEnt::default();
+        let path = self.path;
+        let read = check(sys_call(Syscall::ReadDir, path.as_ptr() as isize, path.len() as isize, self.index as isize, &mut entry as *mut DirEnt as isize, 0, 0));
+        self.index += 1;
+        match read {
+            Ok(0) => {
+                self.done = true;
+                None
+            },
+            Ok(_) => Some(Ok(entry)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl File {
+    /// Opens the file at `path` for reading and writing from its start. Fails with
+    /// OsError::NotFound if there is no such file.
+    pub fn open(path: &str) -> Result<File, OsError> {
+        Self::open_with(path, 0)
+    }
+
+    /// Opens the file at `path` as open does, creating it if it does not exist and emptying it
+    /// if it does.
+    pub fn create(path: &str) -> Result<File, OsError> {
+        Self::open_with(path, OPEN_CREATE | OPEN_TRUNC)
+    }
+
+    /// Opens the file at `path` so that every write goes to its end.
+    pub fn append(path: &str) -> Result<File, OsError> {
+        Self::open_with(path, OPEN_APPEND)
+    }
+
+    /// Opens the file at `path` with `flags`, a combination of OPEN_APPEND, OPEN_CREATE and
+    /// OPEN_TRUNC.
+    pub fn open_with(path: &str, flags: usize) -> Result<File, OsError> {
+        crate::open(path, flags).map(|fd| File { fd })
+    }
+
+    pub fn fd(&self) -> usize {
+        self.fd
+    }
+
+    /// Moves the offset the next read or write starts at, returning it counted from the start.
+    /// Seeking past the end is allowed, and a write there grows the file.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, OsError> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset as isize, SEEK_SET),
+            SeekFrom::Current(offset) => (offset, SEEK_CUR),
+            SeekFrom::End(offset) => (offset, SEEK_END),
+        };
+        check(sys_call(Syscall::Seek, self.fd as isize, offset, whence as isize, 0, 0, 0))
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, OsError> {
+        let mut stat = Stat::default();
+        check(sys_call(Syscall::Fstat, self.fd as isize, &mut stat as *mut Stat as isize, 0, 0, 0, 0))?;
+        Ok(Metadata { stat })
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OsError> {
+        crate::read(self.fd, buf)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OsError> {
+        crate::write(self.fd, buf)
+    }
+
+    // Writes go straight to the kernel, which keeps them until fsync.
+    fn flush(&mut self) -> Result<(), OsError> {
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = crate::close(self.fd);
+    }
+}
+
+/// Reads the whole file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, OsError> {
+    let mut file = File::open(path)?;
+    let mut bytes = Vec::with_capacity(file.metadata()?.len());
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads the whole file at `path` as text. Fails with OsError::Invalid if it is not UTF-8.
+pub fn read_to_string(path: &str) -> Result<String, OsError> {
+    String::from_utf8(read(path)?).map_err(|_| OsError::Invalid)
+}
+
+/// Reads from the file at `path` into `buf`, starting `offset` bytes in, without opening it.
+/// Returns how many bytes were read, which is 0 at the end of the file, and may be fewer than
+/// `buf` holds before then.
+pub fn read_at(path: &str, offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+    check(sys_call(Syscall::ReadFile, path.as_ptr() as isize, path.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize, offset as isize, 0))
+}
+
+/// Replaces the contents of the file at `path` with `bytes`, creating it if it does not exist.
+pub fn write(path: &str, bytes: &[u8]) -> Result<(), OsError> {
+    File::create(path)?.write_all(bytes)
+}
+
+/// Returns the size and inode of the file at `path`.
+pub fn metadata(path: &str) -> Result<Metadata, OsError> {
+    File::open(path)?.metadata()
+}
+
+/// Removes the file at `path`. Fails with OsError::Busy while any process has it open.
+pub fn remove_file(path: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::Unlink, path.as_ptr() as isize, path.len() as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Moves the file at `from` to `to`, on the same file system. Fails with OsError::Exists if there
+/// is a file at `to` already.
+pub fn rename(from: &str, to: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::Rename, from.as_ptr() as isize, from.len() as isize, to.as_ptr() as isize, to.len() as isize, 0, 0)).map(|_| ())
+}
+
+/// Lists the directory at `path`, the mount point of a file system. Entries come in the order
+/// the file system keeps them. The first fails with OsError::Invalid if `path` is a file.
+pub fn read_dir(path: &str) -> ReadDir<'_> {
+    ReadDir { path, index: 0, done: false }
+}
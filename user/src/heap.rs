@@ -0,0 +1,110 @@
+//! Heap for os1k programs
+//!
+//! The global allocator, so programs can use Box, Vec and String. Memory comes from the kernel
+//! with Syscall::Sbrk, a few pages at a time, and is handed out by bumping a pointer. Freed blocks
+//! go on a free list and are reused, first fit, splitting a block that is larger than needed.
+//! Neighbouring free blocks are not merged.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::sbrk;
+use crate::sync::Mutex;
+
+const BLOCK_ALIGN: usize = 16;      // Every block is a multiple of this in size and alignment
+const GROW_MIN: usize = 16 * 1024;  // Least the heap grows by at once
+
+// A free block, stored in the block itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    next: usize,            // Start of the memory not yet handed out
+    end: usize,             // The break
+    free: *mut FreeBlock,
+}
+
+// Safety: the free list is only reached through the Mutex
+unsafe impl Send for Heap {}
+
+struct Allocator(Mutex<Heap>);
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator(Mutex::new(Heap { next: 0, end: 0, free: ptr::null_mut() }));
+
+const fn align_up(value: usize, align: usize) -> usize {
+    value.next_multiple_of(align)
+}
+
+// The size of the block that holds `layout`.
+fn block_size(layout: Layout) -> usize {
+    align_up(layout.size().max(size_of::<FreeBlock>()), BLOCK_ALIGN)
+}
+
+impl Heap {
+    // Takes the first free block that fits, handing back the rest of it.
+    fn take_free(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut link: *mut *mut FreeBlock = &mut self.free;
+        // Safety: the free list only holds blocks handed back by dealloc, which nothing else uses
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let addr = block as usize;
+                if (*block).size >= size && addr.is_multiple_of(align) {
+                    let rest = (*block).size - size;
+                    if rest >= BLOCK_ALIGN {
+                        let tail = (addr + size) as *mut FreeBlock;
+                        tail.write(FreeBlock { size: rest, next: (*block).next });
+                        *link = tail;
+                    } else {
+                        *link = (*block).next;
+                    }
+                    return Some(addr);
+                }
+                link = &mut (*block).next;
+            }
+        }
+        None
+    }
+
+    // Hands out new memory from the end of the heap, growing it if need be.
+    fn bump(&mut self, size: usize, align: usize) -> Option<usize> {
+        if self.end == 0 {
+            let start = sbrk(0).ok()?;
+            self.next = start;
+            self.end = start;
+        }
+        let addr = align_up(self.next, align);
+        let needed = (addr + size).saturating_sub(self.end);
+        if needed > 0 {
+            // Nothing else moves the break, so the new memory follows on from the old.
+            let grow = align_up(needed.max(GROW_MIN), BLOCK_ALIGN);
+            sbrk(grow).ok()?;
+            self.end += grow;
+        }
+        self.next = addr + size;
+        Some(addr)
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+        let mut heap = self.0.lock();
+        heap.take_free(size, align)
+            .or_else(|| heap.bump(size, align))
+            .map_or(ptr::null_mut(), |addr| addr as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let mut heap = self.0.lock();
+        let block = block as *mut FreeBlock;
+        // Safety: the caller hands back a block from alloc with the same layout, so it is ours,
+        // aligned for a FreeBlock and at least block_size(layout) long.
+        unsafe { block.write(FreeBlock { size: block_size(layout), next: heap.free }) };
+        heap.free = block;
+    }
+}
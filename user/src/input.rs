@@ -0,0 +1,112 @@
+//! Reading input for os1k programs
+//!
+//! Helpers over buffered stdin for interactive programs: read_line for a line of text, read and
+//! read_u32 for a value typed on a line of its own, and scan! to pick several values out of a
+//! line. read_line_array reads a line without the heap. They read a line typed at the console
+//! or piped in alike.
+
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
+
+use common::array::ArrayString;
+use common::OsError;
+
+use crate::io::{read_line_with, stdin};
+
+/// Why a value could not be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputError {
+    /// The input ended, with Ctrl-D at the console or the end of a pipe or file, before a line.
+    Eof,
+    /// The line did not hold a value of the type asked for.
+    Invalid,
+    Os(OsError),
+}
+
+impl From<OsError> for InputError {
+    fn from(e: OsError) -> Self {
+        InputError::Os(e)
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::Eof => f.write_str("end of input"),
+            InputError::Invalid => f.write_str("invalid input"),
+            InputError::Os(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Reads a line from stdin into `line`, replacing what it held, without the '\n'. Fails with
+/// InputError::Eof at the end of input.
+pub fn read_line(line: &mut String) -> Result<(), InputError> {
+    line.clear();
+    if stdin().read_line(line)? == 0 {
+        return Err(InputError::Eof);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(())
+}
+
+/// Reads a line from stdin into `line`, replacing what it held, without the '\n'. The line is
+/// cut short to N - 1 bytes, and a character cut in two is dropped. Fails with InputError::Eof
+/// at the end of input.
+pub fn read_line_array<const N: usize>(line: &mut ArrayString<N>) -> Result<(), InputError> {
+    let mut buf = [0u8; N];
+    let mut len = 0;
+    let read = read_line_with(|part| {
+        let part = part.strip_suffix(b"\n").unwrap_or(part);
+        let fits = part.len().min(N.saturating_sub(1) - len);
+        buf[len..len + fits].copy_from_slice(&part[..fits]);
+        len += fits;
+    })?;
+    if read == 0 {
+        return Err(InputError::Eof);
+    }
+    let bytes = &buf[..len];
+    let text = match str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    line.clear();
+    line.push_str(text).map_err(|_| InputError::Invalid)
+}
+
+/// Reads a line from stdin and parses it, ignoring spaces around the value.
+pub fn read<T: FromStr>() -> Result<T, InputError> {
+    let mut line = String::new();
+    read_line(&mut line)?;
+    line.trim().parse().map_err(|_| InputError::Invalid)
+}
+
+/// Reads a line from stdin holding a number.
+pub fn read_u32() -> Result<u32, InputError> {
+    read()
+}
+
+/// Splits a line at whitespace and parses one field into each type, giving a tuple:
+/// `scan!(line => u32, String)`. Gives None if a field is missing, does not parse, or is left
+/// over.
+#[macro_export]
+macro_rules! scan {
+    ($line:expr => $($ty:ty),+ $(,)?) => {
+        'scan: {
+            let mut fields = $line.split_whitespace();
+            let values = ($(
+                match fields.next().and_then(|field| field.parse::<$ty>().ok()) {
+                    Some(value) => value,
+                    None => break 'scan None,
+                },
+            )+);
+            if fields.next().is_some() {
+                break 'scan None;
+            }
+            Some(values)
+        }
+    };
+}
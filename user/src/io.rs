@@ -0,0 +1,232 @@
+//! Standard input and output for os1k programs
+//!
+//! stdout() collects what is written and passes it to the kernel a line at a time, or when its
+//! buffer fills, rather than a syscall per byte; print! and println! go through it. stdin()
+//! reads ahead into a buffer, a line at a time through the kernel's line discipline when STDIN is
+//! the console, or straight from STDIN when it is a pipe or file. Reading from stdin writes out
+//! stdout first, so a prompt shows before the program waits. stderr() is not buffered, so errors are never held back.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use common::{OsError, STDERR, STDIN, STDOUT};
+
+use crate::sync::Mutex;
+
+const STDOUT_BUF_SIZE: usize = 256;
+const STDIN_BUF_SIZE: usize = 128;
+
+pub trait Read {
+    /// Reads into `buf`, returning the number of bytes read, 0 at the end of input.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OsError>;
+
+    /// Reads until the end of input, appending to `buf`. Returns the number of bytes read.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, OsError> {
+        let start = buf.len();
+        let mut chunk = [0u8; 128];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(buf.len() - start),
+                len => buf.extend_from_slice(&chunk[..len]),
+            }
+        }
+    }
+}
+
+pub trait Write {
+    /// Writes from `buf`, returning the number of bytes taken.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OsError>;
+
+    /// Writes out anything held back in a buffer.
+    fn flush(&mut self) -> Result<(), OsError>;
+
+    /// Writes all of `buf`.
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), OsError> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(OsError::NoSpace),
+                len => buf = &buf[len..],
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Buffer<const N: usize> {
+    bytes: [u8; N],
+    start: usize,   // Bytes before this have been consumed
+    end: usize,
+}
+
+impl<const N: usize> Buffer<N> {
+    const fn new() -> Self {
+        Self { bytes: [0; N], start: 0, end: 0 }
+    }
+
+    fn pending(&self) -> &[u8] {
+        &self.bytes[self.start..self.end]
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+}
+
+static STDOUT_BUF: Mutex<Buffer<STDOUT_BUF_SIZE>> = Mutex::new(Buffer::new());
+static STDIN_BUF: Mutex<Buffer<STDIN_BUF_SIZE>> = Mutex::new(Buffer::new());
+
+fn flush_stdout(buf: &mut Buffer<STDOUT_BUF_SIZE>) -> Result<(), OsError> {
+    while !buf.pending().is_empty() {
+        let len = crate::write(STDOUT, buf.pending())?;
+        buf.consume(len);
+    }
+    Ok(())
+}
+
+// Writes out stdout's buffer unless it is in use, as when panicking partway through a write.
+pub(crate) fn try_flush_stdout() {
+    if let Some(mut buf) = STDOUT_BUF.try_lock() {
+        let _ = flush_stdout(&mut buf);
+    }
+}
+
+/// Standard output, buffered a line at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct Stdout;
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+impl Write for Stdout {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, OsError> {
+        let mut buf = STDOUT_BUF.lock();
+        for &b in bytes {
+            if buf.end == STDOUT_BUF_SIZE {
+                flush_stdout(&mut buf)?;
+            }
+            let end = buf.end;
+            buf.bytes[end] = b;
+            buf.end += 1;
+            if b == b'\n' {
+                flush_stdout(&mut buf)?;
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> Result<(), OsError> {
+        flush_stdout(&mut STDOUT_BUF.lock())
+    }
+}
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Standard error, written straight through.
+#[derive(Clone, Copy, Debug)]
+pub struct Stderr;
+
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OsError> {
+        crate::write(STDERR, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), OsError> {
+        Ok(())
+    }
+}
+
+impl fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+// Refills stdin's buffer, with an edited line from the console, or whatever STDIN gives when it
+// is not the console.
+fn fill_stdin(buf: &mut Buffer<STDIN_BUF_SIZE>) -> Result<(), OsError> {
+    buf.end = match crate::read_line(&mut buf.bytes) {
+        Err(OsError::Unsupported) => crate::read(STDIN, &mut buf.bytes)?,
+        len => len?,
+    };
+    Ok(())
+}
+
+/// Drops what stdin has read ahead, when STDIN has been pointed at another file with dup2.
+pub fn discard_stdin() {
+    let mut buf = STDIN_BUF.lock();
+    buf.start = 0;
+    buf.end = 0;
+}
+
+/// Standard input, read ahead into a buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct Stdin;
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+impl Stdin {
+    /// Reads a line, edited by the kernel's line discipline if STDIN is the console, and appends
+    /// it to `line`, with its '\n' if it has one. Returns the number of bytes read, 0 at the end
+    /// of input.
+    pub fn read_line(&mut self, line: &mut String) -> Result<usize, OsError> {
+        let mut bytes = Vec::new();
+        let len = read_line_with(|part| bytes.extend_from_slice(part))?;
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(len)
+    }
+}
+
+// Reads a line from stdin, passing its bytes to `take` as they come out of the buffer, with its
+// '\n' if it has one. Returns the line's length, 0 at the end of input.
+pub(crate) fn read_line_with(mut take: impl FnMut(&[u8])) -> Result<usize, OsError> {
+    let _ = stdout().flush();
+    let mut buf = STDIN_BUF.lock();
+    let mut total = 0;
+    loop {
+        if buf.pending().is_empty() {
+            fill_stdin(&mut buf)?;
+            if buf.pending().is_empty() {
+                return Ok(total);
+            }
+        }
+        let pending = buf.pending();
+        let len = pending.iter().position(|&b| b == b'\n').map_or(pending.len(), |i| i + 1);
+        let ends_line = pending[len - 1] == b'\n';
+        take(&pending[..len]);
+        buf.consume(len);
+        total += len;
+        if ends_line {
+            return Ok(total);
+        }
+    }
+}
+
+impl Read for Stdin {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, OsError> {
+        let _ = stdout().flush();
+        let mut buf = STDIN_BUF.lock();
+        if buf.pending().is_empty() {
+            fill_stdin(&mut buf)?;
+        }
+        let len = out.len().min(buf.pending().len());
+        out[..len].copy_from_slice(&buf.pending()[..len]);
+        buf.consume(len);
+        Ok(len)
+    }
+}
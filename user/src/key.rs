@@ -0,0 +1,155 @@
+//! Key events for os1k programs
+//!
+//! get_char hands over the bytes a terminal sends, one at a time: arrow keys and the like come
+//! as VT100 escape sequences, and Ctrl with a letter as a control character. Keys reads those
+//! bytes and puts them back together into one Key per key pressed.
+//!
+//! Escape on its own is only told apart from the start of an escape sequence by the byte after
+//! it, so it is reported once the next key is pressed, and that key is kept for the next read.
+
+use common::OsError;
+
+use crate::get_char;
+use crate::sync::Mutex;
+
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+/// A key pressed at the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// Ctrl with a letter, given in lower case, other than those with keys of their own
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+}
+
+struct Keys {
+    pending: Option<u8>,    // Read past the end of the last key
+}
+
+static KEYS: Mutex<Keys> = Mutex::new(Keys { pending: None });
+
+/// Waits for the next key pressed at the console. Escape sequences that are not known are
+/// skipped.
+pub fn read_key() -> Result<Key, OsError> {
+    KEYS.lock().read()
+}
+
+impl Keys {
+    fn read(&mut self) -> Result<Key, OsError> {
+        loop {
+            let b = self.next_byte()?;
+            let key = match b {
+                ESC => self.escape()?,
+                b'\r' | b'\n' => Some(Key::Enter),
+                b'\t' => Some(Key::Tab),
+                0x08 | DEL => Some(Key::Backspace),
+                0x01..=0x1a => Some(Key::Ctrl((b'a' + b - 1) as char)),
+                0x00 | 0x1c..=0x1f => None,
+                0x20..=0x7e => Some(Key::Char(b as char)),
+                _ => Some(Key::Char(self.utf8(b)?)),
+            };
+            if let Some(key) = key {
+                return Ok(key);
+            }
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<u8, OsError> {
+        match self.pending.take() {
+            Some(b) => Ok(b),
+            None => get_char(),
+        }
+    }
+
+    // Decodes what follows an ESC.
+    fn escape(&mut self) -> Result<Option<Key>, OsError> {
+        match self.next_byte()? {
+            b'[' => self.csi(),
+            // SS3: the arrow keys, Home and End in application mode
+            b'O' => Ok(match self.next_byte()? {
+                b'A' => Some(Key::Up),
+                b'B' => Some(Key::Down),
+                b'C' => Some(Key::Right),
+                b'D' => Some(Key::Left),
+                b'H' => Some(Key::Home),
+                b'F' => Some(Key::End),
+                _ => None,
+            }),
+            b => {
+                self.pending = Some(b);
+                Ok(Some(Key::Escape))
+            },
+        }
+    }
+
+    // Decodes a control sequence, ESC [ then parameters then a final byte, ignoring modifiers
+    // such as the 5 in ESC [ 1 ; 5 A (Ctrl-Up).
+    fn csi(&mut self) -> Result<Option<Key>, OsError> {
+        let mut param = 0u32;
+        let mut first = true;
+        loop {
+            let b = self.next_byte()?;
+            match b {
+                b'0'..=b'9' if first => param = param.saturating_mul(10).saturating_add((b - b'0') as u32),
+                b';' => first = false,
+                0x20..=0x3f => {},
+                _ => return Ok(match b {
+                    b'A' => Some(Key::Up),
+                    b'B' => Some(Key::Down),
+                    b'C' => Some(Key::Right),
+                    b'D' => Some(Key::Left),
+                    b'H' => Some(Key::Home),
+                    b'F' => Some(Key::End),
+                    b'~' => match param {
+                        1 | 7 => Some(Key::Home),
+                        2 => Some(Key::Insert),
+                        3 => Some(Key::Delete),
+                        4 | 8 => Some(Key::End),
+                        5 => Some(Key::PageUp),
+                        6 => Some(Key::PageDown),
+                        _ => None,
+                    },
+                    _ => None,
+                }),
+            }
+        }
+    }
+
+    // Reads the rest of a UTF-8 character that starts with `lead`.
+    fn utf8(&mut self, lead: u8) -> Result<char, OsError> {
+        let len = match lead {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Ok(char::REPLACEMENT_CHARACTER),
+        };
+        let mut bytes = [lead, 0, 0, 0];
+        for slot in &mut bytes[1..len] {
+            let b = self.next_byte()?;
+            if b & 0xc0 != 0x80 {
+                // Not a continuation byte, so it starts the next key.
+                self.pending = Some(b);
+                return Ok(char::REPLACEMENT_CHARACTER);
+            }
+            *slot = b;
+        }
+        Ok(str::from_utf8(&bytes[..len]).ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
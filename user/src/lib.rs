@@ -2,30 +2,111 @@
 
 #![no_std]
 
+pub extern crate alloc;
+
+pub mod abi;
+pub mod env;
+pub mod fs;
+mod heap;
+pub mod input;
+pub mod io;
+pub mod key;
+pub mod line;
+pub mod process;
+pub mod rand;
+pub mod signal;
+pub mod sync;
+pub mod test;
+pub mod thread;
+pub mod time;
+
 use core::arch::{asm, naked_asm};
+use core::fmt::{self, Write as _};
+use core::ops::Range;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use io::Write as _;
+use process::PANIC_EXIT_CODE;
 
-pub use common::{print, println};
+pub use common::{print, println, print_color, println_color};
+pub use common::print::{color_enabled, set_color, Cursor, Styled};
+pub use common::print::{BLUE, BOLD, CYAN, DIM, GREEN, MAGENTA, RED, RESET, YELLOW};
+pub use common::net::SockAddr;
+pub use common::{OPEN_APPEND, OPEN_CREATE, OPEN_TRUNC, STDIN, STDOUT, STDERR};
+pub use common::CLOCK_MONOTONIC;
+pub use common::{LOG_DEBUG, LOG_ERROR, LOG_INFO, LOG_TRACE, LOG_WARN};
+pub use common::{LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+pub use common::OsError;
+pub use common::abi::TimeSpec;
+pub use common::array::{ArrayString, ArrayVec, CapacityError};
 
+use common::backtrace::backtrace;
+
+use common::error::check;
 use common::{
-    SYS_PUTBYTE,
-    SYS_GETCHAR,
-    SYS_EXIT,
-    SYS_READFILE,
-    SYS_WRITEFILE,
+    FUTEX_WAIT,
+    FUTEX_WAKE,
+    SHUTDOWN_POWEROFF,
+    SHUTDOWN_REBOOT,
+    SHUTDOWN_EXIT,
+    Syscall,
 };
 
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// The stack `sp` is on: the program's own, or a thread's. A thread's stack is on the heap and
+// ends at most thread::STACK_SIZE above `sp`, and the heap ends at the break.
+fn current_stack(sp: usize) -> Range<usize> {
+    let stack = (&raw const __user_stack_bottom as usize)..(&raw const __user_stack_top as usize);
+    if stack.contains(&sp) {
+        return stack;
+    }
+    let heap_end = sbrk(0).unwrap_or(sp);
+    sp..(sp + thread::STACK_SIZE).min(heap_end)
+}
+
+// Prints the return addresses of the calls leading here, innermost first. Look the addresses
+// up in user/user.map, or with `llvm-addr2line -e` on the program's ELF file.
+fn user_backtrace(out: &mut impl fmt::Write) -> fmt::Result {
+    let (fp, sp): (usize, usize);
+    // Safety: only reads s0, the frame pointer, and sp
+    unsafe { asm!("mv {}, s0", "mv {}, sp", out(reg) fp, out(reg) sp) };
+    // Safety: the stack is mapped memory of the program's
+    unsafe { backtrace(out, fp, current_stack(sp)) }
+}
+
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    println!("😬 User Panic! {}", info);
-    exit();
+    // A panic while reporting a panic goes straight to exit.
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        process::exit(PANIC_EXIT_CODE);
+    }
+
+    // Through stderr, as the panic may have come partway through writing to stdout.
+    io::try_flush_stdout();
+    let message = info.message();
+    let mut stderr = io::stderr();
+    let _ = match info.location() {
+        Some(at) => writeln!(stderr, "{}", Styled(RED, format_args!("😬 User Panic at {}: {}", at, message))),
+        None => writeln!(stderr, "{}", Styled(RED, format_args!("😬 User Panic: {}", message))),
+    };
+    let _ = user_backtrace(&mut stderr);
+
+    // Stop in the kernel's debugger, which shows the registers and can continue on to the exit.
+    #[cfg(feature = "panic-ebreak")]
+    // Safety: ebreak only traps to the kernel
+    unsafe { asm!("ebreak") };
+
+    process::exit(PANIC_EXIT_CODE);
 }
 
 unsafe extern "C" {
+    static __user_stack_bottom: u8;
     static __user_stack_top: u8;
 }
 
-pub fn sys_call(sysno: usize, arg0: isize, arg1: isize, arg2: isize, arg3: isize) -> isize {
+pub fn sys_call(sysno: Syscall, arg0: isize, arg1: isize, arg2: isize, arg3: isize, arg4: isize, arg5: isize) -> isize {
     let a0: isize;
     unsafe{asm!(
         "ecall",
@@ -33,42 +114,204 @@ pub fn sys_call(sysno: usize, arg0: isize, arg1: isize, arg2: isize, arg3: isize
         in("a1") arg1,
         in("a2") arg2,
         in("a3") arg3,
-        in("a4") sysno,
+        in("a4") sysno as usize,
+        in("a5") arg4,
+        in("a6") arg5,
     )}
     a0
 }
 
+/// Writes `b` to stdout, which print! and println! use. It is buffered until the end of the
+/// line: see `io`.
 #[unsafe(no_mangle)]
-pub fn put_byte(b: u8) -> Result<(), isize> {
-    let result = sys_call(SYS_PUTBYTE, b as isize, 0, 0, 0);
-    if result == 0 {
-        Ok(())
-    } else {
-        Err(result)
-    }
+pub fn put_byte(b: u8) -> Result<(), OsError> {
+    io::stdout().write(&[b]).map(|_| ())
 }
 
-pub fn get_char() -> Option<usize> {
-    let ch = sys_call(SYS_GETCHAR, 0, 0, 0, 0);
-    if ch == -1 {
-        None
-    } else {
-        Some(ch as usize)
-    }
+/// Reads a byte of console input, waiting for one. Unlike stdin, this takes keys as they are
+/// typed, without line editing or echo. key::read_key puts together the escape sequences
+/// that arrow and other keys send.
+pub fn get_char() -> Result<u8, OsError> {
+    let _ = io::stdout().flush();
+    check(sys_call(Syscall::GetChar, 0, 0, 0, 0, 0, 0)).map(|ch| ch as u8)
 }
 
-#[unsafe(no_mangle)]
-pub fn exit() -> ! {
-    let _ = sys_call(SYS_EXIT, 0, 0, 0, 0);
-    unreachable!("just in case!");
+/// Writes any changes to the file back to the disk.
+/// Until then, writes only live in kernel memory and are lost on power off.
+pub fn fsync(filename: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::Fsync, filename.as_ptr() as isize, filename.len() as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Writes changes to every file back to the disk.
+pub fn sync() -> Result<(), OsError> {
+    check(sys_call(Syscall::Fsync, 0, 0, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Opens the file at `path`, such as "hello.txt" or "/dev/null", returning a file descriptor.
+/// `flags` is a combination of OPEN_APPEND, OPEN_CREATE and OPEN_TRUNC. fs::File wraps this, and
+/// closes the descriptor when dropped.
+pub fn open(path: &str, flags: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Open, path.as_ptr() as isize, path.len() as isize, flags as isize, 0, 0, 0))
+}
+
+/// Reads from the file descriptor's current offset, returning the number of bytes read.
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Read, fd as isize, buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, 0))
+}
+
+/// Writes at the file descriptor's current offset, returning the number of bytes written.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Write, fd as isize, buf.as_ptr() as isize, buf.len() as isize, 0, 0, 0))
+}
+
+/// Closes the file descriptor, or fails with OsError::BadFd if nothing is open at it.
+pub fn close(fd: usize) -> Result<(), OsError> {
+    check(sys_call(Syscall::Close, fd as isize, 0, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Makes a pipe, returning descriptors for its read and write ends: what is written to the
+/// second is read from the first. Reads at the end of input once every descriptor for the write
+/// end is closed, and writes fail with OsError::BrokenPipe once the read end's are.
+pub fn pipe() -> Result<(usize, usize), OsError> {
+    let mut fds = [0usize; 2];
+    check(sys_call(Syscall::Pipe, fds.as_mut_ptr() as isize, 0, 0, 0, 0, 0))?;
+    Ok((fds[0], fds[1]))
+}
+
+/// Opens another descriptor for the file open at `fd`, the lowest one free. It starts at the
+/// same offset, but each then keeps its own.
+pub fn dup(fd: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Dup, fd as isize, 0, 0, 0, 0, 0))
+}
+
+/// Makes `to` a descriptor for the file open at `fd`, closing what was open at `to` first. A
+/// program spawned next starts with the process's STDIN, STDOUT and STDERR, so this points them
+/// at a file or pipe for it.
+pub fn dup2(fd: usize, to: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Dup2, fd as isize, to as isize, 0, 0, 0, 0))
+}
+
+/// Takes (LOCK_SH or LOCK_EX) or releases (LOCK_UN) an advisory lock on the file open at `fd`.
+/// Waits for other processes to release conflicting locks, or fails with OsError::WouldBlock
+/// with LOCK_NB. The wait fails with OsError::Interrupted if Ctrl-C is typed for the process.
+/// The lock is released when the process closes its last descriptor for the file, or exits.
+pub fn flock(fd: usize, op: usize) -> Result<(), OsError> {
+    check(sys_call(Syscall::Flock, fd as isize, op as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Mounts a file system at `path`. `fstype` is "fat" or "tar" for the disk called `device`, such
+/// as "disk1" ("auto" to probe it, and an empty name for disk0), or "devfs", "proc" or "9p", which
+/// ignore `device`. Only the first process may mount, and each disk can only be mounted once.
+pub fn mount(fstype: &str, path: &str, device: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::Mount, path.as_ptr() as isize, path.len() as isize, fstype.as_ptr() as isize, fstype.len() as isize, device.as_ptr() as isize, device.len() as isize)).map(|_| ())
+}
+
+/// Writes any changes on the file system at `path` to the disk and unmounts it.
+pub fn umount(path: &str) -> Result<(), OsError> {
+    check(sys_call(Syscall::Umount, path.as_ptr() as isize, path.len() as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Sends `buf` as a UDP datagram from local port `port` to `to`. The host is at 10.0.2.2 under
+/// QEMU user networking. Returns the number of bytes sent, or fails with OsError::Unreachable if
+/// nothing answers. A port belongs to the first process to send or receive on it, until that
+/// process exits.
+pub fn sendto(port: u16, buf: &[u8], to: &SockAddr) -> Result<usize, OsError> {
+    check(sys_call(Syscall::SendTo, port as isize, buf.as_ptr() as isize, buf.len() as isize, to as *const SockAddr as isize, 0, 0))
+}
+
+/// Waits for a UDP datagram on local port `port` and reads it into `buf`, truncating it to fit.
+/// Returns its length and stores the sender in `from`. Fails with OsError::Interrupted if Ctrl-C
+/// is typed for the process while it waits.
+pub fn recvfrom(port: u16, buf: &mut [u8], from: &mut SockAddr) -> Result<usize, OsError> {
+    check(sys_call(Syscall::RecvFrom, port as isize, buf.as_mut_ptr() as isize, buf.len() as isize, from as *mut SockAddr as isize, 0, 0))
+}
+
+/// Reads clock `clock`, such as CLOCK_MONOTONIC. Fails with OsError::Unsupported for an unknown
+/// clock.
+pub fn clock_gettime(clock: usize) -> Result<TimeSpec, OsError> {
+    let mut time = TimeSpec::default();
+    check(sys_call(Syscall::ClockGettime, clock as isize, &mut time as *mut TimeSpec as isize, 0, 0, 0, 0))?;
+    Ok(time)
+}
+
+/// Returns the nanoseconds since boot. time::Instant wraps this.
+pub fn monotonic_ns() -> u64 {
+    clock_gettime(CLOCK_MONOTONIC).expect("the monotonic clock is always there").as_nanos()
+}
+
+/// Sleeps while `word` holds `expected`, until futex_wake is called on it. Returns once woken,
+/// which may be spuriously, or fails with OsError::WouldBlock if `word` no longer held `expected`.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), OsError> {
+    check(sys_call(Syscall::Futex, word.as_ptr() as isize, FUTEX_WAIT as isize, expected as isize, 0, 0, 0)).map(|_| ())
+}
+
+/// Wakes up to `count` processes sleeping in futex_wait on `word`. Returns how many it woke.
+pub fn futex_wake(word: &AtomicU32, count: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Futex, word.as_ptr() as isize, FUTEX_WAKE as isize, count as isize, 0, 0, 0))
+}
+
+/// Copies the kernel's message log, from `offset` bytes into it, into `buf`. Returns the number
+/// of bytes copied, 0 at the end of the log.
+pub fn dmesg(offset: usize, buf: &mut [u8]) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Dmesg, buf.as_mut_ptr() as isize, buf.len() as isize, offset as isize, 0, 0, 0))
+}
+
+/// Reads a line of console input into `buf`, with the kernel echoing it and handling
+/// backspace, Ctrl-U and Ctrl-W. The line ends with '\n', and is cut short to fit `buf`.
+/// Returns its length, which is 0 only at the end of input (Ctrl-D on an empty line). Fails
+/// with OsError::Unsupported when STDIN is not the console, as when input is piped in.
+pub fn read_line(buf: &mut [u8]) -> Result<usize, OsError> {
+    let _ = io::stdout().flush();
+    check(sys_call(Syscall::ReadLine, buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, 0, 0))
+}
+
+/// Moves the end of the heap up by `increment` bytes. Returns the old end, or fails with
+/// OsError::NoSpace if the heap cannot grow that far, or OsError::NoMem if the kernel is out of
+/// memory. `sbrk(0)` returns the end without moving it. Programs get memory through Box, Vec and
+/// String, whose allocator calls this.
+pub fn sbrk(increment: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::Sbrk, increment as isize, 0, 0, 0, 0, 0))
+}
+
+/// Fills `buf` with random bytes from the kernel. rand draws on this for its seed, and is
+/// cheaper for many values.
+pub fn getrandom(buf: &mut [u8]) -> Result<usize, OsError> {
+    check(sys_call(Syscall::GetRandom, buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, 0, 0))
+}
+
+/// Has the kernel print only messages at `level`, such as LOG_WARN, and the levels before it;
+/// 0 silences it. Returns the previous level, or fails with OsError::Invalid for an unknown level.
+pub fn log_level(level: usize) -> Result<usize, OsError> {
+    check(sys_call(Syscall::LogLevel, level as isize, 0, 0, 0, 0, 0))
+}
+
+/// Writes every file system back to its disk and powers the machine off. Only returns, with
+/// OsError::Permission, if the caller is not the init process.
+pub fn poweroff() -> OsError {
+    shutdown(SHUTDOWN_POWEROFF, 0)
+}
+
+/// Writes every file system back to its disk and reboots the machine. Only returns, with
+/// OsError::Permission, if the caller is not the init process.
+pub fn reboot() -> OsError {
+    shutdown(SHUTDOWN_REBOOT, 0)
+}
+
+/// Writes every file system back to its disk and powers the machine off, with QEMU exiting with
+/// `status`: 0 for a passing test run. Only returns, with OsError::Permission, if the caller is
+/// not the init process.
+pub fn test_exit(status: u16) -> OsError {
+    shutdown(SHUTDOWN_EXIT, status as usize)
 }
 
-pub fn readfile(filename: &str, buf: &mut [u8]) {
-    let _ = sys_call(SYS_READFILE, filename.as_ptr() as isize, filename.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize);
+fn shutdown(action: usize, arg: usize) -> OsError {
+    let ret = sys_call(Syscall::Shutdown, action as isize, arg as isize, 0, 0, 0, 0);
+    check(ret).err().unwrap_or(OsError::Invalid)
 }
 
-pub fn writefile(filename: &str, buf: &[u8]) {
-    let _ = sys_call(SYS_WRITEFILE, filename.as_ptr() as isize, filename.len() as isize,  buf.as_ptr() as isize, buf.len() as isize);
+// Where start goes once main returns.
+extern "C" fn main_returned() -> ! {
+    process::exit(0)
 }
 
 #[unsafe(link_section = ".text.start")]
@@ -76,10 +319,18 @@ pub fn writefile(filename: &str, buf: &[u8]) {
 #[unsafe(naked)]
 unsafe extern "C" fn start() {
     naked_asm!(
+        // A zero frame pointer ends a panic backtrace.
         "la sp, {stack_top}",
+        "li s0, 0",
+        // a0 and a1 hold argc and argv from the kernel.
+        "call {init_env}",
+        "call {init_abi}",
         "call main",
-        "call exit",
-        stack_top = sym __user_stack_top
+        "call {main_returned}",
+        stack_top = sym __user_stack_top,
+        init_env = sym env::init,
+        init_abi = sym abi::init,
+        main_returned = sym main_returned
     )
 }
 
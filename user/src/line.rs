@@ -0,0 +1,204 @@
+//! Line editing for os1k programs
+//!
+//! LineEditor reads a line key by key with key::read_key, redrawing it as it is edited, rather
+//! than leaving the editing to the kernel. Left and Right (or Home and End, Ctrl-A and Ctrl-E)
+//! move the cursor, Backspace and Delete erase around it, Ctrl-U erases up to it and Ctrl-W the
+//! word before it. Up and Down step through the lines entered before, which the editor keeps in
+//! a ring of the last HISTORY_MAX, and a recalled line can be edited like a new one.
+//!
+//! Tab completes the word before the cursor from what a Completer offers for it. One candidate
+//! replaces the word. Several are completed as far as they agree, and once they agree no
+//! further, a second Tab lists them under the line.
+//!
+//! Each character is taken to be one column wide.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::input::InputError;
+use crate::key::{read_key, Key};
+use crate::{print, println, Cursor};
+
+/// Most lines kept in the history. Entering another drops the oldest.
+pub const HISTORY_MAX: usize = 32;
+
+/// Given the line up to the cursor, returns what the word before the cursor could be. The word
+/// is what follows the last space, and the candidates replace all of it.
+pub type Completer = fn(&str) -> Vec<String>;
+
+/// Reads lines from the console with editing and history.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    history: VecDeque<String>,  // Oldest first
+    completer: Option<Completer>,
+}
+
+// The line being edited.
+struct Edit {
+    chars: Vec<char>,
+    cursor: usize,          // Index into chars the next character goes at
+    recalled: usize,        // How many lines back in the history, 0 for the new line
+    draft: Vec<char>,       // The new line, put aside while a recalled one is shown
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Completes words with `completer` when Tab is pressed.
+    pub fn with_completer(completer: Completer) -> Self {
+        Self { history: VecDeque::new(), completer: Some(completer) }
+    }
+
+    /// The lines entered so far, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Adds `line` to the history, unless it is blank or the same as the last line.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_MAX {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    /// Shows `prompt` and reads a line, without the '\n', adding it to the history. Ctrl-C
+    /// gives up on the line and reads as an empty one. Fails with InputError::Eof for Ctrl-D on
+    /// an empty line.
+    pub fn read_line(&mut self, prompt: impl fmt::Display) -> Result<String, InputError> {
+        let mut edit = Edit { chars: Vec::new(), cursor: 0, recalled: 0, draft: Vec::new() };
+        let mut tabs = 0;   // Tabs pressed in a row
+        print!("{}", prompt);
+
+        loop {
+            let key = read_key()?;
+            tabs = if key == Key::Tab { tabs + 1 } else { 0 };
+            match key {
+                Key::Enter => break,
+                Key::Ctrl('c') => {
+                    println!("^C");
+                    return Ok(String::new());
+                },
+                Key::Ctrl('d') if edit.chars.is_empty() => {
+                    println!();
+                    return Err(InputError::Eof);
+                },
+                Key::Char(c) => {
+                    edit.chars.insert(edit.cursor, c);
+                    edit.cursor += 1;
+                },
+                Key::Backspace if edit.cursor > 0 => {
+                    edit.cursor -= 1;
+                    edit.chars.remove(edit.cursor);
+                },
+                Key::Delete if edit.cursor < edit.chars.len() => {
+                    edit.chars.remove(edit.cursor);
+                },
+                Key::Ctrl('u') => {
+                    edit.chars.drain(..edit.cursor);
+                    edit.cursor = 0;
+                },
+                Key::Ctrl('w') => {
+                    let end = edit.cursor;
+                    while edit.cursor > 0 && edit.chars[edit.cursor - 1] == ' ' {
+                        edit.cursor -= 1;
+                    }
+                    while edit.cursor > 0 && edit.chars[edit.cursor - 1] != ' ' {
+                        edit.cursor -= 1;
+                    }
+                    edit.chars.drain(edit.cursor..end);
+                },
+                Key::Left if edit.cursor > 0 => edit.cursor -= 1,
+                Key::Right if edit.cursor < edit.chars.len() => edit.cursor += 1,
+                Key::Home | Key::Ctrl('a') => edit.cursor = 0,
+                Key::End | Key::Ctrl('e') => edit.cursor = edit.chars.len(),
+                Key::Up if edit.recalled < self.history.len() => self.recall(&mut edit, true),
+                Key::Down if edit.recalled > 0 => self.recall(&mut edit, false),
+                Key::Tab => match self.completer {
+                    Some(completer) => complete(completer, &mut edit, tabs > 1),
+                    None => continue,
+                },
+                _ => continue,
+            }
+            redraw(&prompt, &edit);
+        }
+
+        println!();
+        let line: String = edit.chars.into_iter().collect();
+        self.add_history(&line);
+        Ok(line)
+    }
+
+    // Shows the line one older or newer in the history in place of the one being edited, with the
+    // cursor at its end. Newer than the newest brings back the new line.
+    fn recall(&self, edit: &mut Edit, older: bool) {
+        let back = if older { edit.recalled + 1 } else { edit.recalled - 1 };
+        if edit.recalled == 0 {
+            edit.draft = core::mem::take(&mut edit.chars);
+        }
+        edit.chars = match back {
+            0 => core::mem::take(&mut edit.draft),
+            _ => self.history[self.history.len() - back].chars().collect(),
+        };
+        edit.recalled = back;
+        edit.cursor = edit.chars.len();
+    }
+}
+
+// Completes the word before the cursor, listing the candidates if asked to and they are ambiguous.
+fn complete(completer: Completer, edit: &mut Edit, list: bool) {
+    let before: String = edit.chars[..edit.cursor].iter().collect();
+    let candidates = completer(&before);
+    let word_start = edit.chars[..edit.cursor].iter()
+        .rposition(|&c| c == ' ')
+        .map_or(0, |space| space + 1);
+
+    let completion = match candidates.as_slice() {
+        [] => return,
+        [only] => {
+            let mut only = String::from(only.as_str());
+            only.push(' ');
+            only
+        },
+        [first, rest @ ..] => {
+            let common = rest.iter().fold(first.chars().count(), |len, c| {
+                first.chars().zip(c.chars()).take(len).take_while(|(a, b)| a == b).count()
+            });
+            let common: String = first.chars().take(common).collect();
+            if list && common.chars().count() <= edit.cursor - word_start {
+                println!();
+                for candidate in &candidates {
+                    print!("{}  ", candidate);
+                }
+                println!();
+            }
+            common
+        },
+    };
+
+    // Never shorten the word, which candidates that do not start with it could.
+    if completion.chars().count() < edit.cursor - word_start {
+        return;
+    }
+    edit.chars.splice(word_start..edit.cursor, completion.chars());
+    edit.cursor = word_start + completion.chars().count();
+}
+
+// Draws the prompt and line over the current console line, and puts the cursor in place.
+fn redraw(prompt: &impl fmt::Display, edit: &Edit) {
+    print!("{}{}", Cursor::ClearLine, prompt);
+    for c in &edit.chars {
+        print!("{}", c);
+    }
+    let back = edit.chars.len() - edit.cursor;
+    if back > 0 {
+        print!("{}", Cursor::Back(back));
+    }
+}
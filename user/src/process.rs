@@ -0,0 +1,147 @@
+//! Processes for os1k programs
+//!
+//! spawn starts a program from the file system as a new process, and the Pid it returns waits
+//! for the process to end. Every process that is spawned should be waited for, as its slot in
+//! the kernel's process table is only freed then.
+//!
+//! Processes are in process groups, for job control: a spawned process joins its parent's group,
+//! and set_group moves it to another, named by the Pid of a process in it. Ctrl-C and Ctrl-Z
+//! reach the group the shell makes the foreground one with set_foreground.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use common::abi::SpawnArgs;
+use common::error::check;
+use common::{OsError, Syscall, EXIT_INTERRUPTED, EXIT_KILLED, EXIT_STOPPED, WAIT_NOHANG, WAIT_STOPPED};
+
+use crate::io::{self, Write as _};
+use crate::sys_call;
+
+/// The exit code of a program that panicked, as for Rust programs elsewhere.
+pub const PANIC_EXIT_CODE: u8 = 101;
+
+/// A process started by spawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pid(pub(crate) usize);
+
+impl Pid {
+    pub fn id(self) -> usize {
+        self.0
+    }
+
+    /// Waits for the process to end, returning how it ended.
+    pub fn wait(self) -> Result<ExitStatus, OsError> {
+        let _ = io::stdout().flush();
+        check(sys_call(Syscall::Wait, self.0 as isize, 0, 0, 0, 0, 0)).map(ExitStatus)
+    }
+
+    /// Returns how the process ended if it has, or None if it is still running.
+    pub fn try_wait(self) -> Result<Option<ExitStatus>, OsError> {
+        match check(sys_call(Syscall::Wait, self.0 as isize, WAIT_NOHANG as isize, 0, 0, 0, 0)) {
+            Ok(status) => Ok(Some(ExitStatus(status))),
+            Err(OsError::WouldBlock) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for the process to end or for Ctrl-Z to stop it. A stopped process has not ended,
+    /// and is still to be waited for.
+    pub fn wait_or_stop(self) -> Result<ExitStatus, OsError> {
+        let _ = io::stdout().flush();
+        check(sys_call(Syscall::Wait, self.0 as isize, WAIT_STOPPED as isize, 0, 0, 0, 0)).map(ExitStatus)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a process ended: with an exit code, or ended by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitStatus(usize);
+
+impl ExitStatus {
+    /// Whether the process exited with code 0.
+    pub fn success(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The code the process passed to exit, if it exited rather than being ended by the kernel.
+    pub fn code(self) -> Option<u8> {
+        u8::try_from(self.0).ok()
+    }
+
+    /// Whether the process exited because it panicked.
+    pub fn panicked(self) -> bool {
+        self.code() == Some(PANIC_EXIT_CODE)
+    }
+
+    /// Whether Ctrl-C ended the process.
+    pub fn interrupted(self) -> bool {
+        self.0 == EXIT_INTERRUPTED
+    }
+
+    /// Whether the kernel killed the process, for a fault or at a breakpoint.
+    pub fn killed(self) -> bool {
+        self.0 == EXIT_KILLED
+    }
+
+    /// Whether Ctrl-Z stopped the process, from Pid::wait_or_stop.
+    pub fn stopped(self) -> bool {
+        self.0 == EXIT_STOPPED
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code() {
+            Some(PANIC_EXIT_CODE) => write!(f, "panicked"),
+            Some(code) => write!(f, "exit code {}", code),
+            None if self.interrupted() => write!(f, "interrupted"),
+            None if self.stopped() => write!(f, "stopped"),
+            None => write!(f, "killed"),
+        }
+    }
+}
+
+/// Starts the program at `path` as a new process. It gets `path` as its first argument, then
+/// `args`. Fails with OsError::NoSpace if the kernel has no room for another process.
+pub fn spawn(path: &str, args: &[&str]) -> Result<Pid, OsError> {
+    // The kernel takes the arguments as NUL-terminated strings, one after the other.
+    let mut argv = Vec::new();
+    for arg in core::iter::once(&path).chain(args) {
+        argv.extend_from_slice(arg.as_bytes());
+        argv.push(0);
+    }
+    let spawn_args = SpawnArgs { path: path.as_ptr(), path_len: path.len(), argv: argv.as_ptr(), argv_len: argv.len() };
+    check(sys_call(Syscall::Spawn, &spawn_args as *const SpawnArgs as isize, 0, 0, 0, 0, 0)).map(Pid)
+}
+
+/// Moves process `pid` and its threads into the process group `group`, or a group of its own if
+/// they are the same. Only the shell may move a process other than its caller.
+pub fn set_group(pid: Pid, group: Pid) -> Result<(), OsError> {
+    check(sys_call(Syscall::SetGroup, pid.0 as isize, group.0 as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Makes `group` the process group Ctrl-C and Ctrl-Z reach, or with None, the shell's own group.
+/// Only the shell may.
+pub fn set_foreground(group: Option<Pid>) -> Result<(), OsError> {
+    let group = group.map_or(0, |group| group.0);
+    check(sys_call(Syscall::SetForeground, group as isize, 0, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Lets the processes of `group` that Ctrl-Z stopped run again. Only the shell may.
+pub fn resume(group: Pid) -> Result<(), OsError> {
+    check(sys_call(Syscall::Continue, group.0 as isize, 0, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Ends the process with `code`, which a process waiting for it gets back: 0 for success. In a
+/// thread, ends just the thread.
+pub fn exit(code: u8) -> ! {
+    io::try_flush_stdout();
+    let _ = sys_call(Syscall::Exit, code as isize, 0, 0, 0, 0, 0);
+    unreachable!("just in case!");
+}
@@ -0,0 +1,76 @@
+//! Random numbers for os1k programs
+//!
+//! A xoshiro128** generator, seeded once from the kernel with Syscall::GetRandom, so that each
+//! value after that costs no syscall. Fine for games and randomized tests, but not for secrets.
+
+use core::ops::Range;
+
+use crate::getrandom;
+use crate::sync::Mutex;
+
+struct Xoshiro128 {
+    s: [u32; 4],
+}
+
+impl Xoshiro128 {
+    fn seeded() -> Self {
+        let mut seed = [0u8; 16];
+        let _ = getrandom(&mut seed);
+        let mut s = [0u32; 4];
+        for (word, bytes) in s.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        // The state must not be all zero.
+        if s == [0; 4] {
+            s[0] = 1;
+        }
+        Self { s }
+    }
+
+    fn next(&mut self) -> u32 {
+        let s = &mut self.s;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 9;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(11);
+        result
+    }
+}
+
+static RNG: Mutex<Option<Xoshiro128>> = Mutex::new(None);
+
+fn with_rng<R>(f: impl FnOnce(&mut Xoshiro128) -> R) -> R {
+    f(RNG.lock().get_or_insert_with(Xoshiro128::seeded))
+}
+
+/// Returns a random u32.
+pub fn random_u32() -> u32 {
+    with_rng(Xoshiro128::next)
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    with_rng(|rng| {
+        for chunk in buf.chunks_mut(size_of::<u32>()) {
+            chunk.copy_from_slice(&rng.next().to_ne_bytes()[..chunk.len()]);
+        }
+    })
+}
+
+/// Returns a random number in `range`, each equally likely. Panics if the range is empty.
+pub fn range(range: Range<u32>) -> u32 {
+    assert!(range.start < range.end, "rand::range on an empty range");
+    let span = range.end - range.start;
+    // Values below `zone` fall evenly across the span; the few above it are drawn again.
+    let zone = u32::MAX - (u32::MAX - span + 1) % span;
+    loop {
+        let value = random_u32();
+        if value <= zone {
+            return range.start + value % span;
+        }
+    }
+}
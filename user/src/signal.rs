@@ -0,0 +1,71 @@
+//! Signals for os1k programs
+//!
+//! Ctrl-C ends the program in the foreground unless it sets a handler for Signal::Interrupt
+//! with `on`. The kernel then saves the registers of whatever the program was doing on its
+//! stack and calls `dispatch`, which runs the handler. On return, `restorer` hands the saved
+//! registers back with Syscall::SigReturn and the program carries on where it was.
+//!
+//! A handler can run between any two instructions, even ones holding a lock, so it should only
+//! set a flag for the program to look at, or clean up what it must and exit.
+
+use core::arch::naked_asm;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use common::error::check;
+use common::{OsError, Syscall};
+
+use crate::sys_call;
+
+pub use common::abi::Signal;
+
+// The handler `on` set for Signal::Interrupt, as a pointer, or null.
+static INTERRUPT_HANDLER: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Runs `handler` when `signal` arrives, instead of the default of being ended.
+pub fn on(signal: Signal, handler: fn(Signal)) -> Result<(), OsError> {
+    match signal {
+        Signal::Interrupt => INTERRUPT_HANDLER.store(handler as *mut (), Ordering::Relaxed),
+    }
+    let dispatch = dispatch as *const () as isize;
+    let restorer = restorer as *const () as isize;
+    check(sys_call(Syscall::SigAction, signal as isize, dispatch, restorer, 0, 0, 0)).map(|_| ())
+}
+
+/// Goes back to being ended when `signal` arrives.
+pub fn reset(signal: Signal) -> Result<(), OsError> {
+    check(sys_call(Syscall::SigAction, signal as isize, 0, 0, 0, 0, 0))?;
+    match signal {
+        Signal::Interrupt => INTERRUPT_HANDLER.store(ptr::null_mut(), Ordering::Relaxed),
+    }
+    Ok(())
+}
+
+// Where the kernel delivers a signal, with its number in a0 and `restorer` in ra.
+extern "C" fn dispatch(signo: usize) {
+    let Ok(signal) = Signal::try_from(signo) else {
+        return;
+    };
+    let handler = match signal {
+        Signal::Interrupt => INTERRUPT_HANDLER.load(Ordering::Relaxed),
+    };
+    if handler.is_null() {
+        return;
+    }
+    // Safety: only `on` stores handlers, and they are all fn(Signal)
+    let handler = unsafe { mem::transmute::<*mut (), fn(Signal)>(handler) };
+    handler(signal);
+}
+
+// Where `dispatch` returns to. The kernel saved the registers just above the stack pointer,
+// which is back where it was when `dispatch` was called.
+#[unsafe(naked)]
+unsafe extern "C" fn restorer() -> ! {
+    naked_asm!(
+        "mv a0, sp",
+        "li a4, {sigreturn}",
+        "ecall",
+        sigreturn = const Syscall::SigReturn as usize,
+    )
+}
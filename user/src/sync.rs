@@ -0,0 +1,74 @@
+//! Locks for os1k programs
+//!
+//! Mutex is the usual three-state futex lock: unlocked, locked, and locked with waiters. Taking
+//! or releasing an uncontended lock is one atomic operation, and only a contended one makes a
+//! syscall, to sleep in the kernel or to wake a sleeper.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering::{Acquire, Relaxed, Release}};
+
+use crate::{futex_wait, futex_wake};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;   // Locked, and someone may be waiting
+
+pub struct Mutex<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.state.compare_exchange(UNLOCKED, LOCKED, Acquire, Relaxed).is_err() {
+            // Mark the lock contended so the holder wakes us, then sleep until it is free.
+            while self.state.swap(CONTENDED, Acquire) != UNLOCKED {
+                let _ = futex_wait(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { lock: self }
+    }
+
+    // Takes the lock if it is free, without waiting.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state.compare_exchange(UNLOCKED, LOCKED, Acquire, Relaxed).ok()?;
+        Some(MutexGuard { lock: self })
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The existence of this guard guarantees exclusive access
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The existence of this guard guarantees exclusive access
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.swap(UNLOCKED, Release) == CONTENDED {
+            let _ = futex_wake(&self.lock.state, 1);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Test harness for os1k programs
+//!
+//! user_test! turns a list of test functions into a program's main. Each test runs in a process
+//! of its own, the program spawned again with `--run` and the test's name, so a test that panics
+//! or faults fails without taking the others down. The runner prints a line per test and a
+//! summary, reports the totals to the kernel with Syscall::TestReport, and exits with 0 if every
+//! test passed. A kernel built for self-test mode runs the tests at boot and exits QEMU with
+//! their result: see `./os1k.sh selftest`.
+
+use common::error::check;
+use common::{OsError, Syscall};
+
+use crate::process::{exit, spawn};
+use crate::{env, println, sys_call};
+
+/// A test function and its name, as user_test! lists them.
+#[derive(Clone, Copy, Debug)]
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+/// Runs `tests` and exits: each in a process of its own, or, when spawned with `--run`, just the
+/// one named.
+pub fn run(tests: &[Test]) -> ! {
+    if env::arg(1) == Some("--run") {
+        match tests.iter().find(|test| Some(test.name) == env::arg(2)) {
+            Some(test) => {
+                (test.run)();
+                exit(0)
+            },
+            None => exit(1),
+        }
+    }
+
+    let path = env::arg(0).expect("a program always gets its path");
+    println!("running {} tests", tests.len());
+    let mut failed = 0;
+    for test in tests {
+        match spawn(path, &["--run", test.name]).and_then(|pid| pid.wait()) {
+            Ok(status) if status.success() => println!("test {} ... ok", test.name),
+            Ok(status) => {
+                println!("test {} ... FAILED ({})", test.name, status);
+                failed += 1;
+            },
+            Err(e) => {
+                println!("test {} ... FAILED (could not run: {})", test.name, e);
+                failed += 1;
+            },
+        }
+    }
+
+    let passed = tests.len() - failed;
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!("test result: {}. {} passed; {} failed", result, passed, failed);
+    let _ = report(passed, failed);
+    exit(if failed == 0 { 0 } else { 1 })
+}
+
+/// Tells the kernel how many tests passed and failed. The kernel logs the totals, and in
+/// self-test mode exits QEMU with status 0 if none failed and 1 otherwise.
+pub fn report(passed: usize, failed: usize) -> Result<(), OsError> {
+    check(sys_call(Syscall::TestReport, passed as isize, failed as isize, 0, 0, 0, 0)).map(|_| ())
+}
+
+/// Defines main to run the test functions listed, such as `user_test!(adds, parses_numbers);`,
+/// with test::run.
+#[macro_export]
+macro_rules! user_test {
+    ($($test:path),* $(,)?) => {
+        #[unsafe(no_mangle)]
+        fn main() {
+            $crate::test::run(&[
+                $($crate::test::Test { name: stringify!($test), run: $test },)*
+            ]);
+        }
+    };
+}
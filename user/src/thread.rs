@@ -0,0 +1,93 @@
+//! Threads for os1k programs
+//!
+//! spawn runs a closure in a new thread: to the kernel a process of its own, with its own stack,
+//! that runs in the program's memory. The heap, statics and signal handlers are shared, so a
+//! sync::Mutex works between threads, but each thread starts with a copy of the open files and
+//! environment variables. join waits for the thread and returns what the closure returned.
+//!
+//! A thread that panics ends on its own, and join reports how. When the program's first thread
+//! ends, so do the others.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::mem;
+
+use common::error::check;
+use common::{OsError, Syscall};
+
+use crate::process::{exit, ExitStatus, Pid};
+use crate::sync::Mutex;
+use crate::sys_call;
+
+/// The size of the stack each thread gets, from the heap.
+pub const STACK_SIZE: usize = 16 * 1024;
+
+// What a new thread runs, handed to it through Syscall::ThreadCreate as a pointer.
+type Start = Box<dyn FnOnce()>;
+
+/// A running thread, from spawn.
+pub struct JoinHandle<T> {
+    pid: Pid,
+    result: Arc<Mutex<Option<T>>>,
+    stack: Option<Box<[u8]>>,     // Freed once the thread has ended
+}
+
+impl<T> JoinHandle<T> {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Waits for the thread to end and returns what its closure returned, or how the thread
+    /// ended if the closure did not return, as when it panicked.
+    pub fn join(mut self) -> Result<T, ExitStatus> {
+        let status = self.pid.wait().expect("only the JoinHandle waits for its thread");
+        drop(self.stack.take());
+        self.result.lock().take().ok_or(status)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // A thread that was never joined may still be running on its stack.
+        if let Some(stack) = self.stack.take() {
+            mem::forget(stack);
+        }
+    }
+}
+
+/// Runs `f` in a new thread. Fails with OsError::NoSpace if the kernel has no room for another
+/// process, which each thread takes.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, OsError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = Arc::clone(&result);
+    let start: Box<Start> = Box::new(Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    }));
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let stack_top = stack.as_mut_ptr_range().end as usize & !0xf;
+
+    let start = Box::into_raw(start);
+    let entry = thread_start as *const () as isize;
+    match check(sys_call(Syscall::ThreadCreate, entry, start as isize, stack_top as isize, 0, 0, 0)) {
+        Ok(pid) => Ok(JoinHandle { pid: Pid(pid), result, stack: Some(stack) }),
+        Err(e) => {
+            // Safety: no thread started to take it
+            drop(unsafe { Box::from_raw(start) });
+            Err(e)
+        },
+    }
+}
+
+// Where a new thread starts, on its own stack, with what spawn passed it.
+extern "C" fn thread_start(start: *mut Start) -> ! {
+    // Safety: spawn leaked the box for this thread alone
+    let start = unsafe { Box::from_raw(start) };
+    start();
+    exit(0)
+}
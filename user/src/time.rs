@@ -0,0 +1,70 @@
+//! Time for os1k programs
+//!
+//! Instant reads the monotonic clock, which counts from boot and never goes backwards. Sleeping
+//! gives up the CPU to other processes until the time is up. The kernel wakes sleepers on its
+//! timer tick, every 10 ms, so a sleep lasts at least as long as asked and up to a tick more.
+
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+use common::error::check;
+use common::{OsError, Syscall};
+
+use crate::io::{self, Write as _};
+use crate::{monotonic_ns, sys_call};
+
+/// A point in time, measured on the monotonic clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);   // Nanoseconds since boot
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(monotonic_ns())
+    }
+
+    /// The time since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+
+    /// The time from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Sleeps for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    // Whatever was printed before the pause should show during it.
+    let _ = io::stdout().flush();
+    // The kernel only fails when it has no timer left, so poll the clock instead.
+    let kernel_ms = ms.min(usize::MAX as u64) as usize;
+    if let Err(OsError::NoSpace) = check(sys_call(Syscall::Sleep, kernel_ms as isize, 0, 0, 0, 0, 0)) {
+        let deadline = Instant::now() + Duration::from_millis(ms);
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Sleeps for at least `duration`, to the next millisecond.
+pub fn sleep(duration: Duration) {
+    let ms = duration.as_nanos().div_ceil(1_000_000);
+    sleep_ms(u64::try_from(ms).unwrap_or(u64::MAX));
+}